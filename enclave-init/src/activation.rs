@@ -0,0 +1,322 @@
+//! Socket activation.
+//!
+//! A `.socket` unit declares listening sockets that init owns on behalf of a
+//! service. The service is started on the first incoming connection and gets
+//! the listeners as file descriptors 3 and up, described by `LISTEN_FDS`,
+//! `LISTEN_PID` and `LISTEN_FDNAMES` as in systemd. The listeners stay open
+//! while the service restarts, so queued connections are not lost.
+
+use anyhow::{anyhow, Context, Result};
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::sys::socket::{bind, listen, socket, AddressFamily, SockFlag, SockType, VsockAddr};
+use nix::unistd::dup2;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// First file descriptor passed to an activated service.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Backlog of VSOCK listeners; std uses the same for TCP and Unix listeners.
+const VSOCK_BACKLOG: usize = 128;
+
+/// A `.socket` unit file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SocketConfig {
+    /// Addresses to listen on: "8080", "127.0.0.1:8080", "[::]:8080",
+    /// "/run/app.sock" or "vsock:PORT" / "vsock:CID:PORT"
+    #[serde(default, deserialize_with = "crate::hooks::deserialize_commands")]
+    pub listen_stream: Vec<String>,
+    /// Service to activate; defaults to the unit's own name
+    #[serde(default)]
+    pub service: Option<String>,
+    /// Name passed in `LISTEN_FDNAMES`; defaults to the unit's name
+    #[serde(default)]
+    pub file_descriptor_name: Option<String>,
+    /// Permissions of Unix socket files, in octal
+    #[serde(default = "default_socket_mode")]
+    pub socket_mode: String,
+}
+
+fn default_socket_mode() -> String {
+    "0666".to_string()
+}
+
+/// Address of a listening stream socket.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    Vsock { cid: u32, port: u32 },
+}
+
+impl ListenAddress {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        if value.starts_with('/') {
+            return Ok(ListenAddress::Unix(PathBuf::from(value)));
+        }
+        if let Some(rest) = value.strip_prefix("vsock:") {
+            let invalid = || format!("invalid VSOCK address '{}'", value);
+            let (cid, port) = match rest.split_once(':') {
+                Some((cid, port)) => (cid.parse().map_err(|_| invalid())?, port),
+                None => (libc::VMADDR_CID_ANY, rest),
+            };
+            let port = port.parse().map_err(|_| invalid())?;
+            return Ok(ListenAddress::Vsock { cid, port });
+        }
+        if let Ok(port) = value.parse::<u16>() {
+            return Ok(ListenAddress::Tcp(SocketAddr::from(([0, 0, 0, 0], port))));
+        }
+        value
+            .parse::<SocketAddr>()
+            .map(ListenAddress::Tcp)
+            .map_err(|_| format!("invalid listen address '{}'", value))
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(addr) => write!(f, "tcp {}", addr),
+            ListenAddress::Unix(path) => write!(f, "unix {}", path.display()),
+            ListenAddress::Vsock { cid, port } => write!(f, "vsock {}:{}", cid, port),
+        }
+    }
+}
+
+/// The listeners of one `.socket` unit.
+#[derive(Debug)]
+pub struct SocketUnit {
+    pub name: String,
+    pub config: SocketConfig,
+    addresses: Vec<ListenAddress>,
+    socket_mode: u32,
+    /// Open listeners, in the order of `addresses`
+    listeners: Vec<Option<OwnedFd>>,
+}
+
+impl SocketUnit {
+    pub fn new(name: String, config: SocketConfig) -> Result<Self, String> {
+        if config.listen_stream.is_empty() {
+            return Err("no ListenStream".to_string());
+        }
+        let socket_mode = u32::from_str_radix(config.socket_mode.trim_start_matches("0o"), 8)
+            .map_err(|_| format!("invalid SocketMode '{}'", config.socket_mode))?;
+        let addresses = config
+            .listen_stream
+            .iter()
+            .map(|a| ListenAddress::parse(a))
+            .collect::<Result<Vec<_>, _>>()?;
+        let listeners = addresses.iter().map(|_| None).collect();
+        Ok(Self {
+            name,
+            config,
+            addresses,
+            socket_mode,
+            listeners,
+        })
+    }
+
+    /// Name of the service this unit activates.
+    pub fn service_name(&self) -> &str {
+        self.config.service.as_deref().unwrap_or(&self.name)
+    }
+
+    fn fd_name(&self) -> &str {
+        self.config.file_descriptor_name.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.listeners.iter().any(|l| l.is_some())
+    }
+
+    /// Bind every address that is not open yet.
+    pub fn open(&mut self) -> Result<()> {
+        for (address, listener) in self.addresses.iter().zip(self.listeners.iter_mut()) {
+            if listener.is_none() {
+                *listener = Some(
+                    open_listener(address, self.socket_mode)
+                        .with_context(|| format!("Failed to listen on {}", address))?,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Take over the open listeners of `old` for addresses this unit also uses.
+    pub fn adopt(&mut self, old: &mut SocketUnit) {
+        for (address, listener) in self.addresses.iter().zip(self.listeners.iter_mut()) {
+            if let Some(i) = old.addresses.iter().position(|a| a == address) {
+                if let Some(fd) = old.listeners[i].take() {
+                    *listener = Some(fd);
+                }
+            }
+        }
+    }
+
+    /// Open listeners with their `LISTEN_FDNAMES` name.
+    pub fn listen_fds(&self) -> Vec<(RawFd, &str)> {
+        self.listeners
+            .iter()
+            .flatten()
+            .map(|fd| (fd.as_raw_fd(), self.fd_name()))
+            .collect()
+    }
+
+    /// Listening addresses, for status output.
+    pub fn describe(&self) -> Vec<String> {
+        self.addresses
+            .iter()
+            .zip(self.listeners.iter())
+            .map(|(address, listener)| {
+                let state = if listener.is_some() { "" } else { " (not listening)" };
+                format!("{} [{}]{}", address, self.fd_name(), state)
+            })
+            .collect()
+    }
+}
+
+impl Drop for SocketUnit {
+    fn drop(&mut self) {
+        for (address, listener) in self.addresses.iter().zip(self.listeners.iter()) {
+            if let (ListenAddress::Unix(path), Some(_)) = (address, listener) {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+fn open_listener(address: &ListenAddress, mode: u32) -> Result<OwnedFd> {
+    match address {
+        ListenAddress::Tcp(addr) => Ok(TcpListener::bind(addr)?.into()),
+        ListenAddress::Unix(path) => {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let _ = fs::remove_file(path);
+            let listener = UnixListener::bind(path)?;
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+            Ok(listener.into())
+        }
+        ListenAddress::Vsock { cid, port } => {
+            let fd = socket(AddressFamily::Vsock, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)?;
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            bind(fd.as_raw_fd(), &VsockAddr::new(*cid, *port))?;
+            listen(fd.as_raw_fd(), VSOCK_BACKLOG)?;
+            Ok(fd)
+        }
+    }
+}
+
+pub fn parse_socket_file(path: &Path) -> Result<SocketConfig> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read socket file: {:?}", path))?;
+
+    toml::from_str(&content).with_context(|| format!("Failed to parse socket file: {:?}", path))
+}
+
+/// Wait until one of `fds` has a pending connection. Returns the indices of
+/// the readable descriptors; empty on timeout.
+pub fn wait_for_connection(fds: &[RawFd], timeout: Duration) -> Vec<usize> {
+    let mut pollfds: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|&fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+
+    let n = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout.as_millis() as i32) };
+    if n <= 0 {
+        return Vec::new();
+    }
+    pollfds
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| p.revents & libc::POLLIN != 0)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// First descriptor above the `count` descriptors passed to a service.
+/// Descriptors the child still needs must be moved here before
+/// `pass_fds_in_child` overwrites the range below it.
+pub fn first_free_fd(count: usize) -> RawFd {
+    LISTEN_FDS_START + count as RawFd
+}
+
+/// In a forked child: move `fds` to descriptors 3 and up, clearing close-on-exec.
+pub fn pass_fds_in_child(fds: &[RawFd]) -> Result<()> {
+    // Copy above the target range first, so no source is overwritten
+    let above = first_free_fd(fds.len());
+    let copies = fds
+        .iter()
+        .map(|&fd| fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(above)))
+        .collect::<nix::Result<Vec<_>>>()
+        .map_err(|e| anyhow!("Failed to duplicate listen fds: {}", e))?;
+
+    for (i, fd) in copies.into_iter().enumerate() {
+        dup2(fd, LISTEN_FDS_START + i as RawFd).map_err(|e| anyhow!("Failed to pass listen fd: {}", e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_address() {
+        assert_eq!(
+            ListenAddress::parse("8080").unwrap(),
+            ListenAddress::Tcp("0.0.0.0:8080".parse().unwrap())
+        );
+        assert_eq!(
+            ListenAddress::parse("[::1]:80").unwrap(),
+            ListenAddress::Tcp("[::1]:80".parse().unwrap())
+        );
+        assert_eq!(
+            ListenAddress::parse("/run/app.sock").unwrap(),
+            ListenAddress::Unix(PathBuf::from("/run/app.sock"))
+        );
+        assert_eq!(
+            ListenAddress::parse("vsock:5000").unwrap(),
+            ListenAddress::Vsock { cid: libc::VMADDR_CID_ANY, port: 5000 }
+        );
+        assert_eq!(
+            ListenAddress::parse("vsock:3:5000").unwrap(),
+            ListenAddress::Vsock { cid: 3, port: 5000 }
+        );
+        assert!(ListenAddress::parse("vsock:x").is_err());
+        assert!(ListenAddress::parse("localhost").is_err());
+    }
+
+    #[test]
+    fn test_socket_unit_listen_and_adopt() {
+        let config: SocketConfig =
+            toml::from_str("ListenStream = \"127.0.0.1:0\"\nFileDescriptorName = \"web\"").unwrap();
+        let mut unit = SocketUnit::new("app".to_string(), config.clone()).unwrap();
+        assert_eq!(unit.service_name(), "app");
+        assert!(!unit.is_listening());
+
+        unit.open().unwrap();
+        let fds = unit.listen_fds();
+        assert_eq!(fds.len(), 1);
+        assert_eq!(fds[0].1, "web");
+        let fd = fds[0].0;
+
+        let mut reloaded = SocketUnit::new("app".to_string(), config).unwrap();
+        reloaded.adopt(&mut unit);
+        assert!(!unit.is_listening());
+        assert_eq!(reloaded.listen_fds()[0].0, fd);
+    }
+}
@@ -0,0 +1,212 @@
+//! Authorization of control connections.
+//!
+//! Unix socket clients are local and get the admin role. VSock peers must
+//! connect from a CID in `vsock_allowed_cids`, and when `vsock_keys` is set
+//! they must answer an HMAC-SHA256 challenge with one of the pre-shared keys.
+//! The key determines the connection's role; requests the role does not
+//! permit are denied.
+
+use crate::config::ControlConfig;
+use crate::protocol::{auth_mac, Request, Role};
+use hmac::Mac;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Size of a challenge nonce in bytes.
+const NONCE_SIZE: usize = 32;
+
+/// Where a control connection comes from.
+#[derive(Debug, Clone, Copy)]
+pub enum Peer {
+    Unix,
+    Vsock { cid: u32 },
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Unix => write!(f, "unix socket"),
+            Peer::Vsock { cid } => write!(f, "VSOCK CID {}", cid),
+        }
+    }
+}
+
+/// Decision on a new connection, before any request is read.
+#[derive(Debug, PartialEq)]
+pub enum Admission {
+    Granted(Role),
+    /// The peer must authenticate with a pre-shared key
+    Challenge,
+    Denied(String),
+}
+
+pub fn admit(peer: Peer, control: &ControlConfig) -> Admission {
+    match peer {
+        Peer::Unix => Admission::Granted(Role::Admin),
+        Peer::Vsock { cid } => {
+            if !control.vsock_allowed_cids.is_empty() && !control.vsock_allowed_cids.contains(&cid) {
+                Admission::Denied(format!("peer CID {} is not allowed", cid))
+            } else if control.vsock_keys.is_empty() {
+                Admission::Granted(control.vsock_default_role)
+            } else {
+                Admission::Challenge
+            }
+        }
+    }
+}
+
+/// Random nonce for a challenge.
+pub fn new_nonce() -> io::Result<Vec<u8>> {
+    let mut nonce = vec![0u8; NONCE_SIZE];
+    File::open("/dev/urandom")?.read_exact(&mut nonce)?;
+    Ok(nonce)
+}
+
+/// Check a challenge response. Returns the role of the key on success.
+pub fn verify(control: &ControlConfig, key_name: &str, nonce: &[u8], mac: &[u8]) -> Option<Role> {
+    let role = *control.vsock_keys.get(key_name)?;
+    let key = load_key(&control.vsock_key_dir, key_name).ok()?;
+    auth_mac(&key, nonce).verify_slice(mac).ok()?;
+    Some(role)
+}
+
+/// Read the key `name` from `<dir>/<name>.key`, without trailing whitespace.
+fn load_key(dir: &str, name: &str) -> io::Result<Vec<u8>> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid key name"));
+    }
+    let mut key = fs::read(Path::new(dir).join(format!("{}.key", name)))?;
+    while key.last().is_some_and(|b| b.is_ascii_whitespace()) {
+        key.pop();
+    }
+    if key.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "empty key"));
+    }
+    Ok(key)
+}
+
+/// Whether `role` may issue `request`.
+pub fn permits(role: Role, request: &Request) -> bool {
+    match role {
+        Role::Admin => true,
+        // Listed exhaustively so new requests must be classified here
+        Role::ReadOnly => match request {
+            Request::ListServices
+            | Request::ServiceStatus { .. }
+            | Request::ServiceLogs { .. }
+            | Request::ServiceLogsQuery { .. }
+            | Request::ServiceLogsFollow { .. }
+            | Request::Subscribe { vsock: None, .. }
+            | Request::ServiceMetrics { .. }
+            | Request::ServiceValidate { .. }
+            | Request::ListTimers
+            | Request::ListDependencies { .. }
+            | Request::ListTargets
+            | Request::MeasurementLog
+            | Request::ProcessList
+            | Request::ProcessStatus { .. }
+            | Request::SystemStatus
+            | Request::Ping => true,
+            Request::ServiceStart { .. }
+            | Request::ServiceStop { .. }
+            | Request::ServiceRestart { .. }
+            | Request::ServiceReload { .. }
+            | Request::ServiceResetFailed { .. }
+            | Request::ServiceEnable { .. }
+            | Request::ServiceDisable { .. }
+            | Request::ServiceLogsClear { .. }
+            | Request::ServiceInstall { .. }
+            | Request::ServiceRemove { .. }
+            // Environment and service files commonly hold secrets
+            | Request::ServiceEnvironment { .. }
+            | Request::ServiceCat { .. }
+            | Request::ServiceLogsStream { .. }
+            | Request::ServiceLogsStreamStop { .. }
+            // Makes init connect out
+            | Request::Subscribe { vsock: Some(_), .. }
+            | Request::ProcessStart { .. }
+            | Request::ProcessStop { .. }
+            | Request::ProcessRestart { .. }
+            | Request::ProcessKill { .. }
+            | Request::Isolate { .. }
+            | Request::SystemReload
+            | Request::SystemReboot
+            | Request::SystemShutdown => false,
+        },
+    }
+}
+
+/// Name of a request's variant, for log messages.
+pub fn request_name(request: &Request) -> String {
+    match serde_json::to_value(request) {
+        Ok(serde_json::Value::String(name)) => name,
+        Ok(serde_json::Value::Object(map)) => map.keys().next().cloned().unwrap_or_default(),
+        _ => "request".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn control_with_key(dir: &Path) -> ControlConfig {
+        fs::write(dir.join("monitor.key"), "s3cret\n").unwrap();
+        let mut control = ControlConfig {
+            vsock_allowed_cids: vec![3],
+            vsock_key_dir: dir.to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        control.vsock_keys.insert("monitor".to_string(), Role::ReadOnly);
+        control
+    }
+
+    #[test]
+    fn test_admit() {
+        let dir = tempfile::tempdir().unwrap();
+        let control = control_with_key(dir.path());
+
+        assert_eq!(admit(Peer::Unix, &control), Admission::Granted(Role::Admin));
+        assert_eq!(admit(Peer::Vsock { cid: 3 }, &control), Admission::Challenge);
+        assert!(matches!(admit(Peer::Vsock { cid: 7 }, &control), Admission::Denied(_)));
+
+        // Without keys, VSOCK peers are read-only unless admin is opted into
+        let open = ControlConfig::default();
+        assert_eq!(admit(Peer::Vsock { cid: 7 }, &open), Admission::Granted(Role::ReadOnly));
+        let opted_in = ControlConfig {
+            vsock_default_role: Role::Admin,
+            ..Default::default()
+        };
+        assert_eq!(admit(Peer::Vsock { cid: 7 }, &opted_in), Admission::Granted(Role::Admin));
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let control = control_with_key(dir.path());
+        let nonce = new_nonce().unwrap();
+
+        let mac = auth_mac(b"s3cret", &nonce).finalize().into_bytes();
+        assert_eq!(verify(&control, "monitor", &nonce, &mac), Some(Role::ReadOnly));
+
+        let wrong = auth_mac(b"guess", &nonce).finalize().into_bytes();
+        assert_eq!(verify(&control, "monitor", &nonce, &wrong), None);
+        assert_eq!(verify(&control, "admin", &nonce, &mac), None);
+        assert_eq!(verify(&control, "../monitor", &nonce, &mac), None);
+    }
+
+    #[test]
+    fn test_permits() {
+        assert!(permits(Role::ReadOnly, &Request::ServiceStatus { name: "x".to_string() }));
+        assert!(!permits(Role::ReadOnly, &Request::SystemShutdown));
+        assert!(!permits(Role::ReadOnly, &Request::ProcessKill { pid: 1, signal: 9 }));
+        assert!(permits(Role::Admin, &Request::SystemShutdown));
+        let content = String::new();
+        assert!(permits(Role::ReadOnly, &Request::ServiceValidate { name: "x".to_string(), content: content.clone() }));
+        assert!(!permits(Role::ReadOnly, &Request::ServiceInstall { name: "x".to_string(), content }));
+        assert!(!permits(Role::ReadOnly, &Request::ServiceCat { name: "x".to_string() }));
+        assert_eq!(request_name(&Request::ProcessKill { pid: 1, signal: 9 }), "ProcessKill");
+        assert_eq!(request_name(&Request::Ping), "Ping");
+    }
+}
@@ -0,0 +1,382 @@
+//! Per-service cgroup v2 management.
+//!
+//! Init mounts the unified hierarchy at `/sys/fs/cgroup` and creates one child
+//! cgroup per service. Resource limits from the service file are written to the
+//! controller interface files before the service process joins the cgroup.
+//! The cgroup of a service that is removed is deleted once it is empty.
+
+use crate::logger::Logger;
+use crate::protocol::CgroupUsage;
+use anyhow::{Context, Result};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use serde::Deserialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Controllers delegated to service cgroups.
+const CONTROLLERS: &[&str] = &["cpu", "memory", "pids", "io"];
+
+/// Default CFS period used to translate `CPUQuota` percentages into `cpu.max`.
+const CPU_PERIOD_USEC: u64 = 100_000;
+
+/// Cgroups of removed services that still had processes when they were retired.
+static RETIRED: LazyLock<Mutex<Vec<Cgroup>>> = LazyLock::new(|| Mutex::new(Vec::new()));
+
+/// Resource limits for a service, applied through its cgroup.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct ResourceLimits {
+    /// Hard memory limit (`memory.max`), e.g. "512M" or "infinity"
+    #[serde(default)]
+    pub memory_max: Option<String>,
+    /// Memory throttling threshold (`memory.high`)
+    #[serde(default)]
+    pub memory_high: Option<String>,
+    /// CPU time quota as a percentage of one CPU (`cpu.max`), e.g. "150%"
+    #[serde(default, rename = "CPUQuota")]
+    pub cpu_quota: Option<String>,
+    /// Relative CPU weight, 1..=10000 (`cpu.weight`)
+    #[serde(default, rename = "CPUWeight")]
+    pub cpu_weight: Option<u64>,
+    /// Maximum number of tasks (`pids.max`)
+    #[serde(default)]
+    pub tasks_max: Option<u64>,
+    /// Relative IO weight, 1..=10000 (`io.weight`)
+    #[serde(default, rename = "IOWeight")]
+    pub io_weight: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Translate limits into `(interface file, value)` pairs.
+    pub fn to_cgroup_files(&self) -> Result<Vec<(&'static str, String)>, String> {
+        let mut files = Vec::new();
+
+        if let Some(ref v) = self.memory_max {
+            files.push(("memory.max", parse_memory(v)?));
+        }
+        if let Some(ref v) = self.memory_high {
+            files.push(("memory.high", parse_memory(v)?));
+        }
+        if let Some(ref v) = self.cpu_quota {
+            files.push(("cpu.max", parse_cpu_quota(v)?));
+        }
+        if let Some(v) = self.cpu_weight {
+            files.push(("cpu.weight", check_weight("CPUWeight", v)?.to_string()));
+        }
+        if let Some(v) = self.tasks_max {
+            files.push(("pids.max", v.to_string()));
+        }
+        if let Some(v) = self.io_weight {
+            files.push(("io.weight", format!("default {}", check_weight("IOWeight", v)?)));
+        }
+
+        Ok(files)
+    }
+}
+
+/// Parse a memory size with an optional K/M/G/T suffix (base 1024), or "infinity".
+fn parse_memory(value: &str) -> Result<String, String> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("infinity") || value == "max" {
+        return Ok("max".to_string());
+    }
+
+    let (digits, multiplier) = match value.chars().last() {
+        Some('K') | Some('k') => (&value[..value.len() - 1], 1u64 << 10),
+        Some('M') | Some('m') => (&value[..value.len() - 1], 1u64 << 20),
+        Some('G') | Some('g') => (&value[..value.len() - 1], 1u64 << 30),
+        Some('T') | Some('t') => (&value[..value.len() - 1], 1u64 << 40),
+        _ => (value, 1),
+    };
+
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .map(|n| n.to_string())
+        .ok_or_else(|| format!("invalid memory size '{}'", value))
+}
+
+/// Parse "NN%" into a `cpu.max` value ("<quota> <period>").
+fn parse_cpu_quota(value: &str) -> Result<String, String> {
+    let percent = value
+        .trim()
+        .strip_suffix('%')
+        .and_then(|p| p.trim().parse::<u64>().ok())
+        .filter(|p| *p > 0)
+        .ok_or_else(|| format!("invalid CPUQuota '{}', expected a percentage like '50%'", value))?;
+
+    Ok(format!("{} {}", percent * CPU_PERIOD_USEC / 100, CPU_PERIOD_USEC))
+}
+
+fn check_weight(key: &str, value: u64) -> Result<u64, String> {
+    if (1..=10000).contains(&value) {
+        Ok(value)
+    } else {
+        Err(format!("{} must be between 1 and 10000, got {}", key, value))
+    }
+}
+
+/// Check whether the unified cgroup v2 hierarchy is mounted.
+pub fn is_available() -> bool {
+    Path::new(CGROUP_ROOT).join("cgroup.controllers").exists()
+}
+
+/// Enable the service controllers in the root cgroup's subtree.
+pub fn enable_controllers() {
+    let available = fs::read_to_string(Path::new(CGROUP_ROOT).join("cgroup.controllers"))
+        .unwrap_or_default();
+
+    for controller in CONTROLLERS {
+        if !available.split_whitespace().any(|c| c == *controller) {
+            Logger::warn(&format!("cgroup controller '{}' not available", controller));
+            continue;
+        }
+        let path = Path::new(CGROUP_ROOT).join("cgroup.subtree_control");
+        match fs::write(&path, format!("+{}", controller)) {
+            Ok(_) => Logger::info(&format!("Enabled cgroup controller: {}", controller)),
+            Err(e) => {
+                Logger::warn(&format!("Failed to enable cgroup controller {}: {}", controller, e))
+            }
+        }
+    }
+}
+
+/// A service's cgroup under the unified hierarchy.
+#[derive(Debug, Clone)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Create (or reuse) the cgroup for a service.
+    pub fn create(service_name: &str) -> Result<Self> {
+        let path = Path::new(CGROUP_ROOT).join(service_name);
+        if !path.exists() {
+            fs::create_dir(&path)
+                .with_context(|| format!("Failed to create cgroup {:?}", path))?;
+        }
+        Ok(Self { path })
+    }
+
+    /// Write resource limits, resetting any that were removed from the service file.
+    pub fn apply_limits(&self, limits: &ResourceLimits) -> Result<()> {
+        let files = limits.to_cgroup_files().map_err(anyhow::Error::msg)?;
+
+        let defaults: [(&str, &str); 6] = [
+            ("memory.max", "max"),
+            ("memory.high", "max"),
+            ("cpu.max", "max"),
+            ("cpu.weight", "100"),
+            ("pids.max", "max"),
+            ("io.weight", "default 100"),
+        ];
+
+        for (file, default) in defaults {
+            let value = files
+                .iter()
+                .find(|(f, _)| *f == file)
+                .map(|(_, v)| v.as_str())
+                .unwrap_or(default);
+
+            let path = self.path.join(file);
+            if !path.exists() {
+                if value != default {
+                    Logger::warn(&format!(
+                        "cgroup interface {:?} not available, limit not applied",
+                        path
+                    ));
+                }
+                continue;
+            }
+            fs::write(&path, value).with_context(|| format!("Failed to write {:?}", path))?;
+        }
+
+        Ok(())
+    }
+
+    /// Move the calling process into this cgroup. Called in the forked child.
+    pub fn enter(&self) -> Result<()> {
+        fs::write(self.path.join("cgroup.procs"), std::process::id().to_string())
+            .with_context(|| format!("Failed to join cgroup {:?}", self.path))
+    }
+
+    /// PIDs of all processes currently in the cgroup.
+    pub fn pids(&self) -> Vec<Pid> {
+        fs::read_to_string(self.path.join("cgroup.procs"))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|l| l.trim().parse::<i32>().ok())
+            .map(Pid::from_raw)
+            .collect()
+    }
+
+    /// Send a signal to every process in the cgroup. Returns the number signalled.
+    pub fn signal_all(&self, signal: Signal) -> usize {
+        self.pids()
+            .into_iter()
+            .filter(|pid| kill(*pid, signal).is_ok())
+            .count()
+    }
+
+    /// SIGKILL every process in the cgroup, using `cgroup.kill` when the kernel supports it.
+    pub fn kill_all(&self) {
+        let kill_file = self.path.join("cgroup.kill");
+        if kill_file.exists() && fs::write(&kill_file, "1").is_ok() {
+            return;
+        }
+        self.signal_all(Signal::SIGKILL);
+    }
+
+    /// Current resource usage of the cgroup.
+    pub fn usage(&self) -> CgroupUsage {
+        usage_at(&self.path)
+    }
+
+    /// Delete the cgroup directory. Returns false while it still has processes.
+    fn remove(&self) -> bool {
+        match fs::remove_dir(&self.path) {
+            Ok(()) => true,
+            Err(e) if e.kind() == ErrorKind::NotFound => true,
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => false,
+            Err(e) => {
+                Logger::warn(&format!("Failed to remove cgroup {:?}: {}", self.path, e));
+                true
+            }
+        }
+    }
+}
+
+/// Delete the cgroup of a service that was removed. Processes left in it are
+/// killed, and the directory is deleted by `remove_retired` once they are gone.
+pub fn retire(cgroup: Cgroup) {
+    if cgroup.remove() {
+        return;
+    }
+    let remaining = cgroup.pids().len();
+    if remaining > 0 {
+        Logger::info(&format!("Killing {} remaining process(es) in cgroup {:?}", remaining, cgroup.path));
+        cgroup.kill_all();
+    }
+    RETIRED.lock().unwrap().push(cgroup);
+}
+
+/// Delete retired cgroups that have become empty.
+pub fn remove_retired() {
+    RETIRED.lock().unwrap().retain(|cgroup| !cgroup.remove());
+}
+
+fn read_u64(path: &Path) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn usage_at(path: &Path) -> CgroupUsage {
+    let cpu_usage_usec = fs::read_to_string(path.join("cpu.stat"))
+        .ok()
+        .and_then(|content| {
+            content
+                .lines()
+                .find_map(|l| l.strip_prefix("usage_usec "))
+                .and_then(|v| v.trim().parse().ok())
+        })
+        .unwrap_or(0);
+
+    let memory_max_bytes = fs::read_to_string(path.join("memory.max"))
+        .ok()
+        .and_then(|v| v.trim().parse().ok());
+
+    CgroupUsage {
+        path: path.to_string_lossy().to_string(),
+        memory_current_bytes: read_u64(&path.join("memory.current")).unwrap_or(0),
+        memory_peak_bytes: read_u64(&path.join("memory.peak")),
+        memory_max_bytes,
+        cpu_usage_usec,
+        tasks_current: read_u64(&path.join("pids.current")).unwrap_or(0),
+    }
+}
+
+/// Resource usage of the cgroup a process belongs to, if it is a service cgroup.
+pub fn usage_for_pid(pid: i32) -> Option<CgroupUsage> {
+    let content = fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    let relative = content
+        .lines()
+        .find_map(|l| l.strip_prefix("0::"))?
+        .trim()
+        .trim_start_matches('/');
+
+    if relative.is_empty() {
+        return None;
+    }
+
+    let path = Path::new(CGROUP_ROOT).join(relative);
+    if path.is_dir() {
+        Some(usage_at(&path))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(parse_memory("1024").unwrap(), "1024");
+        assert_eq!(parse_memory("512M").unwrap(), (512u64 << 20).to_string());
+        assert_eq!(parse_memory("2G").unwrap(), (2u64 << 30).to_string());
+        assert_eq!(parse_memory("infinity").unwrap(), "max");
+        assert!(parse_memory("lots").is_err());
+    }
+
+    #[test]
+    fn test_parse_cpu_quota() {
+        assert_eq!(parse_cpu_quota("50%").unwrap(), "50000 100000");
+        assert_eq!(parse_cpu_quota("200%").unwrap(), "200000 100000");
+        assert!(parse_cpu_quota("50").is_err());
+        assert!(parse_cpu_quota("0%").is_err());
+    }
+
+    #[test]
+    fn test_limits_to_files() {
+        let limits = ResourceLimits {
+            memory_max: Some("1G".to_string()),
+            cpu_weight: Some(200),
+            tasks_max: Some(64),
+            io_weight: Some(50),
+            ..Default::default()
+        };
+        let files = limits.to_cgroup_files().unwrap();
+        assert!(files.contains(&("memory.max", (1u64 << 30).to_string())));
+        assert!(files.contains(&("cpu.weight", "200".to_string())));
+        assert!(files.contains(&("pids.max", "64".to_string())));
+        assert!(files.contains(&("io.weight", "default 50".to_string())));
+
+        let bad = ResourceLimits {
+            cpu_weight: Some(0),
+            ..Default::default()
+        };
+        assert!(bad.to_cgroup_files().is_err());
+    }
+
+    #[test]
+    fn test_retire() {
+        let dir = tempfile::tempdir().unwrap();
+        let cgroup = Cgroup { path: dir.path().join("web") };
+        fs::create_dir(&cgroup.path).unwrap();
+        retire(cgroup.clone());
+        assert!(!cgroup.path.exists());
+        assert!(RETIRED.lock().unwrap().iter().all(|c| c.path != cgroup.path));
+
+        // Retired cgroups are dropped once their directory is gone
+        RETIRED.lock().unwrap().push(cgroup.clone());
+        remove_retired();
+        assert!(RETIRED.lock().unwrap().iter().all(|c| c.path != cgroup.path));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::protocol::Role;
 use anyhow::{Context, Result};
 use serde::Deserialize;
 use std::collections::HashMap;
@@ -25,6 +26,18 @@ pub struct InitConfig {
     /// Maximum number of log files to keep
     pub max_log_files: usize,
 
+    /// Maximum size of all log files of a service in bytes; 0 for no limit
+    pub max_log_total_size: u64,
+
+    /// Age in seconds after which rotated log files are deleted; 0 to keep them
+    pub max_log_age_sec: u64,
+
+    /// Compression of rotated log files
+    pub log_compression: Compression,
+
+    /// Maximum size of the log directory across all services in bytes; 0 for no limit
+    pub log_disk_budget: u64,
+
     /// Environment variables for init system
     pub environment: HashMap<String, String>,
 
@@ -39,6 +52,18 @@ pub struct InitConfig {
 
     /// Pivot root source directory
     pub pivot_root_dir: String,
+
+    /// Target to boot into; without one, every enabled service is started
+    pub default_target: Option<String>,
+
+    /// Forwarding of all service logs to a collector on the host
+    pub log_forward: LogForwardConfig,
+
+    /// Sampling of per-service metrics
+    pub metrics: MetricsConfig,
+
+    /// Hashing of every service executable before it runs
+    pub measurement: MeasurementConfig,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -58,6 +83,20 @@ pub struct ControlConfig {
 
     /// VSOCK port for control interface
     pub vsock_port: u32,
+
+    /// Peer CIDs allowed to connect over VSOCK (empty allows any)
+    pub vsock_allowed_cids: Vec<u32>,
+
+    /// Pre-shared keys of VSOCK clients by name, with the role each grants.
+    /// When set, VSOCK clients must authenticate with one of them.
+    pub vsock_keys: HashMap<String, Role>,
+
+    /// Directory holding the key files, named `<name>.key`
+    pub vsock_key_dir: String,
+
+    /// Role of VSOCK clients when no keys are configured. Admin must be
+    /// opted into explicitly.
+    pub vsock_default_role: Role,
 }
 
 impl Default for ControlConfig {
@@ -68,6 +107,10 @@ impl Default for ControlConfig {
             vsock_enabled: false,
             vsock_cid: 3,
             vsock_port: 9001,
+            vsock_allowed_cids: Vec::new(),
+            vsock_keys: HashMap::new(),
+            vsock_key_dir: "/etc/init/keys".to_string(),
+            vsock_default_role: Role::ReadOnly,
         }
     }
 }
@@ -95,6 +138,120 @@ impl Default for VsockConfig {
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LogForwardConfig {
+    /// Enable log forwarding
+    pub enabled: bool,
+
+    /// VSOCK CID of the collector
+    pub cid: u32,
+
+    /// VSOCK port of the collector
+    pub port: u32,
+
+    /// Records held in memory while the collector is unreachable or slow
+    pub buffer_records: usize,
+
+    /// Directory for records that do not fit in memory; none keeps them in memory only
+    pub spool_dir: Option<String>,
+
+    /// Maximum size of the on-disk spool in bytes
+    pub spool_max_size: u64,
+
+    /// What to do when the buffers are full
+    pub overflow: OverflowPolicy,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Discard the oldest records
+    #[default]
+    DropOldest,
+    /// Make services wait when writing output until there is room
+    Block,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Sample service metrics
+    pub enabled: bool,
+
+    /// Seconds between samples
+    pub interval_sec: u64,
+
+    /// Samples kept per service
+    pub history: usize,
+
+    /// Serve the latest samples in the Prometheus text format over VSOCK
+    pub prometheus_enabled: bool,
+
+    /// VSOCK CID to bind the Prometheus endpoint to
+    pub prometheus_cid: u32,
+
+    /// VSOCK port of the Prometheus endpoint
+    pub prometheus_port: u32,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_sec: 10,
+            history: 360, // 1 hour at the default interval
+            prometheus_enabled: false,
+            prometheus_cid: 4294967295, // VMADDR_CID_ANY
+            prometheus_port: 9003,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MeasurementConfig {
+    /// Hash the executable of every service start, not only of services with
+    /// `ExecHash`, and record it in the measurement log
+    pub enabled: bool,
+
+    /// Hash algorithm for services without `ExecHash`
+    pub algorithm: HashAlgorithm,
+}
+
+/// Hash algorithm of executable measurements
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    #[default]
+    #[serde(rename = "sha256")]
+    Sha256,
+    #[serde(rename = "sha3-512")]
+    Sha3_512,
+}
+
+/// How rotated log files are stored
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    None,
+    #[default]
+    Gzip,
+}
+
+impl Default for LogForwardConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cid: 3,
+            port: 9002,
+            buffer_records: 10000,
+            spool_dir: None,
+            spool_max_size: 64 * 1024 * 1024, // 64 MB
+            overflow: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
 impl Default for InitConfig {
     fn default() -> Self {
         Self {
@@ -103,11 +260,19 @@ impl Default for InitConfig {
             control: ControlConfig::default(),
             max_log_size: 10 * 1024 * 1024, // 10 MB
             max_log_files: 5,
+            max_log_total_size: 0,
+            max_log_age_sec: 0,
+            log_compression: Compression::Gzip,
+            log_disk_budget: 0,
             environment: HashMap::new(),
             vsock: VsockConfig::default(),
             nsm_driver_path: Some("nsm.ko".to_string()),
             pivot_root: true,
             pivot_root_dir: "/rootfs".to_string(),
+            default_target: None,
+            log_forward: LogForwardConfig::default(),
+            metrics: MetricsConfig::default(),
+            measurement: MeasurementConfig::default(),
         }
     }
 }
@@ -158,6 +323,12 @@ pub struct InitctlConfig {
 
     /// VSOCK port
     pub vsock_port: u32,
+
+    /// Name of the pre-shared key to authenticate with
+    pub key_name: Option<String>,
+
+    /// File holding the pre-shared key
+    pub key_file: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize, PartialEq)]
@@ -180,6 +351,8 @@ impl Default for InitctlConfig {
             unix_socket_path: "/run/init.sock".to_string(),
             vsock_cid: 3,
             vsock_port: 9001,
+            key_name: None,
+            key_file: None,
         }
     }
 }
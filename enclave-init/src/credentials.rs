@@ -0,0 +1,506 @@
+//! Service identity and capabilities.
+//!
+//! `User`, `Group` and `SupplementaryGroups` are resolved in init before the
+//! fork; the forked child then narrows the capability bounding set, switches
+//! to the service's identity, raises `AmbientCapabilities` and sets
+//! `NoNewPrivileges` right before exec. `RuntimeDirectory` (under `/run`) and
+//! `StateDirectory` (under `/var/lib`) are created for the service's user; the
+//! runtime directories are removed again when the service stops.
+
+use anyhow::{anyhow, Context, Result};
+use nix::unistd::{chown, getgrouplist, setgroups, setresgid, setresuid, Gid, Group, Uid, User};
+use serde::{Deserialize, Deserializer};
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+const RUNTIME_DIRECTORY_ROOT: &str = "/run";
+const STATE_DIRECTORY_ROOT: &str = "/var/lib";
+
+/// Linux capabilities by number.
+const CAPABILITIES: [&str; 41] = [
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+/// Identity and privilege settings of a service.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct CredentialsConfig {
+    /// User name or numeric UID to run as
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Group name or numeric GID; defaults to the user's primary group
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_words")]
+    pub supplementary_groups: Vec<String>,
+    /// Capabilities kept across the switch to `User`
+    #[serde(default, deserialize_with = "deserialize_words")]
+    pub ambient_capabilities: Vec<String>,
+    /// Capabilities the service may ever hold; a leading `~` inverts the list
+    #[serde(default, deserialize_with = "deserialize_optional_words")]
+    pub capability_bounding_set: Option<Vec<String>>,
+    #[serde(default)]
+    pub no_new_privileges: bool,
+    /// Directories created under /run, removed when the service stops
+    #[serde(default, deserialize_with = "deserialize_words")]
+    pub runtime_directory: Vec<String>,
+    #[serde(default = "default_directory_mode")]
+    pub runtime_directory_mode: String,
+    /// Directories created under /var/lib and kept across restarts
+    #[serde(default, deserialize_with = "deserialize_words")]
+    pub state_directory: Vec<String>,
+    #[serde(default = "default_directory_mode")]
+    pub state_directory_mode: String,
+}
+
+impl Default for CredentialsConfig {
+    fn default() -> Self {
+        Self {
+            user: None,
+            group: None,
+            supplementary_groups: Vec::new(),
+            ambient_capabilities: Vec::new(),
+            capability_bounding_set: None,
+            no_new_privileges: false,
+            runtime_directory: Vec::new(),
+            runtime_directory_mode: default_directory_mode(),
+            state_directory: Vec::new(),
+            state_directory_mode: default_directory_mode(),
+        }
+    }
+}
+
+fn default_directory_mode() -> String {
+    "0755".to_string()
+}
+
+/// A space-separated string or a list of strings.
+pub fn deserialize_words<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let values = crate::hooks::deserialize_commands(deserializer)?;
+    Ok(values.iter().flat_map(|v| v.split_whitespace()).map(str::to_string).collect())
+}
+
+fn deserialize_optional_words<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    deserialize_words(deserializer).map(Some)
+}
+
+impl CredentialsConfig {
+    /// Check capability names, directory names and modes.
+    pub fn validate(&self) -> Result<(), String> {
+        let ambient = capability_mask(&self.ambient_capabilities)?;
+        if let Some(ref set) = self.capability_bounding_set {
+            let bounding = capability_mask(set)?;
+            if ambient & !bounding != 0 {
+                return Err("AmbientCapabilities must be included in CapabilityBoundingSet".to_string());
+            }
+        }
+        for directory in self.runtime_directory.iter().chain(self.state_directory.iter()) {
+            let path = Path::new(directory);
+            if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+                return Err(format!("directory '{}' must be a relative path without '..'", directory));
+            }
+        }
+        parse_mode(&self.runtime_directory_mode)?;
+        parse_mode(&self.state_directory_mode)?;
+        Ok(())
+    }
+
+    /// Whether the service runs with init's own identity and privileges.
+    pub fn is_default(&self) -> bool {
+        self.user.is_none()
+            && self.group.is_none()
+            && self.supplementary_groups.is_empty()
+            && self.ambient_capabilities.is_empty()
+            && self.capability_bounding_set.is_none()
+            && !self.no_new_privileges
+    }
+
+    fn runtime_directories(&self) -> Vec<PathBuf> {
+        self.runtime_directory.iter().map(|d| Path::new(RUNTIME_DIRECTORY_ROOT).join(d)).collect()
+    }
+
+    fn state_directories(&self) -> Vec<PathBuf> {
+        self.state_directory.iter().map(|d| Path::new(STATE_DIRECTORY_ROOT).join(d)).collect()
+    }
+
+    /// Runtime and state directories of the service.
+    pub fn directories(&self) -> Vec<PathBuf> {
+        let mut directories = self.runtime_directories();
+        directories.extend(self.state_directories());
+        directories
+    }
+
+    /// Look up users and groups. Runs in init, before the fork.
+    pub fn resolve(&self) -> Result<Credentials> {
+        let user = self.user.as_deref().map(lookup_user).transpose()?;
+        let gid = match self.group.as_deref() {
+            Some(group) => Some(lookup_group(group)?),
+            None => user.as_ref().map(|u| u.gid),
+        };
+
+        let mut groups = None;
+        if user.is_some() || gid.is_some() || !self.supplementary_groups.is_empty() {
+            let mut list = Vec::new();
+            if let (Some(user), Some(gid)) = (&user, gid) {
+                if let Ok(name) = CString::new(user.name.as_str()) {
+                    list = getgrouplist(&name, gid).unwrap_or_default();
+                }
+            }
+            for group in &self.supplementary_groups {
+                list.push(lookup_group(group)?);
+            }
+            list.sort_by_key(|g| g.as_raw());
+            list.dedup();
+            groups = Some(list);
+        }
+
+        let mut env = Vec::new();
+        if let Some(ref user) = user {
+            env.push(format!("USER={}", user.name));
+            env.push(format!("LOGNAME={}", user.name));
+            env.push(format!("HOME={}", user.dir.display()));
+            env.push(format!("SHELL={}", user.shell.display()));
+        }
+        for (var, directories) in [
+            ("RUNTIME_DIRECTORY", self.runtime_directories()),
+            ("STATE_DIRECTORY", self.state_directories()),
+        ] {
+            if !directories.is_empty() {
+                let paths: Vec<String> = directories.iter().map(|d| d.display().to_string()).collect();
+                env.push(format!("{}={}", var, paths.join(":")));
+            }
+        }
+
+        let bounding = match self.capability_bounding_set {
+            Some(ref set) => Some(capability_mask(set).map_err(|e| anyhow!(e))?),
+            None => None,
+        };
+
+        Ok(Credentials {
+            uid: user.map(|u| u.uid),
+            gid,
+            groups,
+            ambient: capability_mask(&self.ambient_capabilities).map_err(|e| anyhow!(e))?,
+            bounding,
+            no_new_privileges: self.no_new_privileges,
+            env,
+        })
+    }
+
+    /// Create the runtime and state directories, owned by the service's user.
+    pub fn create_directories(&self, credentials: &Credentials) -> Result<()> {
+        for (directories, mode) in [
+            (self.runtime_directories(), &self.runtime_directory_mode),
+            (self.state_directories(), &self.state_directory_mode),
+        ] {
+            let mode = parse_mode(mode).map_err(|e| anyhow!(e))?;
+            for directory in directories {
+                fs::create_dir_all(&directory)
+                    .with_context(|| format!("Failed to create {}", directory.display()))?;
+                chown(&directory, credentials.uid, credentials.gid)
+                    .with_context(|| format!("Failed to chown {}", directory.display()))?;
+                fs::set_permissions(&directory, fs::Permissions::from_mode(mode))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the runtime directories of a stopped service.
+    pub fn remove_runtime_directories(&self) {
+        for directory in self.runtime_directories() {
+            let _ = fs::remove_dir_all(directory);
+        }
+    }
+
+    /// Summary for `initctl status`, e.g. "app:app +video, NoNewPrivileges".
+    pub fn describe(&self) -> Option<String> {
+        if self.is_default() {
+            return None;
+        }
+        let mut parts = Vec::new();
+        let mut identity = self.user.clone().unwrap_or_else(|| "root".to_string());
+        if let Some(ref group) = self.group {
+            identity = format!("{}:{}", identity, group);
+        }
+        for group in &self.supplementary_groups {
+            identity = format!("{} +{}", identity, group);
+        }
+        parts.push(identity);
+        if !self.ambient_capabilities.is_empty() {
+            parts.push(format!("ambient {}", self.ambient_capabilities.join(" ")));
+        }
+        if let Some(ref set) = self.capability_bounding_set {
+            parts.push(format!("bounding {}", set.join(" ")));
+        }
+        if self.no_new_privileges {
+            parts.push("NoNewPrivileges".to_string());
+        }
+        Some(parts.join(", "))
+    }
+}
+
+/// Resolved identity of a service, applied in the forked child.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub uid: Option<Uid>,
+    pub gid: Option<Gid>,
+    groups: Option<Vec<Gid>>,
+    ambient: u64,
+    bounding: Option<u64>,
+    no_new_privileges: bool,
+    /// USER, HOME, RUNTIME_DIRECTORY and friends
+    pub env: Vec<String>,
+}
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+impl Credentials {
+    /// Drop privileges in the forked child. Must run right before exec.
+    pub fn apply_in_child(&self) -> Result<()> {
+        if let Some(bounding) = self.bounding {
+            for cap in 0..CAPABILITIES.len() as u64 {
+                if bounding & (1 << cap) == 0 {
+                    prctl(libc::PR_CAPBSET_DROP, cap).context("Failed to drop capability from bounding set")?;
+                }
+            }
+        }
+
+        let switch_user = self.uid.is_some_and(|uid| !uid.is_root());
+        if self.ambient != 0 && switch_user {
+            prctl(libc::PR_SET_KEEPCAPS, 1).context("Failed to keep capabilities")?;
+        }
+        if let Some(ref groups) = self.groups {
+            setgroups(groups).context("Failed to set supplementary groups")?;
+        }
+        if let Some(gid) = self.gid {
+            setresgid(gid, gid, gid).context("Failed to set group")?;
+        }
+        if let Some(uid) = self.uid {
+            setresuid(uid, uid, uid).context("Failed to set user")?;
+        }
+
+        if self.ambient != 0 {
+            capset(self.ambient)?;
+            for cap in 0..CAPABILITIES.len() as u64 {
+                if self.ambient & (1 << cap) != 0 {
+                    let raise = libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong;
+                    let ret = unsafe { libc::prctl(libc::PR_CAP_AMBIENT, raise, cap as libc::c_ulong, 0 as libc::c_ulong, 0 as libc::c_ulong) };
+                    if ret != 0 {
+                        return Err(anyhow!(
+                            "Failed to raise ambient {}: {}",
+                            CAPABILITIES[cap as usize],
+                            std::io::Error::last_os_error()
+                        ));
+                    }
+                }
+            }
+        }
+
+        if self.no_new_privileges {
+            prctl(libc::PR_SET_NO_NEW_PRIVS, 1).context("Failed to set NoNewPrivileges")?;
+        }
+        Ok(())
+    }
+}
+
+fn prctl(option: libc::c_int, arg: u64) -> std::io::Result<()> {
+    let ret = unsafe { libc::prctl(option, arg as libc::c_ulong, 0 as libc::c_ulong, 0 as libc::c_ulong, 0 as libc::c_ulong) };
+    // Capabilities unknown to the running kernel cannot be dropped
+    if ret != 0 && !(option == libc::PR_CAPBSET_DROP && nix::errno::Errno::last() == nix::errno::Errno::EINVAL) {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Set the permitted, effective and inheritable sets to `caps`, as required
+/// before raising them as ambient capabilities.
+fn capset(caps: u64) -> Result<()> {
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapUserData::default(); 2];
+    for (i, word) in data.iter_mut().enumerate() {
+        let bits = (caps >> (32 * i)) as u32;
+        *word = CapUserData {
+            effective: bits,
+            permitted: bits,
+            inheritable: bits,
+        };
+    }
+    let ret = unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_mut_ptr()) };
+    if ret != 0 {
+        return Err(anyhow!("Failed to set capabilities: {}", std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Number of a capability, by name with or without the `CAP_` prefix.
+fn capability_number(name: &str) -> Option<u64> {
+    let upper = name.to_ascii_uppercase();
+    let full = if upper.starts_with("CAP_") { upper } else { format!("CAP_{}", upper) };
+    CAPABILITIES.iter().position(|c| *c == full).map(|n| n as u64)
+}
+
+/// Bit mask of a capability list. A list starting with `~` selects every
+/// capability except the listed ones.
+fn capability_mask(names: &[String]) -> Result<u64, String> {
+    let all = (1u64 << CAPABILITIES.len()) - 1;
+    let mut invert = false;
+    let mut mask = 0u64;
+    for (i, name) in names.iter().enumerate() {
+        let name = match name.strip_prefix('~') {
+            Some(rest) if i == 0 => {
+                invert = true;
+                rest
+            }
+            _ => name.as_str(),
+        };
+        if name.is_empty() {
+            continue;
+        }
+        let cap = capability_number(name).ok_or_else(|| format!("unknown capability '{}'", name))?;
+        mask |= 1 << cap;
+    }
+    Ok(if invert { all & !mask } else { mask })
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|m| *m <= 0o7777)
+        .ok_or_else(|| format!("invalid directory mode '{}'", mode))
+}
+
+fn lookup_user(user: &str) -> Result<User> {
+    let found = match user.parse::<u32>() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid))?,
+        Err(_) => User::from_name(user)?,
+    };
+    found.ok_or_else(|| anyhow!("Unknown user '{}'", user))
+}
+
+fn lookup_group(group: &str) -> Result<Gid> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(Gid::from_raw(gid));
+    }
+    Group::from_name(group)?
+        .map(|g| g.gid)
+        .ok_or_else(|| anyhow!("Unknown group '{}'", group))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capability_mask() {
+        let names = |s: &str| s.split_whitespace().map(str::to_string).collect::<Vec<_>>();
+        assert_eq!(capability_mask(&names("CAP_NET_BIND_SERVICE")).unwrap(), 1 << 10);
+        assert_eq!(capability_mask(&names("chown cap_kill")).unwrap(), 1 | 1 << 5);
+        let inverted = capability_mask(&names("~CAP_SYS_ADMIN")).unwrap();
+        assert_eq!(inverted & (1 << 21), 0);
+        assert_ne!(inverted & (1 << 10), 0);
+        assert!(capability_mask(&names("CAP_FLY")).is_err());
+    }
+
+    #[test]
+    fn test_credentials_config() {
+        let config: CredentialsConfig = toml::from_str(
+            "User = \"app\"\nSupplementaryGroups = \"video audio\"\nAmbientCapabilities = [\"CAP_NET_BIND_SERVICE\"]\nCapabilityBoundingSet = \"CAP_NET_BIND_SERVICE CAP_NET_RAW\"\nRuntimeDirectory = \"app\"",
+        )
+        .unwrap();
+        assert_eq!(config.supplementary_groups, vec!["video", "audio"]);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.describe().unwrap(), "app +video +audio, ambient CAP_NET_BIND_SERVICE, bounding CAP_NET_BIND_SERVICE CAP_NET_RAW");
+
+        let outside = CredentialsConfig {
+            ambient_capabilities: vec!["CAP_SYS_ADMIN".to_string()],
+            capability_bounding_set: Some(vec!["CAP_CHOWN".to_string()]),
+            ..Default::default()
+        };
+        assert!(outside.validate().is_err());
+
+        let escape = CredentialsConfig {
+            state_directory: vec!["../etc".to_string()],
+            ..Default::default()
+        };
+        assert!(escape.validate().is_err());
+        assert!(CredentialsConfig::default().describe().is_none());
+    }
+
+    #[test]
+    fn test_resolve_root() {
+        let config = CredentialsConfig {
+            user: Some("0".to_string()),
+            state_directory: vec!["app".to_string()],
+            ..Default::default()
+        };
+        let credentials = config.resolve().unwrap();
+        assert_eq!(credentials.uid, Some(Uid::from_raw(0)));
+        assert!(credentials.env.contains(&"USER=root".to_string()));
+        assert!(credentials.env.contains(&"STATE_DIRECTORY=/var/lib/app".to_string()));
+
+        let unknown = CredentialsConfig {
+            user: Some("no-such-user-here".to_string()),
+            ..Default::default()
+        };
+        assert!(unknown.resolve().is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{HashMap, HashSet};
 
 #[derive(Debug, Clone)]
 pub struct ServiceDependencies {
@@ -6,6 +6,10 @@ pub struct ServiceDependencies {
     pub after: Vec<String>,
     pub requires: Vec<String>,
     pub required_by: Vec<String>,
+    /// Like `requires`, and stopped whenever one of these stops
+    pub binds_to: Vec<String>,
+    /// Stopped and restarted together with these, without being started by them
+    pub part_of: Vec<String>,
 }
 
 impl ServiceDependencies {
@@ -15,10 +19,48 @@ impl ServiceDependencies {
             after: Vec::new(),
             requires: Vec::new(),
             required_by: Vec::new(),
+            binds_to: Vec::new(),
+            part_of: Vec::new(),
         }
     }
 }
 
+/// Why one service is linked to another in a dependency tree.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Relation {
+    Requires,
+    BindsTo,
+    /// Inverse of `PartOf`
+    ConsistsOf,
+    RequiredBy,
+    BoundBy,
+    PartOf,
+}
+
+impl Relation {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Relation::Requires => "requires",
+            Relation::BindsTo => "binds-to",
+            Relation::ConsistsOf => "consists-of",
+            Relation::RequiredBy => "required-by",
+            Relation::BoundBy => "bound-by",
+            Relation::PartOf => "part-of",
+        }
+    }
+}
+
+/// Which dependents follow a service when it stops.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopCause {
+    /// Stopped or restarted on request: `Requires`, `BindsTo` and `PartOf`
+    Explicit,
+    /// Failed and will not be restarted: `Requires` and `BindsTo`
+    Failed,
+    /// Main process exited: `BindsTo` only
+    Exited,
+}
+
 pub struct DependencyResolver {
     services: HashMap<String, ServiceDependencies>,
 }
@@ -36,6 +78,19 @@ impl DependencyResolver {
 
     /// Compute the startup order respecting dependencies
     pub fn compute_startup_order(&self) -> Result<Vec<String>, String> {
+        Ok(self.compute_startup_levels()?.into_iter().flatten().collect())
+    }
+
+    /// Shutdown order in levels: the services of a level may stop together
+    /// once every later-started level has stopped
+    pub fn compute_shutdown_levels(&self) -> Result<Vec<Vec<String>>, String> {
+        let mut levels = self.compute_startup_levels()?;
+        levels.reverse();
+        Ok(levels)
+    }
+
+    /// Startup order in levels; each level only depends on earlier ones
+    fn compute_startup_levels(&self) -> Result<Vec<Vec<String>>, String> {
         let mut in_degree: HashMap<String, usize> = HashMap::new();
         let mut graph: HashMap<String, Vec<String>> = HashMap::new();
 
@@ -63,8 +118,8 @@ impl DependencyResolver {
                 }
             }
 
-            // Handle "Requires" - must start after required services
-            for required in &deps.requires {
+            // Handle "Requires" and "BindsTo" - must start after required services
+            for required in deps.requires.iter().chain(deps.binds_to.iter()) {
                 if self.services.contains_key(required) {
                     graph.get_mut(required).unwrap().push(service_name.clone());
                     *in_degree.get_mut(service_name).unwrap() += 1;
@@ -72,44 +127,138 @@ impl DependencyResolver {
             }
         }
 
-        // Topological sort using Kahn's algorithm
-        let mut queue: VecDeque<String> = VecDeque::new();
-        let mut result: Vec<String> = Vec::new();
+        // Topological sort using Kahn's algorithm, one level at a time
+        let mut levels: Vec<Vec<String>> = Vec::new();
+        let mut sorted = 0;
 
         // Find all nodes with no incoming edges
-        for (service, &degree) in &in_degree {
-            if degree == 0 {
-                queue.push_back(service.clone());
+        let mut level: Vec<String> = in_degree
+            .iter()
+            .filter(|(_, &degree)| degree == 0)
+            .map(|(service, _)| service.clone())
+            .collect();
+
+        while !level.is_empty() {
+            level.sort();
+            let mut next = Vec::new();
+            for service in &level {
+                if let Some(neighbors) = graph.get(service) {
+                    for neighbor in neighbors {
+                        if let Some(degree) = in_degree.get_mut(neighbor) {
+                            *degree -= 1;
+                            if *degree == 0 {
+                                next.push(neighbor.clone());
+                            }
+                        }
+                    }
+                }
             }
+            sorted += level.len();
+            levels.push(level);
+            level = next;
         }
 
-        while let Some(service) = queue.pop_front() {
-            result.push(service.clone());
+        // Check for cycles
+        if sorted != self.services.len() {
+            return Err("Circular dependency detected in service definitions".to_string());
+        }
 
-            if let Some(neighbors) = graph.get(&service) {
-                for neighbor in neighbors {
-                    if let Some(degree) = in_degree.get_mut(neighbor) {
-                        *degree -= 1;
-                        if *degree == 0 {
-                            queue.push_back(neighbor.clone());
-                        }
+        Ok(levels)
+    }
+
+    /// Services linked to `name` by `relation`, directly.
+    pub fn related(&self, name: &str, relation: Relation) -> Vec<String> {
+        let Some(deps) = self.services.get(name) else {
+            return Vec::new();
+        };
+        let mut related: Vec<String> = match relation {
+            Relation::Requires => {
+                // `RequiredBy` on the other service means the same
+                let mut names = deps.requires.clone();
+                names.extend(self.names_where(|d| d.required_by.iter().any(|r| r == name)));
+                names
+            }
+            Relation::BindsTo => deps.binds_to.clone(),
+            Relation::ConsistsOf => self.names_where(|d| d.part_of.iter().any(|p| p == name)),
+            Relation::RequiredBy => {
+                let mut names = deps.required_by.clone();
+                names.extend(self.names_where(|d| d.requires.iter().any(|r| r == name)));
+                names
+            }
+            Relation::BoundBy => self.names_where(|d| d.binds_to.iter().any(|b| b == name)),
+            Relation::PartOf => deps.part_of.clone(),
+        };
+        related.retain(|n| self.services.contains_key(n));
+        related.sort();
+        related.dedup();
+        related
+    }
+
+    fn names_where(&self, predicate: impl Fn(&ServiceDependencies) -> bool) -> Vec<String> {
+        self.services
+            .iter()
+            .filter(|(_, deps)| predicate(deps))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Follow `first` from `name`, then `relations` transitively, excluding
+    /// `name` itself.
+    fn closure(&self, name: &str, first: &[Relation], relations: &[Relation]) -> HashSet<String> {
+        let mut seen = HashSet::new();
+        let mut pending = vec![name.to_string()];
+        while let Some(current) = pending.pop() {
+            let relations = if current == name { first } else { relations };
+            for relation in relations {
+                for next in self.related(&current, *relation) {
+                    if next != name && seen.insert(next.clone()) {
+                        pending.push(next);
                     }
                 }
             }
         }
+        seen
+    }
 
-        // Check for cycles
-        if result.len() != self.services.len() {
-            return Err("Circular dependency detected in service definitions".to_string());
+    /// Services `names` restricted to, in startup order. Falls back to name
+    /// order if the dependencies contain a cycle.
+    fn in_startup_order(&self, names: HashSet<String>) -> Vec<String> {
+        match self.compute_startup_order() {
+            Ok(order) => order.into_iter().filter(|n| names.contains(n)).collect(),
+            Err(_) => {
+                let mut names: Vec<String> = names.into_iter().collect();
+                names.sort();
+                names
+            }
         }
+    }
+
+    /// Everything starting `name` pulls in through `Requires`, `BindsTo`
+    /// and `RequiredBy`, in the order to start it.
+    pub fn requirements(&self, name: &str) -> Vec<String> {
+        let relations = [Relation::Requires, Relation::BindsTo];
+        self.in_startup_order(self.closure(name, &relations, &relations))
+    }
 
-        Ok(result)
+    /// Services that stop with `name` for the given cause, in the order to
+    /// stop them. Services stopped this way take their own dependents along
+    /// as if stopped explicitly.
+    pub fn dependents(&self, name: &str, cause: StopCause) -> Vec<String> {
+        let explicit = [Relation::RequiredBy, Relation::BoundBy, Relation::ConsistsOf];
+        let first: &[Relation] = match cause {
+            StopCause::Explicit => &explicit,
+            StopCause::Failed => &[Relation::RequiredBy, Relation::BoundBy],
+            StopCause::Exited => &[Relation::BoundBy],
+        };
+        let mut order = self.in_startup_order(self.closure(name, first, &explicit));
+        order.reverse();
+        order
     }
 
     /// Check if all required dependencies exist
     pub fn validate_dependencies(&self) -> Result<(), String> {
         for (service_name, deps) in &self.services {
-            for required in &deps.requires {
+            for required in deps.requires.iter().chain(deps.binds_to.iter()) {
                 if !self.services.contains_key(required) {
                     return Err(format!(
                         "Service '{}' requires '{}' which does not exist",
@@ -179,6 +328,55 @@ mod tests {
         assert!(pos_db < pos_app);
     }
 
+    #[test]
+    fn test_propagation() {
+        // storage <-BindsTo- db <-Requires- app <-PartOf- sidecar
+        let mut resolver = DependencyResolver::new();
+        resolver.add_service("storage".to_string(), ServiceDependencies::new());
+
+        let mut deps_db = ServiceDependencies::new();
+        deps_db.binds_to.push("storage".to_string());
+        resolver.add_service("db".to_string(), deps_db);
+
+        let mut deps_app = ServiceDependencies::new();
+        deps_app.requires.push("db".to_string());
+        resolver.add_service("app".to_string(), deps_app);
+
+        let mut deps_sidecar = ServiceDependencies::new();
+        deps_sidecar.part_of.push("app".to_string());
+        resolver.add_service("sidecar".to_string(), deps_sidecar);
+
+        // RequiredBy = ["app"] on cache means app requires cache
+        let mut deps_cache = ServiceDependencies::new();
+        deps_cache.required_by.push("app".to_string());
+        resolver.add_service("cache".to_string(), deps_cache);
+
+        let requirements = resolver.requirements("app");
+        assert_eq!(requirements.len(), 3);
+        let pos = |n: &str| requirements.iter().position(|x| x == n).unwrap();
+        assert!(pos("storage") < pos("db"));
+        assert!(resolver.requirements("sidecar").is_empty());
+
+        let sorted = |mut names: Vec<String>| {
+            names.sort();
+            names
+        };
+        let explicit = resolver.dependents("storage", StopCause::Explicit);
+        assert_eq!(sorted(explicit.clone()), vec!["app", "db", "sidecar"]);
+        let pos = |n: &str| explicit.iter().position(|x| x == n).unwrap();
+        assert!(pos("app") < pos("db"));
+        assert_eq!(sorted(resolver.dependents("storage", StopCause::Exited)), vec!["app", "db", "sidecar"]);
+        assert_eq!(sorted(resolver.dependents("db", StopCause::Failed)), vec!["app", "sidecar"]);
+        assert_eq!(resolver.dependents("app", StopCause::Explicit), vec!["sidecar"]);
+        assert!(resolver.dependents("app", StopCause::Failed).is_empty());
+        assert!(resolver.dependents("cache", StopCause::Exited).is_empty());
+        assert_eq!(sorted(resolver.dependents("cache", StopCause::Explicit)), vec!["app", "sidecar"]);
+
+        assert_eq!(resolver.related("app", Relation::ConsistsOf), vec!["sidecar"]);
+        assert_eq!(resolver.related("app", Relation::Requires), vec!["cache", "db"]);
+        assert_eq!(resolver.related("db", Relation::RequiredBy), vec!["app"]);
+    }
+
     #[test]
     fn test_circular_dependency() {
         let mut resolver = DependencyResolver::new();
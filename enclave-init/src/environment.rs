@@ -0,0 +1,311 @@
+//! Service environment.
+//!
+//! The environment of a service is built in layers, later layers overriding
+//! earlier ones: the default `PATH`, init's global `environment`, variables
+//! listed in `PassEnvironment` (copied from init's own environment),
+//! `Environment`, and finally the `EnvironmentFile`s in order.
+//! `UnsetEnvironment` then removes variables from the result. `${VAR}`
+//! references in `ExecStart` and `WorkingDirectory` are expanded against it.
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+use crate::logger::Logger;
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct EnvironmentConfig {
+    /// Files of `KEY=VALUE` lines; a `-` prefix ignores a missing file
+    #[serde(default, deserialize_with = "crate::hooks::deserialize_commands")]
+    pub environment_file: Vec<String>,
+    /// Variables copied from init's environment
+    #[serde(default, deserialize_with = "crate::credentials::deserialize_words")]
+    pub pass_environment: Vec<String>,
+    /// Variable names, or exact `KEY=VALUE` assignments, to remove
+    #[serde(default, deserialize_with = "crate::credentials::deserialize_words")]
+    pub unset_environment: Vec<String>,
+}
+
+/// Ordered set of variables; setting an existing variable keeps its position.
+#[derive(Debug, Default)]
+struct Variables(Vec<(String, String)>);
+
+impl Variables {
+    fn set(&mut self, key: &str, value: &str) {
+        match self.0.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value.to_string(),
+            None => self.0.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    /// Set a `KEY=VALUE` assignment; entries without `=` are ignored.
+    fn assign(&mut self, assignment: &str) {
+        if let Some((key, value)) = assignment.split_once('=') {
+            self.set(key, value);
+        }
+    }
+
+    fn unset(&mut self, spec: &str) {
+        match spec.split_once('=') {
+            Some((key, value)) => self.0.retain(|(k, v)| k != key || v != value),
+            None => self.0.retain(|(k, _)| k != spec),
+        }
+    }
+
+    fn into_assignments(self) -> Vec<String> {
+        self.0.into_iter().map(|(k, v)| format!("{}={}", k, v)).collect()
+    }
+}
+
+impl EnvironmentConfig {
+    /// Check paths and variable names.
+    pub fn validate(&self) -> Result<(), String> {
+        for file in &self.environment_file {
+            if !Path::new(file.strip_prefix('-').unwrap_or(file)).is_absolute() {
+                return Err(format!("EnvironmentFile '{}' must be an absolute path", file));
+            }
+        }
+        for name in &self.pass_environment {
+            if !is_valid_name(name) {
+                return Err(format!("PassEnvironment: invalid variable name '{}'", name));
+            }
+        }
+        for spec in &self.unset_environment {
+            let name = spec.split_once('=').map_or(spec.as_str(), |(k, _)| k);
+            if !is_valid_name(name) {
+                return Err(format!("UnsetEnvironment: invalid variable name '{}'", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Build the environment from `base` (default and global variables) and
+    /// the service's `Environment` assignments. Fails if a required
+    /// `EnvironmentFile` cannot be read.
+    pub fn resolve(&self, base: &[String], environment: &[String]) -> Result<Vec<String>> {
+        let mut vars = Variables::default();
+        for assignment in base {
+            vars.assign(assignment);
+        }
+        for name in &self.pass_environment {
+            if let Ok(value) = std::env::var(name) {
+                vars.set(name, &value);
+            }
+        }
+        for assignment in environment {
+            vars.assign(assignment);
+        }
+        for file in &self.environment_file {
+            let (path, optional) = match file.strip_prefix('-') {
+                Some(path) => (path, true),
+                None => (file.as_str(), false),
+            };
+            let contents = match fs::read_to_string(path) {
+                Ok(contents) => contents,
+                Err(e) if optional && e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(anyhow!(e)).with_context(|| format!("Failed to read EnvironmentFile {}", path)),
+            };
+            for (key, value) in parse_environment_file(&contents, path) {
+                vars.set(&key, &value);
+            }
+        }
+        for spec in &self.unset_environment {
+            vars.unset(spec);
+        }
+        Ok(vars.into_assignments())
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parse `KEY=VALUE` lines. Blank lines and lines starting with `#` or `;`
+/// are skipped, an `export ` prefix is allowed, and values may be single- or
+/// double-quoted. A trailing backslash continues the line. Invalid lines are
+/// logged and skipped.
+fn parse_environment_file(contents: &str, path: &str) -> Vec<(String, String)> {
+    let mut vars = Vec::new();
+    let mut lines = contents.lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        let mut line = line.to_string();
+        while line.ends_with('\\') {
+            line.pop();
+            match lines.next() {
+                Some((_, next)) => line.push_str(next),
+                None => break,
+            }
+        }
+
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        match line.split_once('=') {
+            Some((key, value)) if is_valid_name(key.trim()) => {
+                vars.push((key.trim().to_string(), unquote(value.trim())));
+            }
+            _ => Logger::warn(&format!("{}:{}: ignoring invalid line", path, index + 1)),
+        }
+    }
+    vars
+}
+
+/// Strip matching outer quotes. Double-quoted values support `\"`, `\\`
+/// and `\$` escapes; single-quoted values are taken literally.
+fn unquote(value: &str) -> String {
+    if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+        return value[1..value.len() - 1].to_string();
+    }
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        let mut result = String::new();
+        let mut chars = value[1..value.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            if c == '\\' {
+                match chars.next() {
+                    Some(next @ ('"' | '\\' | '$')) => result.push(next),
+                    Some(next) => {
+                        result.push('\\');
+                        result.push(next);
+                    }
+                    None => result.push('\\'),
+                }
+            } else {
+                result.push(c);
+            }
+        }
+        return result;
+    }
+    value.to_string()
+}
+
+/// Expand `${VAR}` references against `env` (`KEY=VALUE` assignments).
+/// Unset variables expand to an empty string and `$$` to a literal `$`;
+/// any other `$` is kept as is.
+pub fn expand(value: &str, env: &[String]) -> String {
+    let mut result = String::new();
+    let mut rest = value;
+    while let Some(pos) = rest.find('$') {
+        result.push_str(&rest[..pos]);
+        let after = &rest[pos + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            result.push('$');
+            rest = after;
+        } else if let Some((name, after)) = after.strip_prefix('{').and_then(|a| a.split_once('}')) {
+            if let Some(value) = lookup(env, name) {
+                result.push_str(value);
+            }
+            rest = after;
+        } else {
+            result.push('$');
+            rest = after;
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn lookup<'a>(env: &'a [String], name: &str) -> Option<&'a str> {
+    env.iter().rev().find_map(|assignment| match assignment.split_once('=') {
+        Some((key, value)) if key == name => Some(value),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_environment_file() {
+        let contents = "# comment\n\
+                        ; another\n\
+                        \n\
+                        PLAIN=value\n\
+                        export EXPORTED=yes\n\
+                        SPACED = padded \n\
+                        DOUBLE=\"a \\\"quoted\\\" \\$HOME\"\n\
+                        SINGLE='${NOT_EXPANDED}'\n\
+                        CONTINUED=one \\\n\
+                        two\n\
+                        not a variable\n\
+                        1BAD=x\n";
+        let vars = parse_environment_file(contents, "test.env");
+        assert_eq!(
+            vars,
+            vec![
+                ("PLAIN".to_string(), "value".to_string()),
+                ("EXPORTED".to_string(), "yes".to_string()),
+                ("SPACED".to_string(), "padded".to_string()),
+                ("DOUBLE".to_string(), "a \"quoted\" $HOME".to_string()),
+                ("SINGLE".to_string(), "${NOT_EXPANDED}".to_string()),
+                ("CONTINUED".to_string(), "one two".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("service.env");
+        fs::write(&file, "MODE=file\nSECRET=s3cret\n").unwrap();
+        std::env::set_var("ENV_TEST_PASSED", "from-init");
+
+        let config = EnvironmentConfig {
+            environment_file: vec![file.display().to_string(), "-/nonexistent/env".to_string()],
+            pass_environment: strings(&["ENV_TEST_PASSED", "ENV_TEST_MISSING"]),
+            unset_environment: strings(&["SECRET", "REGION=eu"]),
+        };
+        let env = config
+            .resolve(
+                &strings(&["PATH=/bin", "REGION=eu", "MODE=global"]),
+                &strings(&["MODE=service", "PATH=/usr/bin"]),
+            )
+            .unwrap();
+        assert_eq!(env, strings(&["PATH=/usr/bin", "MODE=file", "ENV_TEST_PASSED=from-init"]));
+
+        let missing = EnvironmentConfig {
+            environment_file: strings(&["/nonexistent/env"]),
+            ..Default::default()
+        };
+        assert!(missing.resolve(&[], &[]).is_err());
+    }
+
+    #[test]
+    fn test_expand() {
+        let env = strings(&["PORT=8080", "DIR=/srv/app", "EMPTY="]);
+        assert_eq!(expand("--port=${PORT}", &env), "--port=8080");
+        assert_eq!(expand("${DIR}/data", &env), "/srv/app/data");
+        assert_eq!(expand("${UNSET}x${EMPTY}", &env), "x");
+        assert_eq!(expand("$$PORT $PORT ${PORT", &env), "$PORT $PORT ${PORT");
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = EnvironmentConfig {
+            environment_file: strings(&["-/etc/app.env"]),
+            pass_environment: strings(&["HOME"]),
+            unset_environment: strings(&["TERM", "LANG=C"]),
+        };
+        assert!(config.validate().is_ok());
+        let relative = EnvironmentConfig {
+            environment_file: strings(&["app.env"]),
+            ..Default::default()
+        };
+        assert!(relative.validate().is_err());
+        let bad_name = EnvironmentConfig {
+            pass_environment: strings(&["NOT-VALID"]),
+            ..Default::default()
+        };
+        assert!(bad_name.validate().is_err());
+    }
+}
@@ -0,0 +1,237 @@
+//! Service event bus.
+//!
+//! Init publishes an event whenever a service starts, exits, is scheduled
+//! for restart, fails or changes health, and when a reload completes. Events
+//! are numbered consecutively per boot. The last `RETAINED_EVENTS` are kept
+//! so that a subscriber can resume after a reconnect from the last sequence
+//! number it saw.
+//!
+//! Each subscriber gets a bounded queue. A subscriber that falls
+//! `QUEUE_SIZE` events behind is dropped instead of slowing init down; its
+//! receiver then reports a disconnect once drained.
+
+use crate::logger::{now_millis, Logger};
+use crate::protocol::{Event, EventFilter, EventKind, FdStream};
+use anyhow::{Context, Result};
+use nix::sys::socket::{connect, socket, AddressFamily, SockFlag, SockType, VsockAddr};
+use nix::unistd::close;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{LazyLock, Mutex};
+use std::thread;
+
+/// Events kept for replay.
+const RETAINED_EVENTS: usize = 1024;
+
+/// Events queued per subscriber before it is dropped.
+const QUEUE_SIZE: usize = 1024;
+
+/// Names of the event kinds, as used in filters.
+const EVENT_NAMES: [&str; 6] = ["started", "exited", "restarting", "failed", "health-changed", "reloaded"];
+
+static BUS: LazyLock<Mutex<EventBus>> = LazyLock::new(|| Mutex::new(EventBus::new(RETAINED_EVENTS)));
+
+struct EventBus {
+    next_seq: u64,
+    retained: VecDeque<Event>,
+    capacity: usize,
+    subscribers: Vec<(EventFilter, SyncSender<Event>)>,
+}
+
+impl EventBus {
+    fn new(capacity: usize) -> Self {
+        Self {
+            next_seq: 1,
+            retained: VecDeque::new(),
+            capacity,
+            subscribers: Vec::new(),
+        }
+    }
+
+    fn publish(&mut self, timestamp: u64, service: Option<&str>, kind: EventKind) {
+        let event = Event {
+            seq: self.next_seq,
+            timestamp,
+            service: service.map(str::to_string),
+            kind,
+        };
+        self.next_seq += 1;
+
+        // Full and closed queues both drop the subscriber
+        self.subscribers
+            .retain(|(filter, sender)| !matches(filter, &event) || sender.try_send(event.clone()).is_ok());
+
+        if self.retained.len() >= self.capacity {
+            self.retained.pop_front();
+        }
+        self.retained.push_back(event);
+    }
+
+    /// Retained events after `filter.after`, and a queue of the events
+    /// published from now on.
+    fn subscribe(&mut self, filter: EventFilter, queue_size: usize) -> (Vec<Event>, Receiver<Event>) {
+        let backlog = match filter.after {
+            Some(after) => self
+                .retained
+                .iter()
+                .filter(|e| e.seq > after && matches(&filter, e))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        self.subscribers.push((filter, sender));
+        (backlog, receiver)
+    }
+
+    fn history(&self, filter: &EventFilter) -> Vec<Event> {
+        let after = filter.after.unwrap_or(0);
+        self.retained.iter().filter(|e| e.seq > after && matches(filter, e)).cloned().collect()
+    }
+}
+
+fn event_name(kind: &EventKind) -> &'static str {
+    match kind {
+        EventKind::Started { .. } => "started",
+        EventKind::Exited { .. } => "exited",
+        EventKind::Restarting { .. } => "restarting",
+        EventKind::Failed { .. } => "failed",
+        EventKind::HealthChanged { .. } => "health-changed",
+        EventKind::Reloaded { .. } => "reloaded",
+    }
+}
+
+fn matches(filter: &EventFilter, event: &Event) -> bool {
+    let service_matches = filter.services.is_empty()
+        || event.service.as_ref().is_some_and(|s| filter.services.contains(s));
+    service_matches && (filter.events.is_empty() || filter.events.iter().any(|e| e == event_name(&event.kind)))
+}
+
+/// Publish an event about `service`, or about init itself with None.
+pub fn publish(service: Option<&str>, kind: EventKind) {
+    BUS.lock().unwrap().publish(now_millis(), service, kind);
+}
+
+/// Subscribe to events matching `filter`. Returns the retained events after
+/// `filter.after` (none without it) and a receiver of later events.
+pub fn subscribe(filter: EventFilter) -> (Vec<Event>, Receiver<Event>) {
+    BUS.lock().unwrap().subscribe(filter, QUEUE_SIZE)
+}
+
+/// Retained events matching `filter`, after `filter.after` if given.
+pub fn history(filter: &EventFilter) -> Vec<Event> {
+    BUS.lock().unwrap().history(filter)
+}
+
+/// Check the event names of a filter.
+pub fn validate_filter(filter: &EventFilter) -> Result<(), String> {
+    match filter.events.iter().find(|e| !EVENT_NAMES.contains(&e.as_str())) {
+        Some(unknown) => Err(format!(
+            "Unknown event '{}', expected one of: {}",
+            unknown,
+            EVENT_NAMES.join(", ")
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Connect to a listener at `cid`:`port` and send it the events matching
+/// `filter`, one JSON object per line, until it disconnects or falls behind.
+pub fn send_to_vsock(filter: EventFilter, cid: u32, port: u32) -> Result<()> {
+    let fd = socket(AddressFamily::Vsock, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)
+        .context("Failed to create VSock socket for events")?;
+    if let Err(e) = connect(fd, &VsockAddr::new(cid, port)) {
+        let _ = close(fd);
+        return Err(e).context(format!("Failed to connect to VSock CID:{} PORT:{} for events", cid, port));
+    }
+    Logger::info(&format!("Sending events to CID:{} PORT:{}", cid, port));
+
+    let (backlog, receiver) = subscribe(filter);
+    thread::spawn(move || {
+        let mut stream = FdStream(fd);
+        let mut send = |event: &Event| {
+            let line = serde_json::to_string(event).unwrap_or_default();
+            writeln!(stream, "{}", line).is_ok()
+        };
+        if backlog.iter().all(&mut send) {
+            while let Ok(event) = receiver.recv() {
+                if !send(&event) {
+                    break;
+                }
+            }
+        }
+        Logger::info(&format!("Stopped sending events to CID:{} PORT:{}", cid, port));
+        let _ = close(fd);
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exited(code: i32) -> EventKind {
+        EventKind::Exited { code }
+    }
+
+    #[test]
+    fn test_publish_and_replay() {
+        let mut bus = EventBus::new(3);
+        let filter = EventFilter {
+            services: vec!["api".to_string()],
+            ..Default::default()
+        };
+        let (backlog, receiver) = bus.subscribe(filter.clone(), 10);
+        assert!(backlog.is_empty());
+
+        bus.publish(1, Some("api"), EventKind::Started { pid: 7 });
+        bus.publish(2, Some("db"), exited(0));
+        bus.publish(3, Some("api"), exited(1));
+        bus.publish(4, None, EventKind::Failed { reason: "x".to_string() });
+
+        let received: Vec<u64> = receiver.try_iter().map(|e| e.seq).collect();
+        assert_eq!(received, vec![1, 3]);
+
+        // Only the last three events are retained
+        let replay = EventFilter { after: Some(0), ..Default::default() };
+        let (backlog, _receiver) = bus.subscribe(replay.clone(), 10);
+        assert_eq!(backlog.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![2, 3, 4]);
+        let after = EventFilter { after: Some(3), ..replay };
+        assert_eq!(bus.history(&after).len(), 1);
+    }
+
+    #[test]
+    fn test_slow_subscriber_dropped() {
+        let mut bus = EventBus::new(10);
+        let (_, receiver) = bus.subscribe(EventFilter::default(), 2);
+        for code in 0..3 {
+            bus.publish(0, Some("api"), exited(code));
+        }
+        assert!(bus.subscribers.is_empty());
+        assert_eq!(receiver.try_iter().count(), 2);
+        assert!(matches!(receiver.try_recv(), Err(mpsc::TryRecvError::Disconnected)));
+    }
+
+    #[test]
+    fn test_filter_by_event() {
+        let filter = EventFilter {
+            events: vec!["failed".to_string(), "health-changed".to_string()],
+            ..Default::default()
+        };
+        let mut bus = EventBus::new(10);
+        bus.publish(0, Some("api"), exited(1));
+        bus.publish(0, Some("api"), EventKind::Failed { reason: "exit code 1".to_string() });
+        let events = bus.history(&filter);
+        assert_eq!(events.len(), 1);
+        assert_eq!(event_name(&events[0].kind), "failed");
+
+        assert!(validate_filter(&filter).is_ok());
+        let unknown = EventFilter { events: vec!["crashed".to_string()], ..Default::default() };
+        assert!(validate_filter(&unknown).is_err());
+
+        let json = serde_json::to_value(&events[0]).unwrap();
+        assert_eq!(json["event"], "failed");
+        assert_eq!(json["seq"], 2);
+    }
+}
@@ -0,0 +1,500 @@
+//! Log forwarding.
+//!
+//! With `log_forward` enabled in `init.yaml`, init sends the records of all
+//! services over one VSock connection to a collector on the host, such as
+//! `initctl logs-collect`. Records are numbered consecutively per boot and
+//! sent in batches, which the collector acknowledges. Records not yet
+//! acknowledged when the connection drops are sent again after reconnecting,
+//! and the collector skips those it already has.
+//!
+//! While the collector is unreachable or slow, records are held in memory up
+//! to `buffer_records`, then appended to a spool file in `spool_dir` up to
+//! `spool_max_size` bytes. When both are full, `overflow` decides: with
+//! `drop-oldest` the oldest records are discarded, which the collector sees as
+//! a gap in the sequence numbers; with `block` services wait when writing
+//! output until there is room again. Init's own lines about a service never
+//! wait.
+
+use crate::config::{LogForwardConfig, OverflowPolicy};
+use crate::logger::Logger;
+use crate::protocol::{
+    read_frame, write_frame, FdStream, ForwardAck, ForwardMessage, ForwardedRecord, LogForwardingStatus, LogRecord,
+    LogStream,
+};
+use anyhow::{anyhow, Context, Result};
+use nix::sys::socket::{connect, shutdown, socket, AddressFamily, Shutdown, SockFlag, SockType, VsockAddr};
+use nix::unistd::close;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SPOOL_FILE: &str = "forward.spool";
+/// Most records sent in one frame
+const BATCH_SIZE: usize = 256;
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+static FORWARDER: OnceLock<Arc<Forwarder>> = OnceLock::new();
+
+struct Forwarder {
+    config: LogForwardConfig,
+    boot_id: String,
+    queue: Mutex<Queue>,
+    /// Signalled when records are queued or acknowledged and when the connection ends
+    changed: Condvar,
+}
+
+/// Records not yet acknowledged, oldest first: those in memory, then those
+/// in the spool.
+struct Queue {
+    capacity: usize,
+    next_seq: u64,
+    memory: VecDeque<ForwardedRecord>,
+    /// Leading records in `memory` sent on the current connection
+    in_flight: usize,
+    spool: Option<Spool>,
+    dropped: u64,
+    connected: bool,
+}
+
+impl Queue {
+    fn new(capacity: usize, spool: Option<Spool>) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            next_seq: 0,
+            memory: VecDeque::new(),
+            in_flight: 0,
+            spool,
+            dropped: 0,
+            connected: false,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.memory.len() + self.spooled()
+    }
+
+    fn spooled(&self) -> usize {
+        self.spool.as_ref().map_or(0, |spool| spool.records)
+    }
+
+    fn is_full(&self) -> bool {
+        self.memory.len() >= self.capacity && self.spool.as_ref().is_none_or(|spool| spool.is_full())
+    }
+
+    fn push(&mut self, record: LogRecord) {
+        let record = ForwardedRecord { seq: self.next_seq, record };
+        self.next_seq += 1;
+        loop {
+            // Once records are spooled, later ones follow them there to stay in order
+            if self.spooled() == 0 && self.memory.len() < self.capacity {
+                self.memory.push_back(record);
+                return;
+            }
+            if let Some(spool) = &mut self.spool {
+                match spool.push(&record) {
+                    Ok(true) => return,
+                    Ok(false) => {}
+                    Err(e) => self.spool_failed(e),
+                }
+            }
+            if !self.drop_oldest() {
+                self.dropped += 1;
+                return;
+            }
+        }
+    }
+
+    fn drop_oldest(&mut self) -> bool {
+        if self.memory.pop_front().is_none() {
+            return false;
+        }
+        self.in_flight = self.in_flight.saturating_sub(1);
+        self.dropped += 1;
+        self.refill();
+        true
+    }
+
+    /// Move spooled records into memory as far as it has room.
+    fn refill(&mut self) {
+        let room = self.capacity.saturating_sub(self.memory.len());
+        let Some(spool) = &mut self.spool else {
+            return;
+        };
+        if room == 0 || spool.records == 0 {
+            return;
+        }
+        match spool.take(room) {
+            Ok(records) => self.memory.extend(records),
+            Err(e) => self.spool_failed(e),
+        }
+    }
+
+    /// Give up on the spool after an I/O error; its records are lost.
+    fn spool_failed(&mut self, error: io::Error) {
+        if let Some(spool) = self.spool.take() {
+            Logger::error(&format!(
+                "Log forwarding spool {} failed, dropping {} records and continuing in memory: {}",
+                spool.path.display(),
+                spool.records,
+                error
+            ));
+            self.dropped += spool.records as u64;
+            let _ = fs::remove_file(&spool.path);
+        }
+    }
+
+    /// Records to send next, marked as in flight.
+    fn next_batch(&mut self) -> Vec<ForwardedRecord> {
+        let batch: Vec<ForwardedRecord> = self.memory.iter().skip(self.in_flight).take(BATCH_SIZE).cloned().collect();
+        self.in_flight += batch.len();
+        batch
+    }
+
+    fn ack(&mut self, seq: u64) {
+        while self.memory.front().is_some_and(|record| record.seq <= seq) {
+            self.memory.pop_front();
+            self.in_flight = self.in_flight.saturating_sub(1);
+        }
+        self.refill();
+    }
+
+    /// Records in flight are sent again on the next connection.
+    fn disconnected(&mut self) {
+        self.in_flight = 0;
+        self.connected = false;
+    }
+}
+
+/// Records that did not fit in memory, as JSON lines appended to a file and
+/// taken from its front.
+struct Spool {
+    path: PathBuf,
+    file: File,
+    /// Offset of the first record not yet taken
+    read_offset: u64,
+    len: u64,
+    records: usize,
+    max_size: u64,
+}
+
+impl Spool {
+    /// Open the spool in `dir`, discarding records of a previous boot.
+    fn open(dir: &Path, max_size: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(SPOOL_FILE);
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+        Ok(Self { path, file, read_offset: 0, len: 0, records: 0, max_size })
+    }
+
+    fn size(&self) -> u64 {
+        self.len - self.read_offset
+    }
+
+    /// May go over `max_size` by one record.
+    fn is_full(&self) -> bool {
+        self.size() >= self.max_size
+    }
+
+    /// Append a record; false if the spool is full.
+    fn push(&mut self, record: &ForwardedRecord) -> io::Result<bool> {
+        if self.is_full() {
+            return Ok(false);
+        }
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        // Taken records stay in the file until it is emptied; rewrite it
+        // before they take up more room than the records left
+        if self.read_offset > self.max_size {
+            self.compact()?;
+        }
+        self.file.seek(SeekFrom::Start(self.len))?;
+        self.file.write_all(&line)?;
+        self.len += line.len() as u64;
+        self.records += 1;
+        Ok(true)
+    }
+
+    /// Take up to `count` records from the front.
+    fn take(&mut self, count: usize) -> io::Result<Vec<ForwardedRecord>> {
+        self.file.seek(SeekFrom::Start(self.read_offset))?;
+        let mut reader = BufReader::new((&self.file).take(self.size()));
+        let mut records = Vec::new();
+        let mut line = String::new();
+        while records.len() < count {
+            line.clear();
+            let n = reader.read_line(&mut line)?;
+            if n == 0 {
+                break;
+            }
+            self.read_offset += n as u64;
+            self.records -= 1;
+            records.push(serde_json::from_str(&line)?);
+        }
+        if self.records == 0 {
+            self.file.set_len(0)?;
+            self.read_offset = 0;
+            self.len = 0;
+        }
+        Ok(records)
+    }
+
+    fn compact(&mut self) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("spool.tmp");
+        let mut tmp = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&tmp_path)?;
+        self.file.seek(SeekFrom::Start(self.read_offset))?;
+        io::copy(&mut (&self.file).take(self.size()), &mut tmp)?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = tmp;
+        self.len -= self.read_offset;
+        self.read_offset = 0;
+        Ok(())
+    }
+}
+
+impl Forwarder {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap()
+    }
+
+    fn destination(&self) -> String {
+        format!("CID:{} PORT:{}", self.config.cid, self.config.port)
+    }
+
+    /// Connect to the collector again and again, with growing delays while
+    /// it cannot be reached.
+    fn run(self: Arc<Self>) {
+        let mut delay = RECONNECT_MIN_DELAY;
+        let mut reported = false;
+        loop {
+            match connect_vsock(self.config.cid, self.config.port) {
+                Ok(fd) => {
+                    Logger::info(&format!("Log forwarding connected to {}", self.destination()));
+                    delay = RECONNECT_MIN_DELAY;
+                    reported = false;
+                    let result = self.serve(fd);
+                    let _ = close(fd);
+                    if let Err(e) = result {
+                        Logger::warn(&format!("Log forwarding to {} interrupted: {:#}", self.destination(), e));
+                    }
+                }
+                Err(e) => {
+                    if !reported {
+                        Logger::warn(&format!("Log forwarding cannot reach {}, retrying: {:#}", self.destination(), e));
+                        reported = true;
+                    }
+                    delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+                }
+            }
+            thread::sleep(delay);
+        }
+    }
+
+    /// Send records over `fd` until the connection fails.
+    fn serve(self: &Arc<Self>, fd: RawFd) -> Result<()> {
+        let hello = ForwardMessage::Hello {
+            boot_id: self.boot_id.clone(),
+            agent: format!("init {}", env!("CARGO_PKG_VERSION")),
+        };
+        write_frame(&mut FdStream(fd), &hello).context("Failed to send hello")?;
+        self.lock().connected = true;
+
+        // Acknowledgements are read on their own thread
+        let forwarder = Arc::clone(self);
+        let reader = thread::spawn(move || {
+            while let Ok(ForwardAck { seq }) = read_frame(&mut FdStream(fd)) {
+                forwarder.lock().ack(seq);
+                forwarder.changed.notify_all();
+            }
+            forwarder.lock().disconnected();
+            forwarder.changed.notify_all();
+        });
+
+        let result = loop {
+            let records = {
+                let mut queue = self.lock();
+                loop {
+                    if !queue.connected {
+                        break None;
+                    }
+                    let batch = queue.next_batch();
+                    if !batch.is_empty() {
+                        break Some(batch);
+                    }
+                    queue = self.changed.wait(queue).unwrap();
+                }
+            };
+            let Some(records) = records else {
+                break Err(anyhow!("connection closed by the collector"));
+            };
+            if let Err(e) = write_frame(&mut FdStream(fd), &ForwardMessage::Records { records }) {
+                break Err(e).context("Failed to send records");
+            }
+        };
+
+        let _ = shutdown(fd, Shutdown::Both);
+        let _ = reader.join();
+        self.lock().disconnected();
+        result
+    }
+}
+
+fn connect_vsock(cid: u32, port: u32) -> Result<RawFd> {
+    let fd = socket(AddressFamily::Vsock, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)
+        .context("Failed to create VSock socket")?;
+    if let Err(e) = connect(fd, &VsockAddr::new(cid, port)) {
+        let _ = close(fd);
+        return Err(e.into());
+    }
+    Ok(fd)
+}
+
+/// Identifies the boot in the collector, which tracks sequence numbers per boot.
+fn boot_id() -> String {
+    fs::read_to_string("/proc/sys/kernel/random/boot_id")
+        .map(|id| id.trim().to_string())
+        .unwrap_or_else(|_| format!("{:x}", crate::logger::now_millis()))
+}
+
+/// Start forwarding, if enabled in `config`.
+pub fn start(config: &LogForwardConfig) {
+    if !config.enabled {
+        return;
+    }
+    let spool = config.spool_dir.as_ref().and_then(|dir| match Spool::open(Path::new(dir), config.spool_max_size) {
+        Ok(spool) => Some(spool),
+        Err(e) => {
+            Logger::error(&format!("Failed to open log forwarding spool in {}, buffering in memory only: {}", dir, e));
+            None
+        }
+    });
+    let forwarder = Arc::new(Forwarder {
+        config: config.clone(),
+        boot_id: boot_id(),
+        queue: Mutex::new(Queue::new(config.buffer_records, spool)),
+        changed: Condvar::new(),
+    });
+    if FORWARDER.set(Arc::clone(&forwarder)).is_err() {
+        return;
+    }
+    Logger::info(&format!("Forwarding service logs to {}", forwarder.destination()));
+    thread::spawn(move || forwarder.run());
+}
+
+/// Queue a record for forwarding. With the `block` overflow policy, waits
+/// while the buffers are full unless the record is one of init's own lines.
+pub fn submit(record: &LogRecord) {
+    let Some(forwarder) = FORWARDER.get() else {
+        return;
+    };
+    let mut queue = forwarder.lock();
+    if forwarder.config.overflow == OverflowPolicy::Block && record.stream != LogStream::Init {
+        while queue.is_full() {
+            queue = forwarder.changed.wait(queue).unwrap();
+        }
+    }
+    queue.push(record.clone());
+    drop(queue);
+    forwarder.changed.notify_all();
+}
+
+/// Wait up to `timeout` for the collector to acknowledge the queued records,
+/// so that the last lines before a shutdown reach it.
+pub fn flush(timeout: Duration) {
+    let Some(forwarder) = FORWARDER.get() else {
+        return;
+    };
+    let deadline = Instant::now() + timeout;
+    let mut queue = forwarder.lock();
+    while queue.connected && queue.len() > 0 {
+        let now = Instant::now();
+        if now >= deadline {
+            Logger::warn(&format!("Log forwarding: {} records not delivered before shutdown", queue.len()));
+            break;
+        }
+        queue = forwarder.changed.wait_timeout(queue, deadline - now).unwrap().0;
+    }
+}
+
+pub fn status() -> Option<LogForwardingStatus> {
+    let forwarder = FORWARDER.get()?;
+    let queue = forwarder.lock();
+    Some(LogForwardingStatus {
+        destination: forwarder.destination(),
+        connected: queue.connected,
+        queued: queue.len(),
+        spooled: queue.spooled(),
+        dropped: queue.dropped,
+        next_seq: queue.next_seq,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::LogLevel;
+
+    fn record(message: &str) -> LogRecord {
+        LogRecord {
+            timestamp: 0,
+            service: "web".to_string(),
+            stream: LogStream::Stdout,
+            level: LogLevel::Info,
+            message: message.to_string(),
+        }
+    }
+
+    fn seqs(batch: &[ForwardedRecord]) -> Vec<u64> {
+        batch.iter().map(|r| r.seq).collect()
+    }
+
+    #[test]
+    fn test_queue_memory() {
+        let mut queue = Queue::new(3, None);
+        for i in 0..5 {
+            queue.push(record(&i.to_string()));
+        }
+        // The two oldest were dropped
+        assert_eq!(queue.dropped, 2);
+        assert_eq!(seqs(&queue.next_batch()), vec![2, 3, 4]);
+        assert!(queue.next_batch().is_empty());
+
+        queue.ack(2);
+        queue.push(record("5"));
+        assert_eq!(seqs(&queue.next_batch()), vec![5]);
+
+        // Unacknowledged records are sent again after a reconnect
+        queue.disconnected();
+        assert_eq!(seqs(&queue.next_batch()), vec![3, 4, 5]);
+        queue.ack(5);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn test_queue_spool() {
+        let dir = tempfile::tempdir().unwrap();
+        let line_len = serde_json::to_vec(&ForwardedRecord { seq: 0, record: record("0") }).unwrap().len() as u64 + 1;
+        // Room for four records on disk
+        let spool = Spool::open(dir.path(), line_len * 4).unwrap();
+        let mut queue = Queue::new(2, Some(spool));
+        for i in 0..8 {
+            queue.push(record(&i.to_string()));
+        }
+        assert_eq!(queue.spooled(), 4);
+        assert_eq!(queue.dropped, 2);
+        assert!(queue.is_full());
+
+        assert_eq!(seqs(&queue.next_batch()), vec![2, 3]);
+        queue.ack(3);
+        assert_eq!(seqs(&queue.next_batch()), vec![4, 5]);
+        queue.ack(5);
+        assert_eq!(seqs(&queue.next_batch()), vec![6, 7]);
+        assert_eq!(queue.spooled(), 0);
+        assert_eq!(fs::metadata(dir.path().join(SPOOL_FILE)).unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,454 @@
+//! Readiness and liveness probes for services.
+//!
+//! A `ReadyCheck` gates the start of dependent services until it succeeds once
+//! (together with `READY=1` for `Type=notify` services).
+//! A `HealthCheck` is run periodically for the lifetime of the service; after
+//! `HealthCheckRetries` consecutive failures the service is marked unhealthy and
+//! stopped, so its restart policy applies.
+
+use crate::protocol::{HealthState, ProbeResult};
+use serde::{Deserialize, Deserializer};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often a pending readiness probe is retried.
+pub const READY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A single probe definition.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Probe {
+    /// Run a command; success means exit code 0
+    Exec(String),
+    /// Connect to a TCP address
+    Tcp(String),
+    /// Connect to a Unix stream socket
+    Unix(String),
+    /// Check that a file exists
+    File(String),
+}
+
+impl Probe {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (kind, arg) = value
+            .split_once(':')
+            .ok_or_else(|| format!("invalid probe '{}', expected <exec|tcp|unix|file>:<target>", value))?;
+        let arg = arg.trim();
+        if arg.is_empty() {
+            return Err(format!("probe '{}' has an empty target", value));
+        }
+
+        match kind.trim() {
+            "exec" => Ok(Probe::Exec(arg.to_string())),
+            "tcp" => {
+                let addr = if arg.parse::<u16>().is_ok() {
+                    format!("127.0.0.1:{}", arg)
+                } else {
+                    arg.to_string()
+                };
+                Ok(Probe::Tcp(addr))
+            }
+            "unix" => Ok(Probe::Unix(arg.to_string())),
+            "file" => Ok(Probe::File(arg.to_string())),
+            other => Err(format!("unknown probe type '{}'", other)),
+        }
+    }
+
+    /// Run the probe once, bounded by `timeout`.
+    pub fn run(&self, timeout: Duration) -> ProbeResult {
+        let started = Instant::now();
+        let outcome = match self {
+            Probe::Exec(command) => run_exec(command, timeout),
+            Probe::Tcp(addr) => run_tcp(addr, timeout),
+            Probe::Unix(path) => UnixStream::connect(path)
+                .map(|_| format!("connected to {}", path))
+                .map_err(|e| format!("connect to {} failed: {}", path, e)),
+            Probe::File(path) => {
+                if Path::new(path).exists() {
+                    Ok(format!("{} exists", path))
+                } else {
+                    Err(format!("{} does not exist", path))
+                }
+            }
+        };
+
+        let (success, message) = match outcome {
+            Ok(m) => (true, m),
+            Err(m) => (false, m),
+        };
+
+        ProbeResult {
+            success,
+            message,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            duration_ms: started.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Probe {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Probe::parse(&value).map_err(serde::de::Error::custom)
+    }
+}
+
+fn run_tcp(addr: &str, timeout: Duration) -> Result<String, String> {
+    let addrs: Vec<SocketAddr> = addr
+        .to_socket_addrs()
+        .map_err(|e| format!("invalid address {}: {}", addr, e))?
+        .collect();
+
+    let mut last_err = format!("no addresses for {}", addr);
+    for sock_addr in addrs {
+        match TcpStream::connect_timeout(&sock_addr, timeout) {
+            Ok(_) => return Ok(format!("connected to {}", sock_addr)),
+            Err(e) => last_err = format!("connect to {} failed: {}", sock_addr, e),
+        }
+    }
+    Err(last_err)
+}
+
+fn run_exec(command: &str, timeout: Duration) -> Result<String, String> {
+    let argv = crate::shell_words::split(command).unwrap_or_else(|_| vec![command.to_string()]);
+    match crate::hooks::run_command(&argv, None, None, timeout, None)? {
+        0 => Ok("exited with code 0".to_string()),
+        code => Err(format!("exited with code {}", code)),
+    }
+}
+
+/// Health check settings from the service file.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct HealthCheckConfig {
+    /// Probe that must succeed once before dependents are started
+    #[serde(default)]
+    pub ready_check: Option<Probe>,
+    /// Seconds to wait for the readiness probe before giving up
+    #[serde(default = "default_ready_timeout_sec")]
+    pub ready_timeout_sec: u64,
+    /// Periodic liveness probe
+    #[serde(default)]
+    pub health_check: Option<Probe>,
+    /// Seconds between liveness probes
+    #[serde(default = "default_interval_sec")]
+    pub health_check_interval_sec: u64,
+    /// Timeout of a single probe in seconds
+    #[serde(default = "default_timeout_sec")]
+    pub health_check_timeout_sec: u64,
+    /// Consecutive failures before the service is considered unhealthy
+    #[serde(default = "default_retries")]
+    pub health_check_retries: u32,
+}
+
+fn default_ready_timeout_sec() -> u64 {
+    30
+}
+
+fn default_interval_sec() -> u64 {
+    10
+}
+
+fn default_timeout_sec() -> u64 {
+    5
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            ready_check: None,
+            ready_timeout_sec: default_ready_timeout_sec(),
+            health_check: None,
+            health_check_interval_sec: default_interval_sec(),
+            health_check_timeout_sec: default_timeout_sec(),
+            health_check_retries: default_retries(),
+        }
+    }
+}
+
+/// What the health checker should do for a service on this tick.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProbeKind {
+    Ready,
+    Liveness,
+}
+
+/// Outcome of applying a probe result to a tracker.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HealthTransition {
+    None,
+    BecameReady,
+    BecameUnhealthy,
+    Recovered,
+}
+
+/// Runtime health state of a service.
+#[derive(Debug, Clone)]
+pub struct HealthTracker {
+    pub state: HealthState,
+    probe_ready: bool,
+    notify_ready: bool,
+    pub consecutive_failures: u32,
+    pub last_result: Option<ProbeResult>,
+    last_probe: Option<Instant>,
+    started_at: Option<Instant>,
+    /// A probe is running; no other is due until its result is recorded
+    probing: bool,
+}
+
+impl HealthTracker {
+    pub fn new() -> Self {
+        Self {
+            state: HealthState::Unknown,
+            probe_ready: false,
+            notify_ready: false,
+            consecutive_failures: 0,
+            last_result: None,
+            last_probe: None,
+            started_at: None,
+            probing: false,
+        }
+    }
+
+    /// Whether the service has passed its readiness probe and, if it awaits
+    /// `READY=1`, has notified readiness.
+    pub fn is_ready(&self) -> bool {
+        self.probe_ready && self.notify_ready
+    }
+
+    /// Reset for a freshly launched process. `awaits_notify` is set for
+    /// `Type=notify` services, which are not ready until they send `READY=1`.
+    pub fn reset(&mut self, config: &HealthCheckConfig, awaits_notify: bool) {
+        self.probe_ready = config.ready_check.is_none();
+        self.notify_ready = !awaits_notify;
+        self.state = if !self.is_ready() {
+            HealthState::Starting
+        } else if config.health_check.is_some() {
+            HealthState::Healthy
+        } else {
+            HealthState::Unknown
+        };
+        self.consecutive_failures = 0;
+        self.last_probe = None;
+        self.started_at = Some(Instant::now());
+        self.probing = false;
+    }
+
+    /// Clear state when the process exits.
+    pub fn stopped(&mut self) {
+        self.probe_ready = false;
+        self.notify_ready = false;
+        self.state = HealthState::Unknown;
+        self.consecutive_failures = 0;
+        self.started_at = None;
+        self.probing = false;
+    }
+
+    /// Which probe, if any, is due now.
+    pub fn due(&self, config: &HealthCheckConfig) -> Option<ProbeKind> {
+        self.started_at?;
+        if self.probing {
+            return None;
+        }
+
+        if !self.probe_ready {
+            config.ready_check.as_ref()?;
+            if self.state == HealthState::Unhealthy {
+                return None;
+            }
+            return match self.last_probe {
+                Some(last) if last.elapsed() < READY_POLL_INTERVAL => None,
+                _ => Some(ProbeKind::Ready),
+            };
+        }
+
+        if !self.notify_ready {
+            return None;
+        }
+
+        config.health_check.as_ref()?;
+        let interval = Duration::from_secs(config.health_check_interval_sec.max(1));
+        let since = self.last_probe.or(self.started_at)?;
+        if since.elapsed() >= interval {
+            Some(ProbeKind::Liveness)
+        } else {
+            None
+        }
+    }
+
+    /// Whether readiness has not been reached within `ReadyTimeoutSec`.
+    pub fn ready_timed_out(&self, config: &HealthCheckConfig) -> bool {
+        !self.is_ready()
+            && self.state != HealthState::Unhealthy
+            && self
+                .started_at
+                .map(|s| s.elapsed() >= Duration::from_secs(config.ready_timeout_sec))
+                .unwrap_or(false)
+    }
+
+    /// Note that the due probe has been started.
+    pub fn probe_started(&mut self) {
+        self.probing = true;
+    }
+
+    /// Mark the service unhealthy (readiness timeout or watchdog expiry).
+    pub fn mark_unhealthy(&mut self) {
+        self.state = HealthState::Unhealthy;
+    }
+
+    /// Record `READY=1` from the service's notification socket.
+    pub fn record_notify_ready(&mut self) -> HealthTransition {
+        if self.notify_ready {
+            return HealthTransition::None;
+        }
+        self.notify_ready = true;
+        self.check_became_ready()
+    }
+
+    fn check_became_ready(&mut self) -> HealthTransition {
+        if self.is_ready() && self.state != HealthState::Unhealthy {
+            self.state = HealthState::Healthy;
+            HealthTransition::BecameReady
+        } else {
+            HealthTransition::None
+        }
+    }
+
+    /// Record a probe result and report any state change.
+    pub fn record(
+        &mut self,
+        kind: ProbeKind,
+        result: ProbeResult,
+        config: &HealthCheckConfig,
+    ) -> HealthTransition {
+        let success = result.success;
+        self.probing = false;
+        self.last_probe = Some(Instant::now());
+        self.last_result = Some(result);
+
+        match kind {
+            ProbeKind::Ready => {
+                if success && !self.probe_ready {
+                    self.probe_ready = true;
+                    self.check_became_ready()
+                } else {
+                    HealthTransition::None
+                }
+            }
+            ProbeKind::Liveness => {
+                if success {
+                    self.consecutive_failures = 0;
+                    if self.state == HealthState::Unhealthy {
+                        self.state = HealthState::Healthy;
+                        return HealthTransition::Recovered;
+                    }
+                    self.state = HealthState::Healthy;
+                    HealthTransition::None
+                } else {
+                    self.consecutive_failures += 1;
+                    if self.consecutive_failures >= config.health_check_retries.max(1)
+                        && self.state != HealthState::Unhealthy
+                    {
+                        self.state = HealthState::Unhealthy;
+                        HealthTransition::BecameUnhealthy
+                    } else {
+                        HealthTransition::None
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(success: bool) -> ProbeResult {
+        ProbeResult {
+            success,
+            message: String::new(),
+            timestamp: 0,
+            duration_ms: 0,
+        }
+    }
+
+    #[test]
+    fn test_parse_probe() {
+        assert_eq!(Probe::parse("tcp:8080").unwrap(), Probe::Tcp("127.0.0.1:8080".to_string()));
+        assert_eq!(Probe::parse("tcp:10.0.0.1:80").unwrap(), Probe::Tcp("10.0.0.1:80".to_string()));
+        assert_eq!(Probe::parse("unix:/run/app.sock").unwrap(), Probe::Unix("/run/app.sock".to_string()));
+        assert_eq!(Probe::parse("file:/run/ready").unwrap(), Probe::File("/run/ready".to_string()));
+        assert_eq!(
+            Probe::parse("exec:/bin/check --quick").unwrap(),
+            Probe::Exec("/bin/check --quick".to_string())
+        );
+        assert!(Probe::parse("http://localhost").is_err());
+        assert!(Probe::parse("tcp:").is_err());
+    }
+
+    #[test]
+    fn test_file_probe() {
+        assert!(Probe::File("/".to_string()).run(Duration::from_secs(1)).success);
+        assert!(!Probe::File("/nonexistent/ready".to_string()).run(Duration::from_secs(1)).success);
+    }
+
+    #[test]
+    fn test_liveness_retries() {
+        let config = HealthCheckConfig {
+            health_check: Some(Probe::File("/".to_string())),
+            health_check_retries: 2,
+            ..Default::default()
+        };
+        let mut tracker = HealthTracker::new();
+        tracker.reset(&config, false);
+        assert!(tracker.is_ready());
+
+        assert_eq!(tracker.record(ProbeKind::Liveness, result(false), &config), HealthTransition::None);
+        assert_eq!(
+            tracker.record(ProbeKind::Liveness, result(false), &config),
+            HealthTransition::BecameUnhealthy
+        );
+        assert_eq!(tracker.state, HealthState::Unhealthy);
+        assert_eq!(tracker.record(ProbeKind::Liveness, result(true), &config), HealthTransition::Recovered);
+    }
+
+    #[test]
+    fn test_readiness() {
+        let config = HealthCheckConfig {
+            ready_check: Some(Probe::File("/".to_string())),
+            ..Default::default()
+        };
+        let mut tracker = HealthTracker::new();
+        tracker.reset(&config, false);
+        assert!(!tracker.is_ready());
+        assert_eq!(tracker.due(&config), Some(ProbeKind::Ready));
+        tracker.probe_started();
+        assert_eq!(tracker.due(&config), None);
+        assert_eq!(tracker.record(ProbeKind::Ready, result(true), &config), HealthTransition::BecameReady);
+        assert!(tracker.is_ready());
+    }
+
+    #[test]
+    fn test_readiness_with_notify() {
+        let config = HealthCheckConfig {
+            ready_check: Some(Probe::File("/".to_string())),
+            ..Default::default()
+        };
+        let mut tracker = HealthTracker::new();
+        tracker.reset(&config, true);
+        assert_eq!(tracker.state, HealthState::Starting);
+        assert_eq!(tracker.record(ProbeKind::Ready, result(true), &config), HealthTransition::None);
+        assert!(!tracker.is_ready());
+        assert_eq!(tracker.record_notify_ready(), HealthTransition::BecameReady);
+        assert!(tracker.is_ready());
+    }
+}
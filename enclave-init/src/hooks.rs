@@ -0,0 +1,268 @@
+//! Service lifecycle hooks and helper commands run by init.
+//!
+//! `ExecStartPre`, `ExecStartPost`, `ExecStop`, `ExecStopPost` and `ExecReload`
+//! commands run synchronously, in order. A command prefixed with `-` may fail
+//! without failing the hook list, as in systemd.
+
+use crate::logger::{Logger, ServiceLogger};
+use crate::output::{forward_lines, OutputStream};
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::process::{Command, Stdio};
+use std::sync::{LazyLock, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Exit codes of helper commands, filled in by init's reaper.
+///
+/// Init reaps every child with `waitpid(-1)`. A helper registers its PID here
+/// before the reaper can see it exit; whichever of the helper's own `waitpid`
+/// and the reaper collects the status first, the helper gets its exit code.
+static HELPER_PIDS: LazyLock<Mutex<HashMap<i32, Option<i32>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Record the exit of a helper command. Returns false if the PID is not a helper.
+pub fn record_exit(pid: i32, exit_code: i32) -> bool {
+    let mut pids = HELPER_PIDS.lock().unwrap();
+    match pids.get_mut(&pid) {
+        Some(slot) => {
+            *slot = Some(exit_code);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Deserialize a command list given either as a single string or an array.
+pub fn deserialize_commands<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(s) => vec![s],
+        OneOrMany::Many(v) => v,
+    })
+}
+
+/// Execution context shared by a service's hook commands.
+#[derive(Debug, Clone)]
+pub struct HookContext {
+    pub env: Vec<String>,
+    pub working_directory: Option<String>,
+    pub timeout: Duration,
+}
+
+/// A failed hook command.
+#[derive(Debug, Clone)]
+pub struct HookError {
+    pub command: String,
+    /// Exit code, or None if the command could not be run or timed out
+    pub exit_code: Option<i32>,
+    pub message: String,
+}
+
+impl std::fmt::Display for HookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "'{}' {}", self.command, self.message)
+    }
+}
+
+/// Run a list of hook commands in order, stopping at the first failure.
+pub fn run_hooks(
+    kind: &str,
+    commands: &[String],
+    ctx: &HookContext,
+    logger: &ServiceLogger,
+) -> Result<(), HookError> {
+    for entry in commands {
+        let (ignore_failure, command) = match entry.strip_prefix('-') {
+            Some(rest) => (true, rest.trim_start()),
+            None => (false, entry.as_str()),
+        };
+
+        logger.log(format!("Running {}: {}", kind, command));
+
+        let argv = crate::shell_words::split(command).unwrap_or_else(|_| vec![command.to_string()]);
+        let result = run_command(&argv, Some(&ctx.env), ctx.working_directory.as_deref(), ctx.timeout, Some(logger));
+
+        let error = match result {
+            Ok(0) => continue,
+            Ok(code) => HookError {
+                command: command.to_string(),
+                exit_code: Some(code),
+                message: format!("exited with code {}", code),
+            },
+            Err(message) => HookError {
+                command: command.to_string(),
+                exit_code: None,
+                message,
+            },
+        };
+
+        if ignore_failure {
+            logger.log(format!("{} {} (ignored)", kind, error));
+            continue;
+        }
+
+        logger.log(format!("{} failed: {}", kind, error));
+        return Err(error);
+    }
+    Ok(())
+}
+
+/// Run a command to completion and return its exit code.
+///
+/// `env` replaces the environment when given. With a `logger`, the command's
+/// stdout and stderr are written to the service log; otherwise they are discarded.
+pub fn run_command(
+    argv: &[String],
+    env: Option<&[String]>,
+    working_directory: Option<&str>,
+    timeout: Duration,
+    logger: Option<&ServiceLogger>,
+) -> Result<i32, String> {
+    if argv.is_empty() {
+        return Err("empty command".to_string());
+    }
+
+    let mut command = Command::new(&argv[0]);
+    command.args(&argv[1..]).stdin(Stdio::null());
+
+    if let Some(env) = env {
+        command.env_clear();
+        for var in env {
+            if let Some((key, value)) = var.split_once('=') {
+                command.env(key, value);
+            }
+        }
+    }
+    if let Some(wd) = working_directory {
+        command.current_dir(wd);
+    }
+    if logger.is_some() {
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    } else {
+        command.stdout(Stdio::null()).stderr(Stdio::null());
+    }
+
+    // Hold the registry lock across spawn and insert, so the reaper cannot
+    // observe the exit before the PID is registered.
+    let mut child = {
+        let mut pids = HELPER_PIDS.lock().unwrap();
+        let child = command
+            .spawn()
+            .map_err(|e| format!("failed to run '{}': {}", argv[0], e))?;
+        pids.insert(child.id() as i32, None);
+        child
+    };
+    let pid = child.id() as i32;
+
+    if let Some(logger) = logger {
+        for (pipe, stream) in [
+            (child.stdout.take().map(|p| Box::new(p) as Box<dyn std::io::Read + Send>), OutputStream::Stdout),
+            (child.stderr.take().map(|p| Box::new(p) as Box<dyn std::io::Read + Send>), OutputStream::Stderr),
+        ] {
+            if let Some(pipe) = pipe {
+                let logger = logger.clone();
+                thread::spawn(move || forward_lines(BufReader::new(pipe), &logger, stream));
+            }
+        }
+    }
+
+    wait_for_exit(pid, timeout)
+}
+
+/// Wait for a registered helper to exit, killing it after `timeout`.
+fn wait_for_exit(pid: i32, timeout: Duration) -> Result<i32, String> {
+    let deadline = Instant::now() + timeout;
+    let mut killed = false;
+
+    loop {
+        let recorded = HELPER_PIDS.lock().unwrap().get(&pid).copied().flatten();
+        if let Some(code) = recorded {
+            HELPER_PIDS.lock().unwrap().remove(&pid);
+            return if killed {
+                Err(format!("timed out after {}s", timeout.as_secs()))
+            } else {
+                Ok(code)
+            };
+        }
+
+        let status = match waitpid(Pid::from_raw(pid), Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::Exited(_, code)) => Some(code),
+            Ok(WaitStatus::Signaled(_, signal, _)) => Some(128 + signal as i32),
+            // Collected by init's reaper; the code arrives in the registry
+            Err(Errno::ECHILD) => None,
+            _ => None,
+        };
+        if let Some(code) = status {
+            record_exit(pid, code);
+            continue;
+        }
+
+        if !killed && Instant::now() >= deadline {
+            Logger::warn(&format!("Helper command (PID {}) timed out, killing", pid));
+            let _ = kill(Pid::from_raw(pid), Signal::SIGKILL);
+            killed = true;
+        }
+
+        // Give up on the exit code if a killed helper is never reported
+        if killed && Instant::now() >= deadline + Duration::from_secs(5) {
+            HELPER_PIDS.lock().unwrap().remove(&pid);
+            return Err(format!("timed out after {}s", timeout.as_secs()));
+        }
+
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_command_exit_code() {
+        let timeout = Duration::from_secs(5);
+        assert_eq!(run_command(&["true".to_string()], None, None, timeout, None), Ok(0));
+        assert_eq!(
+            run_command(&["sh".to_string(), "-c".to_string(), "exit 3".to_string()], None, None, timeout, None),
+            Ok(3)
+        );
+        assert!(run_command(&["/nonexistent/cmd".to_string()], None, None, timeout, None).is_err());
+    }
+
+    #[test]
+    fn test_run_command_timeout() {
+        let result = run_command(
+            &["sleep".to_string(), "10".to_string()],
+            None,
+            None,
+            Duration::from_millis(200),
+            None,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_deserialize_commands() {
+        #[derive(Deserialize)]
+        struct Hooks {
+            #[serde(default, deserialize_with = "deserialize_commands")]
+            pre: Vec<String>,
+        }
+
+        let one: Hooks = toml::from_str("pre = \"mkdir -p /run/app\"").unwrap();
+        assert_eq!(one.pre, vec!["mkdir -p /run/app".to_string()]);
+        let many: Hooks = toml::from_str("pre = [\"a\", \"-b\"]").unwrap();
+        assert_eq!(many.pre.len(), 2);
+    }
+}
@@ -1,21 +1,30 @@
 mod config;
 mod protocol;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::{Parser, Subcommand};
 use config::{ControlProtocol, InitctlConfig};
 use nix::sys::socket::{
-    accept, bind, connect, listen, recv, send, socket, AddressFamily, MsgFlags, SockFlag,
+    accept, bind, connect, listen, recv, socket, AddressFamily, MsgFlags, SockFlag,
     SockType, UnixAddr, VsockAddr,
 };
 use nix::unistd::close;
-use protocol::{Request, Response};
-use std::fs::File;
+use hmac::Mac;
+use protocol::{
+    auth_mac, bracketed_time, read_frame, write_frame, ActiveState, AuthResponse, CgroupUsage, DependencyNode, Event,
+    EventFilter, EventKind, FdStream, ForwardAck, ForwardMessage, ForwardedRecord, FrameError, HealthState, Hello,
+    HelloReply, LogLevel, LogQuery, LogRecord, MetricsSample, Request, RequestFrame, Response, ResponseFrame, Role,
+    ServiceMetrics, VsockEndpoint, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use std::collections::HashMap;
+use std::fs::{self, File};
 use std::io::Write;
+use std::os::unix::io::RawFd;
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Parser)]
 #[command(name = "initctl")]
@@ -41,6 +50,14 @@ struct Cli {
     #[arg(long)]
     vsock_port: Option<u32>,
 
+    /// Name of the pre-shared key to authenticate with
+    #[arg(long, env = "INITCTL_KEY_NAME")]
+    key_name: Option<String>,
+
+    /// File holding the pre-shared key
+    #[arg(long, env = "INITCTL_KEY_FILE")]
+    key_file: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -96,15 +113,54 @@ enum Commands {
         name: String,
     },
 
-    /// Show logs of a service
-    Logs {
+    /// Clear the failed state and start limit of a service (all services if omitted)
+    ResetFailed {
         /// Service name
         #[arg(value_name = "SERVICE")]
-        name: String,
+        name: Option<String>,
+    },
 
-        /// Number of lines to show
-        #[arg(short = 'n', long, default_value = "50")]
-        lines: usize,
+    /// Show logs of a service, or of several services merged by time
+    Logs {
+        /// Service names
+        #[arg(value_name = "SERVICE", required_unless_present = "all")]
+        names: Vec<String>,
+
+        /// Number of lines to show [default: 50, all with --since or --until]
+        #[arg(short = 'n', long)]
+        lines: Option<usize>,
+
+        /// Keep printing new lines as they are logged
+        #[arg(short, long, conflicts_with_all = ["since", "until", "grep", "level", "all", "output"])]
+        follow: bool,
+
+        /// Show records from this time on, e.g. "2024-05-01 12:00", "-1h" or "today"
+        #[arg(long, allow_hyphen_values = true)]
+        since: Option<String>,
+
+        /// Show records up to this time
+        #[arg(long, allow_hyphen_values = true)]
+        until: Option<String>,
+
+        /// Show only messages containing this text
+        #[arg(long)]
+        grep: Option<String>,
+
+        /// Treat --grep as a regular expression
+        #[arg(long, requires = "grep")]
+        regex: bool,
+
+        /// Show only records of this level or more severe: error, warn, info or debug
+        #[arg(long, value_parser = parse_log_level)]
+        level: Option<LogLevel>,
+
+        /// Show the logs of all services
+        #[arg(long, conflicts_with = "names")]
+        all: bool,
+
+        /// Output format: text, or json with one record per line
+        #[arg(short, long, value_parser = ["text", "json"])]
+        output: Option<String>,
     },
 
     /// Stream logs of a service in real-time (from enclave to host via VSock)
@@ -131,6 +187,25 @@ enum Commands {
         follow: bool,
     },
 
+    /// Receive the logs init forwards with `log_forward` (run on the host)
+    LogsCollect {
+        /// Directory for per-service log files; without one, records are printed
+        #[arg(short, long)]
+        dir: Option<String>,
+
+        /// VSock CID to listen on (default VMADDR_CID_ANY)
+        #[arg(long, default_value = "4294967295")]
+        listen_cid: u32,
+
+        /// VSock port to listen on, `log_forward.port` in init.yaml
+        #[arg(long, default_value = "9002")]
+        listen_port: u32,
+
+        /// Output format: text, or json with one record per line
+        #[arg(short, long, value_parser = ["text", "json"])]
+        output: Option<String>,
+    },
+
     /// Clear logs of a service
     LogsClear {
         /// Service name
@@ -138,6 +213,138 @@ enum Commands {
         name: String,
     },
 
+    /// Install a service file into init's service directory and reload
+    Install {
+        /// Service file; the service is named after it without `.service`
+        #[arg(value_name = "FILE")]
+        file: String,
+
+        /// Service name, if not the file name
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Only validate the file and show the resulting startup order
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Check a service file against the loaded services without installing it
+    Validate {
+        /// Service file; the service is named after it without `.service`
+        #[arg(value_name = "FILE")]
+        file: String,
+
+        /// Service name, if not the file name
+        #[arg(long)]
+        name: Option<String>,
+    },
+
+    /// Remove a service file from init's service directory and reload
+    Remove {
+        /// Service name
+        #[arg(value_name = "SERVICE")]
+        name: String,
+    },
+
+    /// Print the file a service is loaded from
+    Cat {
+        /// Service name
+        #[arg(value_name = "SERVICE")]
+        name: String,
+    },
+
+    /// Show the environment a service's next start would get
+    ShowEnv {
+        /// Service name
+        #[arg(value_name = "SERVICE")]
+        name: String,
+    },
+
+    /// Show recent service events, or follow new ones
+    Events {
+        /// Keep printing new events until interrupted
+        #[arg(short, long)]
+        follow: bool,
+
+        /// Only events of this service (repeatable)
+        #[arg(long = "service", value_name = "SERVICE")]
+        services: Vec<String>,
+
+        /// Only events of this type (repeatable): started, exited, restarting,
+        /// failed, health-changed or reloaded
+        #[arg(long = "event", value_name = "EVENT")]
+        events: Vec<String>,
+
+        /// Start after this sequence number, replaying retained events
+        #[arg(long, value_name = "SEQ")]
+        after: Option<u64>,
+
+        /// Output format: text, or json with one event per line
+        #[arg(short, long, value_parser = ["text", "json"])]
+        output: Option<String>,
+
+        /// Have init send the events to a VSock listener at this CID instead
+        #[arg(long, requires = "vsock_port")]
+        vsock_cid: Option<u32>,
+
+        /// VSock port of the listener
+        #[arg(long, requires = "vsock_cid")]
+        vsock_port: Option<u32>,
+    },
+
+    /// Show the latest CPU, memory, FD and thread usage of every service
+    Top {
+        /// Refresh every sampling interval until interrupted
+        #[arg(short, long)]
+        watch: bool,
+    },
+
+    /// Show the sampled metrics history of a service
+    Stats {
+        /// Service name
+        #[arg(value_name = "SERVICE")]
+        name: String,
+
+        /// Number of recent samples to list
+        #[arg(short = 'n', long, default_value = "20")]
+        lines: usize,
+    },
+
+    /// List timers with their last and next trigger times
+    ListTimers,
+
+    /// Show the services a service requires, as a tree
+    ListDependencies {
+        /// Service name
+        #[arg(value_name = "SERVICE")]
+        name: String,
+
+        /// Show the services that require this one instead
+        #[arg(long)]
+        reverse: bool,
+    },
+
+    /// List targets with their services
+    ListTargets,
+
+    /// Switch to a target, stopping the services it does not include
+    Isolate {
+        /// Target name; the `.target` suffix may be omitted
+        #[arg(value_name = "TARGET")]
+        target: String,
+    },
+
+    /// List the service executables hashed since boot
+    Measurements {
+        /// Only executables of this service
+        #[arg(long = "service", value_name = "SERVICE")]
+        service: Option<String>,
+
+        /// Output format: text, or json with one entry per line
+        #[arg(short, long, value_parser = ["text", "json"])]
+        output: Option<String>,
+    },
+
     /// Process management commands
     #[command(subcommand)]
     Ps(PsCommands),
@@ -145,8 +352,11 @@ enum Commands {
     /// Show system status
     SystemStatus,
 
-    /// Reload service configurations
-    Reload,
+    /// Reload service configurations, or reload a single service
+    Reload {
+        /// Service to reload via its ExecReload commands (or SIGHUP)
+        name: Option<String>,
+    },
 
     /// Reboot the system
     Reboot,
@@ -212,68 +422,167 @@ enum PsCommands {
     },
 }
 
-fn send_request_unix(socket_path: &str, request: Request) -> Result<Response> {
-    let socket_fd = socket(
-        AddressFamily::Unix,
-        SockType::Stream,
-        SockFlag::empty(),
-        None,
-    )
-    .context("Failed to create Unix socket")?;
+/// A framed, version-negotiated connection to init's control socket.
+///
+/// Several requests can be sent over one connection; streaming requests
+/// produce response frames until the stream ends.
+struct ControlClient {
+    fd: RawFd,
+    next_id: u64,
+    /// Pre-shared key name and secret, answered to an authentication challenge
+    key: Option<(String, Vec<u8>)>,
+    /// Role granted by init in the handshake
+    role: Role,
+}
 
-    let addr = UnixAddr::new(socket_path).context("Failed to create Unix socket address")?;
+impl ControlClient {
+    fn connect(config: &InitctlConfig) -> Result<Self> {
+        let key = match (&config.key_name, &config.key_file) {
+            (Some(name), Some(path)) => {
+                let mut secret =
+                    fs::read(path).with_context(|| format!("Failed to read key file {}", path))?;
+                while secret.last().is_some_and(|b| b.is_ascii_whitespace()) {
+                    secret.pop();
+                }
+                Some((name.clone(), secret))
+            }
+            (None, None) => None,
+            _ => bail!("key_name and key_file must be given together"),
+        };
 
-    connect(socket_fd, &addr).context("Failed to connect to Unix socket")?;
+        let mut client = match config.protocol {
+            ControlProtocol::Unix => {
+                let fd = socket(AddressFamily::Unix, SockType::Stream, SockFlag::empty(), None)
+                    .context("Failed to create Unix socket")?;
+                let client = Self { fd, next_id: 1, key, role: Role::default() };
+                let addr = UnixAddr::new(config.unix_socket_path.as_str())
+                    .context("Failed to create Unix socket address")?;
+                connect(fd, &addr).context("Failed to connect to Unix socket")?;
+                client
+            }
+            ControlProtocol::Vsock => {
+                let fd = socket(AddressFamily::Vsock, SockType::Stream, SockFlag::empty(), None)
+                    .context("Failed to create VSOCK socket")?;
+                let client = Self { fd, next_id: 1, key, role: Role::default() };
+                let addr = VsockAddr::new(config.vsock_cid, config.vsock_port);
+                connect(fd, &addr).context("Failed to connect to VSOCK")?;
+                client
+            }
+        };
 
-    let request_data = serde_json::to_vec(&request).context("Failed to serialize request")?;
+        let addr_desc = match config.protocol {
+            ControlProtocol::Unix => config.unix_socket_path.clone(),
+            ControlProtocol::Vsock => format!("CID:{} PORT:{}", config.vsock_cid, config.vsock_port),
+        };
+        client.handshake(&addr_desc)?;
+        Ok(client)
+    }
 
-    send(socket_fd, &request_data, MsgFlags::empty())
-        .context("Failed to send request")?;
+    fn handshake(&mut self, addr_desc: &str) -> Result<()> {
+        let mut stream = FdStream(self.fd);
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            agent: format!("initctl {}", env!("CARGO_PKG_VERSION")),
+            key: self.key.as_ref().map(|(name, _)| name.clone()),
+        };
+        write_frame(&mut stream, &hello).context("Failed to send handshake")?;
+
+        let mut reply = read_frame::<_, HelloReply>(&mut stream);
+        if let Ok(HelloReply::Challenge { nonce }) = &reply {
+            let Some((name, key)) = &self.key else {
+                bail!(
+                    "init at {} requires authentication; set key_name and key_file in the initctl \
+                     configuration or pass --key-name and --key-file",
+                    addr_desc
+                );
+            };
+            let mac = auth_mac(key, nonce).finalize().into_bytes().to_vec();
+            write_frame(&mut stream, &AuthResponse { mac })
+                .with_context(|| format!("Failed to authenticate with key '{}'", name))?;
+            reply = read_frame(&mut stream);
+        }
 
-    let mut buffer = vec![0u8; 65536];
-    let n = recv(socket_fd, &mut buffer, MsgFlags::empty())
-        .context("Failed to receive response")?;
+        match reply {
+            Ok(HelloReply::Accepted { role, .. }) => {
+                self.role = role;
+                Ok(())
+            }
+            Ok(HelloReply::Challenge { .. }) => bail!("init at {} sent a second challenge", addr_desc),
+            Ok(HelloReply::Rejected { message, min_version, max_version })
+                if max_version < MIN_PROTOCOL_VERSION || min_version > PROTOCOL_VERSION =>
+            {
+                bail!(
+                    "init at {} supports control protocol versions {}-{}, this initctl speaks {}-{}: {}",
+                    addr_desc, min_version, max_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, message
+                )
+            }
+            Ok(HelloReply::Rejected { message, .. }) if self.key.is_none() => bail!(
+                "init at {} rejected the connection: {}; set key_name and key_file in the initctl \
+                 configuration or pass --key-name and --key-file",
+                addr_desc, message
+            ),
+            Ok(HelloReply::Rejected { message, .. }) => {
+                bail!("init at {} rejected the connection: {}", addr_desc, message)
+            }
+            // An init predating the framed protocol answers with bare JSON, or hangs up
+            Err(FrameError::Unframed) | Err(FrameError::Closed) => bail!(
+                "init at {} does not support the framed control protocol (version {}); \
+                 upgrade init or use an initctl of the same release",
+                addr_desc, PROTOCOL_VERSION
+            ),
+            Err(e) => Err(e).context("Control protocol handshake failed"),
+        }
+    }
 
-    buffer.truncate(n);
+    fn send(&mut self, request: Request) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        write_frame(&mut FdStream(self.fd), &RequestFrame { id, request })
+            .context("Failed to send request")?;
+        Ok(id)
+    }
 
-    let response: Response = serde_json::from_slice(&buffer)
-        .context("Failed to deserialize response")?;
+    fn receive(&mut self, id: u64) -> Result<ResponseFrame> {
+        loop {
+            let frame: ResponseFrame =
+                read_frame(&mut FdStream(self.fd)).context("Failed to receive response")?;
+            // Id 0 answers a request init could not parse
+            if frame.id == id || frame.id == 0 {
+                return Ok(frame);
+            }
+        }
+    }
 
-    close(socket_fd).context("Failed to close socket")?;
+    /// Send a request and wait for its response.
+    fn request(&mut self, request: Request) -> Result<Response> {
+        let id = self.send(request)?;
+        Ok(self.receive(id)?.response)
+    }
 
-    Ok(response)
+    /// Send a streaming request and pass every response to `on_response`
+    /// until init ends the stream.
+    fn stream(&mut self, request: Request, mut on_response: impl FnMut(Response)) -> Result<()> {
+        let id = self.send(request)?;
+        loop {
+            let frame = self.receive(id)?;
+            on_response(frame.response);
+            if frame.done {
+                return Ok(());
+            }
+        }
+    }
 }
 
-fn send_request_vsock(cid: u32, port: u32, request: Request) -> Result<Response> {
-    let socket_fd = socket(
-        AddressFamily::Vsock,
-        SockType::Stream,
-        SockFlag::empty(),
-        None,
-    )
-    .context("Failed to create VSOCK socket")?;
-
-    let addr = VsockAddr::new(cid, port);
-
-    connect(socket_fd, &addr).context("Failed to connect to VSOCK")?;
-
-    let request_data = serde_json::to_vec(&request).context("Failed to serialize request")?;
-
-    send(socket_fd, &request_data, MsgFlags::empty())
-        .context("Failed to send request")?;
-
-    let mut buffer = vec![0u8; 65536];
-    let n = recv(socket_fd, &mut buffer, MsgFlags::empty())
-        .context("Failed to receive response")?;
-
-    buffer.truncate(n);
-
-    let response: Response = serde_json::from_slice(&buffer)
-        .context("Failed to deserialize response")?;
-
-    close(socket_fd).context("Failed to close socket")?;
+impl Drop for ControlClient {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
 
-    Ok(response)
+/// Send a single request over a new connection.
+fn send_request(config: &InitctlConfig, request: Request) -> Result<Response> {
+    ControlClient::connect(config)?.request(request)
 }
 
 /// Listen on VSock for incoming log stream data from enclave
@@ -397,10 +706,7 @@ fn handle_logs_stream(
         vsock_port: listen_port,
     };
 
-    let response = match config.protocol {
-        ControlProtocol::Unix => send_request_unix(&config.unix_socket_path, request)?,
-        ControlProtocol::Vsock => send_request_vsock(config.vsock_cid, config.vsock_port, request)?,
-    };
+    let response = send_request(config, request)?;
 
     match response {
         Response::LogsStreamStarted { service, vsock_cid, vsock_port } => {
@@ -431,10 +737,7 @@ fn handle_logs_stream(
         let stop_request = Request::ServiceLogsStreamStop {
             name: service_name.to_string(),
         };
-        let _ = match config.protocol {
-            ControlProtocol::Unix => send_request_unix(&config.unix_socket_path, stop_request),
-            ControlProtocol::Vsock => send_request_vsock(config.vsock_cid, config.vsock_port, stop_request),
-        };
+        let _ = send_request(config, stop_request);
         eprintln!("Log streaming stopped");
     } else {
         // Non-follow mode: just setup streaming and exit
@@ -449,6 +752,127 @@ fn handle_logs_stream(
     Ok(())
 }
 
+/// Where `logs-collect` writes forwarded records, and the last sequence
+/// number written for each boot.
+struct Collector {
+    dir: Option<String>,
+    json: bool,
+    files: HashMap<String, File>,
+    last_seq: HashMap<String, u64>,
+}
+
+impl Collector {
+    /// Write a batch from boot `boot_id`, skipping records already written
+    /// and reporting gaps. Returns the sequence number to acknowledge.
+    fn receive(&mut self, boot_id: &str, records: &[ForwardedRecord]) -> Result<Option<u64>> {
+        for forwarded in records {
+            match self.last_seq.get(boot_id) {
+                Some(&last) if forwarded.seq <= last => continue,
+                Some(&last) if forwarded.seq > last + 1 => eprintln!(
+                    "Warning: {} records from boot {} were lost (#{} to #{})",
+                    forwarded.seq - last - 1,
+                    boot_id,
+                    last + 1,
+                    forwarded.seq - 1
+                ),
+                None if forwarded.seq > 0 => {
+                    eprintln!("First record received from boot {} is #{}", boot_id, forwarded.seq)
+                }
+                _ => {}
+            }
+            self.write(&forwarded.record)?;
+            self.last_seq.insert(boot_id.to_string(), forwarded.seq);
+        }
+        Ok(records.last().map(|forwarded| forwarded.seq))
+    }
+
+    fn write(&mut self, record: &LogRecord) -> Result<()> {
+        let line = if self.json {
+            serde_json::to_string(record)?
+        } else {
+            record.to_line(self.dir.is_none())
+        };
+        let Some(dir) = &self.dir else {
+            println!("{}", line);
+            return Ok(());
+        };
+        let file = match self.files.entry(record.service.clone()) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                let path = Path::new(dir).join(format!("{}.log", record.service.replace('/', "_")));
+                let file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .with_context(|| format!("Failed to open {}", path.display()))?;
+                entry.insert(file)
+            }
+        };
+        writeln!(file, "{}", line)?;
+        Ok(())
+    }
+}
+
+/// Receive records from one forwarding connection until it closes.
+fn collect_connection(fd: RawFd, collector: &Mutex<Collector>) -> Result<()> {
+    let mut stream = FdStream(fd);
+    let boot_id = match read_frame(&mut stream)? {
+        ForwardMessage::Hello { boot_id, agent } => {
+            eprintln!("Receiving logs from {} (boot {})", agent, boot_id);
+            boot_id
+        }
+        ForwardMessage::Records { .. } => bail!("connection did not start with a hello"),
+    };
+    loop {
+        let records = match read_frame(&mut stream) {
+            Ok(ForwardMessage::Records { records }) => records,
+            Ok(ForwardMessage::Hello { .. }) => bail!("unexpected second hello"),
+            Err(FrameError::Closed) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let ack = collector.lock().unwrap().receive(&boot_id, &records)?;
+        if let Some(seq) = ack {
+            write_frame(&mut stream, &ForwardAck { seq }).context("Failed to acknowledge records")?;
+        }
+    }
+}
+
+/// Handle the logs-collect command: accept forwarding connections from init
+/// until interrupted.
+fn handle_logs_collect(dir: Option<String>, listen_cid: u32, listen_port: u32, json: bool) -> Result<()> {
+    if let Some(dir) = &dir {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir))?;
+    }
+
+    let socket_fd = socket(AddressFamily::Vsock, SockType::Stream, SockFlag::empty(), None)
+        .context("Failed to create VSock listener socket")?;
+    bind(socket_fd, &VsockAddr::new(listen_cid, listen_port)).context("Failed to bind VSock listener")?;
+    listen(socket_fd, 4).context("Failed to listen on VSock")?;
+    eprintln!("Collecting forwarded logs on CID:{} PORT:{}", listen_cid, listen_port);
+
+    let collector = Arc::new(Mutex::new(Collector {
+        dir,
+        json,
+        files: HashMap::new(),
+        last_seq: HashMap::new(),
+    }));
+    loop {
+        let fd = match accept(socket_fd) {
+            Ok(fd) => fd,
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(e) => return Err(e).context("Failed to accept VSock connection"),
+        };
+        let collector = Arc::clone(&collector);
+        thread::spawn(move || {
+            match collect_connection(fd, &collector) {
+                Ok(()) => eprintln!("Log forwarding connection closed"),
+                Err(e) => eprintln!("Log forwarding connection failed: {:#}", e),
+            }
+            let _ = close(fd);
+        });
+    }
+}
+
 fn format_uptime(secs: u64) -> String {
     let days = secs / 86400;
     let hours = (secs % 86400) / 3600;
@@ -476,6 +900,149 @@ fn format_memory(kb: u64) -> String {
     }
 }
 
+fn print_cgroup_usage(cgroup: &CgroupUsage) {
+    println!("  Cgroup: {}", cgroup.path);
+    let memory_max = cgroup
+        .memory_max_bytes
+        .map(|max| format_memory(max / 1024))
+        .unwrap_or_else(|| "unlimited".to_string());
+    print!("    Memory: {} (max: {}", format_memory(cgroup.memory_current_bytes / 1024), memory_max);
+    if let Some(peak) = cgroup.memory_peak_bytes {
+        print!(", peak: {}", format_memory(peak / 1024));
+    }
+    println!(")");
+    println!("    CPU Time: {:.2}s", cgroup.cpu_usage_usec as f64 / 1_000_000.0);
+    println!("    Tasks: {}", cgroup.tasks_current);
+}
+
+/// Print the latest sample of each service, busiest first.
+fn print_metrics_table(services: &[ServiceMetrics]) {
+    let mut rows: Vec<(&ServiceMetrics, &MetricsSample)> =
+        services.iter().filter_map(|s| Some((s, s.samples.last()?))).collect();
+    rows.sort_by(|a, b| b.1.cpu_percent.total_cmp(&a.1.cpu_percent).then_with(|| a.0.name.cmp(&b.0.name)));
+
+    println!("{:<25} {:<10} {:>6} {:>8} {:>6} {:>8} {:>9}", "SERVICE", "STATE", "CPU%", "MEM", "FDS", "THREADS", "RESTARTS");
+    println!("{}", "-".repeat(78));
+    for (service, sample) in rows {
+        println!(
+            "{:<25} {:<10} {:>6.1} {:>8} {:>6} {:>8} {:>9}",
+            service.name,
+            service.state.as_str(),
+            sample.cpu_percent,
+            format_memory(sample.memory_bytes / 1024),
+            sample.open_fds,
+            sample.threads,
+            sample.restarts
+        );
+    }
+}
+
+/// Print a summary of a service's history and its last `lines` samples.
+fn print_service_stats(service: &ServiceMetrics, interval_sec: u64, lines: usize) {
+    println!("Service: {} ({})", service.name, service.state.as_str());
+    let samples = &service.samples;
+    let (Some(first), Some(last)) = (samples.first(), samples.last()) else {
+        println!("  No samples yet");
+        return;
+    };
+    println!(
+        "  Samples: {} over {} (every {}s)",
+        samples.len(),
+        format_uptime((last.timestamp - first.timestamp) / 1000),
+        interval_sec
+    );
+
+    let n = samples.len() as f64;
+    let summary = |value: fn(&MetricsSample) -> f64| {
+        let min = samples.iter().map(value).fold(f64::INFINITY, f64::min);
+        let max = samples.iter().map(value).fold(0.0, f64::max);
+        let avg = samples.iter().map(value).sum::<f64>() / n;
+        (value(last), min, avg, max)
+    };
+    println!("  {:<10} {:>9} {:>9} {:>9} {:>9}", "", "CURRENT", "MIN", "AVG", "MAX");
+    let (cur, min, avg, max) = summary(|s| s.cpu_percent as f64);
+    println!("  {:<10} {:>9.1} {:>9.1} {:>9.1} {:>9.1}", "CPU%", cur, min, avg, max);
+    let (cur, min, avg, max) = summary(|s| s.memory_bytes as f64 / 1024.0);
+    let mem = |kb: f64| format_memory(kb as u64);
+    println!("  {:<10} {:>9} {:>9} {:>9} {:>9}", "Memory", mem(cur), mem(min), mem(avg), mem(max));
+    let (cur, min, avg, max) = summary(|s| s.open_fds as f64);
+    println!("  {:<10} {:>9} {:>9} {:>9.1} {:>9}", "FDs", cur, min, avg, max);
+    let (cur, min, avg, max) = summary(|s| s.threads as f64);
+    println!("  {:<10} {:>9} {:>9} {:>9.1} {:>9}", "Threads", cur, min, avg, max);
+    println!("  Restarts: {}", last.restarts);
+
+    println!();
+    println!("  {:<10} {:>6} {:>8} {:>6} {:>8} {:>9}", "AGE", "CPU%", "MEM", "FDS", "THREADS", "RESTARTS");
+    for sample in &samples[samples.len().saturating_sub(lines)..] {
+        println!(
+            "  {:<10} {:>6.1} {:>8} {:>6} {:>8} {:>9}",
+            format!("-{}", format_uptime((last.timestamp - sample.timestamp) / 1000)),
+            sample.cpu_percent,
+            format_memory(sample.memory_bytes / 1024),
+            sample.open_fds,
+            sample.threads,
+            sample.restarts
+        );
+    }
+}
+
+/// Text form of an event, `[2024-05-01 12:00:00] #42 api: exited with code 1`.
+fn format_event(event: &Event) -> String {
+    let mut line = format!("{}#{} ", bracketed_time(event.timestamp), event.seq);
+    if let Some(service) = &event.service {
+        line.push_str(service);
+        line.push_str(": ");
+    }
+    line.push_str(&match &event.kind {
+        EventKind::Started { pid } => format!("started with PID {}", pid),
+        EventKind::Exited { code } => format!("exited with code {}", code),
+        EventKind::Restarting { delay_sec } => format!("restarting in {}s", delay_sec),
+        EventKind::Failed { reason } => format!("failed: {}", reason),
+        EventKind::HealthChanged { health, detail } => format!("health {} ({})", health.as_str(), detail),
+        EventKind::Reloaded { summary } => format!("services reloaded: {}", summary.describe()),
+    });
+    line
+}
+
+/// Read a service file for `install` or `validate`, with the service name.
+fn read_service_file(file: &str, name: &Option<String>) -> Result<(String, String)> {
+    let content = fs::read_to_string(file).with_context(|| format!("Failed to read {}", file))?;
+    let name = match name {
+        Some(name) => name.clone(),
+        None => match Path::new(file).file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_suffix(".service")) {
+            Some(stem) => stem.to_string(),
+            None => bail!("{} is not named <SERVICE>.service; pass --name", file),
+        },
+    };
+    Ok((name, content))
+}
+
+/// Print a `list-dependencies` node and its children as a tree.
+fn print_dependency_node(node: &DependencyNode, prefix: &str, connector: &str) {
+    let relation = node.relation.as_deref().map(|r| format!(" [{}]", r)).unwrap_or_default();
+    println!("{}{}{}{} ({})", prefix, connector, node.name, relation, node.state.as_str());
+
+    let child_prefix = match connector {
+        "├── " => format!("{}│   ", prefix),
+        "└── " => format!("{}    ", prefix),
+        _ => prefix.to_string(),
+    };
+    for (i, child) in node.children.iter().enumerate() {
+        let connector = if i + 1 == node.children.len() { "└── " } else { "├── " };
+        print_dependency_node(child, &child_prefix, connector);
+    }
+}
+
+fn parse_log_level(value: &str) -> Result<LogLevel, String> {
+    match value {
+        "error" => Ok(LogLevel::Error),
+        "warn" | "warning" => Ok(LogLevel::Warn),
+        "info" => Ok(LogLevel::Info),
+        "debug" => Ok(LogLevel::Debug),
+        _ => Err("expected error, warn, info or debug".to_string()),
+    }
+}
+
 fn format_state(state: &str) -> &str {
     match state {
         "R" => "Running",
@@ -524,6 +1091,14 @@ fn main() -> Result<()> {
         config.vsock_port = port;
     }
 
+    if cli.key_name.is_some() {
+        config.key_name = cli.key_name.clone();
+    }
+
+    if cli.key_file.is_some() {
+        config.key_file = cli.key_file.clone();
+    }
+
     // Handle logs-stream command specially
     if let Commands::LogsStream {
         ref name,
@@ -543,24 +1118,40 @@ fn main() -> Result<()> {
         );
     }
 
+    if let Commands::LogsCollect { ref dir, listen_cid, listen_port, ref output } = cli.command {
+        return handle_logs_collect(dir.clone(), listen_cid, listen_port, output.as_deref() == Some("json"));
+    }
+
+    // Handle top --watch specially: redraw on every new sample
+    if let Commands::Top { watch: true } = cli.command {
+        let mut client = ControlClient::connect(&config)?;
+        loop {
+            match client.request(Request::ServiceMetrics { name: None, samples: 1 })? {
+                Response::ServiceMetrics { interval_sec, services } => {
+                    print!("\x1b[2J\x1b[H");
+                    print_metrics_table(&services);
+                    thread::sleep(Duration::from_secs(interval_sec.max(1)));
+                }
+                Response::Error { message } | Response::PermissionDenied { message } => {
+                    eprintln!("✗ Error: {}", message);
+                    std::process::exit(1);
+                }
+                _ => bail!("Unexpected response to metrics request"),
+            }
+        }
+    }
+
     // Handle enable --now specially
     if let Commands::Enable { ref name, now } = cli.command {
-        let enable_request = Request::ServiceEnable { name: name.clone() };
-        let response = match config.protocol {
-            ControlProtocol::Unix => send_request_unix(&config.unix_socket_path, enable_request)?,
-            ControlProtocol::Vsock => send_request_vsock(config.vsock_cid, config.vsock_port, enable_request)?,
-        };
+        let mut client = ControlClient::connect(&config)?;
+        let response = client.request(Request::ServiceEnable { name: name.clone() })?;
 
         match response {
             Response::Success { message } => {
                 println!("✓ {}", message);
 
                 if now {
-                    let start_request = Request::ServiceStart { name: name.clone() };
-                    let start_response = match config.protocol {
-                        ControlProtocol::Unix => send_request_unix(&config.unix_socket_path, start_request)?,
-                        ControlProtocol::Vsock => send_request_vsock(config.vsock_cid, config.vsock_port, start_request)?,
-                    };
+                    let start_response = client.request(Request::ServiceStart { name: name.clone() })?;
 
                     match start_response {
                         Response::Success { message } => {
@@ -592,9 +1183,63 @@ fn main() -> Result<()> {
         Commands::Restart { name } => Request::ServiceRestart { name: name.clone() },
         Commands::Enable { name, .. } => Request::ServiceEnable { name: name.clone() },
         Commands::Disable { name } => Request::ServiceDisable { name: name.clone() },
-        Commands::Logs { name, lines } => Request::ServiceLogs { name: name.clone(), lines: *lines },
+        Commands::Logs { names, lines, follow: true, .. } => match &names[..] {
+            [name] => Request::ServiceLogsFollow { name: name.clone(), lines: lines.unwrap_or(50) },
+            _ => bail!("--follow takes a single service"),
+        },
+        Commands::Logs { names, lines, since, until, grep, regex, level, .. } => {
+            let default_lines = if since.is_some() || until.is_some() { 0 } else { 50 };
+            Request::ServiceLogsQuery {
+                query: LogQuery {
+                    services: names.clone(),
+                    since: since.clone(),
+                    until: until.clone(),
+                    level: *level,
+                    grep: grep.clone(),
+                    regex: *regex,
+                    lines: lines.unwrap_or(default_lines),
+                },
+            }
+        }
         Commands::LogsStream { .. } => unreachable!(), // Handled above
+        Commands::LogsCollect { .. } => unreachable!(), // Handled above
         Commands::LogsClear { name } => Request::ServiceLogsClear { name: name.clone() },
+        Commands::ShowEnv { name } => Request::ServiceEnvironment { name: name.clone() },
+        Commands::Install { file, name, dry_run } => {
+            let (name, content) = read_service_file(file, name)?;
+            if *dry_run {
+                Request::ServiceValidate { name, content }
+            } else {
+                Request::ServiceInstall { name, content }
+            }
+        }
+        Commands::Validate { file, name } => {
+            let (name, content) = read_service_file(file, name)?;
+            Request::ServiceValidate { name, content }
+        }
+        Commands::Remove { name } => Request::ServiceRemove { name: name.clone() },
+        Commands::Cat { name } => Request::ServiceCat { name: name.clone() },
+        Commands::ListDependencies { name, reverse } => Request::ListDependencies {
+            name: name.clone(),
+            reverse: *reverse,
+        },
+        Commands::ResetFailed { name } => Request::ServiceResetFailed { name: name.clone() },
+        Commands::Events { follow, services, events, after, vsock_cid, vsock_port, .. } => Request::Subscribe {
+            filter: EventFilter {
+                services: services.clone(),
+                events: events.clone(),
+                // Without --follow, show all retained events
+                after: if *follow || vsock_cid.is_some() { *after } else { Some(after.unwrap_or(0)) },
+            },
+            history_only: !*follow,
+            vsock: vsock_cid.zip(*vsock_port).map(|(cid, port)| VsockEndpoint { cid, port }),
+        },
+        Commands::Top { .. } => Request::ServiceMetrics { name: None, samples: 1 },
+        Commands::Stats { name, .. } => Request::ServiceMetrics { name: Some(name.clone()), samples: 0 },
+        Commands::ListTimers => Request::ListTimers,
+        Commands::ListTargets => Request::ListTargets,
+        Commands::Measurements { .. } => Request::MeasurementLog,
+        Commands::Isolate { target } => Request::Isolate { target: target.clone() },
 
         Commands::Ps(ps_cmd) => match ps_cmd {
             PsCommands::List => Request::ProcessList,
@@ -610,20 +1255,36 @@ fn main() -> Result<()> {
         },
 
         Commands::SystemStatus => Request::SystemStatus,
-        Commands::Reload => Request::SystemReload,
+        Commands::Reload { name } => match name {
+            Some(name) => Request::ServiceReload { name: name.clone() },
+            None => Request::SystemReload,
+        },
         Commands::Reboot => Request::SystemReboot,
         Commands::Shutdown => Request::SystemShutdown,
         Commands::Ping => Request::Ping,
     };
 
-    let response = match config.protocol {
-        ControlProtocol::Unix => {
-            send_request_unix(&config.unix_socket_path, request)?
-        }
-        ControlProtocol::Vsock => {
-            send_request_vsock(config.vsock_cid, config.vsock_port, request)?
-        }
-    };
+    if request.is_streaming() {
+        let json = matches!(&cli.command, Commands::Events { output: Some(o), .. } if o == "json");
+        let mut client = ControlClient::connect(&config)?;
+        return client.stream(request, |response| match response {
+            Response::LogLine { line, .. } => println!("{}", line),
+            Response::Event { event } if json => {
+                println!("{}", serde_json::to_string(&event).unwrap_or_default())
+            }
+            Response::Event { event } => println!("{}", format_event(&event)),
+            Response::StreamEnd { reason } if reason.is_empty() => {}
+            Response::StreamEnd { reason } => eprintln!("{}", reason),
+            Response::Error { message } | Response::PermissionDenied { message } => {
+                eprintln!("✗ Error: {}", message);
+                std::process::exit(1);
+            }
+            _ => {}
+        });
+    }
+
+    let mut client = ControlClient::connect(&config)?;
+    let response = client.request(request)?;
 
     match response {
         Response::Success { message } => {
@@ -633,41 +1294,194 @@ fn main() -> Result<()> {
             eprintln!("✗ Error: {}", message);
             std::process::exit(1);
         }
+        Response::PermissionDenied { message } => {
+            eprintln!("✗ Permission denied: {}", message);
+            std::process::exit(1);
+        }
         Response::ServiceList { services } => {
             if services.is_empty() {
                 println!("No services found");
             } else {
-                println!("{:<25} {:<10} {:<10} {:<15} {:<10}", "NAME", "ENABLED", "ACTIVE", "RESTART", "RESTARTS");
+                println!("{:<25} {:<10} {:<10} {:<15} {:<10}", "NAME", "ENABLED", "STATE", "RESTART", "RESTARTS");
                 println!("{}", "-".repeat(75));
                 for service in services {
                     println!(
                         "{:<25} {:<10} {:<10} {:<15} {:<10}",
                         service.name,
                         if service.enabled { "enabled" } else { "disabled" },
-                        if service.active { "active" } else { "inactive" },
+                        service.state.as_str(),
                         service.restart_policy,
                         service.restart_count
                     );
                 }
             }
         }
+        Response::TimerList { now, timers } => {
+            if timers.is_empty() {
+                println!("No timers found");
+            } else {
+                println!("TIMER                SERVICE              NEXT               LAST               SCHEDULE");
+                println!("{}", "-".repeat(100));
+                for timer in timers {
+                    let next = match timer.next_trigger {
+                        Some(next) => format!("in {}", format_uptime(next.saturating_sub(now))),
+                        None => "-".to_string(),
+                    };
+                    let last = match timer.last_trigger {
+                        Some(last) => format!("{} ago", format_uptime(now.saturating_sub(last))),
+                        None => "never".to_string(),
+                    };
+                    println!(
+                        "{:<20} {:<20} {:<18} {:<18} {}",
+                        timer.name,
+                        timer.service,
+                        next,
+                        last,
+                        timer.schedule.join(", ")
+                    );
+                }
+            }
+        }
+        Response::TargetList { targets } => {
+            if targets.is_empty() {
+                println!("No targets found");
+            } else {
+                println!("  {:<25} {:<10} {:<10} DESCRIPTION", "TARGET", "SERVICES", "ACTIVE");
+                println!("{}", "-".repeat(80));
+                for target in targets {
+                    println!(
+                        "{} {:<25} {:<10} {:<10} {}",
+                        if target.current { "*" } else { " " },
+                        target.name,
+                        target.services.len(),
+                        target.active_services,
+                        target.description.unwrap_or_default()
+                    );
+                }
+            }
+        }
+        Response::MeasurementLog { mut entries } => {
+            let json = match &cli.command {
+                Commands::Measurements { service, output } => {
+                    if let Some(service) = service {
+                        entries.retain(|e| &e.service == service);
+                    }
+                    output.as_deref() == Some("json")
+                }
+                _ => false,
+            };
+            if json {
+                for entry in &entries {
+                    println!("{}", serde_json::to_string(entry)?);
+                }
+            } else if entries.is_empty() {
+                println!("No executables measured");
+            } else {
+                println!("{:<20} {:<7} {:<6} {:<22} EXECUTABLE", "SERVICE", "PINNED", "RUNS", "LAST RUN");
+                println!("{}", "-".repeat(80));
+                for entry in entries {
+                    println!(
+                        "{:<20} {:<7} {:<6} {:<22} {}",
+                        entry.service,
+                        if entry.pinned { "yes" } else { "no" },
+                        entry.exec_count,
+                        bracketed_time(entry.last_exec).trim_end(),
+                        entry.path
+                    );
+                    println!("  {}", entry.hash);
+                }
+            }
+        }
         Response::ServiceStatus { status } => {
             println!("Service: {}", status.name);
             println!("  Enabled: {}", if status.enabled { "yes" } else { "no" });
-            println!("  Status: {}", if status.active { "active (running)" } else { "inactive (dead)" });
+            let state = match status.state {
+                ActiveState::Running if status.exited => "active (exited)",
+                ActiveState::Running => "active (running)",
+                ActiveState::Starting => "activating (start)",
+                ActiveState::Stopping => "deactivating (stop)",
+                ActiveState::Failed if status.start_limit_hit => "failed (start-limit-hit)",
+                ActiveState::Failed => "failed",
+                ActiveState::Inactive => "inactive (dead)",
+            };
+            println!("  Status: {}", state);
             if let Some(pid) = status.pid {
                 println!("  PID: {}", pid);
             }
+            if !status.service_type.is_empty() {
+                println!("  Type: {}", status.service_type);
+            }
+            if let Some(ref text) = status.status_text {
+                println!("  Status Text: {}", text);
+            }
+            if status.watchdog_sec > 0 {
+                println!("  Watchdog: {}s", status.watchdog_sec);
+            }
             println!("  Command: {}", status.exec_start);
+            if let Some(ref hash) = status.exec_hash {
+                println!("  Exec Hash: {}", hash);
+            }
+            for (key, commands) in [
+                ("ExecStartPre", &status.hooks.exec_start_pre),
+                ("ExecStartPost", &status.hooks.exec_start_post),
+                ("ExecStop", &status.hooks.exec_stop),
+                ("ExecStopPost", &status.hooks.exec_stop_post),
+                ("ExecReload", &status.hooks.exec_reload),
+            ] {
+                for command in commands {
+                    println!("  {}: {}", key, command);
+                }
+            }
             if let Some(wd) = status.working_directory {
                 println!("  Working Directory: {}", wd);
             }
+            if !status.kill_mode.is_empty() {
+                println!(
+                    "  Stop: {} to {}, {}",
+                    status.kill_signal,
+                    status.kill_mode,
+                    if status.send_sigkill {
+                        format!("SIGKILL after {}s", status.timeout_stop_sec)
+                    } else {
+                        format!("timeout {}s, no SIGKILL", status.timeout_stop_sec)
+                    }
+                );
+            }
             println!("  Restart Policy: {}", status.restart_policy);
-            println!("  Restart Delay: {}s", status.restart_sec);
+            if status.next_restart_sec > status.restart_sec {
+                println!("  Restart Delay: {}s (backoff, base {}s)", status.next_restart_sec, status.restart_sec);
+            } else {
+                println!("  Restart Delay: {}s", status.restart_sec);
+            }
             println!("  Restart Count: {}", status.restart_count);
+            if let Some(ref credentials) = status.credentials {
+                println!("  User: {}", credentials);
+            }
+            if !status.sandbox.is_empty() {
+                println!("  Sandbox: {}", status.sandbox.join(", "));
+            }
+            for listen in &status.listen {
+                println!("  Listen: {}", listen);
+            }
             if let Some(exit_code) = status.exit_status {
                 println!("  Last Exit Code: {}", exit_code);
             }
+            if status.health != HealthState::Unknown || status.last_probe.is_some() {
+                println!("  Health: {}", status.health.as_str());
+            }
+            if let Some(probe) = status.last_probe {
+                let ago = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs().saturating_sub(probe.timestamp))
+                    .unwrap_or(0);
+                println!(
+                    "  Last Probe: {} ({}, {}ms, {} ago)",
+                    if probe.success { "ok" } else { "failed" },
+                    probe.message,
+                    probe.duration_ms,
+                    format_uptime(ago)
+                );
+            }
 
             if !status.dependencies.before.is_empty() {
                 println!("  Before: {}", status.dependencies.before.join(", "));
@@ -681,6 +1495,77 @@ fn main() -> Result<()> {
             if !status.dependencies.required_by.is_empty() {
                 println!("  Required By: {}", status.dependencies.required_by.join(", "));
             }
+            if !status.dependencies.binds_to.is_empty() {
+                println!("  Binds To: {}", status.dependencies.binds_to.join(", "));
+            }
+            if !status.dependencies.part_of.is_empty() {
+                println!("  Part Of: {}", status.dependencies.part_of.join(", "));
+            }
+            if !status.dependencies.wanted_by.is_empty() {
+                println!("  Wanted By: {}", status.dependencies.wanted_by.join(", "));
+            }
+            if let Some(cgroup) = status.cgroup {
+                print_cgroup_usage(&cgroup);
+            }
+        }
+        Response::DependencyTree { tree } => {
+            print_dependency_node(&tree, "", "");
+        }
+        Response::ServiceValidated { name, startup_order } => {
+            println!("✓ Service {} is valid", name);
+            println!("Startup order:");
+            for (i, service) in startup_order.iter().enumerate() {
+                let marker = if *service == name { "  <-" } else { "" };
+                println!("  {:>3}. {}{}", i + 1, service, marker);
+            }
+        }
+        Response::ServiceFile { path, content, .. } => {
+            println!("# {}", path);
+            print!("{}", content);
+            if !content.is_empty() && !content.ends_with('\n') {
+                println!();
+            }
+        }
+        Response::Reloaded { summary } => {
+            match &cli.command {
+                Commands::Install { file, .. } => println!("✓ Installed {}", file),
+                Commands::Remove { name } => println!("✓ Removed service {}", name),
+                _ => {}
+            }
+            println!("✓ Services reloaded: {}", summary.describe());
+            for error in &summary.errors {
+                eprintln!("✗ {}", error);
+            }
+        }
+        Response::ServiceMetrics { interval_sec, services } => match &cli.command {
+            Commands::Stats { lines, .. } => {
+                for service in &services {
+                    print_service_stats(service, interval_sec, *lines);
+                }
+            }
+            _ => print_metrics_table(&services),
+        },
+        Response::ServiceEnvironment { environment, .. } => {
+            for assignment in environment {
+                println!("{}", assignment);
+            }
+        }
+        Response::LogRecords { records } => {
+            let (json, merged) = match &cli.command {
+                Commands::Logs { names, all, output, .. } => (output.as_deref() == Some("json"), *all || names.len() > 1),
+                _ => (false, false),
+            };
+            if json {
+                for record in &records {
+                    println!("{}", serde_json::to_string(record)?);
+                }
+            } else if records.is_empty() {
+                println!("No logs available");
+            } else {
+                for record in &records {
+                    println!("{}", record.to_line(merged));
+                }
+            }
         }
         Response::ServiceLogs { logs } => {
             if logs.is_empty() {
@@ -736,6 +1621,9 @@ fn main() -> Result<()> {
             if let Some(service_name) = process.service_name {
                 println!("  Service: {}", service_name);
             }
+            if let Some(cgroup) = process.cgroup {
+                print_cgroup_usage(&cgroup);
+            }
         }
         Response::ProcessStarted { pid, message } => {
             println!("✓ {} (PID: {})", message, pid);
@@ -743,14 +1631,37 @@ fn main() -> Result<()> {
         Response::SystemStatus { status } => {
             println!("System Status");
             println!("  Uptime: {}", format_uptime(status.uptime_secs));
-            println!("  Services: {} total, {} enabled, {} active",
-                     status.total_services, status.enabled_services, status.active_services);
+            println!("  Services: {} total, {} enabled, {} active, {} failed",
+                     status.total_services, status.enabled_services, status.active_services,
+                     status.failed_services);
             println!("  Processes: {} total", status.total_processes);
+            if let Some(target) = status.target {
+                println!("  Target: {}", target);
+            }
+            if let Some(forwarding) = status.log_forwarding {
+                println!(
+                    "  Log Forwarding: {} {} ({} queued, {} spooled, {} dropped)",
+                    if forwarding.connected { "connected to" } else { "waiting for" },
+                    forwarding.destination,
+                    forwarding.queued,
+                    forwarding.spooled,
+                    forwarding.dropped
+                );
+            }
             println!("  Service Directory: {}", status.service_dir);
             println!("  Log Directory: {}", status.log_dir);
         }
         Response::Pong => {
-            println!("✓ Pong - init system is responsive");
+            println!("✓ Pong - init system is responsive ({} access)", client.role.as_str());
+        }
+        Response::LogLine { line, .. } => {
+            println!("{}", line);
+        }
+        Response::Event { event } => {
+            println!("{}", format_event(&event));
+        }
+        Response::StreamEnd { reason } => {
+            eprintln!("{}", reason);
         }
     }
 
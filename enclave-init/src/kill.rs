@@ -0,0 +1,120 @@
+//! How services are stopped.
+//!
+//! A stop sends `KillSignal` to the processes selected by `KillMode` and arms a
+//! `TimeoutStopSec` deadline. Init's main loop escalates to SIGKILL when the
+//! deadline passes, unless `SendSIGKILL` is disabled.
+
+use nix::sys::signal::Signal;
+use serde::{Deserialize, Deserializer};
+use std::str::FromStr;
+
+/// Which processes receive the stop signal.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum KillMode {
+    /// Every process in the service's cgroup (process group without cgroups)
+    #[default]
+    ControlGroup,
+    /// The main process's process group
+    ProcessGroup,
+    /// Only the main process
+    Process,
+}
+
+impl KillMode {
+    pub fn as_str(&self) -> &str {
+        match self {
+            KillMode::ControlGroup => "control-group",
+            KillMode::ProcessGroup => "process-group",
+            KillMode::Process => "process",
+        }
+    }
+}
+
+/// Stop settings of a service.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct KillConfig {
+    /// First signal sent on stop, e.g. "SIGTERM", "INT" or "15"
+    #[serde(default = "default_kill_signal", deserialize_with = "deserialize_signal")]
+    pub kill_signal: Signal,
+    /// Seconds to wait after `KillSignal` before escalating
+    #[serde(default = "default_timeout_stop_sec")]
+    pub timeout_stop_sec: u64,
+    #[serde(default)]
+    pub kill_mode: KillMode,
+    /// Send SIGKILL once `TimeoutStopSec` has passed
+    #[serde(default = "default_send_sigkill", rename = "SendSIGKILL")]
+    pub send_sigkill: bool,
+}
+
+impl Default for KillConfig {
+    fn default() -> Self {
+        Self {
+            kill_signal: default_kill_signal(),
+            timeout_stop_sec: default_timeout_stop_sec(),
+            kill_mode: KillMode::default(),
+            send_sigkill: default_send_sigkill(),
+        }
+    }
+}
+
+fn default_kill_signal() -> Signal {
+    Signal::SIGTERM
+}
+
+fn default_timeout_stop_sec() -> u64 {
+    90
+}
+
+fn default_send_sigkill() -> bool {
+    true
+}
+
+/// Parse a signal given by name ("SIGTERM", "TERM") or number ("15").
+pub fn parse_signal(value: &str) -> Result<Signal, String> {
+    let value = value.trim();
+    if let Ok(number) = value.parse::<i32>() {
+        return Signal::try_from(number).map_err(|_| format!("invalid signal number {}", number));
+    }
+
+    let upper = value.to_ascii_uppercase();
+    let name = if upper.starts_with("SIG") {
+        upper
+    } else {
+        format!("SIG{}", upper)
+    };
+    Signal::from_str(&name).map_err(|_| format!("invalid signal '{}'", value))
+}
+
+fn deserialize_signal<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Signal, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_signal(&value).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("SIGTERM").unwrap(), Signal::SIGTERM);
+        assert_eq!(parse_signal("int").unwrap(), Signal::SIGINT);
+        assert_eq!(parse_signal("9").unwrap(), Signal::SIGKILL);
+        assert!(parse_signal("SIGNOPE").is_err());
+        assert!(parse_signal("0").is_err());
+    }
+
+    #[test]
+    fn test_kill_config_defaults() {
+        let config: KillConfig = toml::from_str("KillMode = \"process\"").unwrap();
+        assert_eq!(config.kill_signal, Signal::SIGTERM);
+        assert_eq!(config.timeout_stop_sec, 90);
+        assert_eq!(config.kill_mode, KillMode::Process);
+        assert!(config.send_sigkill);
+
+        let config: KillConfig = toml::from_str("KillSignal = \"SIGINT\"\nSendSIGKILL = false").unwrap();
+        assert_eq!(config.kill_signal, Signal::SIGINT);
+        assert!(!config.send_sigkill);
+    }
+}
@@ -1,8 +1,16 @@
+use crate::forwarder;
+use crate::logquery;
+use crate::output::OutputStream;
+use crate::protocol::{LogLevel, LogRecord, LogStream};
+use crate::config::Compression;
+use crate::retention::{self, RetentionPolicy};
 use anyhow::Result;
 use std::fs::{self, File, OpenOptions};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 const MAX_MEMORY_LOG_LINES: usize = 100;
 
@@ -14,13 +22,18 @@ pub trait LogSubscriber: Send + Sync {
     fn is_active(&self) -> bool;
 }
 
+/// Log of one service. Each line of the log file is a JSON `LogRecord`;
+/// subscribers and `get_logs` get the records' text form.
 #[derive(Clone)]
 pub struct ServiceLogger {
+    service: String,
     log_file: Arc<Mutex<Option<File>>>,
     log_path: PathBuf,
-    memory_logs: Arc<Mutex<Vec<String>>>,
-    max_log_size: u64,
-    max_log_files: usize,
+    memory_logs: Arc<Mutex<Vec<LogRecord>>>,
+    policy: Arc<Mutex<RetentionPolicy>>,
+    /// Held while segments are renamed, compressed or deleted, so that the
+    /// stdout and stderr readers and the compression thread do not interleave
+    rotation: Arc<Mutex<()>>,
     /// Subscribers for log streaming
     subscribers: Arc<Mutex<Vec<Arc<dyn LogSubscriber>>>>,
 }
@@ -29,19 +42,13 @@ impl std::fmt::Debug for ServiceLogger {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ServiceLogger")
             .field("log_path", &self.log_path)
-            .field("max_log_size", &self.max_log_size)
-            .field("max_log_files", &self.max_log_files)
+            .field("policy", &self.policy)
             .finish()
     }
 }
 
 impl ServiceLogger {
-    pub fn new(
-        log_dir: &str,
-        service_name: &str,
-        max_log_size: u64,
-        max_log_files: usize,
-    ) -> Result<Self> {
+    pub fn new(log_dir: &str, service_name: &str, policy: RetentionPolicy) -> Result<Self> {
         // Create log directory if it doesn't exist
         fs::create_dir_all(log_dir)?;
 
@@ -53,15 +60,31 @@ impl ServiceLogger {
             .open(&log_path)?;
 
         Ok(Self {
+            service: service_name.to_string(),
             log_file: Arc::new(Mutex::new(Some(file))),
             log_path,
             memory_logs: Arc::new(Mutex::new(Vec::with_capacity(MAX_MEMORY_LOG_LINES))),
-            max_log_size,
-            max_log_files,
+            policy: Arc::new(Mutex::new(policy)),
+            rotation: Arc::new(Mutex::new(())),
             subscribers: Arc::new(Mutex::new(Vec::new())),
         })
     }
 
+    pub fn policy(&self) -> RetentionPolicy {
+        self.policy.lock().unwrap().clone()
+    }
+
+    /// Change the rotation and retention policy; applies from the next write.
+    pub fn set_policy(&self, policy: RetentionPolicy) {
+        *self.policy.lock().unwrap() = policy;
+    }
+
+    /// Delete the rotated segments the policy no longer keeps.
+    pub fn enforce_retention(&self) -> usize {
+        let _rotation = self.rotation.lock().unwrap();
+        retention::enforce(&self.log_path, &self.policy(), SystemTime::now())
+    }
+
     /// Subscribe to log stream
     pub fn subscribe(&self, subscriber: Arc<dyn LogSubscriber>) {
         if let Ok(mut subs) = self.subscribers.lock() {
@@ -108,18 +131,41 @@ impl ServiceLogger {
     }
 
     pub fn log(&self, message: String) {
-        let timestamp = Self::timestamp();
-        let formatted = format!("[{}] {}", timestamp, message);
+        self.write(LogStream::Init, LogLevel::Info, message);
+    }
+
+    /// Log a line of service output, tagged with the stream it came from.
+    /// A syslog priority prefix such as `<3>` sets the level and is removed.
+    pub fn log_output(&self, stream: OutputStream, line: &str) {
+        let stream = match stream {
+            OutputStream::Stdout => LogStream::Stdout,
+            OutputStream::Stderr => LogStream::Stderr,
+        };
+        let (level, message) = split_level_prefix(line);
+        self.write(stream, level, message.to_string());
+    }
+
+    fn write(&self, stream: LogStream, level: LogLevel, message: String) {
+        let record = LogRecord {
+            timestamp: now_millis(),
+            service: self.service.clone(),
+            stream,
+            level,
+            message,
+        };
+        let formatted = record.to_line(false);
 
         // Write to file
         if let Ok(mut file_opt) = self.log_file.lock() {
             if let Some(ref mut file) = *file_opt {
-                let _ = writeln!(file, "{}", formatted);
+                if let Ok(json) = serde_json::to_string(&record) {
+                    let _ = writeln!(file, "{}", json);
+                }
                 let _ = file.flush();
 
                 // Check file size and rotate if needed
                 if let Ok(metadata) = file.metadata() {
-                    if metadata.len() > self.max_log_size {
+                    if metadata.len() > self.policy().max_size {
                         drop(file_opt); // Release lock before rotating
                         let _ = self.rotate_logs();
                     }
@@ -127,109 +173,117 @@ impl ServiceLogger {
             }
         }
 
+        // Forward to the host collector, if enabled
+        forwarder::submit(&record);
+
         // Keep last N lines in memory for quick access
         if let Ok(mut logs) = self.memory_logs.lock() {
             if logs.len() >= MAX_MEMORY_LOG_LINES {
                 logs.remove(0);
             }
-            logs.push(formatted.clone());
+            logs.push(record);
         }
 
         // Notify subscribers (for streaming)
         self.notify_subscribers(&formatted);
     }
 
+    /// Move the current file to segment 1 and start a new one. Writers wait
+    /// for the rename; compression runs on a separate thread.
     fn rotate_logs(&self) -> Result<()> {
-        // Close current file
-        if let Ok(mut file_opt) = self.log_file.lock() {
-            *file_opt = None;
+        let policy = self.policy();
+        let rotation = self.rotation.lock().unwrap();
+        let mut file_opt = self.log_file.lock().unwrap();
+
+        // The other stream's reader may have rotated while this one waited
+        let size = file_opt.as_ref().and_then(|f| f.metadata().ok()).map(|m| m.len());
+        if size.is_some_and(|size| size <= policy.max_size) {
+            return Ok(());
         }
+        *file_opt = None;
 
-        // Rotate existing log files
-        for i in (1..self.max_log_files).rev() {
-            let old_path = if i == 1 {
-                self.log_path.clone()
-            } else {
-                self.log_path.with_extension(format!("log.{}", i - 1))
-            };
-
-            let new_path = self.log_path.with_extension(format!("log.{}", i));
+        // Make room for the new segment 1, dropping those past max_files
+        retention::shift(&self.log_path, policy.max_files);
+        let moved = if policy.max_files > 1 {
+            fs::rename(&self.log_path, retention::segment_path(&self.log_path, 1, false))
+        } else {
+            fs::remove_file(&self.log_path)
+        };
 
-            if old_path.exists() {
-                let _ = fs::rename(&old_path, &new_path);
+        // Reopen even if the rename failed, so that logging goes on
+        let result = match OpenOptions::new().create(true).append(true).open(&self.log_path) {
+            Ok(file) => {
+                *file_opt = Some(file);
+                moved
             }
-        }
-
-        // Delete oldest log file if we've reached the limit
-        let oldest = self
-            .log_path
-            .with_extension(format!("log.{}", self.max_log_files));
-        if oldest.exists() {
-            let _ = fs::remove_file(oldest);
-        }
-
-        // Open new log file
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&self.log_path)?;
+            Err(e) => Err(e),
+        };
+        drop(file_opt);
 
-        if let Ok(mut file_opt) = self.log_file.lock() {
-            *file_opt = Some(file);
+        if policy.compression == Compression::Gzip {
+            drop(rotation);
+            self.compress_segments(policy);
+        } else {
+            retention::enforce(&self.log_path, &policy, SystemTime::now());
         }
+        Ok(result?)
+    }
 
-        Ok(())
+    /// Compress the uncompressed segments on a separate thread, then apply
+    /// the retention limits to their compressed sizes.
+    fn compress_segments(&self, policy: RetentionPolicy) {
+        let rotation = self.rotation.clone();
+        let log_path = self.log_path.clone();
+        thread::spawn(move || {
+            let _rotation = rotation.lock().unwrap();
+            for segment in retention::segments(&log_path).iter().filter(|s| !s.compressed) {
+                if let Err(e) = retention::compress(&segment.path) {
+                    Logger::warn(&format!("Failed to compress {}: {}", segment.path.display(), e));
+                }
+            }
+            retention::enforce(&log_path, &policy, SystemTime::now());
+        });
     }
 
     pub fn get_logs(&self, lines: usize) -> Vec<String> {
         // Try to read from file first
         if let Ok(content) = fs::read_to_string(&self.log_path) {
-            let all_lines: Vec<String> = content.lines().map(|s| s.to_string()).collect();
-            let start = if all_lines.len() > lines {
-                all_lines.len() - lines
-            } else {
-                0
-            };
-            return all_lines.into_iter().skip(start).collect();
+            let all_lines: Vec<&str> = content.lines().collect();
+            let start = all_lines.len().saturating_sub(lines);
+            return all_lines[start..]
+                .iter()
+                .map(|line| parse_record(line, &self.service).to_line(false))
+                .collect();
         }
 
         // Fallback to memory logs
         if let Ok(logs) = self.memory_logs.lock() {
-            let start = if logs.len() > lines {
-                logs.len() - lines
-            } else {
-                0
-            };
-            return logs.iter().skip(start).cloned().collect();
+            let start = logs.len().saturating_sub(lines);
+            return logs[start..].iter().map(|r| r.to_line(false)).collect();
         }
 
         Vec::new()
     }
 
-    pub fn get_all_logs(&self) -> Result<String> {
-        let mut all_content = String::new();
+    /// All records in the current and rotated log files, oldest first.
+    pub fn records(&self) -> Vec<LogRecord> {
+        let mut records = Vec::new();
 
-        // Read rotated logs first (oldest to newest)
-        for i in (1..self.max_log_files).rev() {
-            let rotated_path = self.log_path.with_extension(format!("log.{}", i));
-            if rotated_path.exists() {
-                if let Ok(content) = fs::read_to_string(&rotated_path) {
-                    all_content.push_str(&content);
-                }
+        // Read rotated logs first (oldest to newest), decompressing as needed
+        for segment in retention::segments(&self.log_path).iter().rev() {
+            if let Ok(content) = retention::read_segment(segment) {
+                records.extend(content.lines().map(|line| parse_record(line, &self.service)));
             }
         }
-
-        // Read current log
-        if self.log_path.exists() {
-            if let Ok(content) = fs::read_to_string(&self.log_path) {
-                all_content.push_str(&content);
-            }
+        if let Ok(content) = fs::read_to_string(&self.log_path) {
+            records.extend(content.lines().map(|line| parse_record(line, &self.service)));
         }
-
-        Ok(all_content)
+        records
     }
 
     pub fn clear(&self) -> Result<()> {
+        let _rotation = self.rotation.lock().unwrap();
+
         // Close file
         if let Ok(mut file_opt) = self.log_file.lock() {
             *file_opt = None;
@@ -237,9 +291,8 @@ impl ServiceLogger {
 
         // Remove log files
         let _ = fs::remove_file(&self.log_path);
-        for i in 1..self.max_log_files {
-            let rotated_path = self.log_path.with_extension(format!("log.{}", i));
-            let _ = fs::remove_file(rotated_path);
+        for segment in retention::segments(&self.log_path) {
+            let _ = fs::remove_file(segment.path);
         }
 
         // Clear memory logs
@@ -259,19 +312,61 @@ impl ServiceLogger {
 
         Ok(())
     }
+}
 
-    fn timestamp() -> String {
-        use std::time::{SystemTime, UNIX_EPOCH};
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
 
-        // Format as human-readable timestamp
-        let naive = chrono::NaiveDateTime::from_timestamp_opt(now as i64, 0)
-            .unwrap_or_default();
-        naive.format("%Y-%m-%d %H:%M:%S").to_string()
+/// Level of a line starting with a syslog priority (`<0>` to `<7>`), and
+/// the line without it. Other lines are `info`.
+fn split_level_prefix(line: &str) -> (LogLevel, &str) {
+    let bytes = line.as_bytes();
+    if bytes.len() >= 3 && bytes[0] == b'<' && bytes[1].is_ascii_digit() && bytes[2] == b'>' && bytes[1] <= b'7' {
+        let level = match bytes[1] - b'0' {
+            0..=3 => LogLevel::Error,
+            4 => LogLevel::Warn,
+            5 | 6 => LogLevel::Info,
+            _ => LogLevel::Debug,
+        };
+        return (level, &line[3..]);
     }
+    (LogLevel::Info, line)
+}
+
+/// Parse a line of a log file. Lines written before logs were structured,
+/// `[2024-05-01 12:00:00] [stdout] message`, are converted; anything else
+/// becomes an `init` record with the whole line as its message.
+fn parse_record(line: &str, service: &str) -> LogRecord {
+    if let Ok(record) = serde_json::from_str::<LogRecord>(line) {
+        return record;
+    }
+
+    let mut record = LogRecord {
+        timestamp: 0,
+        service: service.to_string(),
+        stream: LogStream::Init,
+        level: LogLevel::Info,
+        message: line.to_string(),
+    };
+    let Some((time, rest)) = line.strip_prefix('[').and_then(|l| l.split_once("] ")) else {
+        return record;
+    };
+    let Ok(secs) = logquery::parse_datetime(time) else {
+        return record;
+    };
+    record.timestamp = secs * 1000;
+    record.message = rest.to_string();
+    for (prefix, stream) in [("[stdout] ", LogStream::Stdout), ("[stderr] ", LogStream::Stderr)] {
+        if let Some(message) = rest.strip_prefix(prefix) {
+            record.stream = stream;
+            record.message = message.to_string();
+        }
+    }
+    record
 }
 
 pub struct Logger;
@@ -396,3 +491,77 @@ mod chrono {
         (12, 31)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_level_prefix() {
+        assert_eq!(split_level_prefix("<3>disk full"), (LogLevel::Error, "disk full"));
+        assert_eq!(split_level_prefix("<4>slow"), (LogLevel::Warn, "slow"));
+        assert_eq!(split_level_prefix("<7>trace"), (LogLevel::Debug, "trace"));
+        assert_eq!(split_level_prefix("<8>x"), (LogLevel::Info, "<8>x"));
+        assert_eq!(split_level_prefix("plain"), (LogLevel::Info, "plain"));
+    }
+
+    #[test]
+    fn test_parse_record() {
+        let record = parse_record(
+            r#"{"timestamp":1709164770123,"service":"web","stream":"stderr","level":"warn","message":"slow"}"#,
+            "web",
+        );
+        assert_eq!(record.timestamp, 1709164770123);
+        assert_eq!(record.stream, LogStream::Stderr);
+        assert_eq!(record.to_line(true), "[2024-02-28 23:59:30] web: [stderr] [warn] slow");
+
+        let legacy = parse_record("[2024-02-28 23:59:30] [stdout] listening", "web");
+        assert_eq!(legacy.timestamp, 1709164770000);
+        assert_eq!(legacy.stream, LogStream::Stdout);
+        assert_eq!(legacy.message, "listening");
+
+        let unknown = parse_record("garbage", "web");
+        assert_eq!((unknown.timestamp, unknown.message.as_str()), (0, "garbage"));
+    }
+
+    #[test]
+    fn test_rotation_reads_compressed_segments() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let policy = RetentionPolicy {
+            max_size: 200,
+            max_files: 3,
+            max_total_size: 0,
+            max_age_sec: 0,
+            compression: Compression::Gzip,
+        };
+        let logger = ServiceLogger::new(dir.to_str().unwrap(), "web", policy).unwrap();
+        for i in 0..20 {
+            logger.log(format!("line {}", i));
+        }
+
+        // Segments are compressed in the background
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        let settled = || {
+            let segments = retention::segments(&dir.join("web.log"));
+            segments.len() == 2 && segments.iter().all(|s| s.compressed)
+        };
+        while !settled() && std::time::Instant::now() < deadline {
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let segments = retention::segments(&dir.join("web.log"));
+        assert_eq!(segments.iter().map(|s| s.index).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(segments.iter().all(|s| s.compressed));
+
+        // The oldest lines were rotated away; the rest read back in order
+        let messages: Vec<String> = logger.records().into_iter().map(|r| r.message).collect();
+        assert!(messages.len() < 20);
+        assert_eq!(messages.last().unwrap(), "line 19");
+        let first: usize = messages[0].trim_start_matches("line ").parse().unwrap();
+        let expected: Vec<String> = (first..20).map(|i| format!("line {}", i)).collect();
+        assert_eq!(messages, expected);
+
+        logger.clear().unwrap();
+        assert!(retention::segments(&dir.join("web.log")).is_empty());
+    }
+}
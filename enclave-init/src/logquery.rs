@@ -0,0 +1,252 @@
+//! Log queries.
+//!
+//! `ServiceLogsQuery` reads the records of one or more services from their
+//! current and rotated log files, keeps those matching the time range, level
+//! and text filters, and merges them by timestamp. Times are given as
+//! `YYYY-MM-DD [HH:MM[:SS]]`, `HH:MM[:SS]` (today), `now`, `today`,
+//! `yesterday`, `@<epoch seconds>` or relative to now as `-30m` or
+//! `2h ago`, and are evaluated on init's clock in UTC.
+
+use crate::logger::ServiceLogger;
+use crate::protocol::{LogLevel, LogQuery, LogRecord};
+use regex::Regex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+enum Pattern {
+    Substring(String),
+    Regex(Regex),
+}
+
+struct LogFilter {
+    /// Milliseconds since the epoch, inclusive
+    since: Option<u64>,
+    until: Option<u64>,
+    level: Option<LogLevel>,
+    pattern: Option<Pattern>,
+}
+
+impl LogFilter {
+    fn new(query: &LogQuery, now: u64) -> Result<Self, String> {
+        let time = |spec: &Option<String>, name: &str| -> Result<Option<u64>, String> {
+            spec.as_deref()
+                .map(|s| parse_time(s, now).map_err(|e| format!("invalid {} time '{}': {}", name, s, e)))
+                .transpose()
+        };
+        let pattern = match &query.grep {
+            Some(grep) if query.regex => Some(Pattern::Regex(
+                Regex::new(grep).map_err(|e| format!("invalid regular expression: {}", e))?,
+            )),
+            Some(grep) => Some(Pattern::Substring(grep.clone())),
+            None => None,
+        };
+        Ok(Self {
+            since: time(&query.since, "since")?,
+            until: time(&query.until, "until")?,
+            level: query.level,
+            pattern,
+        })
+    }
+
+    fn matches(&self, record: &LogRecord) -> bool {
+        if self.since.is_some_and(|since| record.timestamp < since)
+            || self.until.is_some_and(|until| record.timestamp > until)
+            || self.level.is_some_and(|level| record.level > level)
+        {
+            return false;
+        }
+        match &self.pattern {
+            Some(Pattern::Substring(text)) => record.message.contains(text.as_str()),
+            Some(Pattern::Regex(regex)) => regex.is_match(&record.message),
+            None => true,
+        }
+    }
+}
+
+/// Run `query` over the logs of `loggers`.
+pub fn run(query: &LogQuery, loggers: &[ServiceLogger]) -> Result<Vec<LogRecord>, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let filter = LogFilter::new(query, now)?;
+    let mut records: Vec<LogRecord> = loggers
+        .iter()
+        .flat_map(|logger| logger.records())
+        .filter(|record| filter.matches(record))
+        .collect();
+    // Stable, so records of one service with the same timestamp keep their order
+    records.sort_by_key(|record| record.timestamp);
+    if query.lines > 0 && records.len() > query.lines {
+        records.drain(..records.len() - query.lines);
+    }
+    Ok(records)
+}
+
+/// Parse a time specification into milliseconds since the epoch.
+fn parse_time(spec: &str, now: u64) -> Result<u64, String> {
+    let spec = spec.trim();
+    let today = now / 1000 / 86400 * 86400 * 1000;
+    match spec {
+        "now" => return Ok(now),
+        "today" => return Ok(today),
+        "yesterday" => return Ok(today.saturating_sub(86400 * 1000)),
+        _ => {}
+    }
+    if let Some(epoch) = spec.strip_prefix('@') {
+        return epoch
+            .parse::<u64>()
+            .map(|secs| secs * 1000)
+            .map_err(|_| "invalid epoch seconds".to_string());
+    }
+    if let Some(ago) = spec.strip_prefix('-').or_else(|| spec.strip_suffix(" ago")) {
+        return Ok(now.saturating_sub(parse_duration(ago.trim())? * 1000));
+    }
+    if !spec.contains('-') {
+        return Ok(today + parse_time_of_day(spec)? * 1000);
+    }
+    Ok(parse_datetime(spec)? * 1000)
+}
+
+/// Seconds in a duration such as `90s`, `15m`, `2h` or `1d`.
+fn parse_duration(value: &str) -> Result<u64, String> {
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("invalid duration '{}'", value))?;
+    let unit = match unit.trim() {
+        "s" | "sec" | "secs" | "second" | "seconds" => 1,
+        "m" | "min" | "mins" | "minute" | "minutes" => 60,
+        "h" | "hour" | "hours" => 3600,
+        "d" | "day" | "days" => 86400,
+        _ => return Err(format!("invalid duration unit in '{}'", value)),
+    };
+    Ok(number * unit)
+}
+
+/// Seconds since midnight of `HH:MM` or `HH:MM:SS`.
+fn parse_time_of_day(value: &str) -> Result<u64, String> {
+    let parts: Vec<&str> = value.split(':').collect();
+    if !(2..=3).contains(&parts.len()) {
+        return Err(format!("invalid time '{}'", value));
+    }
+    let mut secs = 0;
+    for (part, max) in parts.iter().zip([23, 59, 59]) {
+        let n: u64 = part.parse().map_err(|_| format!("invalid time '{}'", value))?;
+        if n > max {
+            return Err(format!("invalid time '{}'", value));
+        }
+        secs = secs * 60 + n;
+    }
+    if parts.len() == 2 {
+        secs *= 60;
+    }
+    Ok(secs)
+}
+
+/// Seconds since the epoch of `YYYY-MM-DD`, optionally followed by a time
+/// of day after a space or `T`, in UTC.
+pub fn parse_datetime(value: &str) -> Result<u64, String> {
+    let (date, time) = match value.split_once([' ', 'T']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (value, None),
+    };
+    let fields: Vec<&str> = date.split('-').collect();
+    let [year, month, day] = fields[..] else {
+        return Err(format!("invalid date '{}'", date));
+    };
+    let invalid = |_| format!("invalid date '{}'", date);
+    let (year, month, day): (i64, u32, u32) = (
+        year.parse().map_err(invalid)?,
+        month.parse().map_err(invalid)?,
+        day.parse().map_err(invalid)?,
+    );
+    if !(1970..=9999).contains(&year) || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(format!("invalid date '{}'", date));
+    }
+    let secs = days_from_civil(year, month, day) as u64 * 86400;
+    match time {
+        Some(time) => Ok(secs + parse_time_of_day(time)?),
+        None => Ok(secs),
+    }
+}
+
+/// Day number counted from 1970-01-01 of a (year, month, day).
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{civil_from_days, LogStream};
+
+    // 2024-02-28 23:59:30 UTC
+    const NOW: u64 = 1709164770 * 1000;
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(days_from_civil(2024, 2, 29)), (2024, 2, 29));
+
+        assert_eq!(parse_time("now", NOW), Ok(NOW));
+        assert_eq!(parse_time("today", NOW), Ok(1709078400 * 1000));
+        assert_eq!(parse_time("yesterday", NOW), Ok(1708992000 * 1000));
+        assert_eq!(parse_time("-30m", NOW), Ok(NOW - 1800 * 1000));
+        assert_eq!(parse_time("2h ago", NOW), Ok(NOW - 7200 * 1000));
+        assert_eq!(parse_time("@1709164770", NOW), Ok(NOW));
+        assert_eq!(parse_time("23:59:30", NOW), Ok(NOW));
+        assert_eq!(parse_time("12:00", NOW), Ok((1709078400 + 12 * 3600) * 1000));
+        assert_eq!(parse_time("2024-02-28 23:59:30", NOW), Ok(NOW));
+        assert_eq!(parse_time("2024-02-28T23:59", NOW), Ok(NOW - 30 * 1000));
+        assert_eq!(parse_time("2024-02-28", NOW), Ok(1709078400 * 1000));
+
+        for invalid in ["soon", "-5y", "25:00", "2024-13-01", "2024-02", "@x"] {
+            assert!(parse_time(invalid, NOW).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let record = |secs: u64, level: LogLevel, message: &str| LogRecord {
+            timestamp: secs * 1000,
+            service: "web".to_string(),
+            stream: LogStream::Stdout,
+            level,
+            message: message.to_string(),
+        };
+        let query = LogQuery {
+            since: Some("-1h".to_string()),
+            level: Some(LogLevel::Warn),
+            grep: Some("time(out|d out)".to_string()),
+            regex: true,
+            ..Default::default()
+        };
+        let filter = LogFilter::new(&query, NOW).unwrap();
+        let now = NOW / 1000;
+        assert!(filter.matches(&record(now - 60, LogLevel::Error, "request timed out")));
+        assert!(filter.matches(&record(now - 60, LogLevel::Warn, "timeout")));
+        assert!(!filter.matches(&record(now - 60, LogLevel::Info, "timeout")));
+        assert!(!filter.matches(&record(now - 7200, LogLevel::Error, "timeout")));
+        assert!(!filter.matches(&record(now - 60, LogLevel::Error, "refused")));
+
+        let substring = LogQuery {
+            grep: Some("a.b".to_string()),
+            ..Default::default()
+        };
+        let filter = LogFilter::new(&substring, NOW).unwrap();
+        assert!(filter.matches(&record(now, LogLevel::Debug, "x a.b y")));
+        assert!(!filter.matches(&record(now, LogLevel::Debug, "axb")));
+
+        let invalid = LogQuery {
+            grep: Some("(".to_string()),
+            regex: true,
+            ..Default::default()
+        };
+        assert!(LogFilter::new(&invalid, NOW).is_err());
+    }
+}
@@ -1,46 +1,111 @@
+mod activation;
+mod auth;
+mod cgroup;
 mod config;
+mod credentials;
 mod dependencies;
+mod environment;
+mod events;
+mod forwarder;
+mod health;
+mod hooks;
+mod kill;
 mod logger;
+mod logquery;
+mod measurement;
+mod metrics;
+mod notify;
+mod output;
 mod process;
 mod protocol;
+mod restart;
+mod retention;
+mod sandbox;
 mod streamer;
+mod target;
+mod template;
+mod timer;
 
+use activation::SocketUnit;
 use anyhow::{Context, Result};
+use auth::{Admission, Peer};
+use cgroup::{Cgroup, ResourceLimits};
 use clap::Parser;
-use config::InitConfig;
-use dependencies::{DependencyResolver, ServiceDependencies};
+use config::{HashAlgorithm, InitConfig};
+use credentials::{Credentials, CredentialsConfig};
+use dependencies::{DependencyResolver, Relation, ServiceDependencies, StopCause};
+use environment::EnvironmentConfig;
+use health::{HealthCheckConfig, HealthTracker, HealthTransition, ProbeKind};
+use hooks::HookContext;
+use kill::{KillConfig, KillMode};
 use logger::{Logger, LogSubscriber, ServiceLogger};
+use measurement::{ExecHashConfig, MeasuredExecutable};
+use metrics::MetricsHistory;
 use nix::errno::Errno;
 use nix::mount::{mount, MsFlags};
+use notify::NotifySocket;
+use output::{OutputStream, OutputTarget, StreamRedirect};
 use nix::sys::signal::{
-    kill, sigaction, sigprocmask, SaFlags, SigAction, SigHandler, SigSet, SigmaskHow, Signal,
+    kill, killpg, sigaction, sigprocmask, SaFlags, SigAction, SigHandler, SigSet, SigmaskHow, Signal,
 };
 use nix::sys::socket::{
-    accept, bind, connect, listen, recv, send, socket, AddressFamily, MsgFlags, SockFlag,
-    SockType, SockaddrLike, UnixAddr, VsockAddr,
+    accept, accept4, bind, connect, getpeername, listen, recv, send, setsockopt, socket, sockopt, AddressFamily,
+    MsgFlags, SockFlag, SockType, SockaddrLike, UnixAddr, VsockAddr,
 };
+use nix::sys::time::TimeVal;
 use nix::sys::stat::{makedev, mknod, Mode, SFlag};
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::{
     chdir, chroot, close, fork, read, setsid, setpgid, symlinkat, unlink, write, ForkResult, Pid,
 };
-use protocol::{Request, Response, ServiceDependencyInfo, ServiceInfo, ServiceStatus, SystemStatus};
+use protocol::{
+    read_frame, read_frame_limited, write_frame, ActiveState, AuthResponse, DependencyNode, Event, EventFilter, EventKind, FdStream,
+    FrameError, HealthState, Hello, HelloReply, ProbeResult, ReloadSummary, Request, RequestFrame, Response, ResponseFrame, Role, ServiceDependencyInfo, ServiceHooksInfo,
+    ServiceInfo, ServiceStatus, SystemStatus, TargetInfo, TimerInfo, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use restart::{RestartTracker, StartLimitConfig};
+use retention::{LogRetentionConfig, RetentionPolicy};
+use sandbox::SandboxConfig;
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::CString;
 use std::fs::{self, create_dir, read_dir, remove_file, rename, File};
 use std::io::{BufRead, BufReader};
 use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
-use streamer::VsockLogStreamer;
+use streamer::{ChannelLogSubscriber, VsockLogStreamer};
+use target::TargetUnit;
+use timer::{Clock, TimerUnit};
 
 // Constants
 const DEFAULT_PATH_ENV: &str = "PATH=/sbin:/usr/sbin:/bin:/usr/bin";
 const HEART_BEAT: u8 = 0xB7;
+/// Timeout for ExecReload commands
+const HOOK_RELOAD_TIMEOUT_SEC: u64 = 90;
+/// Time allowed for a SIGKILLed service to be reaped
+const STOP_KILL_GRACE: Duration = Duration::from_secs(5);
+/// How often rotated logs are checked against age and disk limits
+const LOG_RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+/// Largest frame read from a peer before it has authenticated
+const HANDSHAKE_FRAME_SIZE: usize = 4096;
+/// Time a new control connection has to send its request or complete the handshake
+const HANDSHAKE_TIMEOUT_SEC: i64 = 10;
+/// Concurrent VSOCK control connections; further peers are turned away
+const MAX_VSOCK_CONNECTIONS: usize = 64;
+/// Log lines queued for a follower before it is dropped
+const LOG_FOLLOW_QUEUE_SIZE: usize = 1024;
+/// Time a log follower may block init's writes before it is disconnected
+const LOG_FOLLOW_WRITE_TIMEOUT_SEC: i64 = 10;
+/// Concurrent Prometheus scrapes; further connections are turned away
+const MAX_METRICS_CONNECTIONS: usize = 16;
+/// Time a scrape has to send its request and read the response
+const METRICS_TIMEOUT_SEC: i64 = 10;
+/// Longest pause after repeated `accept` failures on the metrics listener
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(5);
 
 // Global flags for signal handling
 static SIGCHLD_RECEIVED: AtomicBool = AtomicBool::new(false);
@@ -48,6 +113,9 @@ static SIGTERM_RECEIVED: AtomicBool = AtomicBool::new(false);
 static SIGINT_RECEIVED: AtomicBool = AtomicBool::new(false);
 static SIGHUP_RECEIVED: AtomicBool = AtomicBool::new(false);
 
+static VSOCK_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static METRICS_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
 // System start time for uptime calculation
 static mut SYSTEM_START_TIME: Option<Instant> = None;
 
@@ -62,11 +130,27 @@ struct Args {
 }
 
 // Service configuration
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 struct ServiceConfig {
+    #[serde(default, rename = "Type")]
+    service_type: ServiceType,
     #[serde(default)]
     exec_start: String,
+    #[serde(default, deserialize_with = "hooks::deserialize_commands")]
+    exec_start_pre: Vec<String>,
+    #[serde(default, deserialize_with = "hooks::deserialize_commands")]
+    exec_start_post: Vec<String>,
+    #[serde(default, deserialize_with = "hooks::deserialize_commands")]
+    exec_stop: Vec<String>,
+    #[serde(default, deserialize_with = "hooks::deserialize_commands")]
+    exec_stop_post: Vec<String>,
+    #[serde(default, deserialize_with = "hooks::deserialize_commands")]
+    exec_reload: Vec<String>,
+    #[serde(default = "default_timeout_start_sec")]
+    timeout_start_sec: u64,
+    #[serde(default)]
+    remain_after_exit: bool,
     #[serde(default)]
     environment: Vec<String>,
     #[serde(default = "default_restart")]
@@ -85,6 +169,58 @@ struct ServiceConfig {
     requires: Vec<String>,
     #[serde(default)]
     required_by: Vec<String>,
+    #[serde(default)]
+    binds_to: Vec<String>,
+    #[serde(default)]
+    part_of: Vec<String>,
+    #[serde(default)]
+    wanted_by: Vec<String>,
+    #[serde(default)]
+    standard_output: OutputTarget,
+    #[serde(default)]
+    standard_error: OutputTarget,
+    #[serde(flatten)]
+    limits: ResourceLimits,
+    #[serde(flatten)]
+    health: HealthCheckConfig,
+    #[serde(default)]
+    watchdog_sec: u64,
+    #[serde(flatten)]
+    kill: KillConfig,
+    #[serde(flatten)]
+    start_limit: StartLimitConfig,
+    #[serde(flatten)]
+    credentials: CredentialsConfig,
+    #[serde(flatten)]
+    sandbox: SandboxConfig,
+    #[serde(flatten)]
+    environment_options: EnvironmentConfig,
+    #[serde(flatten)]
+    log_retention: LogRetentionConfig,
+    #[serde(flatten)]
+    exec_hash: ExecHashConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum ServiceType {
+    /// The service is started as soon as the process is forked
+    #[default]
+    Simple,
+    /// The service is started once it sends READY=1 over NOTIFY_SOCKET
+    Notify,
+    /// The service is started once the process has exited successfully
+    Oneshot,
+}
+
+impl ServiceType {
+    fn as_str(&self) -> &str {
+        match self {
+            ServiceType::Simple => "simple",
+            ServiceType::Notify => "notify",
+            ServiceType::Oneshot => "oneshot",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, PartialEq)]
@@ -115,6 +251,10 @@ fn default_restart_sec() -> u64 {
     5
 }
 
+fn default_timeout_start_sec() -> u64 {
+    90
+}
+
 fn default_true() -> bool {
     true
 }
@@ -122,7 +262,15 @@ fn default_true() -> bool {
 impl Default for ServiceConfig {
     fn default() -> Self {
         Self {
+            service_type: ServiceType::Simple,
             exec_start: String::new(),
+            exec_start_pre: Vec::new(),
+            exec_start_post: Vec::new(),
+            exec_stop: Vec::new(),
+            exec_stop_post: Vec::new(),
+            exec_reload: Vec::new(),
+            timeout_start_sec: 90,
+            remain_after_exit: false,
             environment: Vec::new(),
             restart: RestartPolicy::No,
             restart_sec: 5,
@@ -132,6 +280,21 @@ impl Default for ServiceConfig {
             after: Vec::new(),
             requires: Vec::new(),
             required_by: Vec::new(),
+            binds_to: Vec::new(),
+            part_of: Vec::new(),
+            wanted_by: Vec::new(),
+            standard_output: OutputTarget::Log,
+            standard_error: OutputTarget::Log,
+            limits: ResourceLimits::default(),
+            health: HealthCheckConfig::default(),
+            watchdog_sec: 0,
+            kill: KillConfig::default(),
+            start_limit: StartLimitConfig::default(),
+            credentials: CredentialsConfig::default(),
+            sandbox: SandboxConfig::default(),
+            environment_options: EnvironmentConfig::default(),
+            log_retention: LogRetentionConfig::default(),
+            exec_hash: ExecHashConfig::default(),
         }
     }
 }
@@ -148,6 +311,80 @@ struct ServiceState {
     logger: ServiceLogger,
     manual_stop: bool,
     enabled: bool,
+    cgroup: Option<Cgroup>,
+    health: HealthTracker,
+    notify: Option<NotifySocket>,
+    /// Oneshot service exited successfully and stays active (RemainAfterExit)
+    remain_active: bool,
+    /// When a stop in progress escalates to SIGKILL
+    stop_deadline: Option<Instant>,
+    restarts: RestartTracker,
+    /// Exited with an error and will not be restarted
+    failed: bool,
+    /// Hit StartLimitBurst; cleared by `initctl reset-failed`
+    start_limit_hit: bool,
+    /// `.socket` units that activate this service
+    sockets: Vec<SocketUnit>,
+    /// `.timer` units that start this service
+    timers: Vec<TimerUnit>,
+    /// Default `PATH` and init's global environment
+    base_environment: Vec<String>,
+    /// Environment resolved at the last start
+    resolved_environment: Vec<String>,
+    /// Included in the current target. Other services are not started at
+    /// boot or by their sockets and timers.
+    wanted: bool,
+    /// Hash of the executable at the last start, if it was measured
+    exec_hash: Option<String>,
+    /// Work in progress outside the services lock
+    helper: Option<Helper>,
+    /// Deadline of a oneshot main process, from `TimeoutStartSec`
+    start_deadline: Option<Instant>,
+    /// Why init stopped the main process, if it did so for a failure. The
+    /// exit then counts as failed whatever its code.
+    failure: Option<String>,
+}
+
+/// Work for a service that runs without the services lock held. The main
+/// loop applies the result in `finish_helpers`.
+#[derive(Debug)]
+enum HelperStep {
+    /// Hashing the executable; the start resumes once it is hashed
+    Measure,
+    /// Running `ExecStartPre`; the main process is forked with `parts` and
+    /// `credentials` once they succeed, unless the start was cancelled
+    StartPre { parts: Vec<String>, credentials: Credentials, cancelled: bool },
+    /// Running `ExecStartPost` of a started main process, which is stopped
+    /// if they fail
+    StartPost,
+    /// Running `ExecStop`; the main process is signalled once they finish
+    Stop,
+    /// Running `ExecReload`; the result is passed to the waiting request
+    Reload { reply: mpsc::Sender<Result<(), String>> },
+    /// Running the hooks that follow an exit of the main process: `ExecStartPost`
+    /// of a oneshot service that succeeded, then `ExecStopPost`. The exit is
+    /// settled once they finish.
+    ExitHooks { exit_code: i32, start_post: bool },
+}
+
+#[derive(Debug)]
+struct Helper {
+    step: HelperStep,
+    result: mpsc::Receiver<Result<(), HelperError>>,
+}
+
+/// A failed helper step.
+#[derive(Debug)]
+struct HelperError {
+    message: String,
+    /// Exit code of the command that failed, if the step runs commands
+    exit_code: Option<i32>,
+}
+
+impl HelperError {
+    fn new(message: impl Into<String>) -> Self {
+        Self { message: message.into(), exit_code: None }
+    }
 }
 
 impl ServiceState {
@@ -155,10 +392,10 @@ impl ServiceState {
         name: String,
         config: ServiceConfig,
         log_dir: &str,
-        max_log_size: u64,
-        max_log_files: usize,
+        log_policy: RetentionPolicy,
+        base_environment: Vec<String>,
     ) -> Result<Self> {
-        let logger = ServiceLogger::new(log_dir, &name, max_log_size, max_log_files)?;
+        let logger = ServiceLogger::new(log_dir, &name, log_policy)?;
         let enabled = config.service_enable;
 
         Ok(Self {
@@ -171,39 +408,447 @@ impl ServiceState {
             logger,
             manual_stop: false,
             enabled,
+            cgroup: None,
+            health: HealthTracker::new(),
+            notify: None,
+            remain_active: false,
+            stop_deadline: None,
+            restarts: RestartTracker::new(),
+            failed: false,
+            start_limit_hit: false,
+            sockets: Vec::new(),
+            timers: Vec::new(),
+            base_environment,
+            resolved_environment: Vec::new(),
+            wanted: true,
+            exec_hash: None,
+            helper: None,
+            start_deadline: None,
+            failure: None,
         })
     }
 
     fn should_restart(&self, exit_code: i32) -> bool {
-        if self.manual_stop || !self.enabled {
+        if self.manual_stop || !self.enabled || self.failed {
+            return false;
+        }
+        let failed = self.exit_failed(exit_code);
+        // A oneshot service that completed has nothing left to do
+        if self.config.service_type == ServiceType::Oneshot && !failed {
             return false;
         }
 
         match self.config.restart {
             RestartPolicy::Always => true,
-            RestartPolicy::OnFailure => exit_code != 0,
-            RestartPolicy::OnSuccess => exit_code == 0,
+            RestartPolicy::OnFailure => failed,
+            RestartPolicy::OnSuccess => !failed,
             RestartPolicy::No => false,
         }
     }
 
+    /// Whether an exit with `exit_code` is a failure: a non-zero code, or a
+    /// main process that init stopped for a failure.
+    fn exit_failed(&self, exit_code: i32) -> bool {
+        exit_code != 0 || self.failure.is_some()
+    }
+
     fn can_restart_now(&self) -> bool {
         if let Some(last) = self.last_restart {
-            last.elapsed() >= Duration::from_secs(self.config.restart_sec)
+            last.elapsed() >= self.restart_delay()
         } else {
             true
         }
     }
 
+    /// `RestartSec`, grown by backoff when `RestartSecMax` is set
+    fn restart_delay(&self) -> Duration {
+        self.restarts.delay(self.config.restart_sec, &self.config.start_limit)
+    }
+
+    /// Decide what follows an exit with `exit_code`: an automatic restart, or
+    /// the failed state when the exit was an error or the start limit is hit.
+    fn settle_exit(&mut self, exit_code: i32) {
+        if self.should_restart(exit_code) {
+            if self.restarts.limit_hit(&self.config.start_limit) {
+                self.failed = true;
+                self.start_limit_hit = true;
+                let msg = format!(
+                    "Service {} start request repeated too quickly ({} starts within {}s), not restarting",
+                    self.name,
+                    self.config.start_limit.start_limit_burst,
+                    self.config.start_limit.start_limit_interval_sec
+                );
+                Logger::error(&msg);
+                self.logger.log(msg);
+                events::publish(Some(&self.name), EventKind::Failed { reason: "start limit hit".to_string() });
+            } else if self.can_restart_now() {
+                Logger::info(&format!(
+                    "Service {} will be restarted (policy: {:?})",
+                    self.name, self.config.restart
+                ));
+                events::publish(Some(&self.name), EventKind::Restarting { delay_sec: 0 });
+            } else {
+                let delay_sec = self.restart_delay().as_secs();
+                Logger::info(&format!("Service {} restart delayed for {} seconds", self.name, delay_sec));
+                events::publish(Some(&self.name), EventKind::Restarting { delay_sec });
+            }
+        } else {
+            if self.exit_failed(exit_code) && !self.manual_stop {
+                self.failed = true;
+                let reason = self
+                    .failure
+                    .clone()
+                    .unwrap_or_else(|| format!("exited with code {}", exit_code));
+                events::publish(Some(&self.name), EventKind::Failed { reason });
+            }
+            Logger::info(&format!(
+                "Service {} will not be restarted (policy: {:?})",
+                self.name, self.config.restart
+            ));
+        }
+    }
+
+    /// Clear the failed state, start history and backoff.
+    fn reset_failed(&mut self) {
+        self.failed = false;
+        self.failure = None;
+        self.start_limit_hit = false;
+        self.restarts.reset();
+    }
+
+    fn active_state(&self) -> ActiveState {
+        if self.pid.is_some() {
+            let awaits_ready = self.config.health.ready_check.is_some()
+                || self.config.service_type == ServiceType::Notify;
+            let step = self.helper.as_ref().map(|h| &h.step);
+            if self.stop_deadline.is_some() || matches!(step, Some(HelperStep::Stop)) {
+                ActiveState::Stopping
+            } else if self.config.service_type == ServiceType::Oneshot
+                || (awaits_ready && !self.health.is_ready())
+                || matches!(step, Some(HelperStep::StartPost))
+            {
+                ActiveState::Starting
+            } else {
+                ActiveState::Running
+            }
+        } else if let Some(ref helper) = self.helper {
+            match helper.step {
+                HelperStep::ExitHooks { start_post: false, .. }
+                | HelperStep::StartPre { cancelled: true, .. }
+                | HelperStep::Stop => ActiveState::Stopping,
+                HelperStep::Reload { .. } => ActiveState::Running,
+                _ => ActiveState::Starting,
+            }
+        } else if self.remain_active {
+            ActiveState::Running
+        } else if self.failed {
+            ActiveState::Failed
+        } else {
+            ActiveState::Inactive
+        }
+    }
+
     fn is_active(&self) -> bool {
-        self.pid.is_some()
+        self.pid.is_some() || self.remain_active || self.helper.is_some()
+    }
+
+    /// Whether the service is started on demand by its `.socket` units
+    fn is_socket_activated(&self) -> bool {
+        self.sockets.iter().any(|s| s.is_listening())
+    }
+
+    /// Whether the service is left to its `.socket` or `.timer` units at boot
+    fn starts_on_demand(&self) -> bool {
+        self.is_socket_activated() || !self.timers.is_empty()
+    }
+
+    /// Environment of the main process, also passed to hook commands.
+    /// Reads the service's `EnvironmentFile`s.
+    fn environment(&self) -> Result<Vec<String>> {
+        self.config
+            .environment_options
+            .resolve(&self.base_environment, &self.config.environment)
+    }
+
+    /// `WorkingDirectory` with variables expanded
+    fn working_directory(&self) -> Option<String> {
+        self.config
+            .working_directory
+            .as_ref()
+            .map(|wd| environment::expand(wd, &self.resolved_environment))
+    }
+
+    /// The executable `program` resolves to with `env`, and the algorithm
+    /// to hash it with, if it has pinned hashes or measurement is enabled.
+    fn measurement_target(&self, program: &str, env: &[String]) -> Result<Option<(PathBuf, HashAlgorithm)>> {
+        let algorithm = match MEASUREMENT.get() {
+            Some(algorithm) => *algorithm,
+            None if self.config.exec_hash.is_pinned() => HashAlgorithm::default(),
+            None => return Ok(None),
+        };
+        let path_env = env.iter().find_map(|var| var.strip_prefix("PATH=")).unwrap_or("");
+        let working_directory = self.config.working_directory.as_ref().map(|wd| environment::expand(wd, env));
+        let path = measurement::resolve_executable(program, path_env, working_directory.as_deref())
+            .with_context(|| format!("{} not found in PATH", program))?;
+        Ok(Some((path, algorithm)))
+    }
+
+    /// The executable of the next start and its algorithm, if it has to be
+    /// hashed before the start. Errors are left to the start to report.
+    fn unhashed_executable(&self, program: &str) -> Option<(PathBuf, HashAlgorithm)> {
+        let env = self.environment().ok()?;
+        let program = environment::expand(program, &env);
+        let (path, algorithm) = self.measurement_target(&program, &env).ok()??;
+        (!measurement::is_hashed(&path, &self.config.exec_hash, algorithm)).then_some((path, algorithm))
+    }
+
+    /// Open and hash the executable `program` if it has pinned hashes or
+    /// measurement is enabled. Fails on a hash mismatch.
+    fn measure_executable(&self, program: &str) -> Result<Option<MeasuredExecutable>> {
+        match self.measurement_target(program, &self.resolved_environment)? {
+            Some((path, algorithm)) => measurement::measure(&path, &self.config.exec_hash, algorithm).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Mark a start as failed because its executable could not be verified.
+    fn fail_verification(&mut self, error: &str) {
+        self.failed = true;
+        self.exec_hash = None;
+        self.logger.log(format!("Refusing to start: {}", error));
+        let reason = format!("executable verification failed: {}", error);
+        events::publish(Some(&self.name), EventKind::Failed { reason });
+    }
+
+    /// Run `work` on its own thread as the service's helper step.
+    fn start_helper(&mut self, step: HelperStep, work: impl FnOnce() -> Result<(), HelperError> + Send + 'static) {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = sender.send(work());
+        });
+        self.helper = Some(Helper { step, result: receiver });
+    }
+
+    /// Context for hook commands. `$MAINPID` is set while the main process runs.
+    fn hook_context(&self, timeout: Duration) -> HookContext {
+        let mut env = self.resolved_environment.clone();
+        if let Some(pid) = self.pid {
+            env.push(format!("MAINPID={}", pid));
+        }
+        HookContext {
+            env,
+            working_directory: self.working_directory(),
+            timeout,
+        }
+    }
+
+    /// Run `ExecStop=` commands as a helper step. Failures are logged, the stop
+    /// proceeds regardless. Without a main process, as for a oneshot service
+    /// kept active by `RemainAfterExit`, `ExecStopPost` follows right away.
+    fn start_stop_hooks(&mut self) {
+        let ctx = self.hook_context(Duration::from_secs(self.config.kill.timeout_stop_sec));
+        let exec_stop = self.config.exec_stop.clone();
+        let exec_stop_post = self.pid.is_none().then(|| self.config.exec_stop_post.clone());
+        let credentials = self.config.credentials.clone();
+        let logger = self.logger.clone();
+        self.start_helper(HelperStep::Stop, move || {
+            let _ = hooks::run_hooks("ExecStop", &exec_stop, &ctx, &logger);
+            if let Some(exec_stop_post) = exec_stop_post {
+                let _ = hooks::run_hooks("ExecStopPost", &exec_stop_post, &ctx, &logger);
+                credentials.remove_runtime_directories();
+            }
+            Ok(())
+        });
+    }
+
+    /// Run the hooks that follow an exit of the main process as a helper step:
+    /// `ExecStartPost` if `start_post` is set, then `ExecStopPost` unless that
+    /// leaves the service active.
+    fn start_exit_hooks(&mut self, exit_code: i32, start_post: bool) {
+        let remain_after_exit = self.config.remain_after_exit;
+        let start_ctx = self.hook_context(Duration::from_secs(self.config.timeout_start_sec));
+        let stop_ctx = self.hook_context(Duration::from_secs(self.config.kill.timeout_stop_sec));
+        let exec_start_post = self.config.exec_start_post.clone();
+        let exec_stop_post = self.config.exec_stop_post.clone();
+        let credentials = self.config.credentials.clone();
+        let logger = self.logger.clone();
+        self.start_helper(HelperStep::ExitHooks { exit_code, start_post }, move || {
+            let mut result = Ok(());
+            if start_post {
+                match hooks::run_hooks("ExecStartPost", &exec_start_post, &start_ctx, &logger) {
+                    Ok(()) if remain_after_exit => return Ok(()),
+                    Ok(()) => {}
+                    Err(e) => {
+                        result = Err(HelperError {
+                            message: format!("ExecStartPost {}", e),
+                            exit_code: Some(e.exit_code.unwrap_or(1)),
+                        })
+                    }
+                }
+            }
+            let _ = hooks::run_hooks("ExecStopPost", &exec_stop_post, &stop_ctx, &logger);
+            credentials.remove_runtime_directories();
+            result
+        });
+    }
+
+    /// Settle an exit of the main process once its hooks have run. Returns
+    /// why the service's dependents have to stop, if they do.
+    fn finish_exit(&mut self, exit_code: i32) -> Option<StopCause> {
+        let exit_code = self.exit_status.unwrap_or(exit_code);
+        if let Some(started) = self.last_restart {
+            self.restarts.record_exit(started.elapsed(), &self.config.start_limit);
+        }
+        self.settle_exit(exit_code);
+
+        // Explicit stops take their dependents down beforehand
+        if self.manual_stop || self.is_active() {
+            return None;
+        }
+        Some(if self.failed { StopCause::Failed } else { StopCause::Exited })
+    }
+
+    /// Whether the service needs a notification socket
+    fn uses_notify(&self) -> bool {
+        self.config.service_type == ServiceType::Notify || self.config.watchdog_sec > 0
+    }
+
+    /// Send a signal to the processes selected by `KillMode`. Without a cgroup,
+    /// `control-group` falls back to the main process's process group.
+    fn send_signal(&self, signal: Signal) -> nix::Result<()> {
+        let Some(pid) = self.pid else {
+            return Ok(());
+        };
+        match self.config.kill.kill_mode {
+            KillMode::ControlGroup => {
+                if let Some(ref cgroup) = self.cgroup {
+                    if signal == Signal::SIGKILL {
+                        cgroup.kill_all();
+                        return Ok(());
+                    }
+                    if cgroup.signal_all(signal) > 0 {
+                        return Ok(());
+                    }
+                }
+                killpg(pid, signal).or_else(|_| kill(pid, signal))
+            }
+            KillMode::ProcessGroup => killpg(pid, signal).or_else(|_| kill(pid, signal)),
+            KillMode::Process => kill(pid, signal),
+        }
+    }
+
+    /// Send `KillSignal` and arm the `TimeoutStopSec` deadline that the main
+    /// loop enforces.
+    fn signal_stop(&mut self) -> nix::Result<()> {
+        if self.pid.is_none() {
+            return Ok(());
+        }
+        self.stop_deadline = Some(Instant::now() + Duration::from_secs(self.config.kill.timeout_stop_sec));
+        self.send_signal(self.config.kill.kill_signal)
+    }
+
+    /// Stop the service: run `ExecStop` and signal the main process once it
+    /// has finished. A oneshot service kept active by `RemainAfterExit` runs
+    /// its stop hooks and is deactivated.
+    fn begin_stop(&mut self) -> nix::Result<()> {
+        if matches!(self.helper, Some(Helper { step: HelperStep::Stop, .. })) {
+            return Ok(());
+        }
+        if self.pid.is_none() {
+            self.cancel_start();
+            if self.remain_active {
+                self.remain_active = false;
+                self.start_stop_hooks();
+            }
+            return Ok(());
+        }
+        if self.config.exec_stop.is_empty() {
+            return self.signal_stop();
+        }
+        self.start_stop_hooks();
+        Ok(())
+    }
+
+    /// Stop the main process because of a failure. Its exit counts as a
+    /// failure whatever its code.
+    fn stop_for_failure(&mut self, reason: String) {
+        let msg = format!("Service {} {}, stopping", self.name, reason);
+        Logger::error(&msg);
+        self.logger.log(msg);
+        self.failure = Some(reason);
+        let _ = self.signal_stop();
+    }
+
+    /// Fail a oneshot service whose main process is still running at the end
+    /// of `TimeoutStartSec`.
+    fn enforce_start_deadline(&mut self) {
+        let Some(deadline) = self.start_deadline else {
+            return;
+        };
+        if self.pid.is_none() {
+            self.start_deadline = None;
+            return;
+        }
+        if Instant::now() < deadline {
+            return;
+        }
+
+        self.start_deadline = None;
+        self.stop_for_failure(format!("did not finish starting within {}s", self.config.timeout_start_sec));
+    }
+
+    /// Cancel a start that has not forked yet. Hashing is abandoned; running
+    /// `ExecStartPre` commands finish, then `ExecStopPost` runs instead of the fork.
+    fn cancel_start(&mut self) {
+        match self.helper {
+            Some(Helper { step: HelperStep::Measure, .. }) => {
+                // The hashing thread finishes on its own; its result is dropped
+                self.helper = None;
+                self.logger.log("Start cancelled while hashing the executable".to_string());
+            }
+            Some(Helper { step: HelperStep::StartPre { ref mut cancelled, .. }, .. }) => {
+                *cancelled = true;
+                self.logger.log("Start cancelled, waiting for ExecStartPre to finish".to_string());
+            }
+            _ => {}
+        }
+    }
+
+    /// Escalate to SIGKILL once the stop deadline has passed.
+    fn enforce_stop_deadline(&mut self) {
+        let Some(deadline) = self.stop_deadline else {
+            return;
+        };
+        if self.pid.is_none() {
+            self.stop_deadline = None;
+            return;
+        }
+        if Instant::now() < deadline {
+            return;
+        }
+
+        self.stop_deadline = None;
+        let msg = if self.config.kill.send_sigkill {
+            let _ = self.send_signal(Signal::SIGKILL);
+            format!(
+                "Service {} did not stop within {}s, sent SIGKILL",
+                self.name, self.config.kill.timeout_stop_sec
+            )
+        } else {
+            format!(
+                "Service {} did not stop within {}s, SendSIGKILL is disabled",
+                self.name, self.config.kill.timeout_stop_sec
+            )
+        };
+        Logger::warn(&msg);
+        self.logger.log(msg);
     }
 
     fn to_service_info(&self) -> ServiceInfo {
         ServiceInfo {
             name: self.name.clone(),
             enabled: self.enabled,
-            active: self.is_active(),
+            state: self.active_state(),
             restart_policy: self.config.restart.as_str().to_string(),
             restart_count: self.restart_count,
         }
@@ -213,7 +858,7 @@ impl ServiceState {
         ServiceStatus {
             name: self.name.clone(),
             enabled: self.enabled,
-            active: self.is_active(),
+            state: self.active_state(),
             pid: self.pid.map(|p| p.as_raw()),
             restart_policy: self.config.restart.as_str().to_string(),
             restart_count: self.restart_count,
@@ -226,7 +871,34 @@ impl ServiceState {
                 after: self.config.after.clone(),
                 requires: self.config.requires.clone(),
                 required_by: self.config.required_by.clone(),
+                binds_to: self.config.binds_to.clone(),
+                part_of: self.config.part_of.clone(),
+                wanted_by: self.config.wanted_by.clone(),
+            },
+            cgroup: self.cgroup.as_ref().map(|c| c.usage()),
+            health: self.health.state,
+            last_probe: self.health.last_result.clone(),
+            service_type: self.config.service_type.as_str().to_string(),
+            status_text: self.notify.as_ref().and_then(|n| n.state().status),
+            watchdog_sec: self.config.watchdog_sec,
+            exited: self.remain_active,
+            hooks: ServiceHooksInfo {
+                exec_start_pre: self.config.exec_start_pre.clone(),
+                exec_start_post: self.config.exec_start_post.clone(),
+                exec_stop: self.config.exec_stop.clone(),
+                exec_stop_post: self.config.exec_stop_post.clone(),
+                exec_reload: self.config.exec_reload.clone(),
             },
+            kill_signal: self.config.kill.kill_signal.as_str().to_string(),
+            kill_mode: self.config.kill.kill_mode.as_str().to_string(),
+            timeout_stop_sec: self.config.kill.timeout_stop_sec,
+            send_sigkill: self.config.kill.send_sigkill,
+            start_limit_hit: self.start_limit_hit,
+            next_restart_sec: self.restart_delay().as_secs(),
+            listen: self.sockets.iter().flat_map(|s| s.describe()).collect(),
+            credentials: self.config.credentials.describe(),
+            sandbox: self.config.sandbox.describe(),
+            exec_hash: self.exec_hash.clone(),
         }
     }
 
@@ -236,12 +908,34 @@ impl ServiceState {
             after: self.config.after.clone(),
             requires: self.config.requires.clone(),
             required_by: self.config.required_by.clone(),
+            binds_to: self.config.binds_to.clone(),
+            part_of: self.config.part_of.clone(),
         }
     }
 }
 
 type ServiceMap = Arc<Mutex<HashMap<String, ServiceState>>>;
 
+/// Loaded `.target` units and the target init is in.
+#[derive(Default)]
+struct TargetState {
+    units: HashMap<String, TargetUnit>,
+    current: Option<String>,
+}
+
+/// Locked after the services map when both are needed.
+static TARGETS: std::sync::LazyLock<Mutex<TargetState>> = std::sync::LazyLock::new(Default::default);
+
+/// Serializes reloads and target switches.
+static RELOAD_LOCK: Mutex<()> = Mutex::new(());
+
+/// Sampled metrics history, set when `metrics.enabled`.
+static METRICS: std::sync::OnceLock<Mutex<MetricsHistory>> = std::sync::OnceLock::new();
+
+/// Hash algorithm for measuring every service executable, set when
+/// `measurement.enabled`.
+static MEASUREMENT: std::sync::OnceLock<HashAlgorithm> = std::sync::OnceLock::new();
+
 /// Map of active log streamers per service
 type StreamerMap = Arc<Mutex<HashMap<String, Arc<VsockLogStreamer>>>>;
 
@@ -345,13 +1039,6 @@ static OPS: std::sync::LazyLock<Vec<InitOp>> = std::sync::LazyLock::new(|| vec![
         flags: MsFlags::MS_NODEV | MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
         data: None,
     },
-    InitOp::Mount {
-        source: "cgroup_root",
-        target: "/sys/fs/cgroup",
-        fstype: "tmpfs",
-        flags: MsFlags::MS_NODEV | MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
-        data: Some("mode=0755"),
-    },
 ]);
 
 fn init_dev() -> Result<()> {
@@ -435,6 +1122,46 @@ fn init_fs(ops: &[InitOp]) -> Result<()> {
 }
 
 fn init_cgroups() -> Result<()> {
+    if cgroup::is_available() {
+        Logger::info("cgroup v2 hierarchy already mounted");
+        cgroup::enable_controllers();
+        return Ok(());
+    }
+
+    match mount(
+        Some("cgroup2"),
+        cgroup::CGROUP_ROOT,
+        Some("cgroup2"),
+        MsFlags::MS_NODEV | MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+        Some("nsdelegate"),
+    ) {
+        Ok(_) => {
+            Logger::info("Mounted cgroup v2 hierarchy");
+            cgroup::enable_controllers();
+            return Ok(());
+        }
+        Err(e) => {
+            Logger::warn(&format!(
+                "Failed to mount cgroup v2 hierarchy: {}, falling back to cgroup v1 (no resource limits)",
+                e
+            ));
+        }
+    }
+
+    init_cgroups_v1()
+}
+
+fn init_cgroups_v1() -> Result<()> {
+    if let Err(e) = mount(
+        Some("cgroup_root"),
+        cgroup::CGROUP_ROOT,
+        Some("tmpfs"),
+        MsFlags::MS_NODEV | MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+        Some("mode=0755"),
+    ) {
+        Logger::warn(&format!("Failed to mount {}: {}", cgroup::CGROUP_ROOT, e));
+    }
+
     let fpath = "/proc/cgroups";
     let file = match File::open(fpath) {
         Ok(f) => f,
@@ -627,22 +1354,111 @@ fn init_nsm_driver(config: &InitConfig) -> Result<()> {
     Ok(())
 }
 
-fn parse_service_file(path: &Path) -> Result<ServiceConfig> {
+/// Parse a service file. For template instances the specifiers are
+/// expanded first.
+fn parse_service_file(path: &Path, name: &str) -> Result<ServiceConfig> {
     let content = fs::read_to_string(path)
         .with_context(|| format!("Failed to read service file: {:?}", path))?;
+    parse_service_definition(&content, name)
+        .with_context(|| format!("Failed to parse service file: {:?}", path))
+}
 
-    let config: ServiceConfig = toml::from_str(&content)
-        .with_context(|| format!("Failed to parse service file: {:?}", path))?;
+/// Parse the text of a service file.
+fn parse_service_definition(content: &str, name: &str) -> Result<ServiceConfig> {
+    if template::template_of(name).is_some() {
+        return Ok(toml::from_str(&template::expand_specifiers(content, name))?);
+    }
+    Ok(toml::from_str(content)?)
+}
 
-    Ok(config)
+/// Check the settings of a parsed service. The message completes
+/// "Service <name> ...".
+fn check_service_config(service_config: &ServiceConfig) -> Result<(), String> {
+    if service_config.exec_start.is_empty() {
+        return Err("has no ExecStart".to_string());
+    }
+    service_config
+        .limits
+        .to_cgroup_files()
+        .map_err(|e| format!("has invalid resource limits: {}", e))?;
+    service_config
+        .start_limit
+        .validate()
+        .map_err(|e| format!("has an invalid start limit: {}", e))?;
+    service_config
+        .credentials
+        .validate()
+        .map_err(|e| format!("has invalid credentials: {}", e))?;
+    service_config
+        .sandbox
+        .validate()
+        .map_err(|e| format!("has invalid sandbox settings: {}", e))?;
+    service_config
+        .environment_options
+        .validate()
+        .map_err(|e| format!("has an invalid environment: {}", e))?;
+    service_config
+        .log_retention
+        .validate()
+        .map_err(|e| format!("has an invalid log retention policy: {}", e))?;
+    service_config
+        .exec_hash
+        .validate()
+        .map_err(|e| format!("has an invalid {}", e))
 }
 
 fn is_service_disabled(path: &Path) -> bool {
     path.extension().and_then(|s| s.to_str()) == Some("disabled")
 }
 
-fn load_services(config: &InitConfig) -> Result<HashMap<String, ServiceState>> {
+/// Parse and validate one service. Errors are logged and the service skipped.
+fn load_service(config: &InitConfig, name: &str, path: &Path, base_environment: &[String]) -> Option<ServiceState> {
+    let service_config = match parse_service_file(path, name) {
+        Ok(c) => c,
+        Err(e) => {
+            Logger::error(&format!("Failed to parse service {}: {}", name, e));
+            return None;
+        }
+    };
+
+    if let Err(e) = check_service_config(&service_config) {
+        Logger::error(&format!("Service {} {}, skipping", name, e));
+        return None;
+    }
+    let log_policy = service_config.log_retention.resolve(&RetentionPolicy::from_config(config));
+
+    match ServiceState::new(
+        name.to_string(),
+        service_config,
+        &config.log_dir,
+        log_policy,
+        base_environment.to_vec(),
+    ) {
+        Ok(state) => {
+            Logger::info(&format!("Loaded service: {} (enabled: {})", name, state.enabled));
+            Some(state)
+        }
+        Err(e) => {
+            Logger::error(&format!("Failed to create logger for service {}: {}", name, e));
+            None
+        }
+    }
+}
+
+/// Load the services and targets in the service directory.
+fn load_services(config: &InitConfig) -> Result<(HashMap<String, ServiceState>, HashMap<String, TargetUnit>)> {
     let mut services = HashMap::new();
+    let mut targets: HashMap<String, TargetUnit> = HashMap::new();
+    let mut templates: HashMap<String, PathBuf> = HashMap::new();
+    let mut socket_paths = Vec::new();
+    let mut timer_paths = Vec::new();
+    let mut target_paths = Vec::new();
+
+    let mut global_environment: Vec<String> =
+        config.environment.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    global_environment.sort();
+    let mut base_environment = vec![DEFAULT_PATH_ENV.to_string()];
+    base_environment.extend(global_environment);
 
     let entries = match read_dir(&config.service_dir) {
         Ok(e) => e,
@@ -651,7 +1467,7 @@ fn load_services(config: &InitConfig) -> Result<HashMap<String, ServiceState>> {
                 "Failed to read service directory {}: {}",
                 config.service_dir, e
             ));
-            return Ok(services);
+            return Ok((services, targets));
         }
     };
 
@@ -668,8 +1484,21 @@ fn load_services(config: &InitConfig) -> Result<HashMap<String, ServiceState>> {
             continue;
         }
 
-        if path.extension().and_then(|s| s.to_str()) != Some("service") {
-            continue;
+        match path.extension().and_then(|s| s.to_str()) {
+            Some("service") => {}
+            Some("socket") => {
+                socket_paths.push(path);
+                continue;
+            }
+            Some("timer") => {
+                timer_paths.push(path);
+                continue;
+            }
+            Some("target") => {
+                target_paths.push(path);
+                continue;
+            }
+            _ => continue,
         }
 
         let name = path
@@ -678,80 +1507,674 @@ fn load_services(config: &InitConfig) -> Result<HashMap<String, ServiceState>> {
             .unwrap_or("unknown")
             .to_string();
 
-        match parse_service_file(&path) {
-            Ok(service_config) => {
-                if service_config.exec_start.is_empty() {
-                    Logger::warn(&format!("Service {} has no ExecStart, skipping", name));
-                    continue;
-                }
-
-                match ServiceState::new(
-                    name.clone(),
-                    service_config,
-                    &config.log_dir,
-                    config.max_log_size,
-                    config.max_log_files,
-                ) {
-                    Ok(state) => {
-                        Logger::info(&format!(
-                            "Loaded service: {} (enabled: {})",
-                            name, state.enabled
-                        ));
-                        services.insert(name.clone(), state);
-                    }
-                    Err(e) => {
-                        Logger::error(&format!("Failed to create logger for service {}: {}", name, e));
-                    }
-                }
-            }
-            Err(e) => {
-                Logger::error(&format!("Failed to parse service {}: {}", name, e));
-            }
+        if template::is_template(&name) {
+            Logger::info(&format!("Loaded template: {}", name));
+            templates.insert(name, path);
+            continue;
         }
-    }
 
-    Ok(services)
-}
-
-fn compute_startup_order(services: &HashMap<String, ServiceState>) -> Vec<String> {
-    let mut resolver = DependencyResolver::new();
+        if let Err(e) = template::validate_instance_name(&name) {
+            Logger::error(&format!("Service {} has an invalid name: {}", name, e));
+            continue;
+        }
 
-    for (name, service) in services {
-        if service.enabled {
-            resolver.add_service(name.clone(), service.get_dependencies());
+        if let Some(state) = load_service(config, &name, &path, &base_environment) {
+            services.insert(name, state);
         }
     }
 
-    match resolver.validate_dependencies() {
-        Ok(_) => Logger::info("Service dependencies validated"),
-        Err(e) => {
-            Logger::error(&format!("Dependency validation failed: {}", e));
-            return services.keys().cloned().collect();
+    for path in target_paths {
+        let name = target::unit_name(path.file_stem().and_then(|s| s.to_str()).unwrap_or("unknown"));
+        match target::parse_target_file(&path) {
+            Ok(target_config) => {
+                Logger::info(&format!("Loaded target: {}", name));
+                targets.insert(name.clone(), TargetUnit::new(name, target_config));
+            }
+            Err(e) => Logger::error(&format!("Failed to parse target {}: {}", name, e)),
         }
     }
 
-    match resolver.compute_startup_order() {
-        Ok(order) => {
-            Logger::info(&format!("Computed startup order: {:?}", order));
-            order
+    // Instances named in Requires or by a target are created from their templates
+    let mut pending: Vec<String> = services.values().flat_map(|s| s.config.requires.clone()).collect();
+    pending.extend(targets.values().flat_map(|t| t.wants.iter().chain(t.requires.iter()).cloned()));
+    while let Some(name) = pending.pop() {
+        if services.contains_key(&name) || template::validate_instance_name(&name).is_err() {
+            continue;
         }
-        Err(e) => {
-            Logger::error(&format!("Failed to compute startup order: {}", e));
-            services.keys().cloned().collect()
+        let Some(path) = template::template_of(&name).and_then(|t| templates.get(&t)) else {
+            continue;
+        };
+        if PathBuf::from(&config.service_dir).join(format!("{}.service.disabled", name)).exists() {
+            continue;
+        }
+        if let Some(state) = load_service(config, &name, path, &base_environment) {
+            pending.extend(state.config.requires.iter().cloned());
+            services.insert(name, state);
         }
     }
-}
 
-fn launch_service(service: &mut ServiceState) -> Result<()> {
-    if !service.enabled {
-        Logger::warn(&format!("Service {} is disabled, not starting", service.name));
-        return Ok(());
+    // Dependencies on a template (`worker@`) apply to all of its instances
+    let names: Vec<String> = services.keys().cloned().collect();
+    for service in services.values_mut() {
+        let deps = &mut service.config;
+        for list in [
+            &mut deps.before,
+            &mut deps.after,
+            &mut deps.requires,
+            &mut deps.required_by,
+            &mut deps.binds_to,
+            &mut deps.part_of,
+        ] {
+            *list = template::expand_references(list, &names);
+        }
+    }
+    for unit in targets.values_mut() {
+        unit.wants = template::expand_references(&unit.wants, &names);
+        unit.requires = template::expand_references(&unit.requires, &names);
     }
 
-    Logger::info(&format!("Launching service: {}", service.name));
-    service
-        .logger
-        .log(format!("Starting service {}", service.name));
+    // Services join targets through WantedBy and RequiredBy
+    for (name, service) in services.iter_mut() {
+        for (target_name, required) in service
+            .config
+            .wanted_by
+            .iter()
+            .map(|t| (t, false))
+            .chain(service.config.required_by.iter().filter(|t| target::is_target(t)).map(|t| (t, true)))
+        {
+            match targets.get_mut(target_name) {
+                Some(unit) if required => unit.requires.push(name.clone()),
+                Some(unit) => unit.wants.push(name.clone()),
+                None => Logger::warn(&format!("Service {} is wanted by unknown target {}", name, target_name)),
+            }
+        }
+        service.config.required_by.retain(|t| !target::is_target(t));
+    }
+    let target_names: HashSet<String> = targets.keys().cloned().collect();
+    for unit in targets.values_mut() {
+        for list in [&mut unit.wants, &mut unit.requires] {
+            list.retain(|member| {
+                let known = services.contains_key(member) || target_names.contains(member);
+                if !known {
+                    Logger::warn(&format!("Target {} includes unknown unit {}, ignoring", unit.name, member));
+                }
+                known
+            });
+        }
+    }
+
+    // A target in a dependency list stands for its services
+    for (name, service) in services.iter_mut() {
+        let deps = &mut service.config;
+        for (list, required_only) in [
+            (&mut deps.before, false),
+            (&mut deps.after, false),
+            (&mut deps.part_of, false),
+            (&mut deps.requires, true),
+            (&mut deps.binds_to, true),
+        ] {
+            for unknown in list.iter().filter(|d| target::is_target(d) && !targets.contains_key(*d)) {
+                Logger::warn(&format!("Service {} depends on unknown target {}, ignoring", name, unknown));
+            }
+            *list = target::expand_references(list, &targets, required_only, name);
+        }
+    }
+
+    for path in socket_paths {
+        let name = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("unknown")
+            .to_string();
+
+        let unit = match activation::parse_socket_file(&path) {
+            Ok(socket_config) => match SocketUnit::new(name.clone(), socket_config) {
+                Ok(unit) => unit,
+                Err(e) => {
+                    Logger::error(&format!("Socket {} is invalid: {}", name, e));
+                    continue;
+                }
+            },
+            Err(e) => {
+                Logger::error(&format!("Failed to parse socket {}: {}", name, e));
+                continue;
+            }
+        };
+
+        match services.get_mut(unit.service_name()) {
+            Some(service) => {
+                Logger::info(&format!("Loaded socket: {} (activates {})", name, service.name));
+                service.sockets.push(unit);
+            }
+            None => Logger::error(&format!(
+                "Socket {} activates unknown service {}, skipping",
+                name,
+                unit.service_name()
+            )),
+        }
+    }
+
+    let clock = Clock::new(boot_time());
+    for path in timer_paths {
+        let name = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("unknown")
+            .to_string();
+
+        let timer = match timer::parse_timer_file(&path) {
+            Ok(timer_config) => match TimerUnit::new(name.clone(), timer_config, &clock) {
+                Ok(timer) => timer,
+                Err(e) => {
+                    Logger::error(&format!("Timer {} is invalid: {}", name, e));
+                    continue;
+                }
+            },
+            Err(e) => {
+                Logger::error(&format!("Failed to parse timer {}: {}", name, e));
+                continue;
+            }
+        };
+
+        match services.get_mut(timer.service_name()) {
+            Some(service) => {
+                Logger::info(&format!("Loaded timer: {} (starts {})", name, service.name));
+                service.timers.push(timer);
+            }
+            None => Logger::error(&format!(
+                "Timer {} starts unknown service {}, skipping",
+                name,
+                timer.service_name()
+            )),
+        }
+    }
+
+    Ok((services, targets))
+}
+
+/// When init started; timers count `OnBootSec` from here.
+fn boot_time() -> Instant {
+    unsafe { SYSTEM_START_TIME.unwrap_or_else(Instant::now) }
+}
+
+/// Start services whose timers have elapsed.
+fn run_timers(services: &mut HashMap<String, ServiceState>) {
+    let clock = Clock::new(boot_time());
+    for (name, service) in services.iter_mut() {
+        let last_active = service.last_restart;
+        let mut elapsed = Vec::new();
+        for timer in service.timers.iter_mut() {
+            if timer.is_due(&clock, last_active) {
+                timer.trigger(&clock);
+                elapsed.push(timer.name.clone());
+            }
+        }
+        if elapsed.is_empty() || !service.enabled || !service.wanted || service.start_limit_hit {
+            continue;
+        }
+        if service.is_active() {
+            Logger::info(&format!(
+                "Timer {} elapsed, but service {} is still active",
+                elapsed.join(", "),
+                name
+            ));
+            continue;
+        }
+
+        let msg = format!("Timer {} elapsed, starting service {}", elapsed.join(", "), name);
+        Logger::info(&msg);
+        service.logger.log(msg);
+        service.manual_stop = false;
+        if let Err(e) = launch_service(service) {
+            Logger::error(&format!("Failed to start service {} from timer: {}", name, e));
+        }
+    }
+}
+
+/// Start listening on the sockets of every enabled service in the current target.
+fn open_sockets(services: &mut HashMap<String, ServiceState>) {
+    for service in services.values_mut() {
+        if !service.enabled || !service.wanted {
+            continue;
+        }
+        for unit in service.sockets.iter_mut() {
+            match unit.open() {
+                Ok(()) => Logger::info(&format!(
+                    "Socket {} listening on {}",
+                    unit.name,
+                    unit.describe().join(", ")
+                )),
+                Err(e) => Logger::error(&format!("Socket {}: {:#}", unit.name, e)),
+            }
+        }
+    }
+}
+
+fn compute_startup_order(services: &HashMap<String, ServiceState>) -> Vec<String> {
+    let mut resolver = DependencyResolver::new();
+
+    for (name, service) in services {
+        if service.enabled {
+            resolver.add_service(name.clone(), service.get_dependencies());
+        }
+    }
+
+    match resolver.validate_dependencies() {
+        Ok(_) => Logger::info("Service dependencies validated"),
+        Err(e) => {
+            Logger::error(&format!("Dependency validation failed: {}", e));
+            return services.keys().cloned().collect();
+        }
+    }
+
+    match resolver.compute_startup_order() {
+        Ok(order) => {
+            Logger::info(&format!("Computed startup order: {:?}", order));
+            order
+        }
+        Err(e) => {
+            Logger::error(&format!("Failed to compute startup order: {}", e));
+            services.keys().cloned().collect()
+        }
+    }
+}
+
+/// Dependency graph of all loaded services, for propagating starts and stops.
+fn dependency_resolver(services: &HashMap<String, ServiceState>) -> DependencyResolver {
+    let mut resolver = DependencyResolver::new();
+    for (name, service) in services {
+        resolver.add_service(name.clone(), service.get_dependencies());
+    }
+    resolver
+}
+
+/// Tree of the services `name` pulls in or, with `reverse`, of those that
+/// pull it in. `path` holds the ancestors, to cut cycles.
+fn dependency_tree(
+    services: &HashMap<String, ServiceState>,
+    resolver: &DependencyResolver,
+    name: &str,
+    relation: Option<Relation>,
+    reverse: bool,
+    path: &mut Vec<String>,
+) -> DependencyNode {
+    let relations: &[Relation] = if reverse {
+        &[Relation::RequiredBy, Relation::BoundBy, Relation::PartOf]
+    } else {
+        &[Relation::Requires, Relation::BindsTo, Relation::ConsistsOf]
+    };
+    let mut children = Vec::new();
+    if !path.iter().any(|p| p == name) {
+        path.push(name.to_string());
+        for relation in relations {
+            for child in resolver.related(name, *relation) {
+                children.push(dependency_tree(services, resolver, &child, Some(*relation), reverse, path));
+            }
+        }
+        path.pop();
+    }
+    DependencyNode {
+        name: name.to_string(),
+        relation: relation.map(|r| r.as_str().to_string()),
+        state: services.get(name).map(|s| s.active_state()).unwrap_or_default(),
+        children,
+    }
+}
+
+/// Append the services affected along with the requested one to a message.
+fn with_related(message: String, label: &str, names: &[String]) -> String {
+    if names.is_empty() {
+        message
+    } else {
+        format!("{} ({}: {})", message, label, names.join(", "))
+    }
+}
+
+/// Start `name` after the services it requires that are not active yet,
+/// in dependency order. Each service is launched only once its dependencies
+/// are ready, with the services lock released while waiting. Returns the
+/// required services that were started.
+fn start_with_requirements(services_map: &ServiceMap, name: &str) -> Result<Vec<String>, String> {
+    let requirements = dependency_resolver(&services_map.lock().unwrap()).requirements(name);
+    let mut started = Vec::new();
+    for required in requirements {
+        {
+            let services = services_map.lock().unwrap();
+            let Some(service) = services.get(&required) else {
+                continue;
+            };
+            if service.is_active() {
+                continue;
+            }
+            if !service.enabled {
+                return Err(format!("Service '{}' requires '{}', which is disabled", name, required));
+            }
+            if service.start_limit_hit {
+                return Err(start_limit_message(&required));
+            }
+        }
+        if !wait_for_dependencies_ready(services_map, &required) {
+            return Err(format!(
+                "Service '{}' not started: a dependency of its requirement '{}' is not ready",
+                name, required
+            ));
+        }
+        let mut services = services_map.lock().unwrap();
+        let Some(service) = services.get_mut(&required) else {
+            continue;
+        };
+        if service.is_active() {
+            continue;
+        }
+        service.manual_stop = false;
+        launch_service(service)
+            .map_err(|e| format!("Failed to start service '{}', required by '{}': {}", required, name, e))?;
+        started.push(required);
+    }
+
+    if !wait_for_dependencies_ready(services_map, name) {
+        return Err(format!("Service '{}' not started: a required dependency is not ready", name));
+    }
+    let mut services = services_map.lock().unwrap();
+    let service = services
+        .get_mut(name)
+        .ok_or_else(|| format!("Service '{}' not found", name))?;
+    if service.is_active() {
+        return Err(format!("Service '{}' is already {}", name, service.active_state().as_str()));
+    }
+    service.manual_stop = false;
+    launch_service(service).map_err(|e| format!("Failed to start service '{}': {}", name, e))?;
+    Ok(started)
+}
+
+/// Begin stopping the active services that follow `name` for `cause`,
+/// without waiting; the main loop reaps them.
+fn stop_dependents(services: &mut HashMap<String, ServiceState>, name: &str, cause: StopCause) {
+    let reason = match cause {
+        StopCause::Explicit => "is stopping",
+        StopCause::Failed => "failed",
+        StopCause::Exited => "exited",
+    };
+    for dependent in dependency_resolver(services).dependents(name, cause) {
+        let Some(service) = services.get_mut(&dependent) else {
+            continue;
+        };
+        if !service.is_active() || service.active_state() == ActiveState::Stopping {
+            continue;
+        }
+        let msg = format!("Stopping service {} because {} {}", dependent, name, reason);
+        Logger::info(&msg);
+        service.logger.log(msg);
+        service.manual_stop = true;
+        let _ = service.begin_stop();
+    }
+}
+
+/// Stop the services that depend on `name`, then `name` itself, waiting for
+/// each. Returns the stop message and the dependents that were stopped.
+fn stop_with_dependents(services_map: &ServiceMap, name: &str) -> Result<(String, Vec<String>), String> {
+    let dependents: Vec<String> = {
+        let services = services_map.lock().unwrap();
+        let service = services
+            .get(name)
+            .ok_or_else(|| format!("Service '{}' not found", name))?;
+        if !service.is_active() {
+            return Err(format!("Service '{}' is not running", name));
+        }
+        dependency_resolver(&services)
+            .dependents(name, StopCause::Explicit)
+            .into_iter()
+            .filter(|d| services[d].is_active())
+            .collect()
+    };
+
+    for dependent in &dependents {
+        Logger::info(&format!("Stopping service {} along with {}", dependent, name));
+        if let Err(e) = stop_service_and_wait(services_map, dependent) {
+            // It may have exited on its own in the meantime
+            if services_map.lock().unwrap().get(dependent).is_some_and(|s| s.is_active()) {
+                return Err(e);
+            }
+        }
+    }
+    let message = stop_service_and_wait(services_map, name)?;
+    Ok((message, dependents))
+}
+
+/// Mark the services included in `target`, and the services they require,
+/// as wanted and all others as not. Without a target every service is wanted.
+fn apply_target(services: &mut HashMap<String, ServiceState>, targets: &HashMap<String, TargetUnit>, target: Option<&str>) {
+    let Some(target) = target else {
+        for service in services.values_mut() {
+            service.wanted = true;
+        }
+        return;
+    };
+    let resolver = dependency_resolver(services);
+    let mut wanted = HashSet::new();
+    for name in target::services_of(targets, target, false) {
+        wanted.extend(resolver.requirements(&name));
+        wanted.insert(name);
+    }
+    for (name, service) in services.iter_mut() {
+        service.wanted = wanted.contains(name);
+    }
+}
+
+/// Switch to `target`: stop the active services it does not include, in
+/// reverse dependency order, then start the enabled services it includes.
+fn isolate_target(services_map: &ServiceMap, name: &str) -> Result<String, String> {
+    let _reload = RELOAD_LOCK.lock().unwrap();
+    let target = target::unit_name(name);
+
+    let (to_stop, to_start) = {
+        let mut services = services_map.lock().unwrap();
+        let mut targets = TARGETS.lock().unwrap();
+        let unit = targets
+            .units
+            .get(&target)
+            .ok_or_else(|| format!("Target '{}' not found", target))?;
+        if !unit.config.allow_isolate {
+            return Err(format!("Target '{}' does not allow isolation", target));
+        }
+        Logger::info(&format!("Isolating target {}", target));
+        apply_target(&mut services, &targets.units, Some(&target));
+        targets.current = Some(target.clone());
+        drop(targets);
+        open_sockets(&mut services);
+
+        let order = dependency_resolver(&services)
+            .compute_startup_order()
+            .unwrap_or_else(|_| services.keys().cloned().collect());
+        let to_stop: Vec<String> = order
+            .iter()
+            .rev()
+            .filter(|n| !services[*n].wanted && services[*n].is_active())
+            .cloned()
+            .collect();
+        let to_start: Vec<String> = order
+            .into_iter()
+            .filter(|n| {
+                let s = &services[n];
+                s.wanted && s.enabled && !s.is_active() && !s.starts_on_demand() && !s.start_limit_hit
+            })
+            .collect();
+        (to_stop, to_start)
+    };
+
+    let mut errors = Vec::new();
+    let mut stopped = Vec::new();
+    for name in &to_stop {
+        Logger::info(&format!("Stopping service {}, not included in {}", name, target));
+        match stop_service_and_wait(services_map, name) {
+            Ok(_) => stopped.push(name.clone()),
+            // It may have exited on its own in the meantime
+            Err(e) if services_map.lock().unwrap().get(name).is_some_and(|s| s.is_active()) => errors.push(e),
+            Err(_) => {}
+        }
+    }
+
+    let mut started = Vec::new();
+    for name in to_start {
+        if !wait_for_dependencies_ready(services_map, &name) {
+            errors.push(format!("Service '{}' not started: a required dependency is not ready", name));
+            continue;
+        }
+        let mut services = services_map.lock().unwrap();
+        let Some(service) = services.get_mut(&name) else {
+            continue;
+        };
+        if service.is_active() {
+            continue;
+        }
+        service.manual_stop = false;
+        match launch_service(service) {
+            Ok(()) => started.push(name),
+            Err(e) => errors.push(format!("Failed to start service '{}': {}", name, e)),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(format!("Isolated {} with errors: {}", target, errors.join("; ")));
+    }
+    let message = with_related(format!("Isolated {}", target), "stopped", &stopped);
+    let message = with_related(message, "started", &started);
+    Logger::info(&message);
+    Ok(message)
+}
+
+/// Wait until every dependency of `name` that has a `ReadyCheck` reports ready,
+/// is a `Type=notify` service that sent `READY=1`, or is a `Type=oneshot`
+/// service that exited successfully.
+///
+/// Children are reaped while waiting, since the main loop is not running yet.
+/// Returns false if a dependency listed in `Requires` (or naming this service in
+/// `RequiredBy`) stopped or became unhealthy before it was ready; the caller
+/// should then not start the service. Ordering-only dependencies that fail are
+/// logged and skipped.
+fn wait_for_dependencies_ready(services_map: &ServiceMap, name: &str) -> bool {
+    let (deps, required) = {
+        let services = services_map.lock().unwrap();
+        let Some(service) = services.get(name) else {
+            return true;
+        };
+
+        let mut required: Vec<String> = service.config.requires.clone();
+        required.extend(service.config.binds_to.iter().cloned());
+        let mut deps: Vec<String> = service.config.after.clone();
+        for (other_name, other) in services.iter() {
+            if other.config.before.iter().any(|b| b == name) {
+                deps.push(other_name.clone());
+            }
+            if other.config.required_by.iter().any(|r| r == name) {
+                required.push(other_name.clone());
+            }
+        }
+        deps.extend(required.iter().cloned());
+        deps.sort();
+        deps.dedup();
+        (deps, required)
+    };
+
+    let mut logged_wait = false;
+    loop {
+        let mut pending = Vec::new();
+        {
+            let mut services = services_map.lock().unwrap();
+            if SIGCHLD_RECEIVED.swap(false, Ordering::Relaxed) {
+                reap_children(&mut services);
+            }
+            for service in services.values_mut() {
+                service.enforce_start_deadline();
+                service.enforce_stop_deadline();
+            }
+            finish_helpers(&mut services);
+            for dep in &deps {
+                let Some(dep_service) = services.get(dep) else {
+                    continue;
+                };
+                // Still hashing its executable or running ExecStartPre/ExecStartPost:
+                // not started yet, whatever its type
+                if dep_service.helper.is_some() {
+                    pending.push(dep.clone());
+                    continue;
+                }
+                let oneshot = dep_service.config.service_type == ServiceType::Oneshot;
+                let gated = dep_service.config.health.ready_check.is_some()
+                    || dep_service.config.service_type == ServiceType::Notify
+                    || oneshot;
+                // Started later by its sockets or timers; nothing to wait for now
+                let lazy = dep_service.starts_on_demand() && !dep_service.is_active();
+                if !dep_service.enabled || !gated || lazy {
+                    continue;
+                }
+
+                // A oneshot dependency is ready once it has exited successfully
+                let (ready, failed) = if oneshot {
+                    let finished = dep_service.pid.is_none() && dep_service.helper.is_none();
+                    let succeeded = dep_service.exit_status.is_some_and(|code| !dep_service.exit_failed(code));
+                    (finished && succeeded, finished && !succeeded)
+                } else {
+                    (
+                        dep_service.health.is_ready(),
+                        !dep_service.is_active() || dep_service.health.state == HealthState::Unhealthy,
+                    )
+                };
+                if ready {
+                    continue;
+                }
+
+                if failed {
+                    let state = if oneshot {
+                        match dep_service.exit_status {
+                            Some(code) => format!("exit code {}", code),
+                            None => "not started".to_string(),
+                        }
+                    } else {
+                        format!("health: {}", dep_service.health.state.as_str())
+                    };
+                    if required.contains(dep) {
+                        Logger::error(&format!(
+                            "Service {} not started: required dependency {} did not become ready ({})",
+                            name, dep, state
+                        ));
+                        return false;
+                    }
+                    Logger::warn(&format!(
+                        "Dependency {} of service {} did not become ready ({}), starting anyway",
+                        dep, name, state
+                    ));
+                    continue;
+                }
+                pending.push(dep.clone());
+            }
+        }
+
+        if pending.is_empty() {
+            return true;
+        }
+
+        if !logged_wait {
+            Logger::info(&format!(
+                "Service {} waiting for readiness of: {}",
+                name,
+                pending.join(", ")
+            ));
+            logged_wait = true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn launch_service(service: &mut ServiceState) -> Result<()> {
+    if !service.enabled {
+        Logger::warn(&format!("Service {} is disabled, not starting", service.name));
+        return Ok(());
+    }
+    if service.helper.is_some() {
+        // Already starting; the main loop resumes the start
+        return Ok(());
+    }
 
     let parts: Vec<String> = shell_words::split(&service.config.exec_start)
         .unwrap_or_else(|_| vec![service.config.exec_start.clone()]);
@@ -761,15 +2184,173 @@ fn launch_service(service: &mut ServiceState) -> Result<()> {
         return Ok(());
     }
 
+    // Large files are hashed without the services lock held; the start
+    // resumes in `finish_helpers` once they are
+    if let Some((path, algorithm)) = service.unhashed_executable(&parts[0]) {
+        let pins = service.config.exec_hash.clone();
+        service.logger.log(format!("Hashing {} before starting", path.display()));
+        service.start_helper(HelperStep::Measure, move || {
+            measurement::hash_ahead(&path, &pins, algorithm).map_err(|e| HelperError::new(format!("{:#}", e)))
+        });
+        return Ok(());
+    }
+
+    Logger::info(&format!("Launching service: {}", service.name));
+    service
+        .logger
+        .log(format!("Starting service {}", service.name));
+
+    if cgroup::is_available() {
+        match Cgroup::create(&service.name) {
+            Ok(cg) => {
+                cg.apply_limits(&service.config.limits).with_context(|| {
+                    format!("Failed to apply resource limits for service {}", service.name)
+                })?;
+                service.cgroup = Some(cg);
+            }
+            Err(e) => {
+                Logger::warn(&format!(
+                    "Service {} will run without a cgroup: {}",
+                    service.name, e
+                ));
+                service.cgroup = None;
+            }
+        }
+    }
+
+    service.remain_active = false;
+    service.failed = false;
+    service.failure = None;
+    service.restarts.record_start(&service.config.start_limit);
+
+    service.resolved_environment = match service.environment() {
+        Ok(env) => env,
+        Err(e) => {
+            service.failed = true;
+            service.logger.log(format!("Failed to set up environment: {:#}", e));
+            let reason = format!("failed to set up environment: {:#}", e);
+            events::publish(Some(&service.name), EventKind::Failed { reason });
+            return Err(e.context(format!("Failed to set up environment for service {}", service.name)));
+        }
+    };
+    let parts: Vec<String> = parts
+        .iter()
+        .map(|word| environment::expand(word, &service.resolved_environment))
+        .collect();
+
+    let credentials = match service
+        .config
+        .credentials
+        .resolve()
+        .and_then(|c| service.config.credentials.create_directories(&c).map(|()| c))
+    {
+        Ok(c) => c,
+        Err(e) => {
+            service.failed = true;
+            service.logger.log(format!("Failed to set up credentials: {:#}", e));
+            let reason = format!("failed to set up credentials: {:#}", e);
+            events::publish(Some(&service.name), EventKind::Failed { reason });
+            return Err(e.context(format!("Failed to set up credentials for service {}", service.name)));
+        }
+    };
+
+    if service.config.exec_start_pre.is_empty() {
+        return fork_service(service, parts, credentials);
+    }
+
+    // The commands run without the services lock; `finish_helpers` forks
+    // the main process once they succeed
+    let ctx = service.hook_context(Duration::from_secs(service.config.timeout_start_sec));
+    let exec_start_pre = service.config.exec_start_pre.clone();
+    let logger = service.logger.clone();
+    let step = HelperStep::StartPre { parts, credentials, cancelled: false };
+    service.start_helper(step, move || {
+        hooks::run_hooks("ExecStartPre", &exec_start_pre, &ctx, &logger).map_err(|e| HelperError {
+            message: format!("ExecStartPre {}", e),
+            exit_code: Some(e.exit_code.unwrap_or(1)),
+        })
+    });
+    Ok(())
+}
+
+/// Fork and exec the main process of a service whose `ExecStartPre` commands
+/// have succeeded.
+fn fork_service(service: &mut ServiceState, parts: Vec<String>, credentials: Credentials) -> Result<()> {
+    let start_timeout = Duration::from_secs(service.config.timeout_start_sec);
+
+    // Drop the previous socket first; it removes its path on drop
+    service.notify = None;
+    if service.uses_notify() {
+        service.notify = Some(NotifySocket::bind(&service.name, credentials.uid, credentials.gid)?);
+    }
+
+    let listen_fds: Vec<(RawFd, String)> = service
+        .sockets
+        .iter()
+        .flat_map(|s| s.listen_fds())
+        .map(|(fd, name)| (fd, name.to_string()))
+        .collect();
+
+    let stdout_redirect = StreamRedirect::new(OutputStream::Stdout, &service.config.standard_output)?;
+    let stderr_redirect = match StreamRedirect::new(OutputStream::Stderr, &service.config.standard_error) {
+        Ok(r) => r,
+        Err(e) => {
+            stdout_redirect.discard();
+            return Err(e);
+        }
+    };
+
+    // Measured last, after ExecStartPre, so the file cannot change between
+    // the hash and the fork unnoticed
+    let measured = service
+        .measure_executable(&parts[0])
+        .and_then(|m| m.map(|m| m.check_unchanged().map(|()| m)).transpose());
+    let mut measured = match measured {
+        Ok(m) => m,
+        Err(e) => {
+            stdout_redirect.discard();
+            stderr_redirect.discard();
+            service.fail_verification(&format!("{:#}", e));
+            return Err(e.context(format!("Failed to verify executable of service {}", service.name)));
+        }
+    };
+
     match unsafe { fork() } {
         Ok(ForkResult::Parent { child }) => {
+            stdout_redirect.attach_in_parent(&service.logger, &service.name);
+            stderr_redirect.attach_in_parent(&service.logger, &service.name);
             service.pid = Some(child);
+            // Recorded only once a process runs it, not when ExecStartPre fails
+            service.exec_hash = measured.as_ref().map(|m| m.hash.clone());
+            if let Some(ref m) = measured {
+                m.record(&service.name, logger::now_millis());
+            }
+            service
+                .health
+                .reset(&service.config.health, service.config.service_type == ServiceType::Notify);
             service.last_restart = Some(Instant::now());
             service.restart_count += 1;
             service.manual_stop = false;
+            service.start_deadline = (service.config.service_type == ServiceType::Oneshot
+                && service.config.timeout_start_sec > 0)
+                .then(|| Instant::now() + start_timeout);
             let log_msg = format!("Service {} started with PID {}", service.name, child);
             Logger::info(&log_msg);
             service.logger.log(log_msg);
+            events::publish(Some(&service.name), EventKind::Started { pid: child.as_raw() });
+
+            // Oneshot services run ExecStartPost once the main process has exited
+            if service.config.service_type != ServiceType::Oneshot && !service.config.exec_start_post.is_empty() {
+                let ctx = service.hook_context(start_timeout);
+                let exec_start_post = service.config.exec_start_post.clone();
+                let logger = service.logger.clone();
+                service.start_helper(HelperStep::StartPost, move || {
+                    hooks::run_hooks("ExecStartPost", &exec_start_post, &ctx, &logger).map_err(|e| HelperError {
+                        message: format!("ExecStartPost {}", e),
+                        exit_code: e.exit_code,
+                    })
+                });
+            }
             Ok(())
         }
         Ok(ForkResult::Child) => {
@@ -779,14 +2360,60 @@ fn launch_service(service: &mut ServiceState) -> Result<()> {
             let _ = setsid();
             let _ = setpgid(Pid::from_raw(0), Pid::from_raw(0));
 
-            if let Some(ref wd) = service.config.working_directory {
+            // Outside its cgroup the service would run without its limits
+            if let Some(ref cg) = service.cgroup {
+                if let Err(e) = cg.enter() {
+                    Logger::error(&format!("Service {}: {:#}", service.name, e));
+                    std::process::exit(1);
+                }
+            }
+
+            stdout_redirect.apply_in_child();
+            stderr_redirect.apply_in_child();
+
+            if let Err(e) = service.config.sandbox.apply_in_child(&service.config.credentials.directories()) {
+                Logger::error(&format!("Service {}: failed to set up sandbox: {:#}", service.name, e));
+                std::process::exit(1);
+            }
+            if let Err(e) = credentials.apply_in_child() {
+                Logger::error(&format!("Service {}: {:#}", service.name, e));
+                std::process::exit(1);
+            }
+
+            if let Some(wd) = service.working_directory() {
                 if let Err(e) = chdir(wd.as_str()) {
                     Logger::error(&format!("Failed to chdir to {}: {}", wd, e));
                 }
             }
 
-            let mut envp = service.config.environment.clone();
-            envp.push(DEFAULT_PATH_ENV.to_string());
+            let mut envp = service.resolved_environment.clone();
+            envp.extend(credentials.env.iter().cloned());
+            if let Some(ref notify) = service.notify {
+                envp.push(format!("NOTIFY_SOCKET={}", notify.path().display()));
+            }
+            if service.config.watchdog_sec > 0 {
+                envp.push(format!("WATCHDOG_USEC={}", service.config.watchdog_sec * 1_000_000));
+                envp.push(format!("WATCHDOG_PID={}", std::process::id()));
+            }
+            if let Some(ref mut m) = measured {
+                // Listeners are passed as descriptors 3 and up
+                if let Err(e) = m.move_fd_above(activation::first_free_fd(listen_fds.len())) {
+                    Logger::error(&format!("Service {}: failed to move executable descriptor: {}", service.name, e));
+                    std::process::exit(1);
+                }
+            }
+            if !listen_fds.is_empty() {
+                let fds: Vec<RawFd> = listen_fds.iter().map(|(fd, _)| *fd).collect();
+                match activation::pass_fds_in_child(&fds) {
+                    Ok(()) => {
+                        let names: Vec<&str> = listen_fds.iter().map(|(_, name)| name.as_str()).collect();
+                        envp.push(format!("LISTEN_FDS={}", fds.len()));
+                        envp.push(format!("LISTEN_PID={}", std::process::id()));
+                        envp.push(format!("LISTEN_FDNAMES={}", names.join(":")));
+                    }
+                    Err(e) => Logger::error(&format!("{}", e)),
+                }
+            }
 
             let argv_c: Vec<CString> = parts
                 .iter()
@@ -805,14 +2432,23 @@ fn launch_service(service: &mut ServiceState) -> Result<()> {
                 envp_c.iter().map(|s| s.as_ptr()).collect();
             envp_ptrs.push(std::ptr::null());
 
-            unsafe {
-                libc::execvpe(argv_ptrs[0], argv_ptrs.as_ptr(), envp_ptrs.as_ptr());
+            match measured {
+                Some(ref m) => {
+                    let e = m.exec_in_child(&argv_ptrs, &envp_ptrs);
+                    Logger::error(&format!("Failed to exec {}: {}", m.path.display(), e));
+                    std::process::exit(1);
+                }
+                None => unsafe {
+                    libc::execvpe(argv_ptrs[0], argv_ptrs.as_ptr(), envp_ptrs.as_ptr());
+                },
             }
 
             Logger::error(&format!("Failed to exec {}", parts[0]));
             std::process::exit(1);
         }
         Err(e) => {
+            stdout_redirect.discard();
+            stderr_redirect.discard();
             Logger::error(&format!("Failed to fork for service {}: {}", service.name, e));
             Ok(())
         }
@@ -899,43 +2535,124 @@ fn reap_children(services: &mut HashMap<String, ServiceState>) {
 }
 
 fn handle_process_exit(services: &mut HashMap<String, ServiceState>, pid: Pid, exit_code: i32) {
+    if hooks::record_exit(pid.as_raw(), exit_code) {
+        return;
+    }
+
     for (_, service) in services.iter_mut() {
         if service.pid == Some(pid) {
             service.pid = None;
+            service.start_deadline = None;
             service.exit_status = Some(exit_code);
+            service.health.stopped();
+
+            // Processes left behind by the main process are killed on stop
+            let stopping = service.stop_deadline.take().is_some();
+            if stopping
+                && service.config.kill.kill_mode == KillMode::ControlGroup
+                && service.config.kill.send_sigkill
+            {
+                if let Some(ref cgroup) = service.cgroup {
+                    let remaining = cgroup.pids().len();
+                    if remaining > 0 {
+                        service.logger.log(format!(
+                            "Killing {} remaining process(es) of service {}",
+                            remaining, service.name
+                        ));
+                        cgroup.kill_all();
+                    }
+                }
+            }
 
             let log_msg = format!("Service {} exited with code {}", service.name, exit_code);
             service.logger.log(log_msg.clone());
             Logger::info(&log_msg);
+            events::publish(Some(&service.name), EventKind::Exited { code: exit_code });
+
+            // The hooks run without the services lock; `finish_helpers`
+            // settles the exit once they are done. A pending ExecStartPost,
+            // ExecStop or ExecReload step is dropped; its commands finish on
+            // their own.
+            let start_post = service.config.service_type == ServiceType::Oneshot && !service.exit_failed(exit_code);
+            service.start_exit_hooks(exit_code, start_post);
+            break;
+        }
+    }
+}
 
-            if service.should_restart(exit_code) {
-                if service.can_restart_now() {
-                    Logger::info(&format!(
-                        "Service {} will be restarted (policy: {:?})",
-                        service.name, service.config.restart
-                    ));
-                } else {
-                    Logger::info(&format!(
-                        "Service {} restart delayed for {} seconds",
-                        service.name, service.config.restart_sec
-                    ));
+/// Apply the results of finished helper steps.
+fn finish_helpers(services: &mut HashMap<String, ServiceState>) {
+    let mut stopped = Vec::new();
+    for service in services.values_mut() {
+        let result = match &service.helper {
+            Some(helper) => match helper.result.try_recv() {
+                Ok(result) => result,
+                Err(mpsc::TryRecvError::Empty) => continue,
+                Err(mpsc::TryRecvError::Disconnected) => Err(HelperError::new("helper thread exited")),
+            },
+            None => continue,
+        };
+        let Some(helper) = service.helper.take() else {
+            continue;
+        };
+        match (helper.step, result) {
+            (HelperStep::Measure, Ok(())) => {
+                if let Err(e) = launch_service(service) {
+                    Logger::error(&format!("Failed to start service {}: {:#}", service.name, e));
+                }
+            }
+            (HelperStep::Measure, Err(e)) => {
+                Logger::error(&format!("Failed to verify executable of service {}: {}", service.name, e.message));
+                service.fail_verification(&e.message);
+            }
+            (HelperStep::StartPre { parts, credentials, cancelled }, result) => match result {
+                Ok(()) if !cancelled => {
+                    if let Err(e) = fork_service(service, parts, credentials) {
+                        Logger::error(&format!("Failed to start service {}: {:#}", service.name, e));
+                    }
+                }
+                Ok(()) => service.start_exit_hooks(0, false),
+                Err(e) => {
+                    Logger::error(&format!("Failed to start service {}: {}", service.name, e.message));
+                    let exit_code = e.exit_code.unwrap_or(1);
+                    service.exit_status = Some(exit_code);
+                    service.last_restart = Some(Instant::now());
+                    service.start_exit_hooks(exit_code, false);
+                }
+            },
+            (HelperStep::StartPost, Ok(())) => {}
+            (HelperStep::StartPost, Err(e)) => service.stop_for_failure(e.message),
+            (HelperStep::Stop, _) => {
+                let _ = service.signal_stop();
+            }
+            (HelperStep::Reload { reply }, result) => {
+                let _ = reply.send(result.map_err(|e| e.message));
+            }
+            (HelperStep::ExitHooks { exit_code, start_post }, result) => {
+                match result {
+                    Ok(()) => service.remain_active = start_post && service.config.remain_after_exit,
+                    Err(e) => {
+                        service.logger.log(format!("Service {} {}", service.name, e.message));
+                        service.exit_status = Some(e.exit_code.unwrap_or(1));
+                    }
+                }
+                if let Some(cause) = service.finish_exit(exit_code) {
+                    stopped.push((service.name.clone(), cause));
                 }
-            } else {
-                Logger::info(&format!(
-                    "Service {} will not be restarted (policy: {:?})",
-                    service.name, service.config.restart
-                ));
             }
-            break;
         }
     }
+
+    for (name, cause) in stopped {
+        stop_dependents(services, &name, cause);
+    }
 }
 
 fn restart_services(services: &mut HashMap<String, ServiceState>) {
     let mut to_restart = Vec::new();
 
     for (name, service) in services.iter() {
-        if service.pid.is_none() && service.enabled {
+        if service.pid.is_none() && service.helper.is_none() && service.enabled {
             if let Some(exit_code) = service.exit_status {
                 if service.should_restart(exit_code) && service.can_restart_now() {
                     to_restart.push(name.clone());
@@ -946,6 +2663,7 @@ fn restart_services(services: &mut HashMap<String, ServiceState>) {
 
     for name in to_restart {
         if let Some(service) = services.get_mut(&name) {
+            service.restarts.record_restart();
             if let Err(e) = launch_service(service) {
                 Logger::error(&format!("Failed to restart service {}: {}", name, e));
             }
@@ -953,32 +2671,77 @@ fn restart_services(services: &mut HashMap<String, ServiceState>) {
     }
 }
 
+/// Stop all services in reverse dependency order: a service is only stopped
+/// once everything that started after it has exited.
 fn shutdown_services(services: &mut HashMap<String, ServiceState>) {
     Logger::info("Shutting down all services...");
 
-    for (name, service) in services.iter() {
-        if let Some(pid) = service.pid {
-            Logger::info(&format!("Sending SIGTERM to service {} (PID {})", name, pid));
-            let _ = kill(pid, Signal::SIGTERM);
+    let levels = match dependency_resolver(services).compute_shutdown_levels() {
+        Ok(levels) => levels,
+        Err(e) => {
+            Logger::warn(&format!("{}, stopping all services at once", e));
+            vec![services.keys().cloned().collect()]
         }
+    };
+
+    // Nothing is started any more; exit hooks still run to completion
+    for service in services.values_mut() {
+        service.cancel_start();
     }
 
-    thread::sleep(Duration::from_secs(5));
+    for level in levels {
+        let mut deadline = Instant::now();
+        for name in &level {
+            let Some(service) = services.get_mut(name) else {
+                continue;
+            };
+            if let Some(pid) = service.pid {
+                Logger::info(&format!(
+                    "Sending {} to service {} (PID {})",
+                    service.config.kill.kill_signal.as_str(),
+                    name,
+                    pid
+                ));
+            }
+            if service.is_active() {
+                // ExecStop runs first, with the same timeout
+                let mut stop_timeout = Duration::from_secs(service.config.kill.timeout_stop_sec);
+                if !service.config.exec_stop.is_empty() {
+                    stop_timeout *= 2;
+                }
+                deadline = deadline.max(Instant::now() + stop_timeout);
+            }
+            service.manual_stop = true;
+            let _ = service.begin_stop();
+        }
+
+        // Wait for the level to exit, escalating to SIGKILL at each deadline
+        deadline += STOP_KILL_GRACE;
+        while level.iter().any(|n| services.get(n).is_some_and(|s| s.pid.is_some() || s.helper.is_some()))
+            && Instant::now() < deadline
+        {
+            reap_children(services);
+            for service in services.values_mut() {
+                service.enforce_stop_deadline();
+            }
+            finish_helpers(services);
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
 
     for (name, service) in services.iter() {
         if let Some(pid) = service.pid {
             Logger::warn(&format!("Sending SIGKILL to service {} (PID {})", name, pid));
             let _ = kill(pid, Signal::SIGKILL);
         }
-    }
-
-    loop {
-        match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
-            Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => break,
-            _ => continue,
+        if let Some(ref cgroup) = service.cgroup {
+            cgroup.kill_all();
         }
     }
 
+    thread::sleep(Duration::from_millis(100));
+    reap_children(services);
+
     Logger::info("All services stopped");
 }
 
@@ -1035,6 +2798,7 @@ fn get_system_status(config: &InitConfig, services: &HashMap<String, ServiceStat
     };
 
     let active_services = services.values().filter(|s| s.is_active()).count();
+    let failed_services = services.values().filter(|s| s.failed).count();
     let enabled_services = services.values().filter(|s| s.enabled).count();
 
     // Count total processes
@@ -1050,10 +2814,13 @@ fn get_system_status(config: &InitConfig, services: &HashMap<String, ServiceStat
         uptime_secs: uptime,
         total_services: services.len(),
         active_services,
+        failed_services,
         enabled_services,
         total_processes,
         log_dir: config.log_dir.clone(),
         service_dir: config.service_dir.clone(),
+        target: TARGETS.lock().unwrap().current.clone(),
+        log_forwarding: forwarder::status(),
     }
 }
 
@@ -1067,6 +2834,7 @@ fn get_service_pids(services: &HashMap<String, ServiceState>) -> HashMap<String,
 }
 
 fn enable_service(config: &InitConfig, name: &str) -> Result<(), String> {
+    template::validate_instance_name(name)?;
     let disabled_path = PathBuf::from(&config.service_dir)
         .join(format!("{}.service.disabled", name));
     let enabled_path = PathBuf::from(&config.service_dir)
@@ -1078,6 +2846,14 @@ fn enable_service(config: &InitConfig, name: &str) -> Result<(), String> {
         Ok(())
     } else if enabled_path.exists() {
         Ok(())
+    } else if let Some(template) = template::template_of(name) {
+        // An instance is a link to its template
+        let template_file = format!("{}.service", template);
+        if !PathBuf::from(&config.service_dir).join(&template_file).exists() {
+            return Err(format!("Template {} not found", template_file));
+        }
+        std::os::unix::fs::symlink(&template_file, &enabled_path)
+            .map_err(|e| format!("Failed to create instance: {}", e))
     } else {
         Err(format!("Service file not found"))
     }
@@ -1089,20 +2865,412 @@ fn disable_service(config: &InitConfig, name: &str) -> Result<(), String> {
     let disabled_path = PathBuf::from(&config.service_dir)
         .join(format!("{}.service.disabled", name));
 
-    if enabled_path.exists() {
-        rename(&enabled_path, &disabled_path)
-            .map_err(|e| format!("Failed to rename service file: {}", e))?;
-        Ok(())
-    } else if disabled_path.exists() {
-        Ok(())
-    } else {
-        Err(format!("Service file not found"))
+    if enabled_path.exists() {
+        rename(&enabled_path, &disabled_path)
+            .map_err(|e| format!("Failed to rename service file: {}", e))?;
+        Ok(())
+    } else if disabled_path.exists() {
+        Ok(())
+    } else {
+        Err(format!("Service file not found"))
+    }
+}
+
+/// Check a service name received over the control socket; it becomes part
+/// of a file name in the service directory.
+fn validate_service_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.starts_with('.') {
+        return Err(format!("Invalid service name '{}'", name));
+    }
+    if let Some(c) = name.chars().find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | ':' | '@'))) {
+        return Err(format!("Service name '{}' contains invalid character '{}'", name, c));
+    }
+    if target::is_target(name) {
+        return Err(format!("'{}' is a target, not a service", name));
+    }
+    if template::is_template(name) {
+        return Ok(());
+    }
+    template::validate_instance_name(name)
+}
+
+/// The file a service is loaded from: its own file, enabled or disabled, or
+/// for an instance without one its template.
+fn service_file_path(config: &InitConfig, name: &str) -> Option<PathBuf> {
+    let dir = PathBuf::from(&config.service_dir);
+    [format!("{}.service", name), format!("{}.service.disabled", name)]
+        .into_iter()
+        .chain(template::template_of(name).map(|t| format!("{}.service", t)))
+        .map(|file| dir.join(file))
+        .find(|path| path.symlink_metadata().is_ok())
+}
+
+/// Startup order of the loaded services with `name` replaced by `candidate`,
+/// or left out when there is none. Fails when a service would require a
+/// missing one or the dependencies would form a cycle.
+fn would_be_startup_order(
+    services: &HashMap<String, ServiceState>,
+    name: &str,
+    candidate: Option<&ServiceConfig>,
+) -> Result<Vec<String>, String> {
+    let mut resolver = DependencyResolver::new();
+    for (other, service) in services {
+        if other != name && service.enabled {
+            resolver.add_service(other.clone(), service.get_dependencies());
+        }
+    }
+
+    if let Some(candidate) = candidate.filter(|c| c.service_enable) {
+        // Resolve template and target references as load_services does
+        let names: Vec<String> = services.keys().cloned().chain([name.to_string()]).collect();
+        let targets = TARGETS.lock().unwrap();
+        let expand = |list: &[String], required_only: bool| {
+            target::expand_references(&template::expand_references(list, &names), &targets.units, required_only, name)
+        };
+        let deps = ServiceDependencies {
+            before: expand(&candidate.before, false),
+            after: expand(&candidate.after, false),
+            requires: expand(&candidate.requires, true),
+            required_by: candidate.required_by.iter().filter(|t| !target::is_target(t)).cloned().collect(),
+            binds_to: expand(&candidate.binds_to, true),
+            part_of: expand(&candidate.part_of, false),
+        };
+        resolver.add_service(name.to_string(), deps);
+    }
+
+    resolver.validate_dependencies()?;
+    resolver.compute_startup_order()
+}
+
+/// Parse and check a service definition, and compute the startup order with
+/// it installed.
+fn validate_service_definition(
+    config: &InitConfig,
+    services: &HashMap<String, ServiceState>,
+    name: &str,
+    content: &str,
+) -> Result<Vec<String>, String> {
+    validate_service_name(name)?;
+    let service_config = parse_service_definition(content, name)
+        .map_err(|e| format!("Failed to parse service {}: {:#}", name, e))?;
+    check_service_config(&service_config).map_err(|e| format!("Service {} {}", name, e))?;
+
+    // Templates are only loaded through their instances, and disabled services not at all
+    let disabled = PathBuf::from(&config.service_dir)
+        .join(format!("{}.service.disabled", name))
+        .exists();
+    let candidate = (!template::is_template(name) && !disabled).then_some(&service_config);
+    would_be_startup_order(services, name, candidate)
+}
+
+/// Write a file in the service directory atomically, through a hidden
+/// temporary file that load_services ignores.
+fn write_service_file(path: &Path, content: &str) -> Result<(), String> {
+    use std::io::Write;
+
+    let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("service");
+    let tmp_path = path.with_file_name(format!(".{}.tmp", file_name));
+    let result = File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(content.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = remove_file(&tmp_path);
+    }
+    result.map_err(|e| format!("Failed to write {:?}: {}", path, e))
+}
+
+/// Validate a service definition, write it to the service directory and
+/// reload. A disabled service stays disabled.
+fn install_service(
+    config: &InitConfig,
+    services_map: &ServiceMap,
+    name: &str,
+    content: &str,
+) -> Result<ReloadSummary, String> {
+    validate_service_definition(config, &services_map.lock().unwrap(), name, content)?;
+
+    let dir = PathBuf::from(&config.service_dir);
+    let disabled_path = dir.join(format!("{}.service.disabled", name));
+    let path = if disabled_path.exists() { disabled_path } else { dir.join(format!("{}.service", name)) };
+    write_service_file(&path, content)?;
+    Logger::info(&format!("Installed service file {:?}", path));
+
+    reload_services(config, services_map).map_err(|e| format!("Installed {:?} but failed to reload: {}", path, e))
+}
+
+/// Delete a service's file, unless other services require the service or,
+/// for a template, instances of it are loaded, then reload.
+fn remove_service(config: &InitConfig, services_map: &ServiceMap, name: &str) -> Result<ReloadSummary, String> {
+    validate_service_name(name)?;
+    let dir = PathBuf::from(&config.service_dir);
+    let path = [format!("{}.service", name), format!("{}.service.disabled", name)]
+        .into_iter()
+        .map(|file| dir.join(file))
+        .find(|path| path.symlink_metadata().is_ok())
+        .ok_or_else(|| format!("Service '{}' has no file in {}", name, config.service_dir))?;
+
+    {
+        let services = services_map.lock().unwrap();
+        let mut instances: Vec<&String> =
+            services.keys().filter(|n| template::template_of(n).as_deref() == Some(name)).collect();
+        if !instances.is_empty() {
+            instances.sort();
+            let instances: Vec<&str> = instances.iter().map(|n| n.as_str()).collect();
+            return Err(format!("Template {} has instances: {}", name, instances.join(", ")));
+        }
+        would_be_startup_order(&services, name, None).map_err(|e| format!("Cannot remove {}: {}", name, e))?;
+    }
+
+    remove_file(&path).map_err(|e| format!("Failed to remove {:?}: {}", path, e))?;
+    Logger::info(&format!("Removed service file {:?}", path));
+
+    reload_services(config, services_map).map_err(|e| format!("Removed {:?} but failed to reload: {}", path, e))
+}
+
+/// Whether a new configuration differs from the running one in more than
+/// its dependency lists and log retention, which can be updated in place.
+fn needs_restart(old: &ServiceConfig, new: &ServiceConfig) -> bool {
+    let without_dependencies = |c: &ServiceConfig| ServiceConfig {
+        log_retention: LogRetentionConfig::default(),
+        before: Vec::new(),
+        after: Vec::new(),
+        requires: Vec::new(),
+        required_by: Vec::new(),
+        binds_to: Vec::new(),
+        part_of: Vec::new(),
+        wanted_by: Vec::new(),
+        ..c.clone()
+    };
+    without_dependencies(old) != without_dependencies(new)
+}
+
+/// Move the socket listeners and timer history of `old` into `new`.
+fn adopt_units(new: &mut ServiceState, old: &mut ServiceState) {
+    for unit in new.sockets.iter_mut() {
+        if let Some(old_unit) = old.sockets.iter_mut().find(|u| u.name == unit.name) {
+            unit.adopt(old_unit);
+        }
+    }
+    for timer in new.timers.iter_mut() {
+        if let Some(old_timer) = old.timers.iter().find(|t| t.name == timer.name) {
+            timer.adopt(old_timer);
+        }
+    }
+}
+
+/// Reload the service files and apply the differences.
+///
+/// Services whose configuration is unchanged keep running with their state;
+/// only their dependencies, sockets and timers are updated. Changed and
+/// removed services are stopped in reverse dependency order, then changed
+/// services that were running and newly added enabled services are started
+/// in dependency order. Blocks while services stop, so it must not run on
+/// the main loop, which reaps them.
+fn reload_services(config: &InitConfig, services_map: &ServiceMap) -> Result<ReloadSummary, String> {
+    let _reload = RELOAD_LOCK.lock().unwrap();
+
+    Logger::info("Reloading service configurations...");
+    let (mut new_services, new_targets) = load_services(config).map_err(|e| e.to_string())?;
+    let mut summary = ReloadSummary::default();
+
+    let (to_stop, was_active) = {
+        let mut services = services_map.lock().unwrap();
+        for (name, new) in new_services.iter_mut() {
+            let Some(old) = services.get_mut(name) else {
+                summary.added.push(name.clone());
+                continue;
+            };
+            if needs_restart(&old.config, &new.config) {
+                summary.changed.push(name.clone());
+            } else {
+                adopt_units(new, old);
+                old.logger.set_policy(new.logger.policy());
+                old.config = new.config.clone();
+                old.sockets = std::mem::take(&mut new.sockets);
+                old.timers = std::mem::take(&mut new.timers);
+            }
+        }
+        summary.removed = services.keys().filter(|n| !new_services.contains_key(*n)).cloned().collect();
+        summary.added.sort();
+        summary.changed.sort();
+        summary.removed.sort();
+
+        let was_active: Vec<String> = summary
+            .changed
+            .iter()
+            .filter(|n| services[*n].is_active())
+            .cloned()
+            .collect();
+        let mut order = compute_startup_order(&services);
+        order.reverse();
+        let to_stop: Vec<String> = order
+            .into_iter()
+            .filter(|n| summary.changed.contains(n) || summary.removed.contains(n))
+            .filter(|n| services[n].is_active())
+            .collect();
+        (to_stop, was_active)
+    };
+
+    // A service that does not stop is left in place rather than orphaned
+    let mut still_running = Vec::new();
+    for name in &to_stop {
+        Logger::info(&format!("Stopping service {} for reload", name));
+        if let Err(e) = stop_service_and_wait(services_map, name) {
+            summary.errors.push(e);
+            still_running.push(name.clone());
+        }
+    }
+
+    let mut services = services_map.lock().unwrap();
+    for name in &summary.removed {
+        if still_running.contains(name) {
+            continue;
+        }
+        if let Some(cgroup) = services.remove(name).and_then(|old| old.cgroup) {
+            cgroup::retire(cgroup);
+        }
+    }
+    for name in &summary.changed {
+        if still_running.contains(name) {
+            continue;
+        }
+        let (Some(mut old), Some(mut new)) = (services.remove(name), new_services.remove(name)) else {
+            continue;
+        };
+        adopt_units(&mut new, &mut old);
+        // Keep the log file handle and its followers
+        std::mem::swap(&mut new.logger, &mut old.logger);
+        new.logger.set_policy(old.logger.policy());
+        new.restart_count = old.restart_count;
+        services.insert(name.clone(), new);
+    }
+    for name in &summary.added {
+        if let Some(new) = new_services.remove(name) {
+            services.insert(name.clone(), new);
+        }
+    }
+    {
+        let mut targets = TARGETS.lock().unwrap();
+        if let Some(current) = targets.current.take() {
+            if new_targets.contains_key(&current) {
+                targets.current = Some(current);
+            } else {
+                Logger::warn(&format!("Current target {} no longer exists", current));
+            }
+        }
+        apply_target(&mut services, &new_targets, targets.current.as_deref());
+        targets.units = new_targets;
+    }
+    open_sockets(&mut services);
+
+    for name in compute_startup_order(&services) {
+        let start = summary.added.contains(&name) || was_active.contains(&name);
+        let Some(service) = services.get_mut(&name) else {
+            continue;
+        };
+        if !start || !service.enabled || !service.wanted || service.is_active() || service.starts_on_demand() {
+            continue;
+        }
+        service.manual_stop = false;
+        match launch_service(service) {
+            Ok(()) if was_active.contains(&name) => summary.restarted.push(name),
+            Ok(()) => {}
+            Err(e) => summary.errors.push(format!("Failed to start service {}: {}", name, e)),
+        }
+    }
+
+    Logger::info(&format!("Services reloaded: {}", summary.describe()));
+    events::publish(None, EventKind::Reloaded { summary: summary.clone() });
+    Ok(summary)
+}
+
+/// Stop a service and wait until its main process has exited, or, without
+/// one, until its stop hooks have run.
+///
+/// The main loop runs the hooks, reaps the process and escalates to SIGKILL
+/// after `TimeoutStopSec`; this only polls, with the services lock released.
+fn stop_service_and_wait(services_map: &ServiceMap, name: &str) -> Result<String, String> {
+    let (pid, kill, hook_timeout) = {
+        let mut services = services_map.lock().unwrap();
+        let service = services
+            .get_mut(name)
+            .ok_or_else(|| format!("Service '{}' not found", name))?;
+        if !service.is_active() {
+            return Err(format!("Service '{}' is not running", name));
+        }
+
+        service.manual_stop = true;
+        let pid = service.pid;
+        service
+            .begin_stop()
+            .map_err(|e| format!("Failed to stop service '{}': {}", name, e))?;
+        service.logger.log(format!("Service {} stopped manually", name));
+
+        if pid.is_none() && service.helper.is_none() {
+            return Ok(format!("Service '{}' stopped", name));
+        }
+        let stop_timeout = Duration::from_secs(service.config.kill.timeout_stop_sec);
+        let mut hook_timeout = Duration::ZERO;
+        if !service.config.exec_stop.is_empty() {
+            hook_timeout += stop_timeout;
+        }
+        if pid.is_none() {
+            // A cancelled ExecStartPre, then ExecStopPost
+            hook_timeout += Duration::from_secs(service.config.timeout_start_sec) + stop_timeout;
+        }
+        (pid, service.config.kill.clone(), hook_timeout)
+    };
+
+    let mut limit = Instant::now() + hook_timeout + Duration::from_secs(kill.timeout_stop_sec);
+    if kill.send_sigkill {
+        limit += STOP_KILL_GRACE;
+    }
+
+    loop {
+        thread::sleep(Duration::from_millis(100));
+
+        let services = services_map.lock().unwrap();
+        let Some(service) = services.get(name) else {
+            return Ok(format!("Service '{}' stopped", name));
+        };
+        let stopped = match pid {
+            Some(pid) => service.pid != Some(pid),
+            None => service.helper.is_none(),
+        };
+        if stopped {
+            return Ok(if service.exit_status == Some(128 + Signal::SIGKILL as i32) {
+                format!(
+                    "Service '{}' stopped (killed after TimeoutStopSec={}s)",
+                    name, kill.timeout_stop_sec
+                )
+            } else {
+                format!("Service '{}' stopped", name)
+            });
+        }
+
+        if Instant::now() >= limit {
+            return Err(match pid {
+                None => format!("Service '{}' did not finish its stop commands", name),
+                Some(pid) if kill.send_sigkill => {
+                    format!("Service '{}' (PID {}) did not exit after SIGKILL", name, pid)
+                }
+                Some(pid) => format!(
+                    "Service '{}' (PID {}) did not stop within {}s and SendSIGKILL is disabled",
+                    name, pid, kill.timeout_stop_sec
+                ),
+            });
+        }
     }
 }
 
-fn reload_services(config: &InitConfig) -> Result<HashMap<String, ServiceState>, String> {
-    Logger::info("Reloading service configurations...");
-    load_services(config).map_err(|e| e.to_string())
+fn start_limit_message(name: &str) -> String {
+    format!(
+        "Service '{}' hit its start limit; run 'initctl reset-failed {}' first",
+        name, name
+    )
 }
 
 fn handle_client_request(
@@ -1126,11 +3294,157 @@ fn handle_client_request(
             }
         }
 
+        Request::ListTimers => {
+            let services = services.lock().unwrap();
+            let clock = Clock::new(boot_time());
+            let mut timers: Vec<TimerInfo> = services
+                .values()
+                .flat_map(|service| {
+                    service.timers.iter().map(move |timer| TimerInfo {
+                        name: timer.name.clone(),
+                        service: service.name.clone(),
+                        schedule: timer.describe(),
+                        last_trigger: timer.last_elapse(&clock),
+                        next_trigger: timer.next_elapse(&clock, service.last_restart),
+                    })
+                })
+                .collect();
+            timers.sort_by_key(|t| (t.next_trigger.is_none(), t.next_trigger, t.name.clone()));
+            Response::TimerList {
+                now: clock.realtime,
+                timers,
+            }
+        }
+
+        Request::ListTargets => {
+            let services = services.lock().unwrap();
+            let targets = TARGETS.lock().unwrap();
+            let mut list: Vec<TargetInfo> = targets
+                .units
+                .values()
+                .map(|unit| {
+                    let members = target::services_of(&targets.units, &unit.name, false);
+                    TargetInfo {
+                        name: unit.name.clone(),
+                        description: unit.config.description.clone(),
+                        current: targets.current.as_ref() == Some(&unit.name),
+                        active_services: members
+                            .iter()
+                            .filter(|m| services.get(*m).is_some_and(|s| s.is_active()))
+                            .count(),
+                        services: members,
+                    }
+                })
+                .collect();
+            list.sort_by(|a, b| a.name.cmp(&b.name));
+            Response::TargetList { targets: list }
+        }
+
+        Request::Isolate { target } => match isolate_target(services, &target) {
+            Ok(message) => Response::Success { message },
+            Err(message) => Response::Error { message },
+        },
+
+        Request::MeasurementLog => Response::MeasurementLog {
+            entries: measurement::entries(),
+        },
+
+        Request::ListDependencies { name, reverse } => {
+            let services = services.lock().unwrap();
+            if !services.contains_key(&name) {
+                return Response::Error {
+                    message: format!("Service '{}' not found", name),
+                };
+            }
+            let resolver = dependency_resolver(&services);
+            Response::DependencyTree {
+                tree: dependency_tree(&services, &resolver, &name, None, reverse, &mut Vec::new()),
+            }
+        }
+
         Request::ServiceStatus { name } => {
             let services = services.lock().unwrap();
             match services.get(&name) {
                 Some(service) => Response::ServiceStatus {
-                    status: service.to_service_status(),
+                    status: Box::new(service.to_service_status()),
+                },
+                None => Response::Error {
+                    message: format!("Service '{}' not found", name),
+                },
+            }
+        }
+
+        Request::ServiceValidate { name, content } => {
+            match validate_service_definition(config, &services.lock().unwrap(), &name, &content) {
+                Ok(startup_order) => Response::ServiceValidated { name, startup_order },
+                Err(message) => Response::Error { message },
+            }
+        }
+
+        Request::ServiceInstall { name, content } => {
+            Logger::info(&format!("Install of service {} requested via control socket", name));
+            match install_service(config, services, &name, &content) {
+                Ok(summary) => Response::Reloaded { summary },
+                Err(message) => Response::Error { message },
+            }
+        }
+
+        Request::ServiceRemove { name } => {
+            Logger::info(&format!("Removal of service {} requested via control socket", name));
+            match remove_service(config, services, &name) {
+                Ok(summary) => Response::Reloaded { summary },
+                Err(message) => Response::Error { message },
+            }
+        }
+
+        Request::ServiceCat { name } => {
+            if let Err(message) = validate_service_name(&name) {
+                return Response::Error { message };
+            }
+            let Some(path) = service_file_path(config, &name) else {
+                return Response::Error {
+                    message: format!("Service '{}' has no file in {}", name, config.service_dir),
+                };
+            };
+            match fs::read_to_string(&path) {
+                Ok(content) => Response::ServiceFile {
+                    name,
+                    path: path.display().to_string(),
+                    content,
+                },
+                Err(e) => Response::Error {
+                    message: format!("Failed to read {:?}: {}", path, e),
+                },
+            }
+        }
+
+        Request::ServiceMetrics { name, samples } => {
+            let Some(history) = METRICS.get() else {
+                return Response::Error {
+                    message: "Metrics sampling is disabled (metrics.enabled in init.yaml)".to_string(),
+                };
+            };
+            if let Some(name) = &name {
+                if !services.lock().unwrap().contains_key(name) {
+                    return Response::Error {
+                        message: format!("Service '{}' not found", name),
+                    };
+                }
+            }
+            Response::ServiceMetrics {
+                interval_sec: config.metrics.interval_sec,
+                services: history.lock().unwrap().snapshot(name.as_deref(), samples),
+            }
+        }
+
+        Request::ServiceEnvironment { name } => {
+            let services = services.lock().unwrap();
+            match services.get(&name) {
+                Some(service) => match service.environment() {
+                    Ok(environment) => Response::ServiceEnvironment { name, environment },
+                    Err(e) => Response::Error {
+                        message: format!("{:#}", e),
+                    },
                 },
                 None => Response::Error {
                     message: format!("Service '{}' not found", name),
@@ -1139,28 +3453,89 @@ fn handle_client_request(
         }
 
         Request::ServiceStart { name } => {
-            let mut services = services.lock().unwrap();
-            match services.get_mut(&name) {
+            let refused = match services.lock().unwrap().get(&name) {
                 Some(service) => {
                     if !service.enabled {
-                        Response::Error {
-                            message: format!("Service '{}' is disabled", name),
-                        }
+                        Some(format!("Service '{}' is disabled", name))
                     } else if service.is_active() {
-                        Response::Error {
-                            message: format!("Service '{}' is already running", name),
-                        }
+                        Some(format!(
+                            "Service '{}' is already {}",
+                            name,
+                            service.active_state().as_str()
+                        ))
+                    } else if service.start_limit_hit {
+                        Some(start_limit_message(&name))
                     } else {
+                        None
+                    }
+                }
+                None => Some(format!("Service '{}' not found", name)),
+            };
+            if let Some(message) = refused {
+                return Response::Error { message };
+            }
+            match start_with_requirements(services, &name) {
+                Ok(started) => Response::Success {
+                    message: with_related(format!("Service '{}' started", name), "also started", &started),
+                },
+                Err(message) => Response::Error { message },
+            }
+        }
+
+        Request::ServiceStop { name } => match stop_with_dependents(services, &name) {
+            Ok((message, stopped)) => Response::Success {
+                message: with_related(message, "also stopped", &stopped),
+            },
+            Err(message) => Response::Error { message },
+        },
+
+        Request::ServiceRestart { name } => {
+            let state = services
+                .lock()
+                .unwrap()
+                .get(&name)
+                .map(|s| (s.enabled, s.is_active(), s.start_limit_hit));
+            match state {
+                Some((false, _, _)) => Response::Error {
+                    message: format!("Service '{}' is disabled", name),
+                },
+                Some((true, _, true)) => Response::Error {
+                    message: start_limit_message(&name),
+                },
+                Some((true, active, false)) => {
+                    let mut dependents = Vec::new();
+                    if active {
+                        match stop_with_dependents(services, &name) {
+                            Ok((_, stopped)) => dependents = stopped,
+                            Err(message) => return Response::Error { message },
+                        }
+                    }
+                    let mut started = match start_with_requirements(services, &name) {
+                        Ok(started) => started,
+                        Err(message) => return Response::Error { message },
+                    };
+                    // Dependents stopped with the service come back with it
+                    for dependent in dependents.iter().rev() {
+                        if !wait_for_dependencies_ready(services, dependent) {
+                            Logger::error(&format!("Service {} not started: a required dependency is not ready", dependent));
+                            continue;
+                        }
+                        let mut services = services.lock().unwrap();
+                        let Some(service) = services.get_mut(dependent) else {
+                            continue;
+                        };
+                        if !service.enabled || service.is_active() {
+                            continue;
+                        }
                         service.manual_stop = false;
                         match launch_service(service) {
-                            Ok(_) => Response::Success {
-                                message: format!("Service '{}' started", name),
-                            },
-                            Err(e) => Response::Error {
-                                message: format!("Failed to start service '{}': {}", name, e),
-                            },
+                            Ok(()) => started.push(dependent.clone()),
+                            Err(e) => Logger::error(&format!("Failed to start service {}: {}", dependent, e)),
                         }
                     }
+                    Response::Success {
+                        message: with_related(format!("Service '{}' restarted", name), "also started", &started),
+                    }
                 }
                 None => Response::Error {
                     message: format!("Service '{}' not found", name),
@@ -1168,64 +3543,121 @@ fn handle_client_request(
             }
         }
 
-        Request::ServiceStop { name } => {
-            let mut services = services.lock().unwrap();
-            match services.get_mut(&name) {
-                Some(service) => {
-                    if let Some(pid) = service.pid {
-                        service.manual_stop = true;
-                        if let Err(e) = kill(pid, Signal::SIGTERM) {
-                            Response::Error {
-                                message: format!("Failed to stop service '{}': {}", name, e),
-                            }
-                        } else {
-                            service.logger.log(format!("Service {} stopped manually", name));
-                            Response::Success {
-                                message: format!("Service '{}' stop signal sent", name),
-                            }
-                        }
-                    } else {
-                        Response::Error {
+        Request::ServiceReload { name } => {
+            let result = {
+                let mut services = services.lock().unwrap();
+                match services.get_mut(&name) {
+                    Some(service) if !service.is_active() => {
+                        return Response::Error {
                             message: format!("Service '{}' is not running", name),
                         }
                     }
+                    Some(service) if service.config.exec_reload.is_empty() => {
+                        return match service.pid {
+                            Some(pid) => match kill(pid, Signal::SIGHUP) {
+                                Ok(_) => Response::Success {
+                                    message: format!("Service '{}' sent SIGHUP", name),
+                                },
+                                Err(e) => Response::Error {
+                                    message: format!("Failed to reload service '{}': {}", name, e),
+                                },
+                            },
+                            None => Response::Error {
+                                message: format!("Service '{}' has no ExecReload", name),
+                            },
+                        }
+                    }
+                    Some(service) if service.helper.is_some() => {
+                        return Response::Error {
+                            message: format!("Service '{}' is busy running other hook commands", name),
+                        }
+                    }
+                    Some(service) => {
+                        let (reply, result) = mpsc::channel();
+                        let ctx = service.hook_context(Duration::from_secs(HOOK_RELOAD_TIMEOUT_SEC));
+                        let exec_reload = service.config.exec_reload.clone();
+                        let logger = service.logger.clone();
+                        service.start_helper(HelperStep::Reload { reply }, move || {
+                            hooks::run_hooks("ExecReload", &exec_reload, &ctx, &logger).map_err(|e| HelperError {
+                                message: format!("ExecReload {}", e),
+                                exit_code: e.exit_code,
+                            })
+                        });
+                        result
+                    }
+                    None => {
+                        return Response::Error {
+                            message: format!("Service '{}' not found", name),
+                        }
+                    }
                 }
-                None => Response::Error {
-                    message: format!("Service '{}' not found", name),
+            };
+
+            // The main loop passes the result on once the commands have run
+            match result.recv() {
+                Ok(Ok(())) => Response::Success {
+                    message: format!("Service '{}' reloaded", name),
+                },
+                Ok(Err(message)) => Response::Error {
+                    message: format!("Failed to reload service '{}': {}", name, message),
+                },
+                Err(_) => Response::Error {
+                    message: format!("Reload of service '{}' was interrupted", name),
                 },
             }
         }
 
-        Request::ServiceRestart { name } => {
+        Request::ServiceResetFailed { name } => {
             let mut services = services.lock().unwrap();
-            match services.get_mut(&name) {
-                Some(service) => {
-                    if !service.enabled {
-                        Response::Error {
-                            message: format!("Service '{}' is disabled", name),
-                        }
-                    } else {
-                        if let Some(pid) = service.pid {
-                            let _ = kill(pid, Signal::SIGTERM);
-                            thread::sleep(Duration::from_millis(500));
+            match name {
+                Some(name) => match services.get_mut(&name) {
+                    Some(service) => {
+                        service.reset_failed();
+                        Response::Success {
+                            message: format!("Failed state of service '{}' reset", name),
                         }
-                        service.manual_stop = false;
-                        match launch_service(service) {
-                            Ok(_) => Response::Success {
-                                message: format!("Service '{}' restarted", name),
-                            },
-                            Err(e) => Response::Error {
-                                message: format!("Failed to restart service '{}': {}", name, e),
-                            },
+                    }
+                    None => Response::Error {
+                        message: format!("Service '{}' not found", name),
+                    },
+                },
+                None => {
+                    let mut count = 0;
+                    for service in services.values_mut() {
+                        if service.failed {
+                            count += 1;
                         }
+                        service.reset_failed();
+                    }
+                    Response::Success {
+                        message: format!("Failed state of {} service(s) reset", count),
                     }
                 }
-                None => Response::Error {
-                    message: format!("Service '{}' not found", name),
+            }
+        }
+
+        Request::ServiceLogsFollow { .. } => Response::Error {
+            message: "Following logs requires the framed control protocol".to_string(),
+        },
+
+        Request::Subscribe { filter, vsock: Some(endpoint), .. } => {
+            if let Err(message) = events::validate_filter(&filter) {
+                return Response::Error { message };
+            }
+            match events::send_to_vsock(filter, endpoint.cid, endpoint.port) {
+                Ok(()) => Response::Success {
+                    message: format!("Sending events to CID:{} PORT:{}", endpoint.cid, endpoint.port),
+                },
+                Err(e) => Response::Error {
+                    message: format!("{:#}", e),
                 },
             }
         }
 
+        Request::Subscribe { vsock: None, .. } => Response::Error {
+            message: "Subscribing to events requires the framed control protocol".to_string(),
+        },
+
         Request::ServiceEnable { name } => {
             match enable_service(config, &name) {
                 Ok(_) => {
@@ -1242,10 +3674,12 @@ fn handle_client_request(
 
         Request::ServiceDisable { name } => {
             let mut services = services.lock().unwrap();
+            if services.get(&name).is_some_and(|s| s.is_active()) {
+                stop_dependents(&mut services, &name, StopCause::Explicit);
+            }
             if let Some(service) = services.get_mut(&name) {
-                if let Some(pid) = service.pid {
-                    let _ = kill(pid, Signal::SIGTERM);
-                }
+                service.manual_stop = true;
+                let _ = service.begin_stop();
             }
             drop(services);
 
@@ -1274,6 +3708,33 @@ fn handle_client_request(
             }
         }
 
+        Request::ServiceLogsQuery { query } => {
+            let loggers = {
+                let services = services.lock().unwrap();
+                let mut names = query.services.clone();
+                if names.is_empty() {
+                    names = services.keys().cloned().collect();
+                    names.sort();
+                }
+                let mut loggers = Vec::new();
+                for name in &names {
+                    match services.get(name) {
+                        Some(service) => loggers.push(service.logger.clone()),
+                        None => {
+                            return Response::Error {
+                                message: format!("Service '{}' not found", name),
+                            }
+                        }
+                    }
+                }
+                loggers
+            };
+            match logquery::run(&query, &loggers) {
+                Ok(records) => Response::LogRecords { records },
+                Err(message) => Response::Error { message },
+            }
+        }
+
         Request::ServiceLogsClear { name } => {
             let services = services.lock().unwrap();
             match services.get(&name) {
@@ -1438,69 +3899,434 @@ fn handle_client_request(
                 );
             }
 
-            Response::Error {
-                message: format!("Process {} is not managed by init, cannot restart", pid),
+            Response::Error {
+                message: format!("Process {} is not managed by init, cannot restart", pid),
+            }
+        }
+
+        Request::ProcessKill { pid, signal } => {
+            let sig = match signal {
+                1 => Signal::SIGHUP,
+                2 => Signal::SIGINT,
+                9 => Signal::SIGKILL,
+                15 => Signal::SIGTERM,
+                _ => {
+                    return Response::Error {
+                        message: format!("Unsupported signal: {}", signal),
+                    };
+                }
+            };
+
+            match process::signal_process(pid, sig) {
+                Ok(_) => Response::Success {
+                    message: format!("Signal {} sent to process {}", signal, pid),
+                },
+                Err(e) => Response::Error {
+                    message: format!("Failed to send signal: {}", e),
+                },
+            }
+        }
+
+        // System management
+        Request::SystemStatus => {
+            let services = services.lock().unwrap();
+            Response::SystemStatus {
+                status: get_system_status(config, &services),
+            }
+        }
+
+        Request::SystemReload => {
+            Logger::info("Reload requested via control socket");
+            match reload_services(config, services) {
+                Ok(summary) => Response::Reloaded { summary },
+                Err(e) => Response::Error {
+                    message: format!("Failed to reload services: {}", e),
+                },
+            }
+        }
+
+        Request::SystemReboot => {
+            Logger::info("Reboot requested via control socket");
+            SIGTERM_RECEIVED.store(true, Ordering::Relaxed);
+            Response::Success {
+                message: "System reboot initiated".to_string(),
+            }
+        }
+
+        Request::SystemShutdown => {
+            Logger::info("Shutdown requested via control socket");
+            SIGTERM_RECEIVED.store(true, Ordering::Relaxed);
+            Response::Success {
+                message: "System shutdown initiated".to_string(),
+            }
+        }
+    }
+}
+
+/// Run due readiness and liveness probes for all running services.
+///
+/// Probes run without holding the service map lock; results are discarded if the
+/// service was restarted in the meantime.
+fn health_check_thread(services_map: ServiceMap) {
+    loop {
+        let mut due = Vec::new();
+        {
+            let mut services = services_map.lock().unwrap();
+            for (name, service) in services.iter_mut() {
+                let Some(pid) = service.pid else {
+                    continue;
+                };
+
+                if let Some(ref notify) = service.notify {
+                    let state = notify.state();
+
+                    if state.ready && service.health.record_notify_ready() == HealthTransition::BecameReady {
+                        let msg = format!(
+                            "Service {} is ready (READY=1{})",
+                            name,
+                            state.status.as_ref().map(|s| format!(", status: {}", s)).unwrap_or_default()
+                        );
+                        Logger::info(&msg);
+                        service.logger.log(msg);
+                        events::publish(
+                            Some(name),
+                            EventKind::HealthChanged { health: service.health.state, detail: "READY=1".to_string() },
+                        );
+                    }
+
+                    if service.config.watchdog_sec > 0
+                        && service.health.is_ready()
+                        && service.health.state != HealthState::Unhealthy
+                        && !state.stopping
+                    {
+                        let timeout = Duration::from_secs(service.config.watchdog_sec);
+                        let overdue = state
+                            .last_watchdog
+                            .map(|last| last.elapsed() > timeout)
+                            .unwrap_or_else(|| {
+                                service.last_restart.map(|s| s.elapsed() > timeout).unwrap_or(false)
+                            });
+
+                        if overdue || state.watchdog_triggered {
+                            notify.clear_watchdog_trigger();
+                            let reason = if state.watchdog_triggered {
+                                "requested watchdog action".to_string()
+                            } else {
+                                format!("watchdog timeout ({}s without WATCHDOG=1)", service.config.watchdog_sec)
+                            };
+                            let msg = format!("Service {} {}, aborting", name, reason);
+                            Logger::error(&msg);
+                            service.logger.log(msg);
+                            service.health.mark_unhealthy();
+                            // The exit counts as a failure even if the service handles SIGABRT
+                            service.failure = Some(reason);
+                            let _ = kill(pid, Signal::SIGABRT);
+                            continue;
+                        }
+                    }
+                }
+
+                if service.health.ready_timed_out(&service.config.health) {
+                    service.health.mark_unhealthy();
+                    let reason = format!("did not become ready within {}s", service.config.health.ready_timeout_sec);
+                    service.stop_for_failure(reason);
+                    continue;
+                }
+
+                if let Some(kind) = service.health.due(&service.config.health) {
+                    let probe = match kind {
+                        ProbeKind::Ready => service.config.health.ready_check.clone(),
+                        ProbeKind::Liveness => service.config.health.health_check.clone(),
+                    };
+                    if let Some(probe) = probe {
+                        let timeout = Duration::from_secs(
+                            service.config.health.health_check_timeout_sec.max(1),
+                        );
+                        service.health.probe_started();
+                        due.push((name.clone(), pid, kind, probe, timeout));
+                    }
+                }
+            }
+        }
+
+        // Each probe runs on its own thread, so a slow probe only delays its own service
+        for (name, pid, kind, probe, timeout) in due {
+            let services_map = Arc::clone(&services_map);
+            thread::spawn(move || {
+                let result = probe.run(timeout);
+                record_probe(&services_map, &name, pid, kind, result);
+            });
+        }
+
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// Apply the result of a probe of the process `pid` of service `name`.
+fn record_probe(services_map: &ServiceMap, name: &str, pid: Pid, kind: ProbeKind, result: ProbeResult) {
+    let mut services = services_map.lock().unwrap();
+    let Some(service) = services.get_mut(name) else {
+        return;
+    };
+    if service.pid != Some(pid) {
+        return;
+    }
+
+    let detail = result.message.clone();
+    let transition = service.health.record(kind, result, &service.config.health);
+    if transition != HealthTransition::None {
+        let health = service.health.state;
+        events::publish(Some(name), EventKind::HealthChanged { health, detail: detail.clone() });
+    }
+    match transition {
+        HealthTransition::BecameReady => {
+            let msg = format!("Service {} is ready ({})", name, detail);
+            Logger::info(&msg);
+            service.logger.log(msg);
+        }
+        HealthTransition::BecameUnhealthy => {
+            let reason = format!(
+                "is unhealthy after {} failed health checks ({})",
+                service.health.consecutive_failures, detail
+            );
+            service.stop_for_failure(reason);
+        }
+        HealthTransition::Recovered => {
+            let msg = format!("Service {} health check recovered ({})", name, detail);
+            Logger::info(&msg);
+            service.logger.log(msg);
+        }
+        HealthTransition::None => {}
+    }
+}
+
+/// Delete rotated logs past their service's age and size limits, then the
+/// oldest rotated logs of any service while the log directory is over
+/// `log_disk_budget`.
+fn log_retention_thread(services_map: ServiceMap, config: InitConfig) {
+    loop {
+        let loggers: Vec<ServiceLogger> = services_map
+            .lock()
+            .unwrap()
+            .values()
+            .map(|s| s.logger.clone())
+            .collect();
+        for logger in &loggers {
+            logger.enforce_retention();
+        }
+        if config.log_disk_budget > 0 {
+            for path in retention::enforce_budget(Path::new(&config.log_dir), config.log_disk_budget) {
+                Logger::info(&format!("Removed {} to stay within the log disk budget", path.display()));
             }
         }
+        thread::sleep(LOG_RETENTION_INTERVAL);
+    }
+}
 
-        Request::ProcessKill { pid, signal } => {
-            let sig = match signal {
-                1 => Signal::SIGHUP,
-                2 => Signal::SIGINT,
-                9 => Signal::SIGKILL,
-                15 => Signal::SIGTERM,
-                _ => {
-                    return Response::Error {
-                        message: format!("Unsupported signal: {}", signal),
-                    };
-                }
-            };
+/// Sample the metrics of every service into `METRICS` every `interval`.
+/// Processes are read with the services lock released.
+fn metrics_thread(services_map: ServiceMap, interval: Duration) {
+    let Some(history) = METRICS.get() else {
+        return;
+    };
+    loop {
+        let services: Vec<_> = services_map
+            .lock()
+            .unwrap()
+            .values()
+            .map(|s| {
+                let process = s.pid.map(|pid| (pid.as_raw(), s.cgroup.clone()));
+                (s.name.clone(), s.active_state(), s.restart_count, process)
+            })
+            .collect();
+
+        let timestamp = logger::now_millis();
+        let readings: Vec<_> = services
+            .into_iter()
+            .map(|(name, state, restarts, process)| {
+                let reading = process.map(|(pid, cgroup)| metrics::read(pid, cgroup.as_ref()));
+                (name, state, restarts, reading)
+            })
+            .collect();
 
-            match process::signal_process(pid, sig) {
-                Ok(_) => Response::Success {
-                    message: format!("Signal {} sent to process {}", signal, pid),
-                },
-                Err(e) => Response::Error {
-                    message: format!("Failed to send signal: {}", e),
-                },
+        {
+            let mut history = history.lock().unwrap();
+            for (name, state, restarts, reading) in &readings {
+                history.record(name, timestamp, *state, *restarts, *reading);
             }
+            history.retain(|name| readings.iter().any(|(n, ..)| n == name));
         }
+        thread::sleep(interval);
+    }
+}
 
-        // System management
-        Request::SystemStatus => {
-            let services = services.lock().unwrap();
-            Response::SystemStatus {
-                status: get_system_status(config, &services),
-            }
+/// Serve the latest metrics samples in the Prometheus text format over VSOCK.
+/// Peers are restricted by `control.vsock_allowed_cids`.
+fn prometheus_thread(config: InitConfig) {
+    let socket_fd = match socket(AddressFamily::Vsock, SockType::Stream, SockFlag::SOCK_CLOEXEC, None) {
+        Ok(fd) => fd,
+        Err(e) => {
+            Logger::error(&format!("Failed to create metrics VSOCK socket: {}", e));
+            return;
         }
+    };
+    let listen_addr = VsockAddr::new(config.metrics.prometheus_cid, config.metrics.prometheus_port);
+    if let Err(e) = bind(socket_fd, &listen_addr).and_then(|_| listen(socket_fd, 5)) {
+        Logger::error(&format!("Failed to listen on metrics VSOCK socket: {}", e));
+        let _ = close(socket_fd);
+        return;
+    }
+    Logger::info(&format!(
+        "Prometheus metrics listening on CID:{} PORT:{}",
+        config.metrics.prometheus_cid, config.metrics.prometheus_port
+    ));
 
-        Request::SystemReload => {
-            Logger::info("Reload requested via control socket");
-            SIGHUP_RECEIVED.store(true, Ordering::Relaxed);
-            Response::Success {
-                message: "System reload initiated".to_string(),
+    let allowed = config.control.vsock_allowed_cids.clone();
+    let mut backoff = Duration::ZERO;
+    loop {
+        let client_fd = match accept4(socket_fd, SockFlag::SOCK_CLOEXEC) {
+            Ok(fd) => {
+                backoff = Duration::ZERO;
+                fd
+            }
+            Err(e) => {
+                // Errors such as EMFILE persist; do not spin on them
+                backoff = (backoff * 2).clamp(Duration::from_millis(100), MAX_ACCEPT_BACKOFF);
+                Logger::warn(&format!("Failed to accept metrics connection: {}", e));
+                thread::sleep(backoff);
+                continue;
             }
+        };
+        let permitted = getpeername::<VsockAddr>(client_fd)
+            .map(|addr| allowed.is_empty() || allowed.contains(&addr.cid()))
+            .unwrap_or(false);
+        if !permitted {
+            let _ = close(client_fd);
+            continue;
         }
-
-        Request::SystemReboot => {
-            Logger::info("Reboot requested via control socket");
-            SIGTERM_RECEIVED.store(true, Ordering::Relaxed);
-            Response::Success {
-                message: "System reboot initiated".to_string(),
+        if METRICS_CONNECTIONS.fetch_add(1, Ordering::SeqCst) >= MAX_METRICS_CONNECTIONS {
+            METRICS_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+            Logger::warn(&format!(
+                "Refusing metrics connection: {} scrapes in progress",
+                MAX_METRICS_CONNECTIONS
+            ));
+            let _ = close(client_fd);
+            continue;
+        }
+        // A scraper that stalls must not hold its thread indefinitely
+        set_receive_timeout(client_fd, Some(METRICS_TIMEOUT_SEC));
+        set_send_timeout(client_fd, METRICS_TIMEOUT_SEC);
+        thread::spawn(move || {
+            let body = || {
+                let snapshot = METRICS.get().map(|h| h.lock().unwrap().snapshot(None, 1)).unwrap_or_default();
+                metrics::prometheus_text(&snapshot)
+            };
+            if let Err(e) = metrics::serve_http(&mut FdStream(client_fd), body) {
+                Logger::debug(&format!("Metrics request failed: {}", e));
             }
+            let _ = close(client_fd);
+            METRICS_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Start socket-activated services when a connection arrives on one of their
+/// listeners. Listeners of running services are left to the service.
+fn socket_activation_thread(services_map: ServiceMap) {
+    loop {
+        let (fds, owners): (Vec<RawFd>, Vec<String>) = {
+            let services = services_map.lock().unwrap();
+            services
+                .iter()
+                .filter(|(_, s)| s.enabled && s.wanted && !s.is_active() && !s.start_limit_hit)
+                .flat_map(|(name, s)| {
+                    s.sockets
+                        .iter()
+                        .flat_map(|u| u.listen_fds())
+                        .map(move |(fd, _)| (fd, name.clone()))
+                })
+                .unzip()
+        };
+
+        if fds.is_empty() {
+            thread::sleep(Duration::from_secs(1));
+            continue;
         }
 
-        Request::SystemShutdown => {
-            Logger::info("Shutdown requested via control socket");
-            SIGTERM_RECEIVED.store(true, Ordering::Relaxed);
-            Response::Success {
-                message: "System shutdown initiated".to_string(),
+        let mut ready: Vec<String> = activation::wait_for_connection(&fds, Duration::from_secs(1))
+            .into_iter()
+            .map(|i| owners[i].clone())
+            .collect();
+        ready.dedup();
+
+        for name in ready {
+            let mut services = services_map.lock().unwrap();
+            let Some(service) = services.get_mut(&name) else {
+                continue;
+            };
+            if !service.enabled || !service.wanted || service.is_active() || service.start_limit_hit {
+                continue;
+            }
+            if service.restarts.limit_hit(&service.config.start_limit) {
+                service.failed = true;
+                service.start_limit_hit = true;
+                let msg = format!("Service {} activated too often, not starting", name);
+                Logger::error(&msg);
+                service.logger.log(msg);
+                events::publish(Some(&name), EventKind::Failed { reason: "start limit hit".to_string() });
+                continue;
             }
+
+            Logger::info(&format!("Activating service {} on incoming connection", name));
+            service.manual_stop = false;
+            if let Err(e) = launch_service(service) {
+                Logger::error(&format!("Failed to activate service {}: {}", name, e));
+            }
+        }
+    }
+}
+
+/// Set how long reads on a control connection may block; None blocks indefinitely.
+fn set_receive_timeout(fd: RawFd, secs: Option<i64>) {
+    let timeout = TimeVal::new(secs.unwrap_or(0), 0);
+    if let Err(e) = setsockopt(fd, sockopt::ReceiveTimeout, &timeout) {
+        Logger::warn(&format!("Failed to set control connection timeout: {}", e));
+    }
+}
+
+/// Set how long writes on a control connection may block.
+fn set_send_timeout(fd: RawFd, secs: i64) {
+    if let Err(e) = setsockopt(fd, sockopt::SendTimeout, &TimeVal::new(secs, 0)) {
+        Logger::warn(&format!("Failed to set control connection timeout: {}", e));
+    }
+}
+
+fn handle_connection(fd: RawFd, peer: Peer, services: &ServiceMap, config: &InitConfig, streamers: &StreamerMap) {
+    // Unauthenticated peers must not hold the connection open indefinitely
+    set_receive_timeout(fd, Some(HANDSHAKE_TIMEOUT_SEC));
+
+    // Legacy clients send a bare JSON request; framed clients start with a length
+    let mut first = [0u8; 1];
+    match recv(fd, &mut first, MsgFlags::MSG_PEEK) {
+        Ok(1) if first[0] == b'{' || first[0] == b'"' => {
+            handle_legacy_connection(fd, peer, services, config, streamers)
         }
+        Ok(1) => handle_framed_connection(fd, peer, services, config, streamers),
+        Ok(_) => Logger::debug("Empty request received"),
+        Err(e) => Logger::warn(&format!("Failed to receive data: {}", e)),
     }
+
+    let _ = close(fd);
 }
 
-fn handle_connection(fd: RawFd, services: &ServiceMap, config: &InitConfig, streamers: &StreamerMap) {
+/// Serve a protocol version 1 client: one unframed JSON request and response.
+fn handle_legacy_connection(
+    fd: RawFd,
+    peer: Peer,
+    services: &ServiceMap,
+    config: &InitConfig,
+    streamers: &StreamerMap,
+) {
     let mut buffer = vec![0u8; 8192];
 
     match recv(fd, &mut buffer, MsgFlags::empty()) {
@@ -1508,12 +4334,28 @@ fn handle_connection(fd: RawFd, services: &ServiceMap, config: &InitConfig, stre
             buffer.truncate(n);
             match serde_json::from_slice::<Request>(&buffer) {
                 Ok(request) => {
-                    let response = handle_client_request(request, services, config, streamers);
+                    let response = match auth::admit(peer, &config.control) {
+                        Admission::Granted(role) => {
+                            authorized_request(request, role, peer, services, config, streamers)
+                        }
+                        Admission::Challenge => {
+                            Logger::warn(&format!("Denied unauthenticated legacy request from {}", peer));
+                            Response::PermissionDenied {
+                                message: format!(
+                                    "authentication required; use an initctl speaking control protocol version {}",
+                                    PROTOCOL_VERSION
+                                ),
+                            }
+                        }
+                        Admission::Denied(message) => {
+                            Logger::warn(&format!("Denied connection from {}: {}", peer, message));
+                            Response::PermissionDenied { message }
+                        }
+                    };
                     let response_data = match serde_json::to_vec(&response) {
                         Ok(data) => data,
                         Err(e) => {
                             Logger::error(&format!("Failed to serialize response: {}", e));
-                            let _ = close(fd);
                             return;
                         }
                     };
@@ -1538,8 +4380,288 @@ fn handle_connection(fd: RawFd, services: &ServiceMap, config: &InitConfig, stre
             Logger::warn(&format!("Failed to receive data: {}", e));
         }
     }
+}
 
-    let _ = close(fd);
+/// Serve a framed connection: version handshake, then any number of requests.
+fn handle_framed_connection(
+    fd: RawFd,
+    peer: Peer,
+    services: &ServiceMap,
+    config: &InitConfig,
+    streamers: &StreamerMap,
+) {
+    let mut stream = FdStream(fd);
+
+    let hello: Hello = match read_frame_limited(&mut stream, HANDSHAKE_FRAME_SIZE) {
+        Ok(hello) => hello,
+        Err(FrameError::Closed) => return,
+        Err(e) => {
+            Logger::warn(&format!("Invalid control handshake: {}", e));
+            return;
+        }
+    };
+
+    if hello.version < MIN_PROTOCOL_VERSION || hello.min_version > PROTOCOL_VERSION {
+        let reply = HelloReply::Rejected {
+            message: format!(
+                "{} speaks protocol versions {}-{}, init supports {}-{}",
+                hello.agent, hello.min_version, hello.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+        };
+        let _ = write_frame(&mut stream, &reply);
+        return;
+    }
+
+    let version = hello.version.min(PROTOCOL_VERSION);
+    let role = match authenticate(&mut stream, peer, &hello, version, config) {
+        Ok(role) => role,
+        Err(message) => {
+            Logger::warn(&format!("Denied connection from {}: {}", peer, message));
+            let reply = HelloReply::Rejected {
+                message,
+                min_version: MIN_PROTOCOL_VERSION,
+                max_version: PROTOCOL_VERSION,
+            };
+            let _ = write_frame(&mut stream, &reply);
+            return;
+        }
+    };
+
+    let reply = HelloReply::Accepted {
+        version,
+        agent: format!("init {}", env!("CARGO_PKG_VERSION")),
+        role,
+    };
+    if write_frame(&mut stream, &reply).is_err() {
+        return;
+    }
+    // Authenticated clients may stay idle between requests
+    set_receive_timeout(fd, None);
+
+    loop {
+        let frame: RequestFrame = match read_frame(&mut stream) {
+            Ok(frame) => frame,
+            Err(FrameError::Closed) => return,
+            Err(FrameError::Json(e)) => {
+                // The frame was consumed, so the connection is still usable
+                let response = ResponseFrame {
+                    id: 0,
+                    response: Response::Error {
+                        message: format!("Invalid request: {}", e),
+                    },
+                    done: true,
+                };
+                if write_frame(&mut stream, &response).is_err() {
+                    return;
+                }
+                continue;
+            }
+            Err(e) => {
+                Logger::warn(&format!("Control connection error: {}", e));
+                return;
+            }
+        };
+
+        if frame.request.is_streaming() && auth::permits(role, &frame.request) {
+            // A stream occupies the connection until the client disconnects
+            match frame.request {
+                Request::ServiceLogsFollow { name, lines } => {
+                    follow_service_logs(&mut stream, frame.id, services, &name, lines)
+                }
+                Request::Subscribe { filter, history_only, .. } => {
+                    stream_events(&mut stream, frame.id, filter, history_only)
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        let response = ResponseFrame {
+            id: frame.id,
+            response: authorized_request(frame.request, role, peer, services, config, streamers),
+            done: true,
+        };
+        if let Err(e) = write_frame(&mut stream, &response) {
+            Logger::warn(&format!("Failed to send response: {}", e));
+            return;
+        }
+    }
+}
+
+/// Apply the connection policy for `peer`, running the pre-shared key
+/// challenge if required. Returns the connection's role.
+fn authenticate(
+    stream: &mut FdStream,
+    peer: Peer,
+    hello: &Hello,
+    version: u32,
+    config: &InitConfig,
+) -> Result<Role, String> {
+    match auth::admit(peer, &config.control) {
+        Admission::Granted(role) => Ok(role),
+        Admission::Denied(message) => Err(message),
+        Admission::Challenge => {
+            if version < 3 {
+                return Err("authentication requires control protocol version 3".to_string());
+            }
+            let key_name = hello
+                .key
+                .clone()
+                .ok_or_else(|| "authentication required: no key name given".to_string())?;
+
+            let nonce = auth::new_nonce().map_err(|e| format!("failed to create challenge: {}", e))?;
+            write_frame(stream, &HelloReply::Challenge { nonce: nonce.clone() })
+                .map_err(|e| format!("failed to send challenge: {}", e))?;
+            let response: AuthResponse = read_frame_limited(stream, HANDSHAKE_FRAME_SIZE)
+                .map_err(|e| format!("invalid challenge response: {}", e))?;
+
+            let role = auth::verify(&config.control, &key_name, &nonce, &response.mac)
+                .ok_or_else(|| format!("authentication failed for key '{}'", key_name))?;
+            Logger::info(&format!("{} authenticated with key '{}' as {}", peer, key_name, role.as_str()));
+            Ok(role)
+        }
+    }
+}
+
+/// Handle a request if `role` permits it.
+fn authorized_request(
+    request: Request,
+    role: Role,
+    peer: Peer,
+    services: &ServiceMap,
+    config: &InitConfig,
+    streamers: &StreamerMap,
+) -> Response {
+    if auth::permits(role, &request) {
+        return handle_client_request(request, services, config, streamers);
+    }
+
+    let name = auth::request_name(&request);
+    Logger::warn(&format!("Denied {} from {} ({} role)", name, peer, role.as_str()));
+    Response::PermissionDenied {
+        message: format!("{} is not permitted for the {} role", name, role.as_str()),
+    }
+}
+
+/// Whether the peer of a connected socket has closed its end.
+fn peer_closed(fd: RawFd) -> bool {
+    let mut probe = [0u8; 1];
+    matches!(
+        recv(fd, &mut probe, MsgFlags::MSG_PEEK | MsgFlags::MSG_DONTWAIT),
+        Ok(0) | Err(Errno::ECONNRESET) | Err(Errno::EPIPE)
+    )
+}
+
+/// Stream the tail of a service log, then new lines, until the client disconnects.
+fn follow_service_logs(stream: &mut FdStream, id: u64, services: &ServiceMap, name: &str, lines: usize) {
+    // A client that stops reading is disconnected rather than holding this thread
+    set_send_timeout(stream.0, LOG_FOLLOW_WRITE_TIMEOUT_SEC);
+    let (subscriber, receiver) = ChannelLogSubscriber::new(LOG_FOLLOW_QUEUE_SIZE);
+    let backlog = {
+        let services = services.lock().unwrap();
+        match services.get(name) {
+            Some(service) => {
+                service.logger.subscribe(subscriber.clone());
+                service.logger.get_logs(lines)
+            }
+            None => {
+                let response = ResponseFrame {
+                    id,
+                    response: Response::Error {
+                        message: format!("Service '{}' not found", name),
+                    },
+                    done: true,
+                };
+                let _ = write_frame(stream, &response);
+                return;
+            }
+        }
+    };
+
+    let send_line = |stream: &mut FdStream, line: String| {
+        let response = ResponseFrame {
+            id,
+            response: Response::LogLine {
+                service: name.to_string(),
+                line,
+            },
+            done: false,
+        };
+        write_frame(stream, &response).is_ok()
+    };
+
+    let mut connected = backlog.into_iter().all(|line| send_line(stream, line));
+    while connected {
+        match receiver.recv_timeout(Duration::from_secs(1)) {
+            Ok(line) => connected = send_line(stream, line),
+            Err(mpsc::RecvTimeoutError::Timeout) => connected = !peer_closed(stream.0),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                let reason = if subscriber.overflowed() {
+                    format!("Fell more than {} lines behind the log of service '{}'", LOG_FOLLOW_QUEUE_SIZE, name)
+                } else {
+                    format!("Log of service '{}' closed", name)
+                };
+                let response = ResponseFrame {
+                    id,
+                    response: Response::StreamEnd { reason },
+                    done: true,
+                };
+                let _ = write_frame(stream, &response);
+                connected = false;
+            }
+        }
+    }
+
+    subscriber.close();
+}
+
+/// Stream events matching `filter` until the client disconnects, or with
+/// `history_only` just the retained ones.
+fn stream_events(stream: &mut FdStream, id: u64, filter: EventFilter, history_only: bool) {
+    let end = |stream: &mut FdStream, response: Response| {
+        let _ = write_frame(stream, &ResponseFrame { id, response, done: true });
+    };
+    if let Err(message) = events::validate_filter(&filter) {
+        return end(stream, Response::Error { message });
+    }
+    let send_event = |stream: &mut FdStream, event: Event| {
+        let response = ResponseFrame {
+            id,
+            response: Response::Event { event },
+            done: false,
+        };
+        write_frame(stream, &response).is_ok()
+    };
+
+    if history_only {
+        if events::history(&filter).into_iter().all(|event| send_event(stream, event)) {
+            end(stream, Response::StreamEnd { reason: String::new() });
+        }
+        return;
+    }
+
+    let (backlog, receiver) = events::subscribe(filter);
+    let mut last_seq = 0;
+    let mut connected = backlog.into_iter().all(|event| {
+        last_seq = event.seq;
+        send_event(stream, event)
+    });
+    while connected {
+        match receiver.recv_timeout(Duration::from_secs(1)) {
+            Ok(event) => {
+                last_seq = event.seq;
+                connected = send_event(stream, event);
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => connected = !peer_closed(stream.0),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                let reason = format!("Subscriber fell behind after event {}; resubscribe with --after {}", last_seq, last_seq);
+                end(stream, Response::StreamEnd { reason });
+                connected = false;
+            }
+        }
+    }
 }
 
 fn unix_socket_thread(services: ServiceMap, config: InitConfig, streamers: StreamerMap) {
@@ -1586,7 +4708,7 @@ fn unix_socket_thread(services: ServiceMap, config: InitConfig, streamers: Strea
                 let config = config.clone();
                 let streamers = streamers.clone();
                 thread::spawn(move || {
-                    handle_connection(client_fd, &services, &config, &streamers);
+                    handle_connection(client_fd, Peer::Unix, &services, &config, &streamers);
                 });
             }
             Err(e) => {
@@ -1630,11 +4752,29 @@ fn vsock_socket_thread(services: ServiceMap, config: InitConfig, streamers: Stre
     loop {
         match accept(socket_fd) {
             Ok(client_fd) => {
+                let peer = match getpeername::<VsockAddr>(client_fd) {
+                    Ok(addr) => Peer::Vsock { cid: addr.cid() },
+                    Err(e) => {
+                        Logger::warn(&format!("Failed to get VSOCK peer address: {}", e));
+                        let _ = close(client_fd);
+                        continue;
+                    }
+                };
+                if VSOCK_CONNECTIONS.fetch_add(1, Ordering::SeqCst) >= MAX_VSOCK_CONNECTIONS {
+                    VSOCK_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+                    Logger::warn(&format!(
+                        "Refusing connection from {}: {} VSOCK connections open",
+                        peer, MAX_VSOCK_CONNECTIONS
+                    ));
+                    let _ = close(client_fd);
+                    continue;
+                }
                 let services = services.clone();
                 let config = config.clone();
                 let streamers = streamers.clone();
                 thread::spawn(move || {
-                    handle_connection(client_fd, &services, &config, &streamers);
+                    handle_connection(client_fd, peer, &services, &config, &streamers);
+                    VSOCK_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
                 });
             }
             Err(e) => {
@@ -1690,9 +4830,30 @@ fn main() {
     if let Err(e) = fs::create_dir_all(&config.log_dir) {
         Logger::warn(&format!("Failed to create log directory after pivot root: {}", e));
     }
+    forwarder::start(&config.log_forward);
+
+    // Before the first start, so boot-time services are measured too
+    if config.measurement.enabled {
+        let _ = MEASUREMENT.set(config.measurement.algorithm);
+    }
 
     let services_map: ServiceMap = match load_services(&config) {
-        Ok(s) => Arc::new(Mutex::new(s)),
+        Ok((mut s, targets)) => {
+            let current = config.default_target.as_deref().map(target::unit_name).filter(|name| {
+                let found = targets.contains_key(name);
+                if !found {
+                    Logger::error(&format!("Default target {} not found, starting all enabled services", name));
+                }
+                found
+            });
+            if let Some(ref name) = current {
+                Logger::info(&format!("Booting into {}", name));
+            }
+            apply_target(&mut s, &targets, current.as_deref());
+            *TARGETS.lock().unwrap() = TargetState { units: targets, current };
+            open_sockets(&mut s);
+            Arc::new(Mutex::new(s))
+        }
         Err(e) => {
             Logger::error(&format!("Failed to load services: {}", e));
             Arc::new(Mutex::new(HashMap::new()))
@@ -1702,18 +4863,32 @@ fn main() {
     // Initialize streamer map for log streaming
     let streamers: StreamerMap = Arc::new(Mutex::new(HashMap::new()));
 
+    {
+        let services_for_health = services_map.clone();
+        thread::spawn(move || {
+            health_check_thread(services_for_health);
+        });
+    }
+
     {
         let services = services_map.lock().unwrap();
         if services.is_empty() {
             Logger::warn("No services found, init will just reap children");
         } else {
-            let startup_order = compute_startup_order(&services);
+            let mut startup_order = compute_startup_order(&services);
+            startup_order.retain(|name| services[name].wanted);
             drop(services);
 
             for service_name in startup_order {
+                if !wait_for_dependencies_ready(&services_map, &service_name) {
+                    continue;
+                }
+
                 let mut services = services_map.lock().unwrap();
                 if let Some(service) = services.get_mut(&service_name) {
-                    if service.enabled {
+                    if service.enabled && service.starts_on_demand() {
+                        Logger::info(&format!("Service {} is started by its socket or timer units", service_name));
+                    } else if service.enabled {
                         if let Err(e) = launch_service(service) {
                             Logger::error(&format!("Failed to launch service {}: {}", service_name, e));
                         }
@@ -1725,6 +4900,36 @@ fn main() {
         }
     }
 
+    {
+        let services_for_activation = services_map.clone();
+        thread::spawn(move || {
+            socket_activation_thread(services_for_activation);
+        });
+    }
+
+    if config.metrics.enabled {
+        let _ = METRICS.set(Mutex::new(MetricsHistory::new(config.metrics.history)));
+        let services_for_metrics = services_map.clone();
+        let interval = Duration::from_secs(config.metrics.interval_sec.max(1));
+        thread::spawn(move || {
+            metrics_thread(services_for_metrics, interval);
+        });
+        if config.metrics.prometheus_enabled {
+            let config_for_prometheus = config.clone();
+            thread::spawn(move || {
+                prometheus_thread(config_for_prometheus);
+            });
+        }
+    }
+
+    {
+        let services_for_retention = services_map.clone();
+        let config_for_retention = config.clone();
+        thread::spawn(move || {
+            log_retention_thread(services_for_retention, config_for_retention);
+        });
+    }
+
     if config.control.unix_socket_enabled {
         let services_for_unix = services_map.clone();
         let config_for_unix = config.clone();
@@ -1735,6 +4940,9 @@ fn main() {
     }
 
     if config.control.vsock_enabled {
+        if config.control.vsock_keys.is_empty() && config.control.vsock_default_role == Role::Admin {
+            Logger::warn("VSOCK control socket has no vsock_keys configured; any allowed peer gets admin access");
+        }
         let services_for_vsock = services_map.clone();
         let config_for_vsock = config.clone();
         let streamers_for_vsock = streamers.clone();
@@ -1764,37 +4972,13 @@ fn main() {
 
         if SIGHUP_RECEIVED.swap(false, Ordering::Relaxed) {
             Logger::info("Reload signal received");
-            match reload_services(&config) {
-                Ok(new_services) => {
-                    let mut services = services_map.lock().unwrap();
-
-                    for (name, service) in services.iter_mut() {
-                        if !new_services.contains_key(name) || !new_services[name].enabled {
-                            if let Some(pid) = service.pid {
-                                Logger::info(&format!("Stopping removed/disabled service: {}", name));
-                                let _ = kill(pid, Signal::SIGTERM);
-                            }
-                        }
-                    }
-
-                    *services = new_services;
-                    Logger::info("Services reloaded successfully");
-
-                    let startup_order = compute_startup_order(&services);
-                    for service_name in startup_order {
-                        if let Some(service) = services.get_mut(&service_name) {
-                            if service.enabled && !service.is_active() {
-                                if let Err(e) = launch_service(service) {
-                                    Logger::error(&format!("Failed to start service {}: {}", service_name, e));
-                                }
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
+            let config = config.clone();
+            let services_map = Arc::clone(&services_map);
+            thread::spawn(move || {
+                if let Err(e) = reload_services(&config, &services_map) {
                     Logger::error(&format!("Failed to reload services: {}", e));
                 }
-            }
+            });
         }
 
         if SIGCHLD_RECEIVED.swap(false, Ordering::Relaxed) {
@@ -1804,8 +4988,15 @@ fn main() {
 
         {
             let mut services = services_map.lock().unwrap();
+            for service in services.values_mut() {
+                service.enforce_start_deadline();
+                service.enforce_stop_deadline();
+            }
+            finish_helpers(&mut services);
             restart_services(&mut services);
+            run_timers(&mut services);
         }
+        cgroup::remove_retired();
 
         // Periodically cleanup inactive streamers
         {
@@ -1822,6 +5013,7 @@ fn main() {
         thread::sleep(Duration::from_millis(100));
     }
 
+    forwarder::flush(Duration::from_secs(2));
     Logger::info("Init system shutting down");
 
     unsafe {
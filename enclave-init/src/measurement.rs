@@ -0,0 +1,431 @@
+//! Pinned executable hashes and the measurement log.
+//!
+//! A service with `ExecHash = "sha256:<hex>"` (or `sha3-512:<hex>`) only
+//! starts if its executable has that hash. The executable is resolved in
+//! init like `execvp` would, through the service's `PATH`, opened and hashed
+//! from the open file, and the child executes that same file with `fexecve`,
+//! so it cannot be swapped between the check and the exec. The executable is
+//! measured right before the fork, after `ExecStartPre`, and the start is
+//! refused if the file changed (its change time moved) after it was hashed.
+//! Files listed in `ExecHashFiles` are checked as well.
+//!
+//! With `measurement.enabled`, the executable of every service is hashed
+//! this way. Each distinct (service, path, hash) is recorded in the
+//! measurement log, served by `MeasurementLog` requests.
+//!
+//! Hashes are cached by file identity (device, inode, size, modification and
+//! change time), which any write to the file changes. Init hashes uncached
+//! files with `hash_ahead` outside the services lock, so that `measure`
+//! during the start only needs the cache.
+
+use crate::config::HashAlgorithm;
+use crate::protocol::MeasurementEntry;
+use anyhow::{bail, Context, Result};
+use nix::fcntl::{fcntl, FcntlArg};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sha3::Sha3_512;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+
+static LOG: Mutex<Vec<MeasurementEntry>> = Mutex::new(Vec::new());
+
+static HASHES: LazyLock<Mutex<HashMap<FileKey, String>>> = LazyLock::new(Default::default);
+
+/// Identity of one version of a file, for the hash cache.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct FileKey {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+    algorithm: HashAlgorithm,
+}
+
+impl FileKey {
+    fn of(file: &File, algorithm: HashAlgorithm) -> io::Result<Self> {
+        let m = file.metadata()?;
+        Ok(Self {
+            dev: m.dev(),
+            ino: m.ino(),
+            size: m.size(),
+            mtime: (m.mtime(), m.mtime_nsec()),
+            ctime: (m.ctime(), m.ctime_nsec()),
+            algorithm,
+        })
+    }
+}
+
+/// Pinned hashes in a service file.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct ExecHashConfig {
+    /// Hash of the executable of ExecStart
+    #[serde(default)]
+    pub exec_hash: Option<String>,
+    /// Hashes of further files, by absolute path
+    #[serde(default)]
+    pub exec_hash_files: BTreeMap<String, String>,
+}
+
+impl ExecHashConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(hash) = &self.exec_hash {
+            parse_hash(hash).map_err(|e| format!("ExecHash: {}", e))?;
+        }
+        for (path, hash) in &self.exec_hash_files {
+            if !Path::new(path).is_absolute() {
+                return Err(format!("ExecHashFiles: '{}' is not an absolute path", path));
+            }
+            parse_hash(hash).map_err(|e| format!("ExecHashFiles {}: {}", path, e))?;
+        }
+        Ok(())
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.exec_hash.is_some() || !self.exec_hash_files.is_empty()
+    }
+}
+
+/// Split `sha256:<hex>` into its algorithm and lowercase hex digest.
+fn parse_hash(hash: &str) -> Result<(HashAlgorithm, String), String> {
+    let (name, hex) = hash
+        .split_once(':')
+        .ok_or_else(|| format!("'{}' is not <algorithm>:<hex>", hash))?;
+    let (algorithm, len) = match name {
+        "sha256" => (HashAlgorithm::Sha256, 64),
+        "sha3-512" => (HashAlgorithm::Sha3_512, 128),
+        _ => return Err(format!("unknown algorithm '{}', expected sha256 or sha3-512", name)),
+    };
+    if hex.len() != len || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("{} digest must be {} hex digits", name, len));
+    }
+    Ok((algorithm, hex.to_ascii_lowercase()))
+}
+
+fn algorithm_name(algorithm: HashAlgorithm) -> &'static str {
+    match algorithm {
+        HashAlgorithm::Sha256 => "sha256",
+        HashAlgorithm::Sha3_512 => "sha3-512",
+    }
+}
+
+/// Hash everything `reader` yields, as `<algorithm>:<hex>`.
+pub fn hash_reader(algorithm: HashAlgorithm, mut reader: impl Read) -> io::Result<String> {
+    fn digest<D: Digest>(mut hasher: D, reader: &mut impl Read) -> io::Result<Vec<u8>> {
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            match reader.read(&mut buf)? {
+                0 => return Ok(hasher.finalize().to_vec()),
+                n => hasher.update(&buf[..n]),
+            }
+        }
+    }
+    let bytes = match algorithm {
+        HashAlgorithm::Sha256 => digest(Sha256::new(), &mut reader)?,
+        HashAlgorithm::Sha3_512 => digest(Sha3_512::new(), &mut reader)?,
+    };
+    let mut hex = format!("{}:", algorithm_name(algorithm));
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    Ok(hex)
+}
+
+/// Find `program` the way `execvp` does: as a path if it contains a slash,
+/// relative to `working_directory`, otherwise in the directories of `path_env`.
+pub fn resolve_executable(program: &str, path_env: &str, working_directory: Option<&str>) -> Option<PathBuf> {
+    if program.contains('/') {
+        let path = match working_directory {
+            Some(dir) if !program.starts_with('/') => Path::new(dir).join(program),
+            _ => PathBuf::from(program),
+        };
+        return Some(path);
+    }
+    path_env
+        .split(':')
+        .map(|dir| Path::new(if dir.is_empty() { "." } else { dir }).join(program))
+        .find(|candidate| {
+            candidate
+                .metadata()
+                .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        })
+}
+
+/// An opened and hashed executable, to be run with `exec_in_child`.
+pub struct MeasuredExecutable {
+    file: File,
+    pub path: PathBuf,
+    /// `<algorithm>:<hex>`
+    pub hash: String,
+    pub pinned: bool,
+    /// Starts with `#!`; the interpreter opens it by its `/dev/fd` path
+    script: bool,
+    /// Identity of the file when it was hashed
+    key: FileKey,
+}
+
+/// A file checked for a start: the executable first, then `ExecHashFiles`.
+struct Target {
+    path: PathBuf,
+    algorithm: HashAlgorithm,
+    /// Pinned `<algorithm>:<hex>`
+    expected: Option<String>,
+}
+
+fn targets(path: &Path, pins: &ExecHashConfig, algorithm: HashAlgorithm) -> Result<Vec<Target>> {
+    let pinned = |hash: &str| {
+        parse_hash(hash)
+            .map(|(algorithm, hex)| (algorithm, format!("{}:{}", algorithm_name(algorithm), hex)))
+            .map_err(anyhow::Error::msg)
+    };
+    let exec = match &pins.exec_hash {
+        Some(hash) => {
+            let (algorithm, expected) = pinned(hash)?;
+            Target { path: path.to_path_buf(), algorithm, expected: Some(expected) }
+        }
+        None => Target { path: path.to_path_buf(), algorithm, expected: None },
+    };
+    let mut targets = vec![exec];
+    for (extra, hash) in &pins.exec_hash_files {
+        let (algorithm, expected) = pinned(hash)?;
+        targets.push(Target { path: PathBuf::from(extra), algorithm, expected: Some(expected) });
+    }
+    Ok(targets)
+}
+
+fn open(path: &Path) -> Result<File> {
+    let file = File::options()
+        .read(true)
+        .custom_flags(libc::O_CLOEXEC)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    if !file.metadata()?.is_file() {
+        bail!("{} is not a regular file", path.display());
+    }
+    Ok(file)
+}
+
+/// Hash of an open file, from the cache or computed and cached, and the
+/// identity of the file before it was read.
+fn hash_file(file: &File, path: &Path, algorithm: HashAlgorithm) -> Result<(String, FileKey)> {
+    let key = FileKey::of(file, algorithm)?;
+    if let Some(hash) = HASHES.lock().unwrap().get(&key) {
+        return Ok((hash.clone(), key));
+    }
+    let mut reader = file;
+    io::Seek::rewind(&mut reader)?;
+    let hash = hash_reader(algorithm, reader).with_context(|| format!("Failed to hash {}", path.display()))?;
+    // A file written to while it was hashed is hashed again next time
+    if FileKey::of(file, algorithm)? == key {
+        HASHES.lock().unwrap().insert(key.clone(), hash.clone());
+    }
+    Ok((hash, key))
+}
+
+/// Whether the executable and `ExecHashFiles` of a start are all hashed
+/// already, so that `measure` does not read them.
+pub fn is_hashed(path: &Path, pins: &ExecHashConfig, algorithm: HashAlgorithm) -> bool {
+    let Ok(targets) = targets(path, pins, algorithm) else {
+        return false;
+    };
+    let hashes = HASHES.lock().unwrap();
+    targets.iter().all(|t| {
+        File::open(&t.path)
+            .and_then(|f| FileKey::of(&f, t.algorithm))
+            .is_ok_and(|key| hashes.contains_key(&key))
+    })
+}
+
+/// Hash the executable and `ExecHashFiles` of a start into the cache. This
+/// reads the whole files, so it runs without holding the services lock.
+pub fn hash_ahead(path: &Path, pins: &ExecHashConfig, algorithm: HashAlgorithm) -> Result<()> {
+    for target in targets(path, pins, algorithm)? {
+        hash_file(&open(&target.path)?, &target.path, target.algorithm)?;
+    }
+    Ok(())
+}
+
+/// Open and hash `path` and the files of `pins`, using cached hashes where
+/// the files are unchanged. Fails if a pinned hash does not match. Without
+/// `ExecHash` the executable is hashed with `algorithm`.
+pub fn measure(path: &Path, pins: &ExecHashConfig, algorithm: HashAlgorithm) -> Result<MeasuredExecutable> {
+    let mut exec = None;
+    for target in targets(path, pins, algorithm)? {
+        let file = open(&target.path)?;
+        let (hash, key) = hash_file(&file, &target.path, target.algorithm)?;
+        if let Some(expected) = &target.expected {
+            check(&target.path, &hash, expected)?;
+        }
+        exec.get_or_insert((file, hash, key));
+    }
+    let (file, hash, key) = exec.expect("the executable is always a target");
+
+    let mut magic = [0u8; 2];
+    let script = std::os::unix::fs::FileExt::read_exact_at(&file, &mut magic, 0).is_ok() && &magic == b"#!";
+    Ok(MeasuredExecutable {
+        file,
+        path: path.to_path_buf(),
+        hash,
+        pinned: pins.is_pinned(),
+        script,
+        key,
+    })
+}
+
+fn check(path: &Path, measured: &str, expected: &str) -> Result<()> {
+    if measured != expected {
+        bail!("hash mismatch for {}: expected {}, measured {}", path.display(), expected, measured);
+    }
+    Ok(())
+}
+
+impl MeasuredExecutable {
+    /// Fail if the file was written to since it was hashed, so that the
+    /// child does not execute bytes other than the measured ones.
+    pub fn check_unchanged(&self) -> Result<()> {
+        if FileKey::of(&self.file, self.key.algorithm)? != self.key {
+            bail!("{} changed after it was hashed", self.path.display());
+        }
+        Ok(())
+    }
+
+    /// In the child: move the file to a descriptor of at least `min_fd`, so
+    /// that descriptors passed at fixed numbers below it do not replace it.
+    pub fn move_fd_above(&mut self, min_fd: RawFd) -> io::Result<()> {
+        let fd = fcntl(self.file.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(min_fd))?;
+        // Closes the old descriptor
+        self.file = unsafe { File::from_raw_fd(fd) };
+        Ok(())
+    }
+
+    /// Replace the current (child) process with the measured file. Returns
+    /// only on failure.
+    pub fn exec_in_child(&self, argv: &[*const libc::c_char], envp: &[*const libc::c_char]) -> io::Error {
+        let fd = self.file.as_raw_fd();
+        if self.script {
+            // Scripts are read through /dev/fd/N, which must survive the exec
+            unsafe { libc::fcntl(fd, libc::F_SETFD, 0) };
+        }
+        unsafe { libc::fexecve(fd, argv.as_ptr(), envp.as_ptr()) };
+        io::Error::last_os_error()
+    }
+
+    /// Add this execution by `service` to the measurement log.
+    pub fn record(&self, service: &str, timestamp: u64) {
+        let path = self.path.display().to_string();
+        let mut log = LOG.lock().unwrap();
+        match log.iter_mut().find(|e| e.service == service && e.path == path && e.hash == self.hash) {
+            Some(entry) => {
+                entry.last_exec = timestamp;
+                entry.exec_count += 1;
+            }
+            None => log.push(MeasurementEntry {
+                service: service.to_string(),
+                path,
+                hash: self.hash.clone(),
+                pinned: self.pinned,
+                first_exec: timestamp,
+                last_exec: timestamp,
+                exec_count: 1,
+            }),
+        }
+    }
+}
+
+/// The measurement log, in order of first execution.
+pub fn entries() -> Vec<MeasurementEntry> {
+    LOG.lock().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const EMPTY_SHA256: &str = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn test_hash_reader() {
+        assert_eq!(hash_reader(HashAlgorithm::Sha256, &b""[..]).unwrap(), EMPTY_SHA256);
+        assert_eq!(
+            hash_reader(HashAlgorithm::Sha3_512, &b""[..]).unwrap(),
+            "sha3-512:a69f73cca23a9ac5c8b567dc185a756e97c982164fe25859e0d1dcc1475c80a6\
+             15b2123af1f5f94c11e3e9402c3ac558f500199d95b6d3e301758586281dcd26"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = |hash: &str| ExecHashConfig {
+            exec_hash: Some(hash.to_string()),
+            ..Default::default()
+        };
+        assert!(config(EMPTY_SHA256).validate().is_ok());
+        assert!(config(&EMPTY_SHA256.replace('e', "E")).validate().is_ok());
+        assert!(config("md5:d41d8cd98f00b204e9800998ecf8427e").validate().is_err());
+        assert!(config("sha256:abc").validate().is_err());
+        assert!(config("e3b0c442").validate().is_err());
+
+        let mut files = ExecHashConfig::default();
+        files.exec_hash_files.insert("lib/model.bin".to_string(), EMPTY_SHA256.to_string());
+        assert!(files.validate().is_err());
+    }
+
+    #[test]
+    fn test_measure() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let exe = dir.join("app");
+        fs::write(&exe, "").unwrap();
+        fs::set_permissions(&exe, fs::Permissions::from_mode(0o755)).unwrap();
+        let data = dir.join("data");
+        fs::write(&data, "x").unwrap();
+
+        let path_env = format!("/nonexistent:{}", dir.display());
+        assert_eq!(resolve_executable("app", &path_env, None), Some(exe.clone()));
+        assert_eq!(resolve_executable("./app", "", Some("/srv")), Some(PathBuf::from("/srv/./app")));
+        assert_eq!(resolve_executable("missing", &path_env, None), None);
+
+        let mut pins = ExecHashConfig {
+            exec_hash: Some(EMPTY_SHA256.to_string()),
+            ..Default::default()
+        };
+        let measured = measure(&exe, &pins, HashAlgorithm::Sha3_512).unwrap();
+        assert_eq!(measured.hash, EMPTY_SHA256);
+        assert!(measured.pinned && !measured.script);
+
+        pins.exec_hash_files.insert(data.display().to_string(), EMPTY_SHA256.to_string());
+        let err = measure(&exe, &pins, HashAlgorithm::Sha256).err().unwrap();
+        assert!(err.to_string().contains("hash mismatch"));
+
+        // Hashes are cached until the file changes
+        let none = ExecHashConfig::default();
+        assert!(!is_hashed(&data, &none, HashAlgorithm::Sha3_512));
+        hash_ahead(&data, &none, HashAlgorithm::Sha3_512).unwrap();
+        assert!(is_hashed(&data, &none, HashAlgorithm::Sha3_512));
+        fs::write(&data, "xy").unwrap();
+        assert!(!is_hashed(&data, &none, HashAlgorithm::Sha3_512));
+
+        let mut unpinned = measure(&exe, &none, HashAlgorithm::Sha256).unwrap();
+        unpinned.check_unchanged().unwrap();
+        unpinned.move_fd_above(100).unwrap();
+        assert!(unpinned.file.as_raw_fd() >= 100);
+        assert!(!unpinned.pinned);
+        unpinned.record("app", 1);
+        unpinned.record("app", 2);
+        let entry = entries().into_iter().find(|e| e.path == exe.display().to_string()).unwrap();
+        assert_eq!((entry.first_exec, entry.last_exec, entry.exec_count), (1, 2, 2));
+
+        // A rewrite of the opened file after hashing refuses the start
+        fs::write(&exe, "#!/bin/sh\n").unwrap();
+        assert!(unpinned.check_unchanged().is_err());
+    }
+}
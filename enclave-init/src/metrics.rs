@@ -0,0 +1,340 @@
+//! Per-service metrics history.
+//!
+//! Every `metrics.interval_sec` seconds init samples the CPU usage, memory,
+//! open file descriptors, threads and restart count of each service and
+//! appends the sample to a ring buffer of `metrics.history` samples. A
+//! service's processes are those in its cgroup or, without one, the process
+//! group of its main process. CPU usage is the percentage of one CPU used
+//! since the previous sample, from the cgroup's `cpu.stat` or the processes'
+//! user and system time.
+//!
+//! The history is served by `ServiceMetrics` requests. With
+//! `metrics.prometheus_enabled`, the latest samples are also served in the
+//! Prometheus text format over VSock.
+
+use crate::cgroup::Cgroup;
+use crate::protocol::{ActiveState, MetricsSample, ServiceMetrics};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Read, Write};
+
+/// Largest HTTP request head read from a Prometheus scraper.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Raw readings of a running service's processes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Reading {
+    /// Cumulative CPU time of the processes
+    pub cpu_usec: u64,
+    pub memory_bytes: u64,
+    pub open_fds: u64,
+    pub threads: u64,
+}
+
+/// Read the usage of a service's processes: those in `cgroup`, or the
+/// process group led by `main_pid`.
+pub fn read(main_pid: i32, cgroup: Option<&Cgroup>) -> Reading {
+    let pids: Vec<i32> = match cgroup {
+        Some(cgroup) => cgroup.pids().into_iter().map(|p| p.as_raw()).collect(),
+        None => process_group(main_pid),
+    };
+    let mut reading = Reading::default();
+    for &pid in &pids {
+        reading.open_fds += fs::read_dir(format!("/proc/{}/fd", pid)).map(|d| d.count() as u64).unwrap_or(0);
+        let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+        reading.threads += status_field(&status, "Threads:").unwrap_or(0);
+        if cgroup.is_none() {
+            reading.memory_bytes += status_field(&status, "VmRSS:").unwrap_or(0) * 1024;
+            reading.cpu_usec += cpu_ticks(pid) * 1_000_000 / clock_ticks_per_sec();
+        }
+    }
+    if let Some(cgroup) = cgroup {
+        let usage = cgroup.usage();
+        reading.cpu_usec = usage.cpu_usage_usec;
+        reading.memory_bytes = usage.memory_current_bytes;
+    }
+    reading
+}
+
+/// PIDs whose process group is `pgid`, `pgid` itself first.
+fn process_group(pgid: i32) -> Vec<i32> {
+    let mut pids = vec![pgid];
+    for entry in fs::read_dir("/proc").into_iter().flatten().flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|n| n.parse::<i32>().ok()) else {
+            continue;
+        };
+        if pid != pgid && stat_fields(pid).and_then(|f| f.get(2)?.parse::<i32>().ok()) == Some(pgid) {
+            pids.push(pid);
+        }
+    }
+    pids
+}
+
+/// Fields of `/proc/<pid>/stat` after the command name, starting with the state.
+fn stat_fields(pid: i32) -> Option<Vec<String>> {
+    let content = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let rest = &content[content.rfind(')')? + 1..];
+    Some(rest.split_whitespace().map(str::to_string).collect())
+}
+
+/// User and system time of a process in clock ticks.
+fn cpu_ticks(pid: i32) -> u64 {
+    let Some(fields) = stat_fields(pid) else {
+        return 0;
+    };
+    let field = |i: usize| fields.get(i).and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+    field(11) + field(12)
+}
+
+fn clock_ticks_per_sec() -> u64 {
+    match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        ticks if ticks > 0 => ticks as u64,
+        _ => 100,
+    }
+}
+
+/// Value of a `Key:   123 kB` line of `/proc/<pid>/status`.
+fn status_field(status: &str, key: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|l| l.strip_prefix(key))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+struct ServiceHistory {
+    state: ActiveState,
+    samples: VecDeque<MetricsSample>,
+    /// Timestamp and CPU time of the previous reading, for the CPU percentage
+    last_cpu: Option<(u64, u64)>,
+}
+
+/// Ring buffers of samples, per service.
+pub struct MetricsHistory {
+    capacity: usize,
+    services: HashMap<String, ServiceHistory>,
+}
+
+impl MetricsHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            services: HashMap::new(),
+        }
+    }
+
+    /// Append a sample for `name` taken at `timestamp` (milliseconds since
+    /// the epoch). `reading` is None when the service is not running.
+    pub fn record(&mut self, name: &str, timestamp: u64, state: ActiveState, restarts: u32, reading: Option<Reading>) {
+        let history = self.services.entry(name.to_string()).or_insert_with(|| ServiceHistory {
+            state,
+            samples: VecDeque::new(),
+            last_cpu: None,
+        });
+        history.state = state;
+
+        let reading = reading.unwrap_or_default();
+        let cpu_percent = match history.last_cpu {
+            // A lower CPU time means the processes were replaced
+            Some((last_time, last_cpu)) if timestamp > last_time && reading.cpu_usec >= last_cpu => {
+                (reading.cpu_usec - last_cpu) as f32 * 100.0 / ((timestamp - last_time) * 1000) as f32
+            }
+            _ => 0.0,
+        };
+        history.last_cpu = (reading.cpu_usec > 0).then_some((timestamp, reading.cpu_usec));
+
+        if history.samples.len() >= self.capacity {
+            history.samples.pop_front();
+        }
+        history.samples.push_back(MetricsSample {
+            timestamp,
+            cpu_percent,
+            memory_bytes: reading.memory_bytes,
+            open_fds: reading.open_fds,
+            threads: reading.threads,
+            restarts,
+        });
+    }
+
+    /// Forget services for which `keep` returns false.
+    pub fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        self.services.retain(|name, _| keep(name));
+    }
+
+    /// The last `samples` samples (all with 0) of service `name`, or of all
+    /// services ordered by name.
+    pub fn snapshot(&self, name: Option<&str>, samples: usize) -> Vec<ServiceMetrics> {
+        let mut metrics: Vec<ServiceMetrics> = self
+            .services
+            .iter()
+            .filter(|(n, _)| name.is_none_or(|name| name == n.as_str()))
+            .map(|(n, history)| {
+                let skip = if samples == 0 { 0 } else { history.samples.len().saturating_sub(samples) };
+                ServiceMetrics {
+                    name: n.clone(),
+                    state: history.state,
+                    samples: history.samples.iter().skip(skip).cloned().collect(),
+                }
+            })
+            .collect();
+        metrics.sort_by(|a, b| a.name.cmp(&b.name));
+        metrics
+    }
+}
+
+/// The latest sample of each service in the Prometheus text format.
+pub fn prometheus_text(metrics: &[ServiceMetrics]) -> String {
+    type Value = fn(&ServiceMetrics, &MetricsSample) -> String;
+    let families: [(&str, &str, &str, Value); 6] = [
+        ("enclave_service_up", "gauge", "Whether the service is active", |m, _| {
+            u8::from(matches!(m.state, ActiveState::Starting | ActiveState::Running | ActiveState::Stopping))
+                .to_string()
+        }),
+        ("enclave_service_cpu_percent", "gauge", "CPU usage as a percentage of one CPU", |_, s| {
+            format!("{:.2}", s.cpu_percent)
+        }),
+        ("enclave_service_memory_bytes", "gauge", "Memory used by the service's processes", |_, s| {
+            s.memory_bytes.to_string()
+        }),
+        ("enclave_service_open_fds", "gauge", "Open file descriptors", |_, s| s.open_fds.to_string()),
+        ("enclave_service_threads", "gauge", "Threads of the service's processes", |_, s| s.threads.to_string()),
+        ("enclave_service_restarts_total", "counter", "Automatic restarts", |_, s| s.restarts.to_string()),
+    ];
+
+    let mut text = String::new();
+    for (name, kind, help, value) in families {
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} {}", name, kind);
+        for service in metrics {
+            if let Some(sample) = service.samples.last() {
+                let label = service.name.replace('\\', "\\\\").replace('"', "\\\"");
+                let _ = writeln!(text, "{}{{service=\"{}\"}} {} {}", name, label, value(service, sample), sample.timestamp);
+            }
+        }
+    }
+    text
+}
+
+/// Answer one HTTP request on `stream`: `GET /metrics` (or `/`) with the
+/// output of `body`, anything else with 404.
+pub fn serve_http<S: Read + Write>(stream: &mut S, body: impl FnOnce() -> String) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+        match stream.read(&mut buf)? {
+            0 => break,
+            n => request.extend_from_slice(&buf[..n]),
+        }
+    }
+    let (status, body) = if request.starts_with(b"GET /metrics ") || request.starts_with(b"GET / ") {
+        ("200 OK", body())
+    } else {
+        ("404 Not Found", "Not found\n".to_string())
+    };
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(cpu_usec: u64) -> Option<Reading> {
+        Some(Reading {
+            cpu_usec,
+            memory_bytes: 4096,
+            open_fds: 5,
+            threads: 2,
+        })
+    }
+
+    #[test]
+    fn test_history() {
+        let mut history = MetricsHistory::new(3);
+        history.record("web", 10_000, ActiveState::Running, 0, reading(1_000_000));
+        // Half a CPU over 10 seconds
+        history.record("web", 20_000, ActiveState::Running, 0, reading(6_000_000));
+        // Restarted: the CPU time starts over
+        history.record("web", 30_000, ActiveState::Running, 1, reading(100_000));
+        history.record("web", 40_000, ActiveState::Inactive, 1, None);
+        history.record("db", 40_000, ActiveState::Running, 0, reading(0));
+
+        let all = history.snapshot(None, 0);
+        assert_eq!(all.iter().map(|m| m.name.as_str()).collect::<Vec<_>>(), vec!["db", "web"]);
+        let web = &all[1];
+        assert_eq!(web.state, ActiveState::Inactive);
+        let cpu: Vec<f32> = web.samples.iter().map(|s| s.cpu_percent).collect();
+        assert_eq!(cpu, vec![50.0, 0.0, 0.0]);
+        assert_eq!(web.samples[2].memory_bytes, 0);
+
+        let latest = history.snapshot(Some("web"), 1);
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].samples.len(), 1);
+        assert_eq!(latest[0].samples[0].timestamp, 40_000);
+
+        history.retain(|name| name != "db");
+        assert!(history.snapshot(Some("db"), 0).is_empty());
+    }
+
+    #[test]
+    fn test_prometheus_text() {
+        let mut history = MetricsHistory::new(10);
+        history.record("web\"1", 1_000, ActiveState::Running, 2, reading(0));
+        let text = prometheus_text(&history.snapshot(None, 1));
+        assert!(text.contains("# TYPE enclave_service_restarts_total counter\n"));
+        assert!(text.contains("enclave_service_up{service=\"web\\\"1\"} 1 1000\n"));
+        assert!(text.contains("enclave_service_memory_bytes{service=\"web\\\"1\"} 4096 1000\n"));
+        assert!(text.contains("enclave_service_restarts_total{service=\"web\\\"1\"} 2 1000\n"));
+    }
+
+    #[test]
+    fn test_serve_http() {
+        struct Exchange {
+            request: io::Cursor<Vec<u8>>,
+            response: Vec<u8>,
+        }
+        impl Read for Exchange {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.request.read(buf)
+            }
+        }
+        impl Write for Exchange {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.response.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let exchange = |request: &str| {
+            let mut exchange = Exchange {
+                request: io::Cursor::new(request.as_bytes().to_vec()),
+                response: Vec::new(),
+            };
+            serve_http(&mut exchange, || "up 1\n".to_string()).unwrap();
+            String::from_utf8(exchange.response).unwrap()
+        };
+
+        let ok = exchange("GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(ok.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(ok.contains("Content-Length: 5\r\n") && ok.ends_with("\r\n\r\nup 1\n"));
+        assert!(exchange("GET /other HTTP/1.1\r\n\r\n").starts_with("HTTP/1.0 404"));
+    }
+
+    #[test]
+    fn test_status_field() {
+        let status = "Name:\tweb\nVmRSS:\t  1234 kB\nThreads:\t7\n";
+        assert_eq!(status_field(status, "VmRSS:"), Some(1234));
+        assert_eq!(status_field(status, "Threads:"), Some(7));
+        assert_eq!(status_field(status, "VmSwap:"), None);
+    }
+}
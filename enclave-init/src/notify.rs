@@ -0,0 +1,206 @@
+//! `sd_notify`-compatible notification sockets.
+//!
+//! Each `Type=notify` service (or service with `WatchdogSec=`) gets its own
+//! datagram socket, exported to the child as `NOTIFY_SOCKET`. A reader thread
+//! parses `KEY=VALUE` messages (`READY=1`, `STATUS=...`, `WATCHDOG=1`) into a
+//! shared state that init's supervision loop polls.
+
+use crate::logger::Logger;
+use anyhow::{Context, Result};
+use nix::unistd::{chown, Gid, Uid};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const NOTIFY_SOCKET_DIR: &str = "/run/init/notify";
+
+/// Maximum size of a single notification datagram.
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+/// State reported by the service over its notification socket.
+#[derive(Debug, Clone, Default)]
+pub struct NotifyState {
+    /// `READY=1` has been received
+    pub ready: bool,
+    /// Last `STATUS=` text
+    pub status: Option<String>,
+    /// Time of the last `WATCHDOG=1` ping
+    pub last_watchdog: Option<Instant>,
+    /// `WATCHDOG=trigger` was received
+    pub watchdog_triggered: bool,
+    /// `STOPPING=1` has been received
+    pub stopping: bool,
+}
+
+impl NotifyState {
+    /// Apply one notification datagram.
+    pub fn apply(&mut self, message: &str) {
+        for line in message.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            match key {
+                "READY" if value == "1" => self.ready = true,
+                "STATUS" => self.status = Some(value.to_string()),
+                "STOPPING" if value == "1" => self.stopping = true,
+                "WATCHDOG" if value == "1" => self.last_watchdog = Some(Instant::now()),
+                "WATCHDOG" if value == "trigger" => self.watchdog_triggered = true,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// A bound notification socket and its reader thread.
+#[derive(Debug)]
+pub struct NotifySocket {
+    path: PathBuf,
+    state: Arc<Mutex<NotifyState>>,
+    running: Arc<AtomicBool>,
+}
+
+impl NotifySocket {
+    /// Bind the service's notification socket and start reading from it.
+    ///
+    /// The socket is owned by the service's `User=`/`Group=` so that it can
+    /// still send to it after dropping privileges.
+    pub fn bind(service_name: &str, uid: Option<Uid>, gid: Option<Gid>) -> Result<Self> {
+        Self::bind_in(Path::new(NOTIFY_SOCKET_DIR), service_name, uid, gid)
+    }
+
+    fn bind_in(dir: &Path, service_name: &str, uid: Option<Uid>, gid: Option<Gid>) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+        let path = dir.join(format!("{}.sock", service_name));
+        let _ = fs::remove_file(&path);
+
+        let socket = UnixDatagram::bind(&path)
+            .with_context(|| format!("Failed to bind notify socket {:?}", path))?;
+        chown(&path, uid, gid).with_context(|| format!("Failed to chown notify socket {:?}", path))?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o660))
+            .with_context(|| format!("Failed to set notify socket mode {:?}", path))?;
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .context("Failed to set notify socket timeout")?;
+
+        let state = Arc::new(Mutex::new(NotifyState::default()));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread_state = state.clone();
+        let thread_running = running.clone();
+        let name = service_name.to_string();
+        thread::Builder::new()
+            .name(format!("{}-notify", service_name))
+            .spawn(move || {
+                let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+                while thread_running.load(Ordering::Relaxed) {
+                    match socket.recv(&mut buf) {
+                        Ok(n) => {
+                            let message = String::from_utf8_lossy(&buf[..n]);
+                            thread_state.lock().unwrap().apply(&message);
+                        }
+                        Err(e)
+                            if e.kind() == std::io::ErrorKind::WouldBlock
+                                || e.kind() == std::io::ErrorKind::TimedOut
+                                || e.kind() == std::io::ErrorKind::Interrupted => {}
+                        Err(e) => {
+                            Logger::warn(&format!("Notify socket for {} failed: {}", name, e));
+                            break;
+                        }
+                    }
+                }
+            })
+            .context("Failed to spawn notify reader thread")?;
+
+        Ok(Self {
+            path,
+            state,
+            running,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Snapshot of the reported state.
+    pub fn state(&self) -> NotifyState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Clear a `WATCHDOG=trigger` request once it has been acted upon.
+    pub fn clear_watchdog_trigger(&self) {
+        self.state.lock().unwrap().watchdog_triggered = false;
+    }
+}
+
+impl Drop for NotifySocket {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_messages() {
+        let mut state = NotifyState::default();
+        state.apply("STATUS=Loading model\n");
+        assert!(!state.ready);
+        assert_eq!(state.status.as_deref(), Some("Loading model"));
+
+        state.apply("READY=1\nSTATUS=Serving\nWATCHDOG=1");
+        assert!(state.ready);
+        assert_eq!(state.status.as_deref(), Some("Serving"));
+        assert!(state.last_watchdog.is_some());
+
+        state.apply("WATCHDOG=trigger");
+        assert!(state.watchdog_triggered);
+    }
+
+    #[test]
+    fn test_bind_for_unprivileged_user() {
+        use nix::unistd::{fork, setgid, setuid, ForkResult};
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+        // Only root can hand the socket to another user
+        let (uid, gid) = if Uid::effective().is_root() {
+            (Uid::from_raw(65534), Gid::from_raw(65534))
+        } else {
+            (Uid::effective(), Gid::effective())
+        };
+        let socket = NotifySocket::bind_in(dir.path(), "web", Some(uid), Some(gid)).unwrap();
+        let metadata = fs::metadata(socket.path()).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (uid.as_raw(), gid.as_raw()));
+        assert_eq!(metadata.mode() & 0o777, 0o660);
+
+        // Send as the service user, the way a child with User=/Group= would
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let sent = setgid(gid).is_ok()
+                    && setuid(uid).is_ok()
+                    && UnixDatagram::unbound().and_then(|s| s.send_to(b"READY=1", socket.path())).is_ok();
+                unsafe { libc::_exit(if sent { 0 } else { 1 }) };
+            }
+            ForkResult::Parent { child } => {
+                let status = nix::sys::wait::waitpid(child, None).unwrap();
+                assert_eq!(status, nix::sys::wait::WaitStatus::Exited(child, 0));
+            }
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !socket.state().ready && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(socket.state().ready);
+    }
+}
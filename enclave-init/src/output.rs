@@ -0,0 +1,236 @@
+//! Service stdout/stderr capture.
+//!
+//! Each service stream is either piped back to init and written through the
+//! service's `ServiceLogger`, inherited from init (console), discarded, or
+//! appended to a file.
+
+use crate::logger::{Logger, ServiceLogger};
+use anyhow::{Context, Result};
+use nix::fcntl::OFlag;
+use nix::unistd::{close, dup2, pipe2};
+use serde::{Deserialize, Deserializer};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::thread;
+
+/// Longest line logged as one record; longer output is split at this size.
+pub const MAX_LINE: usize = 64 * 1024;
+
+/// Where a service's stdout or stderr is sent.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum OutputTarget {
+    /// Capture into the service log (default)
+    #[default]
+    Log,
+    /// Inherit init's console
+    Console,
+    /// Discard output
+    Null,
+    /// Append to a file
+    File(String),
+}
+
+impl OutputTarget {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "log" => Ok(OutputTarget::Log),
+            "console" | "inherit" => Ok(OutputTarget::Console),
+            "null" => Ok(OutputTarget::Null),
+            _ => {
+                let path = value.strip_prefix("file:").unwrap_or(value);
+                if path.starts_with('/') {
+                    Ok(OutputTarget::File(path.to_string()))
+                } else {
+                    Err(format!(
+                        "invalid output target '{}', expected log, console, null or an absolute path",
+                        value
+                    ))
+                }
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for OutputTarget {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        OutputTarget::parse(&value).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Standard stream of a service process.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputStream::Stdout => "stdout",
+            OutputStream::Stderr => "stderr",
+        }
+    }
+
+    fn fd(&self) -> RawFd {
+        match self {
+            OutputStream::Stdout => libc::STDOUT_FILENO,
+            OutputStream::Stderr => libc::STDERR_FILENO,
+        }
+    }
+}
+
+/// Prepared redirection for one service stream, created before `fork()`.
+#[derive(Debug)]
+pub struct StreamRedirect {
+    stream: OutputStream,
+    target: OutputTarget,
+    /// Read and write ends of the capture pipe for `OutputTarget::Log`
+    pipe: Option<(RawFd, RawFd)>,
+}
+
+impl StreamRedirect {
+    /// Prepare the redirection in the parent. For `Log` targets this creates
+    /// a close-on-exec pipe, so sibling services never inherit it.
+    pub fn new(stream: OutputStream, target: &OutputTarget) -> Result<Self> {
+        let pipe = if *target == OutputTarget::Log {
+            Some(pipe2(OFlag::O_CLOEXEC).context("Failed to create output pipe")?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            stream,
+            target: target.clone(),
+            pipe,
+        })
+    }
+
+    /// Apply the redirection in the forked child, before exec.
+    pub fn apply_in_child(&self) {
+        let target_fd = self.stream.fd();
+
+        let source_fd = match &self.target {
+            OutputTarget::Console => return,
+            OutputTarget::Log => match self.pipe {
+                Some((_, write_fd)) => write_fd,
+                None => return,
+            },
+            OutputTarget::Null => match OpenOptions::new().write(true).open("/dev/null") {
+                Ok(f) => f.into_raw_fd(),
+                Err(_) => return,
+            },
+            OutputTarget::File(path) => {
+                match OpenOptions::new().create(true).append(true).open(path) {
+                    Ok(f) => f.into_raw_fd(),
+                    Err(e) => {
+                        Logger::error(&format!("Failed to open output file {}: {}", path, e));
+                        return;
+                    }
+                }
+            }
+        };
+
+        // dup2 clears O_CLOEXEC on the target descriptor
+        let _ = dup2(source_fd, target_fd);
+        if source_fd != target_fd {
+            let _ = close(source_fd);
+        }
+    }
+
+    /// Finish setup in the parent after a successful fork: close the write end
+    /// and start a reader thread that forwards lines to the service logger.
+    pub fn attach_in_parent(self, logger: &ServiceLogger, service_name: &str) {
+        let (read_fd, write_fd) = match self.pipe {
+            Some(fds) => fds,
+            None => return,
+        };
+        let _ = close(write_fd);
+
+        let logger = logger.clone();
+        let stream = self.stream;
+        let service_name = service_name.to_string();
+
+        let spawned = thread::Builder::new()
+            .name(format!("{}-{}", service_name, stream.as_str()))
+            .spawn(move || {
+                let file = unsafe { File::from_raw_fd(read_fd) };
+                forward_lines(BufReader::new(file), &logger, stream);
+            });
+
+        if let Err(e) = spawned {
+            Logger::error(&format!(
+                "Failed to spawn {} reader for service {}: {}",
+                stream.as_str(),
+                service_name,
+                e
+            ));
+        }
+    }
+
+    /// Release the pipe if the fork failed.
+    pub fn discard(self) {
+        if let Some((read_fd, write_fd)) = self.pipe {
+            let _ = close(read_fd);
+            let _ = close(write_fd);
+        }
+    }
+}
+
+/// Read lines until EOF (all writers closed) and log each one tagged with its
+/// stream. Lines longer than `MAX_LINE` are logged in `MAX_LINE` pieces.
+pub fn forward_lines<R: BufRead>(mut reader: R, logger: &ServiceLogger, stream: OutputStream) {
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match read_line_limited(&mut reader, &mut buf, MAX_LINE) {
+            Ok(0) => break,
+            Ok(_) => {
+                let line = String::from_utf8_lossy(&buf);
+                let line = line.trim_end_matches(['\n', '\r']);
+                logger.log_output(stream, line);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        }
+    }
+}
+
+/// Read up to and including the next newline, but at most `limit` bytes.
+fn read_line_limited<R: BufRead>(reader: &mut R, buf: &mut Vec<u8>, limit: usize) -> io::Result<usize> {
+    reader.take(limit as u64).read_until(b'\n', buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_output_target() {
+        assert_eq!(OutputTarget::parse("log").unwrap(), OutputTarget::Log);
+        assert_eq!(OutputTarget::parse("console").unwrap(), OutputTarget::Console);
+        assert_eq!(OutputTarget::parse("null").unwrap(), OutputTarget::Null);
+        assert_eq!(
+            OutputTarget::parse("/var/log/app.out").unwrap(),
+            OutputTarget::File("/var/log/app.out".to_string())
+        );
+        assert_eq!(
+            OutputTarget::parse("file:/tmp/x").unwrap(),
+            OutputTarget::File("/tmp/x".to_string())
+        );
+        assert!(OutputTarget::parse("relative/path").is_err());
+    }
+
+    #[test]
+    fn test_read_line_limited() {
+        let mut reader = BufReader::new(&b"abcdefgh\nij\n"[..]);
+        let mut buf = Vec::new();
+        let mut lines = Vec::new();
+        while read_line_limited(&mut reader, &mut buf, 3).unwrap() > 0 {
+            lines.push(String::from_utf8(std::mem::take(&mut buf)).unwrap());
+        }
+        assert_eq!(lines, vec!["abc", "def", "gh\n", "ij\n"]);
+    }
+}
@@ -138,6 +138,7 @@ pub fn get_process_info(
         start_time,
         managed,
         service_name,
+        cgroup: crate::cgroup::usage_for_pid(pid),
     })
 }
 
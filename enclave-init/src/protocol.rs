@@ -1,7 +1,24 @@
+use hmac::{Hmac, Mac};
+use nix::unistd::{read, write};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use sha2::Sha256;
+use std::os::unix::io::RawFd;
 
 pub const SOCKET_PATH: &str = "/run/init.sock";
 
+/// Version of the framed control protocol. Version 1 is the legacy format of a
+/// single unframed JSON request and response per connection. Version 3 adds
+/// the pre-shared key challenge to the handshake.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Oldest framed protocol version understood by this build.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// Largest accepted frame payload.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
     // Service management
@@ -10,10 +27,34 @@ pub enum Request {
     ServiceStart { name: String },
     ServiceStop { name: String },
     ServiceRestart { name: String },
+    /// Run the service's ExecReload commands, or send SIGHUP if it has none
+    ServiceReload { name: String },
+    /// Clear the failed state and start limit of one service, or of all services
+    ServiceResetFailed { name: Option<String> },
     ServiceEnable { name: String },
     ServiceDisable { name: String },
     ServiceLogs { name: String, lines: usize },
+    /// Log records of one or more services, filtered and merged by time
+    ServiceLogsQuery { query: LogQuery },
     ServiceLogsClear { name: String },
+    /// Environment the service's next start would get
+    ServiceEnvironment { name: String },
+    /// Sampled metrics of one service, or of all services. `samples` limits
+    /// the history to the most recent samples; 0 returns all of it.
+    ServiceMetrics {
+        name: Option<String>,
+        #[serde(default)]
+        samples: usize,
+    },
+    /// Check a service definition (TOML) against the loaded services as if it
+    /// were installed as `name`
+    ServiceValidate { name: String, content: String },
+    /// Validate a service definition, write it to the service directory and reload
+    ServiceInstall { name: String, content: String },
+    /// Delete a service's file from the service directory and reload
+    ServiceRemove { name: String },
+    /// The file a service is loaded from
+    ServiceCat { name: String },
 
     /// Request to initialize log streaming for a service
     /// The init system will stream logs to the specified VSock address
@@ -28,6 +69,42 @@ pub enum Request {
     /// Stop streaming logs for a service
     ServiceLogsStreamStop { name: String },
 
+    /// Send the last `lines` log lines, then follow the log over this
+    /// connection (streaming, framed protocol only)
+    ServiceLogsFollow { name: String, lines: usize },
+
+    /// Service events matching `filter`, streamed over this connection
+    /// (streaming, framed protocol only) or, with `vsock`, sent as JSON lines
+    /// to a listener at that address
+    Subscribe {
+        #[serde(default)]
+        filter: EventFilter,
+        /// Only send the retained events after `filter.after`, then end the stream
+        #[serde(default)]
+        history_only: bool,
+        #[serde(default)]
+        vsock: Option<VsockEndpoint>,
+    },
+
+    /// List `.timer` units with their last and next trigger times
+    ListTimers,
+
+    /// Services pulled in by `name`, or with `reverse` the services that pull it in
+    ListDependencies {
+        name: String,
+        #[serde(default)]
+        reverse: bool,
+    },
+
+    /// List `.target` units
+    ListTargets,
+
+    /// Start the services of `target` and stop all others
+    Isolate { target: String },
+
+    /// Executables hashed before service starts since boot
+    MeasurementLog,
+
     // Process management
     ProcessList,
     ProcessStatus { pid: i32 },
@@ -48,15 +125,40 @@ pub enum Request {
 pub enum Response {
     Success { message: String },
     Error { message: String },
+    /// The connection's role does not allow the request
+    PermissionDenied { message: String },
     ServiceList { services: Vec<ServiceInfo> },
-    ServiceStatus { status: ServiceStatus },
+    ServiceStatus { status: Box<ServiceStatus> },
     ServiceLogs { logs: Vec<String> },
+    /// Result of `ServiceLogsQuery`, oldest first
+    LogRecords { records: Vec<LogRecord> },
+    /// `KEY=VALUE` assignments in the order they are passed to the process
+    ServiceEnvironment { name: String, environment: Vec<String> },
+    /// Result of `ServiceValidate`: the startup order with the service installed
+    ServiceValidated { name: String, startup_order: Vec<String> },
+    ServiceFile { name: String, path: String, content: String },
+    /// Result of `ServiceMetrics`, ordered by name; samples are oldest first
+    ServiceMetrics { interval_sec: u64, services: Vec<ServiceMetrics> },
+    /// Timers ordered by next trigger; `now` is init's clock, in seconds since the epoch
+    TimerList { now: u64, timers: Vec<TimerInfo> },
     /// Response for log streaming request
     LogsStreamStarted {
         service: String,
         vsock_cid: u32,
         vsock_port: u32,
     },
+    /// One line of a followed service log
+    LogLine { service: String, line: String },
+    /// One event of a `Subscribe` stream
+    Event { event: Event },
+    /// Last frame of a stream ended by init
+    StreamEnd { reason: String },
+    /// Result of `SystemReload`
+    Reloaded { summary: ReloadSummary },
+    DependencyTree { tree: DependencyNode },
+    TargetList { targets: Vec<TargetInfo> },
+    /// Result of `MeasurementLog`, in order of first execution
+    MeasurementLog { entries: Vec<MeasurementEntry> },
     ProcessList { processes: Vec<ProcessInfo> },
     ProcessStatus { process: ProcessInfo },
     ProcessStarted { pid: i32, message: String },
@@ -68,16 +170,84 @@ pub enum Response {
 pub struct ServiceInfo {
     pub name: String,
     pub enabled: bool,
-    pub active: bool,
+    pub state: ActiveState,
     pub restart_policy: String,
     pub restart_count: u32,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimerInfo {
+    pub name: String,
+    /// Service the timer starts
+    pub service: String,
+    pub schedule: Vec<String>,
+    /// Seconds since the epoch
+    pub last_trigger: Option<u64>,
+    pub next_trigger: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TargetInfo {
+    pub name: String,
+    pub description: Option<String>,
+    /// The target init booted into or was last isolated to
+    pub current: bool,
+    /// Services included in the target, through nested targets
+    pub services: Vec<String>,
+    /// How many of `services` are active
+    pub active_services: usize,
+}
+
+/// A service in a `ListDependencies` tree.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DependencyNode {
+    pub name: String,
+    /// How the service relates to its parent, e.g. `requires`; None for the root
+    pub relation: Option<String>,
+    pub state: ActiveState,
+    /// Empty for a service already shown higher up in the same branch
+    pub children: Vec<DependencyNode>,
+}
+
+/// Services affected by a reload, by name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReloadSummary {
+    pub added: Vec<String>,
+    /// Services whose configuration changed in more than their dependencies
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+    /// Changed services that were running and have been started again
+    pub restarted: Vec<String>,
+    /// Services that failed to stop or start
+    pub errors: Vec<String>,
+}
+
+impl ReloadSummary {
+    /// One-line summary, without the errors.
+    pub fn describe(&self) -> String {
+        let parts: Vec<String> = [
+            ("added", &self.added),
+            ("changed", &self.changed),
+            ("removed", &self.removed),
+            ("restarted", &self.restarted),
+        ]
+        .iter()
+        .filter(|(_, names)| !names.is_empty())
+        .map(|(label, names)| format!("{} {}", label, names.join(", ")))
+        .collect();
+        if parts.is_empty() {
+            "no changes".to_string()
+        } else {
+            parts.join("; ")
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServiceStatus {
     pub name: String,
     pub enabled: bool,
-    pub active: bool,
+    pub state: ActiveState,
     pub pid: Option<i32>,
     pub restart_policy: String,
     pub restart_count: u32,
@@ -86,6 +256,356 @@ pub struct ServiceStatus {
     pub exec_start: String,
     pub working_directory: Option<String>,
     pub dependencies: ServiceDependencyInfo,
+    /// Resource usage of the service's cgroup (None when cgroup v2 is unavailable)
+    pub cgroup: Option<CgroupUsage>,
+    /// Health as determined by ReadyCheck/HealthCheck probes
+    #[serde(default)]
+    pub health: HealthState,
+    /// Result of the most recent probe
+    #[serde(default)]
+    pub last_probe: Option<ProbeResult>,
+    /// Service type ("simple", "notify")
+    #[serde(default)]
+    pub service_type: String,
+    /// Last STATUS= text sent over the notification socket
+    #[serde(default)]
+    pub status_text: Option<String>,
+    /// Watchdog timeout in seconds (0 when disabled)
+    #[serde(default)]
+    pub watchdog_sec: u64,
+    /// Oneshot service has exited and remains active (RemainAfterExit)
+    #[serde(default)]
+    pub exited: bool,
+    #[serde(default)]
+    pub hooks: ServiceHooksInfo,
+    /// Signal sent on stop (KillSignal)
+    #[serde(default)]
+    pub kill_signal: String,
+    /// Processes that receive the stop signal (KillMode)
+    #[serde(default)]
+    pub kill_mode: String,
+    #[serde(default)]
+    pub timeout_stop_sec: u64,
+    /// Whether SIGKILL is sent once TimeoutStopSec has passed
+    #[serde(default)]
+    pub send_sigkill: bool,
+    /// Service hit StartLimitBurst and is not restarted until reset
+    #[serde(default)]
+    pub start_limit_hit: bool,
+    /// Delay before the next automatic restart, including backoff
+    #[serde(default)]
+    pub next_restart_sec: u64,
+    /// Listening sockets that activate the service
+    #[serde(default)]
+    pub listen: Vec<String>,
+    /// User, groups and capabilities, when not running as init's root identity
+    #[serde(default)]
+    pub credentials: Option<String>,
+    /// Enabled sandbox options, empty when the service is not sandboxed
+    #[serde(default)]
+    pub sandbox: Vec<String>,
+    /// Hash of the executable at the last start, when it was verified or measured
+    #[serde(default)]
+    pub exec_hash: Option<String>,
+}
+
+/// Severity of a log record, most severe first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    pub fn as_str(&self) -> &str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        }
+    }
+}
+
+/// Where a log record came from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogStream {
+    /// Written by init about the service
+    #[default]
+    Init,
+    Stdout,
+    Stderr,
+}
+
+/// One line of a service log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogRecord {
+    /// Milliseconds since the epoch
+    pub timestamp: u64,
+    pub service: String,
+    #[serde(default)]
+    pub stream: LogStream,
+    #[serde(default)]
+    pub level: LogLevel,
+    pub message: String,
+}
+
+impl LogRecord {
+    /// Text form, `[2024-05-01 12:00:00] [stdout] message`, optionally with
+    /// the service name after the time.
+    pub fn to_line(&self, with_service: bool) -> String {
+        let mut line = bracketed_time(self.timestamp);
+        if with_service {
+            line.push_str(&self.service);
+            line.push_str(": ");
+        }
+        match self.stream {
+            LogStream::Init => {}
+            LogStream::Stdout => line.push_str("[stdout] "),
+            LogStream::Stderr => line.push_str("[stderr] "),
+        }
+        if self.level != LogLevel::Info {
+            line.push_str(&format!("[{}] ", self.level.as_str()));
+        }
+        line.push_str(&self.message);
+        line
+    }
+}
+
+/// Filters of a `ServiceLogsQuery`. Records must match all of them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LogQuery {
+    /// Services to read; empty for all services
+    #[serde(default)]
+    pub services: Vec<String>,
+    /// Time such as `2024-05-01 12:00`, `-1h` or `today`, on init's clock (UTC)
+    #[serde(default)]
+    pub since: Option<String>,
+    #[serde(default)]
+    pub until: Option<String>,
+    /// Least severe level to include
+    #[serde(default)]
+    pub level: Option<LogLevel>,
+    /// Text the message must contain
+    #[serde(default)]
+    pub grep: Option<String>,
+    /// Treat `grep` as a regular expression
+    #[serde(default)]
+    pub regex: bool,
+    /// Return only the last `lines` matching records; 0 for all
+    #[serde(default)]
+    pub lines: usize,
+}
+
+/// `[2024-05-01 12:00:00] ` for a time in milliseconds since the epoch.
+pub fn bracketed_time(timestamp: u64) -> String {
+    let secs = timestamp / 1000;
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    format!(
+        "[{:04}-{:02}-{:02} {:02}:{:02}:{:02}] ",
+        year,
+        month,
+        day,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct VsockEndpoint {
+    pub cid: u32,
+    pub port: u32,
+}
+
+/// A change of service state. Sequence numbers are consecutive within a
+/// boot, starting at 1.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub seq: u64,
+    /// Milliseconds since the epoch
+    pub timestamp: u64,
+    /// None for events about init itself
+    #[serde(default)]
+    pub service: Option<String>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum EventKind {
+    Started { pid: i32 },
+    /// The main process exited; `code` is 128 + the signal number if it was killed
+    Exited { code: i32 },
+    /// An automatic restart is scheduled in `delay_sec` seconds
+    Restarting { delay_sec: u64 },
+    Failed { reason: String },
+    HealthChanged { health: HealthState, detail: String },
+    Reloaded { summary: ReloadSummary },
+}
+
+/// Which events a subscription receives. Events must match all filters;
+/// empty lists match everything.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EventFilter {
+    /// Services whose events to receive; events about init itself only
+    /// match an empty list
+    #[serde(default)]
+    pub services: Vec<String>,
+    /// Event names: `started`, `exited`, `restarting`, `failed`,
+    /// `health-changed` or `reloaded`
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// First send the retained events with a higher sequence number
+    #[serde(default)]
+    pub after: Option<u64>,
+}
+
+/// Messages init sends on a log forwarding connection. The collector
+/// answers each `Records` batch with a `ForwardAck`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ForwardMessage {
+    /// First message on each connection
+    Hello { boot_id: String, agent: String },
+    Records { records: Vec<ForwardedRecord> },
+}
+
+/// A log record with its sequence number. Numbers are consecutive within a
+/// boot, so a gap means records were dropped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForwardedRecord {
+    pub seq: u64,
+    pub record: LogRecord,
+}
+
+/// The collector has written all records up to and including `seq`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForwardAck {
+    pub seq: u64,
+}
+
+/// State of the log forwarder, in `SystemStatus`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogForwardingStatus {
+    /// `CID:3 PORT:9002`
+    pub destination: String,
+    pub connected: bool,
+    /// Records not yet acknowledged by the collector
+    pub queued: usize,
+    /// Of those, records in the on-disk spool
+    pub spooled: usize,
+    /// Records discarded because the buffers were full
+    pub dropped: u64,
+    /// Sequence number of the next record
+    pub next_seq: u64,
+}
+
+/// (year, month, day) of a day number counted from 1970-01-01.
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Lifecycle state of a service.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActiveState {
+    #[default]
+    Inactive,
+    /// Running but not yet ready (readiness probe, READY=1 or oneshot still running)
+    Starting,
+    Running,
+    /// Stop signal sent, waiting for the service to exit
+    Stopping,
+    /// Exited with an error or hit its start limit
+    Failed,
+}
+
+/// Permissions of a control connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Status, listing and log requests only
+    ReadOnly,
+    #[default]
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Role::ReadOnly => "read-only",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl ActiveState {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ActiveState::Inactive => "inactive",
+            ActiveState::Starting => "starting",
+            ActiveState::Running => "running",
+            ActiveState::Stopping => "stopping",
+            ActiveState::Failed => "failed",
+        }
+    }
+}
+
+/// Lifecycle hook commands of a service
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServiceHooksInfo {
+    pub exec_start_pre: Vec<String>,
+    pub exec_start_post: Vec<String>,
+    pub exec_stop: Vec<String>,
+    pub exec_stop_post: Vec<String>,
+    pub exec_reload: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthState {
+    /// No probes configured, or service not running
+    #[default]
+    Unknown,
+    /// Waiting for the readiness probe to succeed
+    Starting,
+    Healthy,
+    Unhealthy,
+}
+
+impl HealthState {
+    pub fn as_str(&self) -> &str {
+        match self {
+            HealthState::Unknown => "unknown",
+            HealthState::Starting => "starting",
+            HealthState::Healthy => "healthy",
+            HealthState::Unhealthy => "unhealthy",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProbeResult {
+    pub success: bool,
+    pub message: String,
+    /// Unix timestamp of the probe in seconds
+    pub timestamp: u64,
+    pub duration_ms: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -94,6 +614,12 @@ pub struct ServiceDependencyInfo {
     pub after: Vec<String>,
     pub requires: Vec<String>,
     pub required_by: Vec<String>,
+    #[serde(default)]
+    pub binds_to: Vec<String>,
+    #[serde(default)]
+    pub part_of: Vec<String>,
+    #[serde(default)]
+    pub wanted_by: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -108,6 +634,57 @@ pub struct ProcessInfo {
     pub start_time: u64,
     pub managed: bool,  // true if managed by init as a service
     pub service_name: Option<String>,
+    /// Resource usage of the cgroup the process belongs to
+    pub cgroup: Option<CgroupUsage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CgroupUsage {
+    pub path: String,
+    pub memory_current_bytes: u64,
+    pub memory_peak_bytes: Option<u64>,
+    /// None when memory.max is unlimited
+    pub memory_max_bytes: Option<u64>,
+    pub cpu_usage_usec: u64,
+    pub tasks_current: u64,
+}
+
+/// Resource usage of a service at one point in time. Usage fields are 0
+/// while the service is not running.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsSample {
+    /// Milliseconds since the epoch
+    pub timestamp: u64,
+    /// Percentage of one CPU used since the previous sample
+    pub cpu_percent: f32,
+    pub memory_bytes: u64,
+    pub open_fds: u64,
+    pub threads: u64,
+    /// Automatic restarts so far
+    pub restarts: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceMetrics {
+    pub name: String,
+    /// State at the latest sample
+    pub state: ActiveState,
+    pub samples: Vec<MetricsSample>,
+}
+
+/// One executable run by a service, with the same hash each time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MeasurementEntry {
+    pub service: String,
+    pub path: String,
+    /// `<algorithm>:<hex>`
+    pub hash: String,
+    /// Checked against `ExecHash` or `ExecHashFiles`
+    pub pinned: bool,
+    /// Milliseconds since the epoch
+    pub first_exec: u64,
+    pub last_exec: u64,
+    pub exec_count: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -115,8 +692,207 @@ pub struct SystemStatus {
     pub uptime_secs: u64,
     pub total_services: usize,
     pub active_services: usize,
+    #[serde(default)]
+    pub failed_services: usize,
     pub enabled_services: usize,
     pub total_processes: usize,
     pub log_dir: String,
     pub service_dir: String,
+    /// Current target, if init booted into one or was isolated
+    #[serde(default)]
+    pub target: Option<String>,
+    /// None when `log_forward` is disabled
+    #[serde(default)]
+    pub log_forwarding: Option<LogForwardingStatus>,
+}
+
+impl Request {
+    /// Whether the request produces a stream of response frames.
+    pub fn is_streaming(&self) -> bool {
+        matches!(self, Request::ServiceLogsFollow { .. } | Request::Subscribe { vsock: None, .. })
+    }
+}
+
+/// First frame sent by a client on a framed connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hello {
+    /// Highest protocol version the client speaks
+    pub version: u32,
+    /// Lowest protocol version the client accepts
+    pub min_version: u32,
+    pub agent: String,
+    /// Name of the pre-shared key the client authenticates with
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+/// Server's answer to `Hello`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum HelloReply {
+    Accepted {
+        version: u32,
+        agent: String,
+        #[serde(default)]
+        role: Role,
+    },
+    Rejected { message: String, min_version: u32, max_version: u32 },
+    /// The client must answer with an `AuthResponse` before it is accepted
+    Challenge { nonce: Vec<u8> },
+}
+
+/// Client's answer to `HelloReply::Challenge`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthResponse {
+    /// `auth_mac(key, nonce)`
+    pub mac: Vec<u8>,
+}
+
+/// HMAC-SHA256 of a challenge nonce under a pre-shared key.
+pub fn auth_mac(key: &[u8], nonce: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(b"enclave-init control auth v1\0");
+    mac.update(nonce);
+    mac
+}
+
+/// A request on a framed connection. `id` is echoed in its response frames.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestFrame {
+    pub id: u64,
+    pub request: Request,
+}
+
+/// A response on a framed connection. Streaming requests produce frames with
+/// `done: false` until the stream ends.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseFrame {
+    pub id: u64,
+    pub response: Response,
+    pub done: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FrameError {
+    #[error("connection closed")]
+    Closed,
+    /// The peer sent bare JSON: it speaks the legacy protocol (version 1)
+    #[error("peer does not speak the framed control protocol")]
+    Unframed,
+    #[error("frame of {0} bytes exceeds the limit of {1} bytes")]
+    TooLarge(usize, usize),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid message: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Read/Write over a borrowed socket descriptor. Does not close it on drop.
+pub struct FdStream(pub RawFd);
+
+impl Read for FdStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        read(self.0, buf).map_err(io::Error::from)
+    }
+}
+
+impl Write for FdStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        write(self.0, buf).map_err(io::Error::from)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Write one frame: a 4-byte big-endian payload length followed by JSON.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<(), FrameError> {
+    let payload = serde_json::to_vec(message)?;
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(FrameError::TooLarge(payload.len(), MAX_FRAME_SIZE));
+    }
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    writer.write_all(&frame)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read one frame. Returns `Closed` if the peer closed the connection between frames.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<T, FrameError> {
+    read_frame_limited(reader, MAX_FRAME_SIZE)
+}
+
+/// Read one frame of at most `limit` bytes, for peers that are not yet
+/// trusted with `MAX_FRAME_SIZE` allocations.
+pub fn read_frame_limited<R: Read, T: DeserializeOwned>(reader: &mut R, limit: usize) -> Result<T, FrameError> {
+    let mut header = [0u8; 4];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Err(FrameError::Closed),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    // A legacy JSON message starts with '{' or '"'; no valid frame length does
+    if header[0] == b'{' || header[0] == b'"' {
+        return Err(FrameError::Unframed);
+    }
+
+    let len = u32::from_be_bytes(header) as usize;
+    if len > limit {
+        return Err(FrameError::TooLarge(len, limit));
+    }
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(serde_json::from_slice(&payload)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let mut buf = Vec::new();
+        let frame = RequestFrame {
+            id: 7,
+            request: Request::ServiceStatus { name: "x".repeat(20_000) },
+        };
+        write_frame(&mut buf, &frame).unwrap();
+        write_frame(&mut buf, &RequestFrame { id: 8, request: Request::Ping }).unwrap();
+
+        let mut reader = io::Cursor::new(buf);
+        let first: RequestFrame = read_frame(&mut reader).unwrap();
+        assert_eq!(first.id, 7);
+        assert!(matches!(first.request, Request::ServiceStatus { ref name } if name.len() == 20_000));
+        let second: RequestFrame = read_frame(&mut reader).unwrap();
+        assert!(matches!(second.request, Request::Ping));
+        assert!(matches!(read_frame::<_, RequestFrame>(&mut reader), Err(FrameError::Closed)));
+    }
+
+    #[test]
+    fn test_unframed_peer_detected() {
+        let mut reader = io::Cursor::new(br#"{"Error":{"message":"Invalid request"}}"#.to_vec());
+        assert!(matches!(read_frame::<_, ResponseFrame>(&mut reader), Err(FrameError::Unframed)));
+
+        let mut oversized = io::Cursor::new(vec![0x7f, 0xff, 0xff, 0xff]);
+        assert!(matches!(read_frame::<_, ResponseFrame>(&mut oversized), Err(FrameError::TooLarge(_, _))));
+
+        // A small limit rejects the frame before its payload is read
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &RequestFrame { id: 1, request: Request::ServiceStatus { name: "x".repeat(100) } }).unwrap();
+        let mut reader = io::Cursor::new(buf);
+        assert!(matches!(
+            read_frame_limited::<_, RequestFrame>(&mut reader, 64),
+            Err(FrameError::TooLarge(_, 64))
+        ));
+        assert_eq!(reader.position(), 4);
+    }
 }
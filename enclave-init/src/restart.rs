@@ -0,0 +1,193 @@
+//! Restart rate limiting and backoff.
+//!
+//! Every start of a service is recorded. When more than `StartLimitBurst`
+//! starts fall within `StartLimitIntervalSec`, automatic restarts stop and the
+//! service enters the `failed` state until `initctl reset-failed`. With
+//! `RestartSecMax` set, the delay between automatic restarts grows by
+//! `RestartSecMultiplier` after each restart, up to the maximum.
+
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Start rate limit and backoff settings of a service.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct StartLimitConfig {
+    /// Maximum number of starts within `StartLimitIntervalSec`
+    #[serde(default = "default_start_limit_burst")]
+    pub start_limit_burst: u32,
+    /// Window for `StartLimitBurst`, in seconds (0 disables rate limiting)
+    #[serde(default = "default_start_limit_interval_sec")]
+    pub start_limit_interval_sec: u64,
+    /// Upper bound of the restart delay; enables exponential backoff
+    #[serde(default)]
+    pub restart_sec_max: Option<u64>,
+    /// Factor applied to the restart delay after each restart
+    #[serde(default = "default_restart_sec_multiplier")]
+    pub restart_sec_multiplier: f64,
+}
+
+impl Default for StartLimitConfig {
+    fn default() -> Self {
+        Self {
+            start_limit_burst: default_start_limit_burst(),
+            start_limit_interval_sec: default_start_limit_interval_sec(),
+            restart_sec_max: None,
+            restart_sec_multiplier: default_restart_sec_multiplier(),
+        }
+    }
+}
+
+impl StartLimitConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.start_limit_burst == 0 {
+            return Err("StartLimitBurst must be at least 1".to_string());
+        }
+        if !(self.restart_sec_multiplier >= 1.0 && self.restart_sec_multiplier.is_finite()) {
+            return Err(format!(
+                "RestartSecMultiplier must be at least 1.0, got {}",
+                self.restart_sec_multiplier
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_start_limit_burst() -> u32 {
+    5
+}
+
+fn default_start_limit_interval_sec() -> u64 {
+    10
+}
+
+fn default_restart_sec_multiplier() -> f64 {
+    2.0
+}
+
+/// Start history of a service.
+#[derive(Debug, Default)]
+pub struct RestartTracker {
+    starts: VecDeque<Instant>,
+    /// Automatic restarts since the service last ran stably
+    backoff_steps: u32,
+}
+
+impl RestartTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forget the start history and backoff.
+    pub fn reset(&mut self) {
+        self.starts.clear();
+        self.backoff_steps = 0;
+    }
+
+    /// Record a start of the service.
+    pub fn record_start(&mut self, config: &StartLimitConfig) {
+        let now = Instant::now();
+        self.starts.push_back(now);
+        self.prune(config, now);
+    }
+
+    /// Record an automatic restart, growing the backoff delay.
+    pub fn record_restart(&mut self) {
+        self.backoff_steps = self.backoff_steps.saturating_add(1);
+    }
+
+    /// Record an exit. A run longer than `StartLimitIntervalSec` resets the backoff.
+    pub fn record_exit(&mut self, run_time: Duration, config: &StartLimitConfig) {
+        if run_time >= Duration::from_secs(config.start_limit_interval_sec) {
+            self.backoff_steps = 0;
+        }
+    }
+
+    /// Whether another start would exceed `StartLimitBurst`.
+    pub fn limit_hit(&mut self, config: &StartLimitConfig) -> bool {
+        if config.start_limit_interval_sec == 0 {
+            return false;
+        }
+        self.prune(config, Instant::now());
+        self.starts.len() >= config.start_limit_burst as usize
+    }
+
+    /// Delay before the next automatic restart.
+    pub fn delay(&self, restart_sec: u64, config: &StartLimitConfig) -> Duration {
+        let base = restart_sec as f64;
+        let secs = match config.restart_sec_max {
+            Some(max) if self.backoff_steps > 0 => {
+                let factor = config.restart_sec_multiplier.powi(self.backoff_steps.min(64) as i32);
+                (base * factor).min(max as f64).max(base)
+            }
+            _ => base,
+        };
+        Duration::from_secs_f64(secs)
+    }
+
+    fn prune(&mut self, config: &StartLimitConfig, now: Instant) {
+        let window = Duration::from_secs(config.start_limit_interval_sec);
+        while let Some(first) = self.starts.front() {
+            if now.duration_since(*first) > window {
+                self.starts.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_start_limit() {
+        let config = StartLimitConfig {
+            start_limit_burst: 3,
+            ..Default::default()
+        };
+        let mut tracker = RestartTracker::new();
+        for _ in 0..2 {
+            tracker.record_start(&config);
+            assert!(!tracker.limit_hit(&config));
+        }
+        tracker.record_start(&config);
+        assert!(tracker.limit_hit(&config));
+
+        tracker.reset();
+        assert!(!tracker.limit_hit(&config));
+
+        let unlimited = StartLimitConfig {
+            start_limit_interval_sec: 0,
+            ..config
+        };
+        for _ in 0..10 {
+            tracker.record_start(&unlimited);
+        }
+        assert!(!tracker.limit_hit(&unlimited));
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let fixed = StartLimitConfig::default();
+        let backoff = StartLimitConfig {
+            restart_sec_max: Some(30),
+            ..Default::default()
+        };
+        let mut tracker = RestartTracker::new();
+        assert_eq!(tracker.delay(5, &backoff), Duration::from_secs(5));
+
+        tracker.record_restart();
+        assert_eq!(tracker.delay(5, &backoff), Duration::from_secs(10));
+        assert_eq!(tracker.delay(5, &fixed), Duration::from_secs(5));
+
+        tracker.record_restart();
+        tracker.record_restart();
+        assert_eq!(tracker.delay(5, &backoff), Duration::from_secs(30));
+
+        tracker.record_exit(Duration::from_secs(60), &backoff);
+        assert_eq!(tracker.delay(5, &backoff), Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,361 @@
+//! Log rotation and retention.
+//!
+//! When a service's log file grows past `max_size`, it becomes segment 1
+//! (`<service>.log.1`, or `<service>.log.1.gz` when compressed) and older
+//! segments move up by one. Segments beyond `max_files`, the oldest segments
+//! while the service's logs take more than `max_total_size` bytes, and
+//! segments last written more than `max_age_sec` ago are deleted. A global
+//! disk budget across all services removes the oldest segments of any
+//! service first. The current log file is never deleted by retention.
+//!
+//! The global policy comes from `init.yaml`; service files can override each
+//! setting with `LogMaxSize`, `LogMaxFiles`, `LogMaxTotalSize`,
+//! `LogMaxAgeSec` and `LogCompression`.
+
+use crate::config::{Compression, InitConfig};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::Deserialize;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Rotation and retention settings of one service.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionPolicy {
+    /// Size of the current log file that triggers a rotation
+    pub max_size: u64,
+    /// Files kept, the current one included
+    pub max_files: usize,
+    /// Size of all files of the service; 0 for no limit
+    pub max_total_size: u64,
+    /// Age of the newest record in a segment; 0 for no limit
+    pub max_age_sec: u64,
+    pub compression: Compression,
+}
+
+impl RetentionPolicy {
+    /// Policy of services that do not override it.
+    pub fn from_config(config: &InitConfig) -> Self {
+        Self {
+            max_size: config.max_log_size,
+            max_files: config.max_log_files,
+            max_total_size: config.max_log_total_size,
+            max_age_sec: config.max_log_age_sec,
+            compression: config.log_compression,
+        }
+    }
+}
+
+/// Overrides of the global policy in a service file.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct LogRetentionConfig {
+    #[serde(default)]
+    pub log_max_size: Option<u64>,
+    #[serde(default)]
+    pub log_max_files: Option<usize>,
+    #[serde(default)]
+    pub log_max_total_size: Option<u64>,
+    #[serde(default)]
+    pub log_max_age_sec: Option<u64>,
+    #[serde(default)]
+    pub log_compression: Option<Compression>,
+}
+
+impl LogRetentionConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.log_max_size == Some(0) {
+            return Err("LogMaxSize must be greater than 0".to_string());
+        }
+        if self.log_max_files == Some(0) {
+            return Err("LogMaxFiles must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// The global policy with this service's overrides applied.
+    pub fn resolve(&self, global: &RetentionPolicy) -> RetentionPolicy {
+        RetentionPolicy {
+            max_size: self.log_max_size.unwrap_or(global.max_size),
+            max_files: self.log_max_files.unwrap_or(global.max_files),
+            max_total_size: self.log_max_total_size.unwrap_or(global.max_total_size),
+            max_age_sec: self.log_max_age_sec.unwrap_or(global.max_age_sec),
+            compression: self.log_compression.unwrap_or(global.compression),
+        }
+    }
+}
+
+/// A rotated log file.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub path: PathBuf,
+    /// 1 for the most recently rotated
+    pub index: usize,
+    pub compressed: bool,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// Path of segment `index` of `log_path`.
+pub fn segment_path(log_path: &Path, index: usize, compressed: bool) -> PathBuf {
+    let mut name = log_path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", index));
+    if compressed {
+        name.push(".gz");
+    }
+    log_path.with_file_name(name)
+}
+
+/// The rotated segments of `log_path`, newest first.
+pub fn segments(log_path: &Path) -> Vec<Segment> {
+    let (Some(dir), Some(file_name)) = (log_path.parent(), log_path.file_name().and_then(|n| n.to_str())) else {
+        return Vec::new();
+    };
+    let prefix = format!("{}.", file_name);
+    let mut segments: Vec<Segment> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let rest = name.strip_prefix(&prefix)?;
+            let (index, compressed) = match rest.strip_suffix(".gz") {
+                Some(index) => (index, true),
+                None => (rest, false),
+            };
+            let index = index.parse::<usize>().ok().filter(|&i| i > 0)?;
+            let metadata = entry.metadata().ok()?;
+            Some(Segment {
+                path: entry.path(),
+                index,
+                compressed,
+                size: metadata.len(),
+                modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            })
+        })
+        .collect();
+    segments.sort_by_key(|s| s.index);
+    segments
+}
+
+/// Move the segments of `log_path` up by one, making room for segment 1.
+/// Segments that would go past `max_files` are deleted.
+pub fn shift(log_path: &Path, max_files: usize) {
+    for segment in segments(log_path).iter().rev() {
+        if segment.index + 1 >= max_files {
+            let _ = fs::remove_file(&segment.path);
+        } else {
+            let _ = fs::rename(&segment.path, segment_path(log_path, segment.index + 1, segment.compressed));
+        }
+    }
+}
+
+/// Replace `path` with a gzip-compressed `<path>.gz`.
+pub fn compress(path: &Path) -> io::Result<PathBuf> {
+    let mut gz_name = path.file_name().unwrap_or_default().to_os_string();
+    gz_name.push(".gz");
+    let gz_path = path.with_file_name(gz_name);
+    let tmp_path = gz_path.with_extension("gz.tmp");
+
+    let mut encoder = GzEncoder::new(File::create(&tmp_path)?, flate2::Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::rename(&tmp_path, &gz_path)?;
+    fs::remove_file(path)?;
+    Ok(gz_path)
+}
+
+/// Contents of a segment, decompressed if needed.
+pub fn read_segment(segment: &Segment) -> io::Result<String> {
+    let mut content = String::new();
+    if segment.compressed {
+        GzDecoder::new(File::open(&segment.path)?).read_to_string(&mut content)?;
+    } else {
+        File::open(&segment.path)?.read_to_string(&mut content)?;
+    }
+    Ok(content)
+}
+
+/// Delete the segments of `log_path` that `policy` no longer keeps.
+/// Returns the number of segments deleted.
+pub fn enforce(log_path: &Path, policy: &RetentionPolicy, now: SystemTime) -> usize {
+    let current_size = fs::metadata(log_path).map(|m| m.len()).unwrap_or(0);
+    let mut total = current_size;
+    let mut removed = 0;
+    for segment in segments(log_path) {
+        let too_old = policy.max_age_sec > 0
+            && now.duration_since(segment.modified).unwrap_or_default() > Duration::from_secs(policy.max_age_sec);
+        let too_big = policy.max_total_size > 0 && total + segment.size > policy.max_total_size;
+        if segment.index >= policy.max_files || too_old || too_big {
+            if fs::remove_file(&segment.path).is_ok() {
+                removed += 1;
+            }
+        } else {
+            total += segment.size;
+        }
+    }
+    removed
+}
+
+/// Delete the oldest segments in `log_dir`, of any service, until all log
+/// files there take at most `budget` bytes. Returns the segments deleted.
+pub fn enforce_budget(log_dir: &Path, budget: u64) -> Vec<PathBuf> {
+    let mut total = 0;
+    let mut rotated = Vec::new();
+    for entry in fs::read_dir(log_dir).into_iter().flatten().flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.ends_with(".log") {
+            total += metadata.len();
+        } else if is_segment_name(&name) {
+            total += metadata.len();
+            rotated.push((metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH), entry.path(), metadata.len()));
+        }
+    }
+
+    rotated.sort();
+    let mut removed = Vec::new();
+    for (_, path, size) in rotated {
+        if total <= budget {
+            break;
+        }
+        if fs::remove_file(&path).is_ok() {
+            total -= size;
+            removed.push(path);
+        }
+    }
+    removed
+}
+
+/// Whether `name` looks like `<service>.log.<N>` or `<service>.log.<N>.gz`.
+fn is_segment_name(name: &str) -> bool {
+    let name = name.strip_suffix(".gz").unwrap_or(name);
+    match name.rsplit_once('.') {
+        Some((base, index)) => {
+            base.ends_with(".log") && !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn write(path: &Path, content: &str) {
+        File::create(path).unwrap().write_all(content.as_bytes()).unwrap();
+    }
+
+    fn policy(max_files: usize) -> RetentionPolicy {
+        RetentionPolicy {
+            max_size: 1024,
+            max_files,
+            max_total_size: 0,
+            max_age_sec: 0,
+            compression: Compression::Gzip,
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let overrides = LogRetentionConfig {
+            log_max_files: Some(2),
+            log_compression: Some(Compression::None),
+            ..Default::default()
+        };
+        let resolved = overrides.resolve(&policy(5));
+        assert_eq!((resolved.max_size, resolved.max_files), (1024, 2));
+        assert_eq!(resolved.compression, Compression::None);
+        assert!(LogRetentionConfig { log_max_files: Some(0), ..Default::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn test_segments_and_shift() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let log = dir.join("web.log");
+        write(&log, "current\n");
+        write(&dir.join("web.log.1"), "one\n");
+        write(&dir.join("web.log.2.gz"), "");
+        write(&dir.join("web.log.3"), "");
+        write(&dir.join("web.log.bak"), "");
+        write(&dir.join("webapp.log.1"), "");
+
+        let found: Vec<(usize, bool)> = segments(&log).iter().map(|s| (s.index, s.compressed)).collect();
+        assert_eq!(found, vec![(1, false), (2, true), (3, false)]);
+
+        shift(&log, 3);
+        let found: Vec<(usize, bool)> = segments(&log).iter().map(|s| (s.index, s.compressed)).collect();
+        assert_eq!(found, vec![(2, false)]);
+    }
+
+    #[test]
+    fn test_compress_and_read() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let log = dir.join("web.log");
+        let content = "line\n".repeat(1000);
+        write(&segment_path(&log, 1, false), &content);
+
+        let gz = compress(&segment_path(&log, 1, false)).unwrap();
+        assert_eq!(gz, dir.join("web.log.1.gz"));
+        let segment = &segments(&log)[0];
+        assert!(segment.compressed && segment.size < content.len() as u64);
+        assert_eq!(read_segment(segment).unwrap(), content);
+    }
+
+    #[test]
+    fn test_enforce() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let log = dir.join("web.log");
+        write(&log, &"x".repeat(100));
+        for i in 1..=4 {
+            write(&segment_path(&log, i, false), &"x".repeat(100));
+        }
+
+        // The current file and segments 1 and 2 fit in 300 bytes
+        let by_size = RetentionPolicy { max_total_size: 300, ..policy(10) };
+        assert_eq!(enforce(&log, &by_size, SystemTime::now()), 2);
+        assert_eq!(segments(&log).len(), 2);
+
+        // Every segment was last written more than a minute before `now`
+        let by_age = RetentionPolicy { max_age_sec: 60, ..policy(10) };
+        assert_eq!(enforce(&log, &by_age, SystemTime::now() + Duration::from_secs(120)), 2);
+        assert!(log.exists());
+    }
+
+    #[test]
+    fn test_enforce_budget() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        write(&dir.join("web.log"), &"x".repeat(100));
+        write(&dir.join("db.log"), &"x".repeat(100));
+        write(&dir.join("web.log.1.gz"), &"x".repeat(100));
+        write(&dir.join("db.log.1"), &"x".repeat(100));
+        write(&dir.join("notes.txt"), &"x".repeat(1000));
+
+        let removed = enforce_budget(dir, 300);
+        assert_eq!(removed.len(), 1);
+        assert!(dir.join("web.log").exists() && dir.join("db.log").exists());
+        assert_eq!(enforce_budget(dir, 0).len(), 1);
+        assert!(dir.join("notes.txt").exists());
+    }
+
+    #[test]
+    fn test_is_segment_name() {
+        assert!(is_segment_name("web.log.1"));
+        assert!(is_segment_name("web@a.b.log.12.gz"));
+        assert!(!is_segment_name("web.log"));
+        assert!(!is_segment_name("web.log.gz"));
+        assert!(!is_segment_name("forward.spool"));
+    }
+}
@@ -0,0 +1,437 @@
+//! Per-service sandboxing with mount and network namespaces.
+//!
+//! All options are opt-in and applied in the forked child, before the switch
+//! to the service's user. Mount options give the service a private mount
+//! namespace that follows init's mounts (as a slave) but whose own changes
+//! are invisible to the rest of the system. `PrivateNetwork` gives it a
+//! network namespace with only a loopback interface; VSOCK is not
+//! namespaced and stays reachable.
+
+use anyhow::{anyhow, Context, Result};
+use nix::errno::Errno;
+use nix::mount::{mount, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::statvfs::statvfs;
+use serde::Deserialize;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use crate::logger::Logger;
+
+/// Empty, mode 000 file mounted over inaccessible files.
+const INACCESSIBLE_FILE: &str = "/run/init/inaccessible";
+
+/// Private `/tmp` directories, mounted with a fresh tmpfs.
+const TMP_DIRECTORIES: [&str; 2] = ["/tmp", "/var/tmp"];
+
+/// API file systems left writable by `ProtectSystem = "strict"`.
+const API_FILESYSTEMS: [&str; 3] = ["/dev", "/proc", "/sys"];
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProtectSystem {
+    #[default]
+    No,
+    /// /usr and /boot are read-only
+    Yes,
+    /// /usr, /boot and /etc are read-only
+    Full,
+    /// Everything except /dev, /proc, /sys and the service's own
+    /// runtime/state directories is read-only
+    Strict,
+}
+
+impl ProtectSystem {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ProtectSystem::No => "no",
+            ProtectSystem::Yes => "yes",
+            ProtectSystem::Full => "full",
+            ProtectSystem::Strict => "strict",
+        }
+    }
+
+    fn read_only_paths(&self) -> &'static [&'static str] {
+        match self {
+            ProtectSystem::No => &[],
+            ProtectSystem::Yes => &["/usr", "/boot", "/efi"],
+            ProtectSystem::Full => &["/usr", "/boot", "/efi", "/etc"],
+            ProtectSystem::Strict => &["/"],
+        }
+    }
+}
+
+/// Sandbox settings of a service.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct SandboxConfig {
+    /// Private, empty /tmp and /var/tmp
+    #[serde(default)]
+    pub private_tmp: bool,
+    /// Paths made read-only; a `-` prefix ignores missing paths
+    #[serde(default, deserialize_with = "crate::credentials::deserialize_words")]
+    pub read_only_paths: Vec<String>,
+    /// Paths made inaccessible; a `-` prefix ignores missing paths
+    #[serde(default, deserialize_with = "crate::credentials::deserialize_words")]
+    pub inaccessible_paths: Vec<String>,
+    /// Writable bind mounts, "SOURCE[:DEST]"; a `-` prefix ignores a missing source
+    #[serde(default, deserialize_with = "crate::credentials::deserialize_words")]
+    pub bind_paths: Vec<String>,
+    /// Network namespace with only a loopback interface
+    #[serde(default)]
+    pub private_network: bool,
+    #[serde(default)]
+    pub protect_system: ProtectSystem,
+}
+
+/// A path option with an optional `-` prefix.
+fn optional_path(value: &str) -> (PathBuf, bool) {
+    match value.strip_prefix('-') {
+        Some(path) => (PathBuf::from(path), true),
+        None => (PathBuf::from(value), false),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct BindPath {
+    source: PathBuf,
+    destination: PathBuf,
+    optional: bool,
+}
+
+impl BindPath {
+    fn parse(value: &str) -> Result<Self, String> {
+        let (spec, optional) = optional_path(value);
+        let spec = spec.to_string_lossy().into_owned();
+        let (source, destination) = match spec.split_once(':') {
+            Some((source, destination)) => (PathBuf::from(source), PathBuf::from(destination)),
+            None => (PathBuf::from(&spec), PathBuf::from(&spec)),
+        };
+        if !source.is_absolute() || !destination.is_absolute() {
+            return Err(format!("BindPaths entry '{}' must use absolute paths", value));
+        }
+        Ok(Self {
+            source,
+            destination,
+            optional,
+        })
+    }
+}
+
+impl SandboxConfig {
+    fn uses_mount_namespace(&self) -> bool {
+        self.private_tmp
+            || self.protect_system != ProtectSystem::No
+            || !self.read_only_paths.is_empty()
+            || !self.inaccessible_paths.is_empty()
+            || !self.bind_paths.is_empty()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for value in self.read_only_paths.iter().chain(self.inaccessible_paths.iter()) {
+            if !optional_path(value).0.is_absolute() {
+                return Err(format!("path '{}' must be absolute", value));
+            }
+        }
+        for value in &self.bind_paths {
+            BindPath::parse(value)?;
+        }
+        Ok(())
+    }
+
+    /// Enabled options, for `initctl status`.
+    pub fn describe(&self) -> Vec<String> {
+        let mut options = Vec::new();
+        if self.private_tmp {
+            options.push("PrivateTmp".to_string());
+        }
+        if self.private_network {
+            options.push("PrivateNetwork".to_string());
+        }
+        if self.protect_system != ProtectSystem::No {
+            options.push(format!("ProtectSystem={}", self.protect_system.as_str()));
+        }
+        for (name, paths) in [
+            ("ReadOnlyPaths", &self.read_only_paths),
+            ("InaccessiblePaths", &self.inaccessible_paths),
+            ("BindPaths", &self.bind_paths),
+        ] {
+            if !paths.is_empty() {
+                options.push(format!("{}={}", name, paths.join(" ")));
+            }
+        }
+        options
+    }
+
+    /// Enter the sandbox in the forked child. `writable` lists the service's
+    /// runtime and state directories, kept writable under `ProtectSystem=strict`.
+    pub fn apply_in_child(&self, writable: &[PathBuf]) -> Result<()> {
+        if self.private_network {
+            unshare(CloneFlags::CLONE_NEWNET).context("Failed to create network namespace")?;
+            loopback_up().context("Failed to bring up loopback interface")?;
+        }
+        if !self.uses_mount_namespace() {
+            return Ok(());
+        }
+
+        unshare(CloneFlags::CLONE_NEWNS).context("Failed to create mount namespace")?;
+        mount(None::<&str>, "/", None::<&str>, MsFlags::MS_REC | MsFlags::MS_SLAVE, None::<&str>)
+            .context("Failed to make mounts private")?;
+
+        // Create mount points while the file system is still writable
+        let mut binds = Vec::new();
+        for value in &self.bind_paths {
+            let bind = BindPath::parse(value).map_err(|e| anyhow!(e))?;
+            if !bind.source.exists() {
+                if bind.optional {
+                    continue;
+                }
+                return Err(anyhow!("BindPaths source {} does not exist", bind.source.display()));
+            }
+            if !bind.destination.exists() {
+                if bind.source.is_dir() {
+                    fs::create_dir_all(&bind.destination)?;
+                } else {
+                    if let Some(parent) = bind.destination.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::File::create(&bind.destination)?;
+                }
+            }
+            binds.push(bind);
+        }
+        if !self.inaccessible_paths.is_empty() {
+            fs::create_dir_all("/run/init")?;
+            OpenOptions::new().create(true).append(true).open(INACCESSIBLE_FILE)?;
+            fs::set_permissions(INACCESSIBLE_FILE, std::os::unix::fs::PermissionsExt::from_mode(0o000))?;
+        }
+
+        for path in self.protect_system.read_only_paths() {
+            if Path::new(path).exists() {
+                make_read_only(Path::new(path), true)?;
+            }
+        }
+        if self.protect_system == ProtectSystem::Strict {
+            for path in API_FILESYSTEMS.iter().map(Path::new).chain(writable.iter().map(|p| p.as_path())) {
+                if path.exists() {
+                    make_read_only(path, false)?;
+                }
+            }
+        }
+
+        for value in &self.read_only_paths {
+            if let Some(path) = existing_path(value)? {
+                make_read_only(&path, true)?;
+            }
+        }
+
+        for value in &self.inaccessible_paths {
+            if let Some(path) = existing_path(value)? {
+                make_inaccessible(&path)?;
+            }
+        }
+
+        for bind in &binds {
+            mount(
+                Some(&bind.source),
+                &bind.destination,
+                None::<&str>,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                None::<&str>,
+            )
+            .with_context(|| format!("Failed to bind {} to {}", bind.source.display(), bind.destination.display()))?;
+            make_read_only(&bind.destination, false)?;
+        }
+
+        if self.private_tmp {
+            for path in TMP_DIRECTORIES.iter().map(Path::new).filter(|p| p.is_dir()) {
+                mount(
+                    Some("tmpfs"),
+                    path,
+                    Some("tmpfs"),
+                    MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                    Some("mode=1777"),
+                )
+                .with_context(|| format!("Failed to mount private {}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Resolve a `[-]PATH` option. None if the path is missing and optional.
+fn existing_path(value: &str) -> Result<Option<PathBuf>> {
+    let (path, optional) = optional_path(value);
+    if path.exists() {
+        Ok(Some(path))
+    } else if optional {
+        Ok(None)
+    } else {
+        Err(anyhow!("{} does not exist", path.display()))
+    }
+}
+
+/// Make `path` and every mount below it read-only or writable again.
+fn make_read_only(path: &Path, read_only: bool) -> Result<()> {
+    let mounts = mount_points_under(&fs::read_to_string("/proc/self/mountinfo")?, path);
+    if !mounts.iter().any(|m| m == path) {
+        // Only mount points carry their own flags
+        mount(Some(path), path, None::<&str>, MsFlags::MS_BIND | MsFlags::MS_REC, None::<&str>)
+            .with_context(|| format!("Failed to bind {}", path.display()))?;
+    }
+
+    let mut targets = vec![path.to_path_buf()];
+    targets.extend(mounts.into_iter().filter(|m| m != path));
+    for (i, target) in targets.iter().enumerate() {
+        if let Err(e) = remount(target, read_only) {
+            if i == 0 {
+                return Err(e.context(format!("Failed to remount {}", target.display())));
+            }
+            // Mounts hidden under a later mount cannot be reached by path
+            if e.downcast_ref::<Errno>() != Some(&Errno::ENOENT) {
+                Logger::warn(&format!("Failed to remount {}: {:#}", target.display(), e));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Change the read-only flag of one mount, keeping its other flags.
+fn remount(path: &Path, read_only: bool) -> Result<()> {
+    let mut flags = MsFlags::from_bits_truncate(statvfs(path)?.flags().bits())
+        & (MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC | MsFlags::MS_NOATIME
+            | MsFlags::MS_NODIRATIME | MsFlags::MS_RELATIME);
+    if read_only {
+        flags |= MsFlags::MS_RDONLY;
+    }
+    mount(None::<&str>, path, None::<&str>, MsFlags::MS_BIND | MsFlags::MS_REMOUNT | flags, None::<&str>)?;
+    Ok(())
+}
+
+fn make_inaccessible(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        mount(
+            Some("tmpfs"),
+            path,
+            Some("tmpfs"),
+            MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            Some("mode=000"),
+        )
+    } else {
+        mount(Some(INACCESSIBLE_FILE), path, None::<&str>, MsFlags::MS_BIND, None::<&str>)
+    }
+    .with_context(|| format!("Failed to make {} inaccessible", path.display()))?;
+    if !path.is_dir() {
+        remount(path, true)?;
+    }
+    Ok(())
+}
+
+/// Mount points at or below `path` listed in `mountinfo`, parents first.
+fn mount_points_under(mountinfo: &str, path: &Path) -> Vec<PathBuf> {
+    let mut mounts: Vec<PathBuf> = mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|field| PathBuf::from(unescape_mountinfo(field)))
+        .filter(|mount_point| mount_point.starts_with(path))
+        .collect();
+    mounts.sort_by_key(|m| m.components().count());
+    mounts.dedup();
+    mounts
+}
+
+/// Undo the octal escapes (`\040` for a space) of mountinfo fields.
+fn unescape_mountinfo(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let digits = std::str::from_utf8(&bytes[i + 1..i + 4]).unwrap_or("");
+            if let Ok(byte) = u8::from_str_radix(digits, 8) {
+                out.push(byte);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Set the loopback interface of a new network namespace up.
+fn loopback_up() -> Result<()> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in request.ifr_name.iter_mut().zip(b"lo\0") {
+        *dst = *src as libc::c_char;
+    }
+    request.ifr_ifru.ifru_flags = (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+    let ret = unsafe { libc::ioctl(fd, libc::SIOCSIFFLAGS as _, &request) };
+    let result = if ret < 0 { Err(std::io::Error::last_os_error().into()) } else { Ok(()) };
+    unsafe { libc::close(fd) };
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sandbox_config() {
+        let config: SandboxConfig = toml::from_str(
+            "PrivateTmp = true\nProtectSystem = \"strict\"\nReadOnlyPaths = \"/etc/app -/opt/missing\"\nBindPaths = [\"/var/data:/data\"]",
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert!(config.uses_mount_namespace());
+        assert_eq!(
+            config.describe(),
+            vec![
+                "PrivateTmp",
+                "ProtectSystem=strict",
+                "ReadOnlyPaths=/etc/app -/opt/missing",
+                "BindPaths=/var/data:/data"
+            ]
+        );
+
+        assert!(!SandboxConfig::default().uses_mount_namespace());
+        assert!(SandboxConfig::default().describe().is_empty());
+        let relative = SandboxConfig {
+            inaccessible_paths: vec!["secrets".to_string()],
+            ..Default::default()
+        };
+        assert!(relative.validate().is_err());
+    }
+
+    #[test]
+    fn test_bind_path() {
+        assert_eq!(
+            BindPath::parse("-/src:/dst").unwrap(),
+            BindPath {
+                source: PathBuf::from("/src"),
+                destination: PathBuf::from("/dst"),
+                optional: true,
+            }
+        );
+        assert_eq!(BindPath::parse("/same").unwrap().destination, PathBuf::from("/same"));
+        assert!(BindPath::parse("/src:dst").is_err());
+    }
+
+    #[test]
+    fn test_mount_points_under() {
+        let mountinfo = "22 1 0:21 / / rw - ext4 /dev/root rw\n\
+                         23 22 0:22 / /var/lib rw - ext4 /dev/vdb rw\n\
+                         24 23 0:23 / /var/lib/my\\040app rw - tmpfs tmpfs rw\n\
+                         25 22 0:24 / /variable rw - tmpfs tmpfs rw\n";
+        assert_eq!(
+            mount_points_under(mountinfo, Path::new("/var")),
+            vec![PathBuf::from("/var/lib"), PathBuf::from("/var/lib/my app")]
+        );
+        assert_eq!(mount_points_under(mountinfo, Path::new("/")).len(), 4);
+    }
+}
@@ -9,7 +9,8 @@ use nix::sys::socket::{connect, send, socket, AddressFamily, MsgFlags, SockFlag,
 use nix::unistd::close;
 use std::os::unix::io::RawFd;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
 
 /// VSock log stream subscriber - streams logs to a VSock connection on the host.
 ///
@@ -106,10 +107,84 @@ impl LogSubscriber for VsockLogStreamer {
     }
 }
 
+/// Log subscriber that forwards lines into a bounded channel.
+///
+/// Used to follow a service log over a control connection, where the
+/// connection thread drains the channel and writes response frames. A
+/// follower that falls `queue_size` lines behind is dropped instead of
+/// growing init's memory; its receiver then reports a disconnect once drained.
+pub struct ChannelLogSubscriber {
+    sender: Mutex<Option<SyncSender<String>>>,
+    active: AtomicBool,
+    overflowed: AtomicBool,
+}
+
+impl ChannelLogSubscriber {
+    pub fn new(queue_size: usize) -> (Arc<Self>, Receiver<String>) {
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        let subscriber = Arc::new(Self {
+            sender: Mutex::new(Some(sender)),
+            active: AtomicBool::new(true),
+            overflowed: AtomicBool::new(false),
+        });
+        (subscriber, receiver)
+    }
+
+    /// Stop receiving lines; the logger drops the subscriber on its next line.
+    pub fn close(&self) {
+        self.active.store(false, Ordering::Relaxed);
+        if let Ok(mut sender) = self.sender.lock() {
+            *sender = None;
+        }
+    }
+
+    /// Whether the subscriber was dropped for falling behind
+    pub fn overflowed(&self) -> bool {
+        self.overflowed.load(Ordering::Relaxed)
+    }
+}
+
+impl LogSubscriber for ChannelLogSubscriber {
+    fn on_log(&self, line: &str) {
+        let sent = self
+            .sender
+            .lock()
+            .ok()
+            .and_then(|sender| sender.as_ref().map(|s| s.try_send(line.to_string())));
+        match sent {
+            Some(Ok(())) | None => {}
+            Some(Err(TrySendError::Full(_))) => {
+                self.overflowed.store(true, Ordering::Relaxed);
+                self.close();
+            }
+            Some(Err(TrySendError::Disconnected(_))) => self.close(),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_channel_subscriber_drops_slow_reader() {
+        let (subscriber, receiver) = ChannelLogSubscriber::new(2);
+        subscriber.on_log("one");
+        subscriber.on_log("two");
+        assert!(subscriber.is_active());
+        subscriber.on_log("three");
+        assert!(!subscriber.is_active() && subscriber.overflowed());
+
+        // Queued lines are still delivered, then the channel reports the drop
+        assert_eq!(receiver.try_recv().unwrap(), "one");
+        assert_eq!(receiver.try_recv().unwrap(), "two");
+        assert_eq!(receiver.try_recv(), Err(mpsc::TryRecvError::Disconnected));
+    }
+
     #[test]
     fn test_streamer_creation_fails_without_listener() {
         // This should fail because there's no listener
@@ -0,0 +1,195 @@
+//! Target units.
+//!
+//! A `.target` unit groups services, such as `network.target` or
+//! `maintenance.target`. A service belongs to a target when the target lists
+//! it in `Wants` or `Requires`, or when the service lists the target in
+//! `WantedBy` or `RequiredBy`; targets can also include other targets.
+//! Targets have no process of their own. Init boots into `default_target`
+//! and `initctl isolate` switches to another target, stopping the services it
+//! does not include. In a service's dependency lists a target stands for its
+//! services: `After = ["network.target"]` orders the service after all of
+//! them, `Requires = ["network.target"]` requires those the target requires.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+const SUFFIX: &str = ".target";
+
+/// A `.target` unit file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TargetConfig {
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Services and targets started with this target
+    #[serde(default, deserialize_with = "crate::credentials::deserialize_words")]
+    pub wants: Vec<String>,
+    /// Like `Wants`; services that require the target require these
+    #[serde(default, deserialize_with = "crate::credentials::deserialize_words")]
+    pub requires: Vec<String>,
+    /// Whether `initctl isolate` may switch to this target
+    #[serde(default = "default_true")]
+    pub allow_isolate: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// A group of services.
+#[derive(Debug, Clone)]
+pub struct TargetUnit {
+    pub name: String,
+    pub config: TargetConfig,
+    /// Members from `Wants` and from services' `WantedBy`
+    pub wants: Vec<String>,
+    /// Members from `Requires` and from services' `RequiredBy`
+    pub requires: Vec<String>,
+}
+
+impl TargetUnit {
+    pub fn new(name: String, config: TargetConfig) -> Self {
+        Self {
+            wants: config.wants.clone(),
+            requires: config.requires.clone(),
+            name,
+            config,
+        }
+    }
+}
+
+pub fn parse_target_file(path: &Path) -> Result<TargetConfig> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read target file: {:?}", path))?;
+
+    toml::from_str(&content).with_context(|| format!("Failed to parse target file: {:?}", path))
+}
+
+/// Whether `name` refers to a target rather than a service.
+pub fn is_target(name: &str) -> bool {
+    name.ends_with(SUFFIX)
+}
+
+/// Target name with the `.target` suffix, which may be omitted on the
+/// command line: `maintenance` -> `maintenance.target`.
+pub fn unit_name(name: &str) -> String {
+    if is_target(name) {
+        name.to_string()
+    } else {
+        format!("{}{}", name, SUFFIX)
+    }
+}
+
+/// Services included in `name`, through nested targets, sorted. With
+/// `required_only`, only members listed in `Requires` are followed.
+pub fn services_of(targets: &HashMap<String, TargetUnit>, name: &str, required_only: bool) -> Vec<String> {
+    let mut services = HashSet::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![name.to_string()];
+    while let Some(target) = pending.pop() {
+        if !visited.insert(target.clone()) {
+            continue;
+        }
+        let Some(unit) = targets.get(&target) else {
+            continue;
+        };
+        let members = if required_only {
+            unit.requires.iter().collect::<Vec<_>>()
+        } else {
+            unit.requires.iter().chain(unit.wants.iter()).collect()
+        };
+        for member in members {
+            if is_target(member) {
+                pending.push(member.clone());
+            } else {
+                services.insert(member.clone());
+            }
+        }
+    }
+    let mut services: Vec<String> = services.into_iter().collect();
+    services.sort();
+    services
+}
+
+/// Replace the targets in a dependency list of service `owner` with their
+/// services, leaving out `owner` itself.
+pub fn expand_references(
+    names: &[String],
+    targets: &HashMap<String, TargetUnit>,
+    required_only: bool,
+    owner: &str,
+) -> Vec<String> {
+    let mut expanded: Vec<String> = Vec::new();
+    for name in names {
+        let services = if is_target(name) {
+            services_of(targets, name, required_only)
+        } else {
+            vec![name.clone()]
+        };
+        for service in services {
+            if service != owner && !expanded.contains(&service) {
+                expanded.push(service);
+            }
+        }
+    }
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(name: &str, wants: &[&str], requires: &[&str]) -> (String, TargetUnit) {
+        let config = TargetConfig {
+            description: None,
+            wants: wants.iter().map(|s| s.to_string()).collect(),
+            requires: requires.iter().map(|s| s.to_string()).collect(),
+            allow_isolate: true,
+        };
+        (name.to_string(), TargetUnit::new(name.to_string(), config))
+    }
+
+    #[test]
+    fn test_parse() {
+        let config: TargetConfig = toml::from_str(
+            "Description = \"Maintenance mode\"\nWants = \"sshd backup\"\nRequires = [\"network.target\"]\n",
+        )
+        .unwrap();
+        assert_eq!(config.description.as_deref(), Some("Maintenance mode"));
+        assert_eq!(config.wants, vec!["sshd", "backup"]);
+        assert_eq!(config.requires, vec!["network.target"]);
+        assert!(config.allow_isolate);
+
+        assert!(is_target("network.target"));
+        assert!(!is_target("network"));
+        assert_eq!(unit_name("maintenance"), "maintenance.target");
+        assert_eq!(unit_name("maintenance.target"), "maintenance.target");
+    }
+
+    #[test]
+    fn test_services_of() {
+        let targets: HashMap<String, TargetUnit> = [
+            target("network.target", &["dhcp"], &["vsock-proxy"]),
+            target("inference.target", &["model", "metrics"], &["network.target"]),
+            // Cycles between targets are harmless
+            target("loop.target", &["inference.target", "loop.target"], &[]),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(
+            services_of(&targets, "inference.target", false),
+            vec!["dhcp", "metrics", "model", "vsock-proxy"]
+        );
+        assert_eq!(services_of(&targets, "inference.target", true), vec!["vsock-proxy"]);
+        assert_eq!(services_of(&targets, "loop.target", false).len(), 4);
+        assert!(services_of(&targets, "missing.target", false).is_empty());
+
+        let after: Vec<String> = ["db", "network.target", "dhcp"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(expand_references(&after, &targets, false, "dhcp"), vec!["db", "vsock-proxy"]);
+        assert_eq!(expand_references(&after, &targets, true, "model"), vec!["db", "vsock-proxy", "dhcp"]);
+    }
+}
@@ -0,0 +1,130 @@
+//! Service templates.
+//!
+//! `worker@.service` is a template for the instances `worker@a`,
+//! `worker@b`, ... An instance exists when `worker@a.service` does, usually
+//! as the symlink to the template created by `initctl enable worker@a`, or
+//! when another service names it in `Requires`. Before an instance's file is
+//! parsed, its specifiers are replaced: `%i` by the instance (`a`), `%n` by
+//! the full name (`worker@a`), `%p` by the prefix (`worker`) and `%%` by a
+//! literal `%`.
+
+/// Split `worker@a` into `("worker", "a")`; a template name such as
+/// `worker@` has an empty instance. None for plain service names.
+pub fn split_instance(name: &str) -> Option<(&str, &str)> {
+    name.split_once('@')
+}
+
+/// Whether `name` is a template (`worker@`) rather than a service.
+pub fn is_template(name: &str) -> bool {
+    matches!(split_instance(name), Some((_, "")))
+}
+
+/// Template of an instance: `worker@a` -> `worker@`.
+pub fn template_of(name: &str) -> Option<String> {
+    match split_instance(name) {
+        Some((prefix, instance)) if !instance.is_empty() => Some(format!("{}@", prefix)),
+        _ => None,
+    }
+}
+
+/// Instance names end up in file names, log names and, through specifiers,
+/// in the service file itself, so they are restricted to a safe alphabet.
+pub fn validate_instance_name(name: &str) -> Result<(), String> {
+    let Some((prefix, instance)) = split_instance(name) else {
+        return Ok(());
+    };
+    if prefix.is_empty() {
+        return Err(format!("'{}' has no template name before '@'", name));
+    }
+    if instance.is_empty() {
+        return Err(format!("'{}' is a template, not an instance", name));
+    }
+    if let Some(c) = instance.chars().find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | ':'))) {
+        return Err(format!("instance name '{}' contains invalid character '{}'", instance, c));
+    }
+    Ok(())
+}
+
+/// Replace the specifiers in the text of an instance's service file.
+/// Unknown specifiers are left as they are.
+pub fn expand_specifiers(text: &str, name: &str) -> String {
+    let (prefix, instance) = split_instance(name).unwrap_or((name, ""));
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        match chars.peek() {
+            Some('i') => result.push_str(instance),
+            Some('n') => result.push_str(name),
+            Some('p') => result.push_str(prefix),
+            Some('%') => result.push('%'),
+            _ => {
+                result.push('%');
+                continue;
+            }
+        }
+        chars.next();
+    }
+    result
+}
+
+/// Replace references to a template (`worker@`) in a dependency list with
+/// all instances of it among `services`.
+pub fn expand_references(names: &[String], services: &[String]) -> Vec<String> {
+    let mut expanded = Vec::new();
+    for name in names {
+        if is_template(name) {
+            let mut instances: Vec<&String> = services
+                .iter()
+                .filter(|s| template_of(s).as_deref() == Some(name.as_str()))
+                .collect();
+            instances.sort();
+            expanded.extend(instances.into_iter().cloned());
+        } else {
+            expanded.push(name.clone());
+        }
+    }
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names() {
+        assert_eq!(split_instance("worker@a"), Some(("worker", "a")));
+        assert_eq!(split_instance("worker"), None);
+        assert!(is_template("worker@"));
+        assert!(!is_template("worker@a"));
+        assert_eq!(template_of("worker@a").as_deref(), Some("worker@"));
+        assert_eq!(template_of("worker@"), None);
+
+        assert!(validate_instance_name("worker@gpu-0.large:8080").is_ok());
+        assert!(validate_instance_name("plain").is_ok());
+        assert!(validate_instance_name("worker@").is_err());
+        assert!(validate_instance_name("@a").is_err());
+        assert!(validate_instance_name("worker@a\"b").is_err());
+        assert!(validate_instance_name("worker@../x").is_err());
+    }
+
+    #[test]
+    fn test_expand_specifiers() {
+        let text = "ExecStart = \"/usr/bin/%p --model %i --log /var/log/%n.log --pct 50%% %d\"";
+        assert_eq!(
+            expand_specifiers(text, "worker@llama"),
+            "ExecStart = \"/usr/bin/worker --model llama --log /var/log/worker@llama.log --pct 50% %d\""
+        );
+        assert_eq!(expand_specifiers("100%", "worker@a"), "100%");
+    }
+
+    #[test]
+    fn test_expand_references() {
+        let services: Vec<String> = ["worker@b", "worker@a", "other@a", "db"].iter().map(|s| s.to_string()).collect();
+        let names: Vec<String> = ["db", "worker@", "cache@"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(expand_references(&names, &services), vec!["db", "worker@a", "worker@b"]);
+    }
+}
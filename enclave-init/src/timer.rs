@@ -0,0 +1,461 @@
+//! Timer units.
+//!
+//! A `.timer` unit starts a service on a schedule: once `OnBootSec` after
+//! init started, `OnUnitActiveSec` after the service was last started, and at
+//! every time matching one of its `OnCalendar` expressions. Init's main loop
+//! evaluates timers; a service that is still running when its timer elapses
+//! is left alone. Calendar expressions are evaluated in UTC.
+
+use anyhow::{Context, Result};
+use crate::protocol::civil_from_days;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How far ahead a calendar expression is searched for its next match.
+const CALENDAR_SEARCH_DAYS: i64 = 5 * 366;
+
+/// A `.timer` unit file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TimerConfig {
+    /// Seconds after init started to trigger the service once
+    #[serde(default)]
+    pub on_boot_sec: Option<u64>,
+    /// Seconds after the service was last started to trigger it again
+    #[serde(default)]
+    pub on_unit_active_sec: Option<u64>,
+    /// Calendar expressions, e.g. "daily" or "Mon..Fri *-*-* 02:30"
+    #[serde(default, deserialize_with = "crate::hooks::deserialize_commands")]
+    pub on_calendar: Vec<String>,
+    /// Service to trigger; defaults to the unit's own name
+    #[serde(default)]
+    pub unit: Option<String>,
+}
+
+/// The clocks a timer is evaluated against.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    /// When init started
+    pub boot: Instant,
+    pub now: Instant,
+    /// Wall-clock time in seconds since the epoch
+    pub realtime: u64,
+}
+
+impl Clock {
+    pub fn new(boot: Instant) -> Self {
+        Self {
+            boot,
+            now: Instant::now(),
+            realtime: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+
+    /// Wall-clock time of a monotonic instant.
+    fn realtime_of(&self, instant: Instant) -> u64 {
+        if instant >= self.now {
+            self.realtime + instant.duration_since(self.now).as_secs()
+        } else {
+            self.realtime.saturating_sub(self.now.duration_since(instant).as_secs())
+        }
+    }
+}
+
+/// A scheduled service start.
+#[derive(Debug)]
+pub struct TimerUnit {
+    pub name: String,
+    pub config: TimerConfig,
+    calendars: Vec<CalendarSpec>,
+    /// Next match of the calendar expressions, in seconds since the epoch
+    next_calendar: Option<u64>,
+    last_trigger: Option<Instant>,
+    /// `OnBootSec` has elapsed
+    boot_elapsed: bool,
+}
+
+impl TimerUnit {
+    pub fn new(name: String, config: TimerConfig, clock: &Clock) -> Result<Self, String> {
+        if config.on_boot_sec.is_none() && config.on_unit_active_sec.is_none() && config.on_calendar.is_empty() {
+            return Err("no OnBootSec, OnUnitActiveSec or OnCalendar".to_string());
+        }
+        if config.on_unit_active_sec == Some(0) {
+            return Err("OnUnitActiveSec must be at least 1".to_string());
+        }
+        let calendars = config
+            .on_calendar
+            .iter()
+            .map(|c| CalendarSpec::parse(c))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut timer = Self {
+            name,
+            config,
+            calendars,
+            next_calendar: None,
+            last_trigger: None,
+            boot_elapsed: false,
+        };
+        timer.next_calendar = timer.calendar_after(clock.realtime);
+        Ok(timer)
+    }
+
+    /// Name of the service this timer starts.
+    pub fn service_name(&self) -> &str {
+        self.config.unit.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Take over the trigger history of the same timer before a reload.
+    pub fn adopt(&mut self, old: &TimerUnit) {
+        self.last_trigger = old.last_trigger;
+        self.boot_elapsed = old.boot_elapsed;
+    }
+
+    fn calendar_after(&self, time: u64) -> Option<u64> {
+        self.calendars.iter().filter_map(|c| c.next_after(time)).min()
+    }
+
+    /// Next `OnBootSec`/`OnUnitActiveSec` elapse. `last_active` is when the
+    /// service was last started, by this timer or otherwise.
+    fn next_monotonic(&self, clock: &Clock, last_active: Option<Instant>) -> Option<Instant> {
+        let on_boot = self
+            .config
+            .on_boot_sec
+            .filter(|_| !self.boot_elapsed)
+            .map(|sec| clock.boot + Duration::from_secs(sec));
+        let reference = last_active.max(self.last_trigger);
+        let on_active = self
+            .config
+            .on_unit_active_sec
+            .zip(reference)
+            .map(|(sec, since)| since + Duration::from_secs(sec));
+        on_boot.into_iter().chain(on_active).min()
+    }
+
+    pub fn is_due(&self, clock: &Clock, last_active: Option<Instant>) -> bool {
+        self.next_monotonic(clock, last_active).is_some_and(|t| t <= clock.now)
+            || self.next_calendar.is_some_and(|t| t <= clock.realtime)
+    }
+
+    /// Record that the timer elapsed and schedule its next elapse.
+    pub fn trigger(&mut self, clock: &Clock) {
+        if let Some(sec) = self.config.on_boot_sec {
+            if clock.boot + Duration::from_secs(sec) <= clock.now {
+                self.boot_elapsed = true;
+            }
+        }
+        if self.next_calendar.is_some_and(|t| t <= clock.realtime) {
+            self.next_calendar = self.calendar_after(clock.realtime);
+        }
+        self.last_trigger = Some(clock.now);
+    }
+
+    /// Next elapse in seconds since the epoch, None if the timer will not elapse again.
+    pub fn next_elapse(&self, clock: &Clock, last_active: Option<Instant>) -> Option<u64> {
+        let monotonic = self.next_monotonic(clock, last_active).map(|t| clock.realtime_of(t));
+        monotonic.into_iter().chain(self.next_calendar).min()
+    }
+
+    /// Last elapse in seconds since the epoch.
+    pub fn last_elapse(&self, clock: &Clock) -> Option<u64> {
+        self.last_trigger.map(|t| clock.realtime_of(t))
+    }
+
+    /// The timer's settings, for `initctl list-timers`.
+    pub fn describe(&self) -> Vec<String> {
+        let mut schedule = Vec::new();
+        if let Some(sec) = self.config.on_boot_sec {
+            schedule.push(format!("OnBootSec={}s", sec));
+        }
+        if let Some(sec) = self.config.on_unit_active_sec {
+            schedule.push(format!("OnUnitActiveSec={}s", sec));
+        }
+        for calendar in &self.config.on_calendar {
+            schedule.push(format!("OnCalendar={}", calendar));
+        }
+        schedule
+    }
+}
+
+pub fn parse_timer_file(path: &Path) -> Result<TimerConfig> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read timer file: {:?}", path))?;
+
+    toml::from_str(&content).with_context(|| format!("Failed to parse timer file: {:?}", path))
+}
+
+/// Values of one field of a calendar expression: `*`, `5`, `1..5`, `*/15`,
+/// `0/10` or a comma-separated list of these.
+#[derive(Debug)]
+struct Field {
+    /// Inclusive ranges with a step; `None` matches any value
+    ranges: Option<Vec<(u32, u32, u32)>>,
+}
+
+impl Field {
+    const ANY: Field = Field { ranges: None };
+
+    fn parse(value: &str, min: u32, max: u32) -> Result<Self, String> {
+        if value == "*" {
+            return Ok(Field::ANY);
+        }
+        let number = |s: &str| -> Result<u32, String> {
+            let n = s.parse::<u32>().map_err(|_| format!("invalid value '{}'", s))?;
+            if n < min || n > max {
+                return Err(format!("value {} out of range {}..{}", n, min, max));
+            }
+            Ok(n)
+        };
+
+        let mut ranges = Vec::new();
+        for part in value.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => {
+                    let step = step.parse::<u32>().ok().filter(|s| *s > 0);
+                    (range, Some(step.ok_or_else(|| format!("invalid step in '{}'", part))?))
+                }
+                None => (part, None),
+            };
+            let (start, end) = if range == "*" {
+                (min, max)
+            } else if let Some((start, end)) = range.split_once("..") {
+                (number(start)?, number(end)?)
+            } else if step.is_some() {
+                (number(range)?, max)
+            } else {
+                let n = number(range)?;
+                (n, n)
+            };
+            if start > end {
+                return Err(format!("empty range '{}'", part));
+            }
+            ranges.push((start, end, step.unwrap_or(1)));
+        }
+        Ok(Field { ranges: Some(ranges) })
+    }
+
+    fn value(value: u32) -> Self {
+        Field {
+            ranges: Some(vec![(value, value, 1)]),
+        }
+    }
+
+    fn matches(&self, value: u32) -> bool {
+        match &self.ranges {
+            None => true,
+            Some(ranges) => ranges
+                .iter()
+                .any(|&(start, end, step)| value >= start && value <= end && (value - start).is_multiple_of(step)),
+        }
+    }
+}
+
+/// A parsed `OnCalendar` expression: `[WEEKDAYS] [YEAR-MONTH-DAY] [HOUR:MINUTE[:SECOND]]`.
+#[derive(Debug)]
+struct CalendarSpec {
+    /// Allowed weekdays, Monday = 0
+    weekdays: [bool; 7],
+    year: Field,
+    month: Field,
+    day: Field,
+    hour: Field,
+    minute: Field,
+    second: Field,
+}
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+impl CalendarSpec {
+    fn parse(expression: &str) -> Result<Self, String> {
+        let normalized = match expression.trim().to_ascii_lowercase().as_str() {
+            "minutely" => "*-*-* *:*:00".to_string(),
+            "hourly" => "*-*-* *:00:00".to_string(),
+            "daily" => "*-*-* 00:00:00".to_string(),
+            "weekly" => "mon *-*-* 00:00:00".to_string(),
+            "monthly" => "*-*-01 00:00:00".to_string(),
+            "yearly" | "annually" => "*-01-01 00:00:00".to_string(),
+            other => other.to_string(),
+        };
+        Self::parse_normalized(&normalized).map_err(|e| format!("invalid calendar expression '{}': {}", expression, e))
+    }
+
+    fn parse_normalized(expression: &str) -> Result<Self, String> {
+        let mut spec = CalendarSpec {
+            weekdays: [true; 7],
+            year: Field::ANY,
+            month: Field::ANY,
+            day: Field::ANY,
+            hour: Field::value(0),
+            minute: Field::value(0),
+            second: Field::value(0),
+        };
+
+        let mut words = expression.split_whitespace().peekable();
+        if let Some(word) = words.peek() {
+            if word.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                spec.weekdays = parse_weekdays(word)?;
+                words.next();
+            }
+        }
+
+        for word in words {
+            if word.contains(':') {
+                let parts: Vec<&str> = word.split(':').collect();
+                if parts.len() < 2 || parts.len() > 3 {
+                    return Err(format!("invalid time '{}'", word));
+                }
+                spec.hour = Field::parse(parts[0], 0, 23)?;
+                spec.minute = Field::parse(parts[1], 0, 59)?;
+                spec.second = Field::parse(parts.get(2).copied().unwrap_or("00"), 0, 59)?;
+            } else if word.contains('-') {
+                let parts: Vec<&str> = word.split('-').collect();
+                let (year, month, day) = match parts.as_slice() {
+                    [year, month, day] => (*year, *month, *day),
+                    [month, day] => ("*", *month, *day),
+                    _ => return Err(format!("invalid date '{}'", word)),
+                };
+                spec.year = Field::parse(year, 1970, 9999)?;
+                spec.month = Field::parse(month, 1, 12)?;
+                spec.day = Field::parse(day, 1, 31)?;
+            } else {
+                return Err(format!("unexpected '{}'", word));
+            }
+        }
+        Ok(spec)
+    }
+
+    /// First matching time strictly after `time`, in seconds since the epoch.
+    fn next_after(&self, time: u64) -> Option<u64> {
+        let start = time as i64 + 1;
+        let first_day = start.div_euclid(86400);
+        for day in first_day..first_day + CALENDAR_SEARCH_DAYS {
+            let (year, month, mday) = civil_from_days(day);
+            let weekday = (day + 3).rem_euclid(7) as usize;
+            if !(self.weekdays[weekday]
+                && self.year.matches(year as u32)
+                && self.month.matches(month)
+                && self.day.matches(mday))
+            {
+                continue;
+            }
+            let earliest = if day == first_day { start.rem_euclid(86400) as u32 } else { 0 };
+            if let Some(seconds) = self.first_time_of_day(earliest) {
+                return Some((day * 86400) as u64 + seconds as u64);
+            }
+        }
+        None
+    }
+
+    /// First matching second of a day at or after `earliest`.
+    fn first_time_of_day(&self, earliest: u32) -> Option<u32> {
+        for hour in (0..24).filter(|h| self.hour.matches(*h)) {
+            if (hour + 1) * 3600 <= earliest {
+                continue;
+            }
+            for minute in (0..60).filter(|m| self.minute.matches(*m)) {
+                if hour * 3600 + (minute + 1) * 60 <= earliest {
+                    continue;
+                }
+                for second in (0..60).filter(|s| self.second.matches(*s)) {
+                    let seconds = hour * 3600 + minute * 60 + second;
+                    if seconds >= earliest {
+                        return Some(seconds);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Parse `mon`, `mon,wed` or `mon..fri`.
+fn parse_weekdays(value: &str) -> Result<[bool; 7], String> {
+    let index = |name: &str| {
+        WEEKDAYS
+            .iter()
+            .position(|w| name.starts_with(w))
+            .ok_or_else(|| format!("invalid weekday '{}'", name))
+    };
+    let mut weekdays = [false; 7];
+    for part in value.split(',') {
+        match part.split_once("..") {
+            Some((start, end)) => {
+                let (start, end) = (index(start)?, index(end)?);
+                if start > end {
+                    return Err(format!("empty weekday range '{}'", part));
+                }
+                weekdays[start..=end].iter_mut().for_each(|w| *w = true);
+            }
+            None => weekdays[index(part)?] = true,
+        }
+    }
+    Ok(weekdays)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-02-28 23:59:30 UTC, a Wednesday
+    const T: u64 = 1709164770;
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(T as i64 / 86400), (2024, 2, 28));
+        assert_eq!(civil_from_days(T as i64 / 86400 + 1), (2024, 2, 29));
+    }
+
+    #[test]
+    fn test_calendar_next_after() {
+        let next = |expr: &str| CalendarSpec::parse(expr).unwrap().next_after(T).unwrap();
+
+        assert_eq!(next("minutely"), T + 30);
+        assert_eq!(next("daily"), T + 30);
+        assert_eq!(next("*-*-* 02:30"), T + 30 + 2 * 3600 + 1800);
+        // Thursday Feb 29 is skipped
+        assert_eq!(next("Fri,Sat 12:00"), T + 30 + 86400 + 12 * 3600);
+        assert_eq!(next("monthly"), T + 30 + 86400);
+        assert_eq!(next("*-02-29 00:00:00"), T + 30);
+        assert_eq!(next("*:0/15"), T + 30);
+        assert_eq!(next("Mon..Fri *-*-* *:*:45"), T + 15);
+
+        assert!(CalendarSpec::parse("Funday 10:00").is_err());
+        assert!(CalendarSpec::parse("*-13-01").is_err());
+        assert!(CalendarSpec::parse("25:00").is_err());
+        assert!(CalendarSpec::parse("*:*/0").is_err());
+    }
+
+    #[test]
+    fn test_timer_elapse() {
+        let boot = Instant::now();
+        let clock = Clock {
+            boot,
+            now: boot,
+            realtime: T,
+        };
+        let config: TimerConfig = toml::from_str("OnBootSec = 60\nOnUnitActiveSec = 300").unwrap();
+        let mut timer = TimerUnit::new("backup".to_string(), config, &clock).unwrap();
+        assert_eq!(timer.service_name(), "backup");
+        assert!(!timer.is_due(&clock, None));
+        assert_eq!(timer.next_elapse(&clock, None), Some(T + 60));
+
+        let later = Clock {
+            now: boot + Duration::from_secs(61),
+            realtime: T + 61,
+            ..clock
+        };
+        assert!(timer.is_due(&later, None));
+        timer.trigger(&later);
+        assert!(!timer.is_due(&later, None));
+        assert_eq!(timer.last_elapse(&later), Some(T + 61));
+        assert_eq!(timer.next_elapse(&later, None), Some(T + 361));
+
+        let empty = TimerConfig::default();
+        assert!(TimerUnit::new("x".to_string(), empty, &clock).is_err());
+    }
+}